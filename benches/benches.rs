@@ -3,7 +3,7 @@ use std::io::{self, Write};
 use std::time::Duration;
 
 use criterion::{black_box, criterion_group, criterion_main, Bencher, Criterion};
-use tracing_allocations::{trace_allocations, TracingAllocator};
+use tracing_allocations::{enable_in_scope, TracingAllocator};
 
 fn no_op_writer() -> impl Write {
     struct NoOpWriter;
@@ -22,6 +22,7 @@ fn no_op_writer() -> impl Write {
 }
 
 fn bench_no_tracing(b: &mut Bencher, allocator: &dyn GlobalAlloc, layout: Layout) {
+    let _guard = tracing_allocations::disabled();
     b.iter(|| unsafe {
         let ptr = black_box(allocator.alloc(layout));
         allocator.dealloc(ptr, layout);
@@ -30,7 +31,7 @@ fn bench_no_tracing(b: &mut Bencher, allocator: &dyn GlobalAlloc, layout: Layout
 
 fn bench_tracing(b: &mut Bencher, allocator: &dyn GlobalAlloc, layout: Layout) {
     b.iter(|| unsafe {
-        trace_allocations(|| {
+        enable_in_scope(|| {
             let ptr = black_box(allocator.alloc(layout));
             allocator.dealloc(ptr, layout);
         })
@@ -49,7 +50,7 @@ fn criterion_benchmark(c: &mut Criterion) {
     const LAYOUT: Layout = Layout::new::<[String; 128]>();
 
     const SYSTEM_ALLOCATOR: System = System;
-    const TRACING_ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+    static TRACING_ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
 
     let mut group = c.benchmark_group("allocation without actual tracing");
 
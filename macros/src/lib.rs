@@ -0,0 +1,405 @@
+//! Attribute macros for [`tracing-allocations`].
+//!
+//! These are re-exported by [`tracing-allocations`] when its `macros` feature
+//! is enabled; depend on that crate, rather than on this one.
+//!
+//! [`tracing-allocations`]: https://docs.rs/tracing-allocations
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Enable allocation tracing for the duration of each call to the annotated
+/// function, in the manner of `enable_in_scope`.
+///
+/// This opts functions into allocation tracing within broader regions in
+/// which it is disabled. The annotated function may be `async`, in which case
+/// tracing is enabled whenever its future is polled, on whichever thread that
+/// happens.
+///
+/// With the `span` argument, each call is also wrapped in an `INFO` span
+/// created with `traced_span!`, named after the function, so that it is traced
+/// by allocators configured with `with_marked_spans_only`. The name may be
+/// overridden with `span = "name"`.
+///
+/// ## Usage
+/// ```
+/// use tracing_allocations::trace_allocations;
+///
+/// #[trace_allocations]
+/// fn parse(input: &str) -> Vec<&str> {
+///     input.split(',').collect()
+/// }
+///
+/// #[trace_allocations(span = "handle")]
+/// async fn handle_request(body: String) -> usize {
+///     body.to_uppercase().len()
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn trace_allocations(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let function = match Function::parse(item) {
+        Ok(function) => function,
+        Err(error) => return error,
+    };
+    let span = match span_name(attr, &function) {
+        Ok(span) => span,
+        Err(error) => return error,
+    };
+    scoped(function, Scope::Traced, span)
+}
+
+/// Disable allocation tracing for the duration of each call to the annotated
+/// function, in the manner of `disable_in_scope`.
+///
+/// This is useful for helpers (e.g., for logging, metrics or serialization)
+/// whose allocations are noise; they can be marked once, instead of wrapping
+/// each of their call sites. The annotated function may be `async`, in which
+/// case tracing is disabled whenever its future is polled, on whichever thread
+/// that happens.
+///
+/// ## Usage
+/// ```
+/// use tracing_allocations::untraced;
+///
+/// #[untraced]
+/// fn report(values: &[u64]) -> String {
+///     format!("{:?}", values)
+/// }
+///
+/// #[untraced]
+/// async fn flush_metrics(metrics: Vec<u64>) -> usize {
+///     metrics.len()
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn untraced(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if let Some(token) = attr.into_iter().next() {
+        return compile_error("unexpected argument", token.span());
+    }
+    match Function::parse(item) {
+        Ok(function) => scoped(function, Scope::Untraced, None),
+        Err(error) => error,
+    }
+}
+
+/// Wrap each call to the annotated function in a span, and record the
+/// allocations it performs as fields of the span when the call returns.
+///
+/// This combines the span of `#[tracing::instrument]` with the counting of
+/// `count_allocations`: each call is wrapped in an `INFO` span, named after
+/// the function, with the following fields:
+/// - **`alloc_bytes`**: the total size of the blocks allocated by the call
+/// - **`alloc_count`**: the number of allocations and reallocations
+///   performed by the call
+///
+/// Unlike `#[tracing::instrument]`, the arguments of the function are not
+/// recorded. The name of the span may be overridden with `name = "name"`.
+/// Operations are counted as by `count_allocations`, so only those performed
+/// on the calling thread are counted; the annotated function may be `async`,
+/// in which case the operations performed whenever its future is polled are
+/// counted, on whichever thread that happens.
+///
+/// ## Usage
+/// ```
+/// use tracing_allocations::instrument_allocations;
+///
+/// #[instrument_allocations]
+/// fn parse(input: &str) -> Vec<&str> {
+///     input.split(',').collect()
+/// }
+///
+/// #[instrument_allocations(name = "handle")]
+/// async fn handle_request(body: String) -> usize {
+///     body.to_uppercase().len()
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn instrument_allocations(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let function = match Function::parse(item) {
+        Ok(function) => function,
+        Err(error) => return error,
+    };
+    let name = match instrument_name(attr, &function) {
+        Ok(name) => name,
+        Err(error) => return error,
+    };
+    let span = counted_span(name);
+    let body = if function.asyncness {
+        let future = path("::tracing_allocations::__count_in_span_async")
+            .chain(parens(TokenStream::from_iter([
+                span,
+                punct(','),
+                async_move(function.body.clone()),
+            ])))
+            .collect();
+        TokenStream::from_iter([future, parse(".await")])
+    } else {
+        let mut body = parse("let __tracing_allocations_span =");
+        body.extend(span);
+        body.extend(parse(
+            "; let __tracing_allocations_entered = __tracing_allocations_span.enter(); \
+             let __tracing_allocations_count = \
+             ::tracing_allocations::__count_in_span(&__tracing_allocations_span);",
+        ));
+        body.extend([TokenTree::Group(function.body.clone())]);
+        body
+    };
+    function.with_body(body)
+}
+
+/// Call `housekeeping` at the start of the annotated `main` function, and
+/// hold its guard until `main` returns.
+///
+/// With an argument, such as `#[tracing_allocations::main(System)]`, also
+/// declare a `TracingAllocator` wrapping the given allocator as the global
+/// allocator, as with `install!`. The attribute may be combined with others,
+/// such as `#[tokio::main]`, if it is applied first.
+///
+/// ## Usage
+/// ```
+/// use std::alloc::System;
+///
+/// #[tracing_allocations::main(System)]
+/// fn main() {
+///     /* your code here */
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn main(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let function = match Function::parse(item) {
+        Ok(function) => function,
+        Err(error) => return error,
+    };
+    let mut install = TokenStream::new();
+    if !attr.is_empty() {
+        install.extend(path("::tracing_allocations::install!"));
+        install.extend(parens(attr));
+        install.extend([TokenTree::Punct(Punct::new(';', Spacing::Alone))]);
+    }
+    let mut body =
+        parse("let __tracing_allocations_housekeeping = ::tracing_allocations::housekeeping();");
+    body.extend([TokenTree::Group(function.body.clone())]);
+    TokenStream::from_iter([install, function.with_body(body)])
+}
+
+/// Whether an attribute enables or disables allocation tracing.
+#[derive(Clone, Copy)]
+enum Scope {
+    Traced,
+    Untraced,
+}
+
+impl Scope {
+    /// The path of the function that returns a guard for this scope.
+    fn guard(self) -> &'static str {
+        match self {
+            Scope::Traced => "::tracing_allocations::enabled",
+            Scope::Untraced => "::tracing_allocations::disabled",
+        }
+    }
+
+    /// The path of the future combinator for this scope.
+    fn combinator(self) -> &'static str {
+        match self {
+            Scope::Traced => "::tracing_allocations::future::AllocationTracingExt::traced",
+            Scope::Untraced => "::tracing_allocations::future::AllocationTracingExt::untraced",
+        }
+    }
+}
+
+/// Wraps the body of `function` in `scope` and, if `span` is named, in a
+/// marked span of that name.
+fn scoped(function: Function, scope: Scope, span: Option<Literal>) -> TokenStream {
+    let body = if function.asyncness {
+        let future = path(scope.combinator())
+            .chain(parens(async_move(function.body.clone())))
+            .collect();
+        let future = match span {
+            Some(name) => path("::tracing_allocations::__tracing::Instrument::instrument")
+                .chain(parens(TokenStream::from_iter([
+                    future,
+                    punct(','),
+                    traced_span(name),
+                ])))
+                .collect(),
+            None => future,
+        };
+        TokenStream::from_iter([future, parse(".await")])
+    } else {
+        let mut body = TokenStream::new();
+        if let Some(name) = span {
+            body.extend(parse("let __tracing_allocations_span ="));
+            body.extend(traced_span(name));
+            body.extend(parse(
+                "; let __tracing_allocations_entered = __tracing_allocations_span.enter();",
+            ));
+        }
+        body.extend(parse("let __tracing_allocations_guard ="));
+        body.extend(path(scope.guard()));
+        body.extend(parse("();"));
+        body.extend([TokenTree::Group(function.body.clone())]);
+        body
+    };
+    function.with_body(body)
+}
+
+/// A function item, split around its body.
+struct Function {
+    /// The tokens preceding the body: attributes, visibility and signature.
+    signature: Vec<TokenTree>,
+    /// Whether the function is `async`.
+    asyncness: bool,
+    /// The name of the function.
+    name: Ident,
+    /// The body of the function.
+    body: Group,
+}
+
+impl Function {
+    /// Parses a function item.
+    fn parse(item: TokenStream) -> Result<Self, TokenStream> {
+        let mut signature: Vec<TokenTree> = item.into_iter().collect();
+        let body = match signature.pop() {
+            Some(TokenTree::Group(body)) if body.delimiter() == Delimiter::Brace => body,
+            other => {
+                let span = other.map_or_else(Span::call_site, |token| token.span());
+                return Err(compile_error("expected a function with a body", span));
+            }
+        };
+        let position = signature.iter().position(
+            |token| matches!(token, TokenTree::Ident(ident) if ident.to_string() == "fn"),
+        );
+        let name = match position.and_then(|position| signature.get(position + 1)) {
+            Some(TokenTree::Ident(name)) => name.clone(),
+            _ => return Err(compile_error("expected a function", body.span())),
+        };
+        let asyncness = signature[..position.unwrap_or(0)]
+            .iter()
+            .any(|token| matches!(token, TokenTree::Ident(ident) if ident.to_string() == "async"));
+        Ok(Self {
+            signature,
+            asyncness,
+            name,
+            body,
+        })
+    }
+
+    /// Reassembles the function, replacing the contents of its body.
+    fn with_body(self, body: TokenStream) -> TokenStream {
+        let mut group = Group::new(Delimiter::Brace, body);
+        group.set_span(self.body.span());
+        self.signature
+            .into_iter()
+            .chain([TokenTree::Group(group)])
+            .collect()
+    }
+}
+
+/// Parses the attribute arguments `span` or `span = "name"`, into the name of
+/// the requested span; `None` if there are no arguments.
+fn span_name(attr: TokenStream, function: &Function) -> Result<Option<Literal>, TokenStream> {
+    let attr: Vec<TokenTree> = attr.into_iter().collect();
+    match &attr[..] {
+        [] => Ok(None),
+        [TokenTree::Ident(key)] if key.to_string() == "span" => {
+            let name = function.name.to_string();
+            let mut name = Literal::string(name.trim_start_matches("r#"));
+            name.set_span(function.name.span());
+            Ok(Some(name))
+        }
+        [TokenTree::Ident(key), TokenTree::Punct(eq), TokenTree::Literal(name)]
+            if key.to_string() == "span"
+                && eq.as_char() == '='
+                && name.to_string().starts_with('"') =>
+        {
+            Ok(Some(name.clone()))
+        }
+        [first, ..] => Err(compile_error(
+            "expected `span` or `span = \"name\"`",
+            first.span(),
+        )),
+    }
+}
+
+/// Parses the attribute arguments `name = "name"`, into the name of the
+/// span; the name of the function if there are no arguments.
+fn instrument_name(attr: TokenStream, function: &Function) -> Result<Literal, TokenStream> {
+    let attr: Vec<TokenTree> = attr.into_iter().collect();
+    match &attr[..] {
+        [] => {
+            let name = function.name.to_string();
+            let mut name = Literal::string(name.trim_start_matches("r#"));
+            name.set_span(function.name.span());
+            Ok(name)
+        }
+        [TokenTree::Ident(key), TokenTree::Punct(eq), TokenTree::Literal(name)]
+            if key.to_string() == "name"
+                && eq.as_char() == '='
+                && name.to_string().starts_with('"') =>
+        {
+            Ok(name.clone())
+        }
+        [first, ..] => Err(compile_error("expected `name = \"name\"`", first.span())),
+    }
+}
+
+/// An `INFO` span of the given name, with empty `alloc_bytes` and
+/// `alloc_count` fields.
+fn counted_span(name: Literal) -> TokenStream {
+    path("::tracing_allocations::__tracing::span!")
+        .chain(parens(TokenStream::from_iter([
+            parse("::tracing_allocations::__tracing::Level::INFO,"),
+            TokenStream::from(TokenTree::Literal(name)),
+            parse(
+                ", alloc_bytes = ::tracing_allocations::__tracing::field::Empty, \
+                 alloc_count = ::tracing_allocations::__tracing::field::Empty",
+            ),
+        ])))
+        .collect()
+}
+
+/// `::tracing_allocations::traced_span!(Level::INFO, name)`.
+fn traced_span(name: Literal) -> TokenStream {
+    path("::tracing_allocations::traced_span!")
+        .chain(parens(TokenStream::from_iter([
+            parse("::tracing_allocations::__tracing::Level::INFO,"),
+            TokenStream::from(TokenTree::Literal(name)),
+        ])))
+        .collect()
+}
+
+/// `async move { .. }`, with the given block.
+fn async_move(block: Group) -> TokenStream {
+    TokenStream::from_iter([parse("async move"), TokenTree::Group(block).into()])
+}
+
+/// `::core::compile_error!(message);`, reported at `span`.
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    path("::core::compile_error!")
+        .chain(parens(TokenTree::Literal(Literal::string(message)).into()))
+        .chain([TokenTree::Punct(Punct::new(';', Spacing::Alone))])
+        .map(|mut token| {
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
+
+/// The tokens of `path`.
+fn path(path: &str) -> impl Iterator<Item = TokenTree> {
+    parse(path).into_iter()
+}
+
+/// A single punctuation character.
+fn punct(ch: char) -> TokenStream {
+    TokenTree::Punct(Punct::new(ch, Spacing::Alone)).into()
+}
+
+/// `tokens`, in parentheses.
+fn parens(tokens: TokenStream) -> [TokenTree; 1] {
+    [TokenTree::Group(Group::new(Delimiter::Parenthesis, tokens))]
+}
+
+/// Parses a fragment of code that is known to be valid.
+fn parse(code: &str) -> TokenStream {
+    code.parse().expect("invalid tokens")
+}
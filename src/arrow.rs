@@ -0,0 +1,324 @@
+//! Allocation events as Apache Arrow IPC streams.
+//!
+//! [`ArrowLayer`] gathers the events it observes into record batches, one
+//! column per field, and writes them in the Arrow IPC streaming format, so
+//! that long traces may be loaded by polars or pyarrow without parsing, and
+//! compress far better than traces in textual formats.
+
+use std::{
+    io::{BufWriter, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use arrow_array::{
+    builder::{BooleanBuilder, StringBuilder, StringDictionaryBuilder, UInt64Builder},
+    types::Int8Type,
+    ArrayRef, RecordBatch,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::event::AllocationEvent;
+
+/// The default number of events in each record batch.
+const BATCH_SIZE: usize = 8192;
+
+/// A [`Layer`] that writes the allocation events it observes as an Apache
+/// Arrow IPC stream, in record batches with the following columns:
+/// - **`kind`: dictionary of [`i8`] to [`str`]**  
+///   "alloc", "alloc_zeroed", "dealloc" or "realloc"
+/// - **`addr`: [`u64`]**  
+///   the address of the block; for reallocations, of the new block
+/// - **`size`: [`u64`]**  
+///   the size of the block; for reallocations, of the new block
+/// - **`align`, `usable_size`, `old_addr`, `old_size`, `zeroed`,
+///   `timestamp_ns`, `span_id`, `sample_rate`, `sample_interval`, `count`,
+///   `age_ns`, `age_events`: nullable**  
+///   the optional fields of [`AllocationEvent`], of the same names and types
+/// - **`thread`: [`u64`]**  
+///   the number of the thread that performed the operation, in the order in
+///   which threads first performed a traced operation, starting from 1
+/// - **`caller`: nullable [`str`]**  
+///   the code that requested the operation; null unless requested (with
+///   `Detail::Caller`)
+///
+/// Events are gathered into batches of 8192 events, or of the number given to
+/// [`ArrowLayer::with_batch_size`], each of which is written once it is full.
+/// The stream is completed when the layer and all [`ArrowHandle`]s to it are
+/// dropped, or when [`ArrowHandle::finish`] is called. Events that are not
+/// allocation events are ignored.
+///
+/// Requires the `arrow` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::ArrowLayer;
+///
+/// let file = std::fs::File::create("allocations.arrows").unwrap();
+/// let layer = ArrowLayer::new(file).unwrap();
+/// let stream = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// stream.finish().unwrap();
+/// ```
+///
+/// ```python
+/// import polars as pl
+/// pl.read_ipc_stream("allocations.arrows")
+/// ```
+#[derive(Clone, Debug)]
+pub struct ArrowLayer {
+    handle: ArrowHandle,
+}
+
+impl ArrowLayer {
+    /// Constructs a new `ArrowLayer`, which writes its stream to `writer`.
+    ///
+    /// Fails if the schema of the stream cannot be written.
+    pub fn new<W>(writer: W) -> Result<Self, ArrowError>
+    where
+        W: Write + Send + 'static,
+    {
+        let stream = crate::disable_in_scope(|| -> Result<_, ArrowError> {
+            let schema = schema();
+            let writer: Box<dyn Write + Send> = Box::new(writer);
+            let writer = StreamWriter::try_new(BufWriter::new(writer), &schema)?;
+            Ok(Stream {
+                writer,
+                schema,
+                batch_size: BATCH_SIZE,
+                batch: Batch::default(),
+                finished: false,
+            })
+        })?;
+        Ok(Self {
+            handle: ArrowHandle {
+                stream: Arc::new(Mutex::new(stream)),
+            },
+        })
+    }
+
+    /// Gather events into batches of `size` events, rather than 8192.
+    pub fn with_batch_size(self, size: usize) -> Self {
+        self.handle.stream().batch_size = size.max(1);
+        self
+    }
+
+    /// A handle through which to flush or finish the stream of this layer.
+    pub fn handle(&self) -> ArrowHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for ArrowLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(decoded) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let mut stream = self.handle.stream();
+            if stream.finished {
+                return;
+            }
+            stream.batch.push(event, &decoded);
+            if stream.batch.len >= stream.batch_size {
+                let _ = stream.write_batch();
+            }
+        });
+    }
+}
+
+/// A handle to the stream of an [`ArrowLayer`].
+///
+/// Handles are cheap to clone, and all clones refer to the same stream.
+#[derive(Clone)]
+pub struct ArrowHandle {
+    stream: Arc<Mutex<Stream>>,
+}
+
+impl ArrowHandle {
+    /// Writes the events gathered so far as a record batch, even if it is not
+    /// full, and flushes the stream.
+    pub fn flush(&self) -> Result<(), ArrowError> {
+        crate::disable_in_scope(|| {
+            let mut stream = self.stream();
+            stream.write_batch()?;
+            stream.writer.flush()
+        })
+    }
+
+    /// Writes the events gathered so far, and terminates and flushes the
+    /// stream; subsequent events are not written.
+    pub fn finish(&self) -> Result<(), ArrowError> {
+        crate::disable_in_scope(|| self.stream().finish())
+    }
+
+    fn stream(&self) -> MutexGuard<'_, Stream> {
+        self.stream.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl core::fmt::Debug for ArrowHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ArrowHandle").finish_non_exhaustive()
+    }
+}
+
+/// The schema of the record batches written by an [`ArrowLayer`] (and by a
+/// `ParquetLayer`).
+pub(crate) fn schema() -> SchemaRef {
+    let optional = |name| Field::new(name, DataType::UInt64, true);
+    Arc::new(Schema::new(vec![
+        Field::new(
+            "kind",
+            DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8)),
+            false,
+        ),
+        Field::new("addr", DataType::UInt64, false),
+        Field::new("size", DataType::UInt64, false),
+        optional("align"),
+        optional("usable_size"),
+        optional("old_addr"),
+        optional("old_size"),
+        Field::new("zeroed", DataType::Boolean, true),
+        optional("timestamp_ns"),
+        optional("span_id"),
+        optional("sample_rate"),
+        optional("sample_interval"),
+        optional("count"),
+        optional("age_ns"),
+        optional("age_events"),
+        Field::new("thread", DataType::UInt64, false),
+        Field::new("caller", DataType::Utf8, true),
+    ]))
+}
+
+/// The stream written by an [`ArrowLayer`].
+struct Stream {
+    writer: StreamWriter<BufWriter<Box<dyn Write + Send>>>,
+    schema: SchemaRef,
+    /// The number of events in each record batch.
+    batch_size: usize,
+    /// The events gathered since the last record batch was written.
+    batch: Batch,
+    /// Whether the stream has been terminated.
+    finished: bool,
+}
+
+impl Stream {
+    /// Writes the events gathered so far as a record batch, if there are any.
+    fn write_batch(&mut self) -> Result<(), ArrowError> {
+        if self.finished || self.batch.len == 0 {
+            return Ok(());
+        }
+        let batch = self.batch.finish(self.schema.clone())?;
+        self.writer.write(&batch)
+    }
+
+    /// Writes the events gathered so far, and terminates and flushes the
+    /// stream.
+    fn finish(&mut self) -> Result<(), ArrowError> {
+        if !self.finished {
+            self.write_batch()?;
+            self.finished = true;
+            self.writer.finish()?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        crate::disable_in_scope(|| {
+            let _ = self.finish();
+        });
+    }
+}
+
+/// The columns of a record batch being gathered.
+#[derive(Default)]
+pub(crate) struct Batch {
+    /// The number of events gathered.
+    pub(crate) len: usize,
+    kind: StringDictionaryBuilder<Int8Type>,
+    addr: UInt64Builder,
+    size: UInt64Builder,
+    align: UInt64Builder,
+    usable_size: UInt64Builder,
+    old_addr: UInt64Builder,
+    old_size: UInt64Builder,
+    zeroed: BooleanBuilder,
+    timestamp_ns: UInt64Builder,
+    span_id: UInt64Builder,
+    sample_rate: UInt64Builder,
+    sample_interval: UInt64Builder,
+    count: UInt64Builder,
+    age_ns: UInt64Builder,
+    age_events: UInt64Builder,
+    thread: UInt64Builder,
+    callers: StringBuilder,
+    /// The caller of the event being gathered.
+    caller: String,
+}
+
+impl Batch {
+    /// Appends `decoded`, the decoding of `event`, which was performed by the
+    /// current thread.
+    pub(crate) fn push(&mut self, event: &Event<'_>, decoded: &AllocationEvent) {
+        self.caller.clear();
+        let caller = crate::event::caller_of(event, &mut self.caller);
+        self.len += 1;
+        self.kind.append_value(decoded.kind.as_str());
+        self.addr.append_value(decoded.addr);
+        self.size.append_value(decoded.size);
+        self.align.append_option(decoded.align);
+        self.usable_size.append_option(decoded.usable_size);
+        self.old_addr.append_option(decoded.old_addr);
+        self.old_size.append_option(decoded.old_size);
+        self.zeroed.append_option(decoded.zeroed);
+        self.timestamp_ns.append_option(decoded.timestamp_ns);
+        self.span_id.append_option(decoded.span_id);
+        self.sample_rate.append_option(decoded.sample_rate);
+        self.sample_interval.append_option(decoded.sample_interval);
+        self.count.append_option(decoded.count);
+        self.age_ns.append_option(decoded.age_ns);
+        self.age_events.append_option(decoded.age_events);
+        self.thread.append_value(crate::thread::number());
+        self.callers
+            .append_option(caller.then_some(self.caller.as_str()));
+    }
+
+    /// Takes the events gathered so far as a record batch of `schema`.
+    pub(crate) fn finish(&mut self, schema: SchemaRef) -> Result<RecordBatch, ArrowError> {
+        self.len = 0;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.kind.finish()),
+            Arc::new(self.addr.finish()),
+            Arc::new(self.size.finish()),
+            Arc::new(self.align.finish()),
+            Arc::new(self.usable_size.finish()),
+            Arc::new(self.old_addr.finish()),
+            Arc::new(self.old_size.finish()),
+            Arc::new(self.zeroed.finish()),
+            Arc::new(self.timestamp_ns.finish()),
+            Arc::new(self.span_id.finish()),
+            Arc::new(self.sample_rate.finish()),
+            Arc::new(self.sample_interval.finish()),
+            Arc::new(self.count.finish()),
+            Arc::new(self.age_ns.finish()),
+            Arc::new(self.age_events.finish()),
+            Arc::new(self.thread.finish()),
+            Arc::new(self.callers.finish()),
+        ];
+        RecordBatch::try_new(schema, columns)
+    }
+}
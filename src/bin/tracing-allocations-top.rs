@@ -0,0 +1,405 @@
+//! A live view of the heap of running processes.
+//!
+//! Listens on a Unix socket for the streams of `SocketLayer`s (or of
+//! `BinaryWriter`s) in instrumented processes, and shows, like `top`, their
+//! live bytes, their rates of allocation and deallocation, and the callers
+//! and tags that hold the most live bytes, updating in place. The blocks of
+//! a process are forgotten when its stream ends.
+//!
+//! Requires the `tui` feature:
+//!
+//! ```sh
+//! cargo install tracing-allocations --features tui
+//! tracing-allocations-top /tmp/allocations.sock
+//! ```
+//!
+//! and, in the instrumented process, with the `socket` feature:
+//!
+//! ```no_run
+//! use tracing_subscriber::prelude::*;
+//! use tracing_allocations::SocketLayer;
+//!
+//! let layer = SocketLayer::unix("/tmp/allocations.sock");
+//! tracing_subscriber::registry().with(layer).init();
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    process::ExitCode,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing_allocations::{
+    event::{AllocationEvent, AllocationKind},
+    BinaryReader,
+};
+
+const USAGE: &str = "\
+usage: tracing-allocations-top [-n <count>] [-d <seconds>] <socket>
+
+Listens on the Unix socket at <socket> for the allocation events of
+instrumented processes, as written by SocketLayer, and shows their live
+bytes, allocation rates, top callers, and top tags; a socket of `-` reads
+a single stream from standard input.
+
+options:
+    -n <count>    the number of callers, and of tags, to list (default: 20)
+    -d <seconds>  the delay between updates (default: 1)
+";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let mut limit = 20;
+    let mut delay = Duration::from_secs(1);
+    let mut socket = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => limit = n,
+                None => return usage("-n requires a number"),
+            },
+            "-d" => match args.next().and_then(|d| d.parse::<f64>().ok()) {
+                Some(d) if d > 0.0 && d.is_finite() => delay = Duration::from_secs_f64(d),
+                _ => return usage("-d requires a positive number of seconds"),
+            },
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if socket.is_none() => socket = Some(arg),
+            _ => return usage(&format!("unexpected argument `{}`", arg)),
+        }
+    }
+    let Some(socket) = socket else {
+        return usage("a socket is required");
+    };
+
+    let heap = Arc::new(Mutex::new(Heap::default()));
+    let started = match socket.as_str() {
+        "-" => {
+            let heap = heap.clone();
+            thread::spawn(move || receive(&heap, io::stdin().lock()));
+            Ok(())
+        }
+        path => listen(path, heap.clone()),
+    };
+    if let Err(error) = started {
+        eprintln!("tracing-allocations-top: {}: {}", socket, error);
+        return ExitCode::FAILURE;
+    }
+
+    let mut previous = Totals::default();
+    let mut last = Instant::now();
+    loop {
+        thread::sleep(delay);
+        let now = Instant::now();
+        let elapsed = now.duration_since(last).as_secs_f64();
+        last = now;
+        let screen = {
+            let heap = heap.lock().unwrap_or_else(PoisonError::into_inner);
+            let screen = heap.render(&previous, elapsed, limit);
+            previous = heap.totals.clone();
+            screen
+        };
+        let mut out = io::stdout().lock();
+        let result = out.write_all(screen.as_bytes()).and_then(|()| out.flush());
+        if result.is_err() {
+            // e.g., the terminal was closed
+            return ExitCode::SUCCESS;
+        }
+    }
+}
+
+/// Reports a misuse of the command line.
+fn usage(message: &str) -> ExitCode {
+    eprintln!("tracing-allocations-top: {}\n\n{}", message, USAGE);
+    ExitCode::from(2)
+}
+
+/// Listens on the Unix socket at `path`, replacing any stale socket there,
+/// and receives the stream of each process that connects to it on a thread
+/// of its own.
+#[cfg(unix)]
+fn listen(path: &str, heap: Arc<Mutex<Heap>>) -> io::Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let heap = heap.clone();
+            thread::spawn(move || receive(&heap, stream));
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen(_path: &str, _heap: Arc<Mutex<Heap>>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "sockets are only supported on Unix; read from standard input with `-`",
+    ))
+}
+
+/// Applies the events of `stream` to `heap`, until it ends or is malformed,
+/// then forgets the blocks of its process.
+fn receive(heap: &Mutex<Heap>, stream: impl Read) {
+    let process = {
+        let mut heap = heap.lock().unwrap_or_else(PoisonError::into_inner);
+        heap.processes += 1;
+        heap.connected += 1;
+        heap.processes
+    };
+    for record in BinaryReader::new(BufReader::new(stream)) {
+        let Ok(record) = record else {
+            break;
+        };
+        heap.lock().unwrap_or_else(PoisonError::into_inner).record(
+            process,
+            &record.event,
+            record.caller,
+            record.tag,
+        );
+    }
+    heap.lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .disconnect(process);
+}
+
+/// A live block.
+struct Block {
+    /// The size of the block, scaled by the weight of its event.
+    size: u64,
+    /// The number of blocks this block stands for.
+    weight: u64,
+    caller: Option<Arc<str>>,
+    tag: Option<Arc<str>>,
+}
+
+/// The running totals of a heap, from which rates are derived.
+#[derive(Clone, Default)]
+struct Totals {
+    allocations: u64,
+    bytes_allocated: u64,
+    deallocations: u64,
+    bytes_freed: u64,
+    /// The number of bytes allocated, by caller.
+    callers: HashMap<Option<Arc<str>>, u64>,
+    /// The number of bytes allocated, by tag.
+    tags: HashMap<Option<Arc<str>>, u64>,
+}
+
+/// The live blocks of a caller, or of a tag.
+#[derive(Clone, Copy, Default)]
+struct Live {
+    blocks: u64,
+    bytes: u64,
+}
+
+/// The state of the heaps of the connected processes.
+#[derive(Default)]
+struct Heap {
+    /// The number of processes that have connected.
+    processes: u64,
+    /// The number of processes still connected.
+    connected: u64,
+    /// The live blocks, by process and address.
+    live: HashMap<(u64, u64), Block>,
+    live_bytes: u64,
+    live_blocks: u64,
+    peak_bytes: u64,
+    /// The live blocks, by caller.
+    callers: HashMap<Option<Arc<str>>, Live>,
+    /// The live blocks, by tag.
+    tags: HashMap<Option<Arc<str>>, Live>,
+    totals: Totals,
+}
+
+impl Heap {
+    /// Applies the operation described by `event` to the heap of `process`.
+    fn record(
+        &mut self,
+        process: u64,
+        event: &AllocationEvent,
+        caller: Option<Arc<str>>,
+        tag: Option<Arc<str>>,
+    ) {
+        let weight = event.weight();
+        let scale = |n: u64| (n as f64 * weight).round() as u64;
+        match event.kind {
+            AllocationKind::Dealloc => {
+                self.free(process, event.addr);
+                return;
+            }
+            AllocationKind::Realloc => self.free(process, event.old_addr.unwrap_or(event.addr)),
+            _ => {}
+        }
+        let block = Block {
+            size: scale(event.size),
+            weight: scale(1),
+            caller,
+            tag,
+        };
+        self.totals.allocations += block.weight;
+        self.totals.bytes_allocated += block.size;
+        *self.totals.callers.entry(block.caller.clone()).or_default() += block.size;
+        *self.totals.tags.entry(block.tag.clone()).or_default() += block.size;
+        self.add(&block);
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+        if let Some(previous) = self.live.insert((process, event.addr), block) {
+            // the deallocation of the previous block was not traced
+            self.remove(&previous);
+        }
+    }
+
+    /// Frees the block of `process` at `addr`, if its allocation was traced.
+    fn free(&mut self, process: u64, addr: u64) {
+        if let Some(block) = self.live.remove(&(process, addr)) {
+            self.totals.deallocations += block.weight;
+            self.totals.bytes_freed += block.size;
+            self.remove(&block);
+        }
+    }
+
+    /// Forgets the blocks of `process`, whose stream has ended.
+    fn disconnect(&mut self, process: u64) {
+        self.connected -= 1;
+        let addrs: Vec<_> = self
+            .live
+            .keys()
+            .filter(|&&(owner, _)| owner == process)
+            .copied()
+            .collect();
+        for key in addrs {
+            if let Some(block) = self.live.remove(&key) {
+                self.remove(&block);
+            }
+        }
+    }
+
+    /// Counts `block` as live.
+    fn add(&mut self, block: &Block) {
+        self.live_bytes += block.size;
+        self.live_blocks += block.weight;
+        for (by, key) in [
+            (&mut self.callers, &block.caller),
+            (&mut self.tags, &block.tag),
+        ] {
+            let live = by.entry(key.clone()).or_default();
+            live.bytes += block.size;
+            live.blocks += block.weight;
+        }
+    }
+
+    /// Counts `block` as no longer live.
+    fn remove(&mut self, block: &Block) {
+        self.live_bytes -= block.size;
+        self.live_blocks -= block.weight;
+        for (by, key) in [
+            (&mut self.callers, &block.caller),
+            (&mut self.tags, &block.tag),
+        ] {
+            if let Some(live) = by.get_mut(key) {
+                live.bytes -= block.size;
+                live.blocks -= block.weight;
+                if live.blocks == 0 && live.bytes == 0 {
+                    by.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Renders the heap as a screen of the terminal, with rates measured
+    /// since `previous`, `elapsed` seconds ago, and the top `limit` callers
+    /// and tags.
+    fn render(&self, previous: &Totals, elapsed: f64, limit: usize) -> String {
+        let totals = &self.totals;
+        let rate = |now: u64, then: u64| (now.saturating_sub(then) as f64 / elapsed) as u64;
+        let mut screen = String::new();
+        // move to the top left corner, and clear the screen
+        screen.push_str("\x1b[H\x1b[2J");
+        screen.push_str(&format!(
+            "tracing-allocations-top: {} of {} processes connected\r\n\r\n",
+            self.connected, self.processes
+        ));
+        screen.push_str(&format!(
+            "live:  {:>12} in {:>10} blocks    peak: {}\r\n",
+            format_bytes(self.live_bytes),
+            self.live_blocks,
+            format_bytes(self.peak_bytes)
+        ));
+        screen.push_str(&format!(
+            "alloc: {:>12}/s {:>10} blocks/s\r\n",
+            format_bytes(rate(totals.bytes_allocated, previous.bytes_allocated)),
+            rate(totals.allocations, previous.allocations)
+        ));
+        screen.push_str(&format!(
+            "free:  {:>12}/s {:>10} blocks/s\r\n\r\n",
+            format_bytes(rate(totals.bytes_freed, previous.bytes_freed)),
+            rate(totals.deallocations, previous.deallocations)
+        ));
+        let panels = [
+            (
+                "CALLER",
+                &self.callers,
+                &totals.callers,
+                &previous.callers,
+                "<unknown>",
+            ),
+            (
+                "TAG",
+                &self.tags,
+                &totals.tags,
+                &previous.tags,
+                "<untagged>",
+            ),
+        ];
+        for (index, (heading, live, allocated, before, unknown)) in panels.into_iter().enumerate() {
+            if index > 0 {
+                screen.push_str("\r\n");
+            }
+            screen.push_str(&format!(
+                "\x1b[7m{:>12} {:>10} {:>14}  {:<40}\x1b[0m\r\n",
+                "LIVE", "BLOCKS", "ALLOC/S", heading
+            ));
+            let mut rows: Vec<_> = live.iter().collect();
+            rows.sort_by_key(|&(_, live)| Reverse(live.bytes));
+            for (key, live) in rows.into_iter().take(limit) {
+                let now = allocated.get(key).copied().unwrap_or(0);
+                let then = before.get(key).copied().unwrap_or(0);
+                screen.push_str(&format!(
+                    "{:>12} {:>10} {:>12}/s  {}\r\n",
+                    format_bytes(live.bytes),
+                    live.blocks,
+                    format_bytes(rate(now, then)),
+                    key.as_deref().unwrap_or(unknown)
+                ));
+            }
+        }
+        screen
+    }
+}
+
+/// Formats a number of bytes with a binary unit.
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", n),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
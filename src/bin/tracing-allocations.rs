@@ -0,0 +1,510 @@
+//! Offline analysis of recorded allocation traces.
+//!
+//! Reads traces written by `JsonLinesLayer` or `BinaryLayer` (or by a
+//! `BinaryWriter`), detecting their format, and reproduces the analyses that
+//! are otherwise performed in-process, so that traces may be captured cheaply
+//! in production and analyzed later.
+//!
+//! Requires the `analyzer` feature:
+//!
+//! ```sh
+//! cargo install tracing-allocations --features analyzer
+//! tracing-allocations leaks allocations.bin
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    process::ExitCode,
+    sync::Arc,
+};
+
+use tracing_allocations::{
+    event::{AllocationEvent, AllocationKind},
+    BinaryReader,
+};
+
+const USAGE: &str = "\
+usage: tracing-allocations <command> [-n <count>] <trace>...
+
+Analyzes traces of allocation events, as written by JsonLinesLayer or
+BinaryLayer; a trace of `-` is read from standard input.
+
+commands:
+    summary     the totals of the operations of the trace
+    leaks       the blocks still live at the end of the trace, by caller and tag
+    top         the callers that allocated the most bytes
+    histogram   the number of blocks allocated, by size
+    lifetimes   the number of blocks freed, by how long they lived
+    diff        the changes to the blocks live at the end of the first trace,
+                by caller and tag, at the end of the second
+
+options:
+    -n <count>  the number of callers or groups to list (default: 20)
+";
+
+/// The magic bytes of the binary format.
+const BINARY_MAGIC: &[u8] = b"TAEV";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let Some(command) = args.next() else {
+        eprint!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    let mut limit = 20;
+    let mut traces = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => limit = n,
+                None => return usage("-n requires a number"),
+            },
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ => traces.push(arg),
+        }
+    }
+    let expected = match command.as_str() {
+        "summary" | "leaks" | "top" | "histogram" | "lifetimes" => 1,
+        "diff" => 2,
+        "-h" | "--help" => {
+            print!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        _ => return usage(&format!("unknown command `{}`", command)),
+    };
+    if traces.len() != expected {
+        return usage(&format!("`{}` takes {} trace(s)", command, expected));
+    }
+
+    let mut heaps = Vec::new();
+    for trace in &traces {
+        match Heap::replay(trace) {
+            Ok(heap) => heaps.push(heap),
+            Err(error) => {
+                eprintln!("tracing-allocations: {}: {}", trace, error);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let mut out = io::stdout().lock();
+    let result = match command.as_str() {
+        "summary" => heaps[0].summary(&mut out),
+        "leaks" => heaps[0].leaks(&mut out, limit),
+        "top" => heaps[0].top(&mut out, limit),
+        "histogram" => histogram(&mut out, &heaps[0].sizes, format_bytes),
+        "lifetimes" => histogram(&mut out, &heaps[0].lifetimes, format_nanos),
+        _ => diff(&mut out, &heaps[0], &heaps[1], limit),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        // e.g., the output was piped to `head`
+        Err(error) if error.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("tracing-allocations: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Reports a misuse of the command line.
+fn usage(message: &str) -> ExitCode {
+    eprintln!("tracing-allocations: {}\n\n{}", message, USAGE);
+    ExitCode::from(2)
+}
+
+/// An allocation event, with its details.
+struct Record {
+    event: AllocationEvent,
+    caller: Option<Arc<str>>,
+    tag: Option<Arc<str>>,
+}
+
+/// Reads the records of the trace at `path`, in either format, and passes
+/// each to `f`.
+fn read(path: &str, mut f: impl FnMut(Record)) -> io::Result<()> {
+    let reader: Box<dyn Read> = match path {
+        "-" => Box::new(io::stdin().lock()),
+        path => Box::new(File::open(path)?),
+    };
+    let mut reader = BufReader::new(reader);
+    if reader.fill_buf()?.starts_with(BINARY_MAGIC) {
+        for record in BinaryReader::new(reader) {
+            let record = record?;
+            f(Record {
+                event: record.event,
+                caller: record.caller,
+                tag: None,
+            });
+        }
+        return Ok(());
+    }
+    let mut strings = Strings::default();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", number + 1, message),
+            )
+        };
+        let value: serde_json::Value =
+            serde_json::from_str(&line).map_err(|error| invalid(&error.to_string()))?;
+        let record =
+            parse(&value, &mut strings).ok_or_else(|| invalid("not an allocation event"))?;
+        f(record);
+    }
+    Ok(())
+}
+
+/// Parses a line written by `JsonLinesLayer`.
+fn parse(value: &serde_json::Value, strings: &mut Strings) -> Option<Record> {
+    let u64_field = |names: &[&str]| names.iter().find_map(|name| value.get(name)?.as_u64());
+    let hex_field = |names: &[&str]| {
+        names.iter().find_map(|name| {
+            let hex = value.get(name)?.as_str()?.strip_prefix("0x")?;
+            u64::from_str_radix(hex, 16).ok()
+        })
+    };
+    let str_field = |name: &str| value.get(name)?.as_str();
+
+    let kind = AllocationKind::from_name(str_field("kind")?)?;
+    let addr =
+        u64_field(&["addr", "new_addr"]).or_else(|| hex_field(&["addr_hex", "new_addr_hex"]))?;
+    let size = u64_field(&["size", "new_size"])?;
+    let mut event = AllocationEvent::new(kind, addr, size);
+    event.align = u64_field(&["align"]);
+    event.usable_size = u64_field(&["usable_size", "new_usable_size"]);
+    event.old_addr = u64_field(&["old_addr"]).or_else(|| hex_field(&["old_addr_hex"]));
+    event.old_size = u64_field(&["old_size"]);
+    event.zeroed = value.get("zeroed").and_then(serde_json::Value::as_bool);
+    event.timestamp_ns = u64_field(&["timestamp_ns"]);
+    event.span_id = u64_field(&["span_id"]);
+    event.sample_rate = u64_field(&["sample_rate"]);
+    event.sample_interval = u64_field(&["sample_interval"]);
+    event.count = u64_field(&["count"]);
+    event.age_ns = u64_field(&["age_ns"]);
+    event.age_events = u64_field(&["age_events"]);
+    Some(Record {
+        event,
+        caller: str_field("caller").map(|caller| strings.intern(caller)),
+        tag: str_field("tag").map(|tag| strings.intern(tag)),
+    })
+}
+
+/// The callers and tags read so far, so that each is stored once.
+#[derive(Default)]
+struct Strings(HashSet<Arc<str>>);
+
+impl Strings {
+    fn intern(&mut self, s: &str) -> Arc<str> {
+        match self.0.get(s) {
+            Some(s) => s.clone(),
+            None => {
+                let s: Arc<str> = Arc::from(s);
+                self.0.insert(s.clone());
+                s
+            }
+        }
+    }
+}
+
+/// A caller and tag, by which blocks are grouped.
+type Group = (Option<Arc<str>>, Option<Arc<str>>);
+
+/// A live block.
+struct Block {
+    /// The size of the block, scaled by the weight of its event.
+    size: u64,
+    /// The number of blocks this block stands for.
+    weight: u64,
+    group: Group,
+    /// The time of its allocation, if known.
+    timestamp_ns: Option<u64>,
+}
+
+/// The state of the heap, replayed from a trace.
+struct Heap {
+    /// The live blocks, by address.
+    live: HashMap<u64, Block>,
+    live_bytes: u64,
+    peak_bytes: u64,
+    /// The number of operations of each kind, by its index.
+    operations: [u64; 4],
+    bytes_allocated: u64,
+    bytes_freed: u64,
+    /// The number of allocations and bytes allocated, by caller.
+    callers: HashMap<Option<Arc<str>>, (u64, u64)>,
+    /// The number of blocks allocated, by the base 2 logarithm of their size,
+    /// rounded up.
+    sizes: [u64; 65],
+    /// The number of blocks freed, by the base 2 logarithm of their lifetime
+    /// in nanoseconds, rounded up.
+    lifetimes: [u64; 65],
+}
+
+impl Heap {
+    /// Replays the trace at `path`.
+    fn replay(path: &str) -> io::Result<Self> {
+        let mut heap = Self {
+            live: HashMap::new(),
+            live_bytes: 0,
+            peak_bytes: 0,
+            operations: [0; 4],
+            bytes_allocated: 0,
+            bytes_freed: 0,
+            callers: HashMap::new(),
+            sizes: [0; 65],
+            lifetimes: [0; 65],
+        };
+        read(path, |record| heap.record(record))?;
+        Ok(heap)
+    }
+
+    /// Applies the operation described by `record`.
+    fn record(&mut self, record: Record) {
+        let Record { event, caller, tag } = record;
+        let weight = event.weight();
+        let scale = |n: u64| (n as f64 * weight).round() as u64;
+        let objects = scale(1);
+        let size = scale(event.size);
+        self.operations[kind_index(event.kind)] += objects;
+        match event.kind {
+            AllocationKind::Dealloc => {
+                self.free(&event, event.addr);
+                return;
+            }
+            AllocationKind::Realloc => self.free(&event, event.old_addr.unwrap_or(event.addr)),
+            _ => {}
+        }
+        self.bytes_allocated += size;
+        let totals = self.callers.entry(caller.clone()).or_default();
+        totals.0 += objects;
+        totals.1 += size;
+        self.sizes[log2(event.size)] += objects;
+        self.live_bytes += size;
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+        let block = Block {
+            size,
+            weight: objects,
+            group: (caller, tag),
+            timestamp_ns: event.timestamp_ns,
+        };
+        if let Some(previous) = self.live.insert(event.addr, block) {
+            // the deallocation of the previous block was not traced
+            self.live_bytes -= previous.size;
+        }
+    }
+
+    /// Frees the block at `addr`, as described by `event`.
+    fn free(&mut self, event: &AllocationEvent, addr: u64) {
+        let block = self.live.remove(&addr);
+        let (size, weight) = match &block {
+            Some(block) => (block.size, block.weight),
+            // the allocation of the block was not traced
+            None => {
+                let weight = event.weight();
+                let size = event.old_size.unwrap_or(event.size);
+                ((size as f64 * weight).round() as u64, weight.round() as u64)
+            }
+        };
+        self.bytes_freed += size;
+        if block.is_some() {
+            self.live_bytes -= size;
+        }
+        let age = event.age_ns.or_else(|| {
+            let allocated = block?.timestamp_ns?;
+            event.timestamp_ns?.checked_sub(allocated)
+        });
+        if let (Some(age), AllocationKind::Dealloc) = (age, event.kind) {
+            self.lifetimes[log2(age)] += weight;
+        }
+    }
+
+    /// The live blocks, by group.
+    fn groups(&self) -> HashMap<&Group, (u64, u64)> {
+        let mut groups: HashMap<&Group, (u64, u64)> = HashMap::new();
+        for block in self.live.values() {
+            let group = groups.entry(&block.group).or_default();
+            group.0 += block.weight;
+            group.1 += block.size;
+        }
+        groups
+    }
+
+    fn summary(&self, out: &mut impl Write) -> io::Result<()> {
+        let [alloc, alloc_zeroed, dealloc, realloc] = self.operations;
+        writeln!(out, "allocations:     {}", alloc + alloc_zeroed)?;
+        writeln!(out, "  zeroed:        {}", alloc_zeroed)?;
+        writeln!(out, "deallocations:   {}", dealloc)?;
+        writeln!(out, "reallocations:   {}", realloc)?;
+        writeln!(
+            out,
+            "bytes allocated: {}",
+            format_bytes(self.bytes_allocated)
+        )?;
+        writeln!(out, "bytes freed:     {}", format_bytes(self.bytes_freed))?;
+        writeln!(out, "peak live bytes: {}", format_bytes(self.peak_bytes))?;
+        writeln!(
+            out,
+            "live at end:     {} in {} blocks",
+            format_bytes(self.live_bytes),
+            self.live.values().map(|block| block.weight).sum::<u64>()
+        )
+    }
+
+    fn leaks(&self, out: &mut impl Write, limit: usize) -> io::Result<()> {
+        let mut groups: Vec<_> = self.groups().into_iter().collect();
+        groups.sort_by_key(|&(_, (_, bytes))| Reverse(bytes));
+        writeln!(
+            out,
+            "{} live at end, in {} blocks",
+            format_bytes(self.live_bytes),
+            self.live.values().map(|block| block.weight).sum::<u64>()
+        )?;
+        for (group, (blocks, bytes)) in groups.into_iter().take(limit) {
+            writeln!(
+                out,
+                "{:>12} in {:>8} blocks  {}",
+                format_bytes(bytes),
+                blocks,
+                format_group(group)
+            )?;
+        }
+        Ok(())
+    }
+
+    fn top(&self, out: &mut impl Write, limit: usize) -> io::Result<()> {
+        let mut callers: Vec<_> = self.callers.iter().collect();
+        callers.sort_by_key(|&(_, &(_, bytes))| Reverse(bytes));
+        for (caller, &(allocations, bytes)) in callers.into_iter().take(limit) {
+            writeln!(
+                out,
+                "{:>12} in {:>8} allocations  {}",
+                format_bytes(bytes),
+                allocations,
+                caller.as_deref().unwrap_or("<unknown>")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The index of `kind` among all kinds.
+fn kind_index(kind: AllocationKind) -> usize {
+    match kind {
+        AllocationKind::Alloc => 0,
+        AllocationKind::AllocZeroed => 1,
+        AllocationKind::Dealloc => 2,
+        _ => 3,
+    }
+}
+
+/// The base 2 logarithm of `n`, rounded up; 0 for 0.
+fn log2(n: u64) -> usize {
+    match n {
+        0 | 1 => 0,
+        n => (64 - (n - 1).leading_zeros()) as usize,
+    }
+}
+
+/// Writes the non-empty range of `buckets`, each of which counts the values
+/// whose base 2 logarithm, rounded up, is its index.
+fn histogram(
+    out: &mut impl Write,
+    buckets: &[u64; 65],
+    format: fn(u64) -> String,
+) -> io::Result<()> {
+    let Some(first) = buckets.iter().position(|&n| n > 0) else {
+        return writeln!(out, "no blocks");
+    };
+    let last = buckets.iter().rposition(|&n| n > 0).unwrap_or(first);
+    let max = buckets.iter().copied().max().unwrap_or(1);
+    for (i, &n) in buckets.iter().enumerate().take(last + 1).skip(first) {
+        let bar = "#".repeat(((n as f64 / max as f64) * 40.0).ceil() as usize);
+        let limit = if i == 64 { u64::MAX } else { 1u64 << i };
+        writeln!(out, "<= {:>10} {:>10}  {}", format(limit), n, bar)?;
+    }
+    Ok(())
+}
+
+/// Writes the changes to the live blocks of each group from `before` to
+/// `after`, in descending order of growth.
+fn diff(out: &mut impl Write, before: &Heap, after: &Heap, limit: usize) -> io::Result<()> {
+    let mut groups: HashMap<&Group, [u64; 4]> = HashMap::new();
+    for (group, (blocks, bytes)) in before.groups() {
+        let diff = groups.entry(group).or_default();
+        diff[0] += blocks;
+        diff[1] += bytes;
+    }
+    for (group, (blocks, bytes)) in after.groups() {
+        let diff = groups.entry(group).or_default();
+        diff[2] += blocks;
+        diff[3] += bytes;
+    }
+    let delta = |diff: &[u64; 4]| diff[3] as i64 - diff[1] as i64;
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    groups.sort_by_key(|(_, diff)| Reverse(delta(diff)));
+    writeln!(
+        out,
+        "{:+} bytes live at end",
+        after.live_bytes as i64 - before.live_bytes as i64
+    )?;
+    for (group, diff) in groups.into_iter().take(limit) {
+        writeln!(
+            out,
+            "{:>+12} bytes {:>+8} blocks ({} -> {})  {}",
+            delta(&diff),
+            diff[2] as i64 - diff[0] as i64,
+            format_bytes(diff[1]),
+            format_bytes(diff[3]),
+            format_group(group)
+        )?;
+    }
+    Ok(())
+}
+
+/// Formats a group as its caller, followed by its tag, if any.
+fn format_group((caller, tag): &Group) -> String {
+    let caller = caller.as_deref().unwrap_or("<unknown>");
+    match tag {
+        Some(tag) => format!("{} [{}]", caller, tag),
+        None => String::from(caller),
+    }
+}
+
+/// Formats a number of bytes with a binary unit.
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", n),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// Formats a number of nanoseconds with a unit of time.
+fn format_nanos(n: u64) -> String {
+    const UNITS: [(&str, f64); 4] = [("s", 1e9), ("ms", 1e6), ("us", 1e3), ("ns", 1.0)];
+    let (unit, scale) = UNITS
+        .into_iter()
+        .find(|&(_, scale)| n as f64 >= scale)
+        .unwrap_or(("ns", 1.0));
+    match unit {
+        "ns" => format!("{} ns", n),
+        _ => format!("{:.1} {}", n as f64 / scale, unit),
+    }
+}
@@ -0,0 +1,592 @@
+//! A compact binary encoding of allocation events.
+//!
+//! Formatting events as text dominates the cost of tracing every allocator
+//! operation. [`BinaryWriter`] instead encodes each [`AllocationEvent`] in a
+//! handful of bytes, and [`BinaryReader`] decodes them offline; with the
+//! `tracing-subscriber` feature, `BinaryLayer` writes the events it
+//! observes with a `BinaryWriter`.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+#[cfg(feature = "tracing-subscriber")]
+use std::{
+    io::BufWriter,
+    sync::{Mutex, PoisonError},
+};
+
+#[cfg(feature = "tracing-subscriber")]
+use tracing::{Event, Subscriber};
+#[cfg(feature = "tracing-subscriber")]
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::event::{AllocationEvent, AllocationKind};
+
+/// The magic bytes that begin a stream.
+const MAGIC: &[u8; 4] = b"TAEV";
+
+/// The version of the format.
+const VERSION: u8 = 1;
+
+/// The tag of a caller record.
+const CALLER: u8 = 0;
+
+/// The tag of an event record.
+const EVENT: u8 = 1;
+
+/// The tag of a tag record.
+const TAG: u8 = 2;
+
+/// The greatest length of a record that is read; longer records are deemed
+/// corrupt, rather than buffered.
+const MAX_RECORD_LEN: usize = 1 << 20;
+
+/// The kinds of operation, by index.
+const KINDS: [AllocationKind; 4] = [
+    AllocationKind::Alloc,
+    AllocationKind::AllocZeroed,
+    AllocationKind::Dealloc,
+    AllocationKind::Realloc,
+];
+
+/// Encodes allocation events in a compact binary format, to a [`Write`]r.
+///
+/// Writes are not buffered; wrap unbuffered writers in a
+/// [`BufWriter`](std::io::BufWriter).
+///
+/// ## Format
+/// A stream begins with the magic bytes `TAEV`, followed by a version byte
+/// (currently 1). Then follow records, each a varint (unsigned LEB128) length
+/// followed by that many bytes, the first of which is the record's tag:
+/// - **0, caller**: a varint ID, followed by the caller's name as UTF-8,
+///   which the events that follow refer to by that ID.
+/// - **1, event**: the index of the event's kind (alloc, alloc_zeroed,
+///   dealloc, realloc), a varint bit set of the optional fields that are
+///   present, then varints of the event's address and size, and of each
+///   present field, in the order of the bits: usable size (bit 0), old
+///   address (1), old size (2), zeroed (3; its value is bit 4), timestamp (5;
+///   zigzag-encoded, relative to that of the previous event), span ID (6),
+///   sample rate (7), sample interval (8), count (9), age (10), age in events
+///   (11), caller ID (12), alignment (13), and tag ID (14).
+/// - **2, tag**: a varint ID, followed by the tag (see
+///   [`tag_in_scope`](crate::tag_in_scope)) as UTF-8, which the events that
+///   follow refer to by that ID.
+///
+/// Records with unknown tags are skipped, so that later versions may add
+/// them.
+///
+/// ## Usage
+/// ```
+/// use tracing_allocations::{
+///     event::{AllocationEvent, AllocationKind},
+///     BinaryReader, BinaryWriter,
+/// };
+///
+/// let mut writer = BinaryWriter::new(Vec::new());
+/// let event = AllocationEvent::new(AllocationKind::Alloc, 0x1000, 64);
+/// writer.write(&event, Some("app::load")).unwrap();
+///
+/// let encoded = writer.into_inner();
+/// let record = BinaryReader::new(&encoded[..]).next().unwrap().unwrap();
+/// assert_eq!(record.event, event);
+/// assert_eq!(record.caller.as_deref(), Some("app::load"));
+/// ```
+#[derive(Debug)]
+pub struct BinaryWriter<W> {
+    writer: W,
+    /// Whether the magic bytes and version have been written.
+    started: bool,
+    /// The ID of each caller written so far.
+    callers: HashMap<String, u64>,
+    /// The ID of each tag written so far.
+    tags: HashMap<String, u64>,
+    /// The timestamp of the previous event that had one.
+    timestamp_ns: u64,
+    /// The record being encoded.
+    record: Vec<u8>,
+    /// The length prefix of the record being encoded.
+    prefix: Vec<u8>,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    /// Constructs a new `BinaryWriter`, which writes to `writer`. Nothing is
+    /// written until the first event.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            started: false,
+            callers: HashMap::new(),
+            tags: HashMap::new(),
+            timestamp_ns: 0,
+            record: Vec::new(),
+            prefix: Vec::new(),
+        }
+    }
+
+    /// Encodes `event`, which was requested by `caller`, if known.
+    pub fn write(&mut self, event: &AllocationEvent, caller: Option<&str>) -> io::Result<()> {
+        self.write_tagged(event, caller, None)
+    }
+
+    /// Encodes `event`, which was requested by `caller` under `tag`, if
+    /// known.
+    pub fn write_tagged(
+        &mut self,
+        event: &AllocationEvent,
+        caller: Option<&str>,
+        tag: Option<&str>,
+    ) -> io::Result<()> {
+        if !self.started {
+            self.writer.write_all(MAGIC)?;
+            self.writer.write_all(&[VERSION])?;
+            self.started = true;
+        }
+        let caller = caller
+            .map(|caller| self.define(CALLER, caller))
+            .transpose()?;
+        let tag = tag.map(|tag| self.define(TAG, tag)).transpose()?;
+
+        let optional = [
+            event.usable_size,
+            event.old_addr,
+            event.old_size,
+            event.zeroed.map(u64::from),
+            None,
+            event.timestamp_ns.map(|timestamp_ns| {
+                let delta = timestamp_ns.wrapping_sub(self.timestamp_ns) as i64;
+                ((delta << 1) ^ (delta >> 63)) as u64
+            }),
+            event.span_id,
+            event.sample_rate,
+            event.sample_interval,
+            event.count,
+            event.age_ns,
+            event.age_events,
+            caller,
+            event.align,
+            tag,
+        ];
+        let mut present = 0u64;
+        for (bit, field) in optional.iter().enumerate() {
+            if field.is_some() {
+                present |= 1 << bit;
+            }
+        }
+        if event.zeroed == Some(true) {
+            present |= 1 << 4;
+        }
+        if let Some(timestamp_ns) = event.timestamp_ns {
+            self.timestamp_ns = timestamp_ns;
+        }
+
+        self.record.clear();
+        self.record.push(EVENT);
+        self.record.push(event.kind.index() as u8);
+        varint(&mut self.record, present);
+        varint(&mut self.record, event.addr);
+        varint(&mut self.record, event.size);
+        for (bit, &field) in optional.iter().enumerate() {
+            // the value of `zeroed` is in the bit set
+            if let (Some(value), false) = (field, bit == 3) {
+                varint(&mut self.record, value);
+            }
+        }
+        self.flush_record()
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// The underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// The ID of `name` among the callers or the tags, as `kind` is `CALLER`
+    /// or `TAG`, writing a record that defines it if it is new.
+    fn define(&mut self, kind: u8, name: &str) -> io::Result<u64> {
+        let names = match kind {
+            CALLER => &self.callers,
+            _ => &self.tags,
+        };
+        if let Some(&id) = names.get(name) {
+            return Ok(id);
+        }
+        let id = names.len() as u64;
+        self.record.clear();
+        self.record.push(kind);
+        varint(&mut self.record, id);
+        self.record.extend_from_slice(name.as_bytes());
+        self.flush_record()?;
+        let names = match kind {
+            CALLER => &mut self.callers,
+            _ => &mut self.tags,
+        };
+        names.insert(String::from(name), id);
+        Ok(id)
+    }
+
+    /// Writes the record being encoded, prefixed by its length.
+    fn flush_record(&mut self) -> io::Result<()> {
+        self.prefix.clear();
+        varint(&mut self.prefix, self.record.len() as u64);
+        self.writer.write_all(&self.prefix)?;
+        self.writer.write_all(&self.record)
+    }
+}
+
+/// An event decoded by a [`BinaryReader`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BinaryRecord {
+    /// The allocator operation.
+    pub event: AllocationEvent,
+    /// The code that requested the operation, if known.
+    pub caller: Option<Arc<str>>,
+    /// The tag in whose scope the operation was requested, if any.
+    pub tag: Option<Arc<str>>,
+}
+
+/// Decodes allocation events in the binary format of [`BinaryWriter`], from
+/// a [`Read`]er.
+///
+/// Reads are not buffered; wrap unbuffered readers in a
+/// [`BufReader`](std::io::BufReader). Reading stops at the first error,
+/// including a stream that ends partway through a record.
+///
+/// ## Usage
+/// ```no_run
+/// use std::{fs::File, io::BufReader};
+/// use tracing_allocations::BinaryReader;
+///
+/// let file = BufReader::new(File::open("allocations.bin").unwrap());
+/// for record in BinaryReader::new(file) {
+///     let record = record.unwrap();
+///     println!("{} {} bytes", record.event.kind, record.event.size);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct BinaryReader<R> {
+    reader: R,
+    /// Whether the magic bytes and version have been read.
+    started: bool,
+    /// Whether the stream has ended, or an error has been returned.
+    done: bool,
+    /// The callers defined so far, by ID.
+    callers: HashMap<u64, Arc<str>>,
+    /// The tags defined so far, by ID.
+    tags: HashMap<u64, Arc<str>>,
+    /// The timestamp of the previous event that had one.
+    timestamp_ns: u64,
+    /// The record being decoded.
+    record: Vec<u8>,
+}
+
+impl<R: Read> BinaryReader<R> {
+    /// Constructs a new `BinaryReader`, which reads from `reader`.
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            started: false,
+            done: false,
+            callers: HashMap::new(),
+            tags: HashMap::new(),
+            timestamp_ns: 0,
+            record: Vec::new(),
+        }
+    }
+
+    /// Reads the next event, or `None` if the stream has ended.
+    fn read(&mut self) -> io::Result<Option<BinaryRecord>> {
+        if !self.started {
+            let mut header = [0; 5];
+            self.reader.read_exact(&mut header)?;
+            if &header[..4] != MAGIC {
+                return Err(invalid("not a binary allocation event stream"));
+            }
+            if header[4] != VERSION {
+                return Err(invalid("unsupported version of the binary format"));
+            }
+            self.started = true;
+        }
+        loop {
+            let Some(len) = self.read_len()? else {
+                return Ok(None);
+            };
+            if len > MAX_RECORD_LEN {
+                return Err(invalid("record is too long"));
+            }
+            self.record.resize(len, 0);
+            self.reader.read_exact(&mut self.record)?;
+            let mut record = Cursor(&self.record);
+            match record.byte()? {
+                CALLER => {
+                    let id = record.varint()?;
+                    let caller = core::str::from_utf8(record.0)
+                        .map_err(|_| invalid("caller is not UTF-8"))?;
+                    self.callers.insert(id, Arc::from(caller));
+                }
+                TAG => {
+                    let id = record.varint()?;
+                    let tag =
+                        core::str::from_utf8(record.0).map_err(|_| invalid("tag is not UTF-8"))?;
+                    self.tags.insert(id, Arc::from(tag));
+                }
+                EVENT => {
+                    let kind = *KINDS
+                        .get(usize::from(record.byte()?))
+                        .ok_or_else(|| invalid("unknown kind of operation"))?;
+                    let present = record.varint()?;
+                    let mut event = AllocationEvent::new(kind, record.varint()?, record.varint()?);
+                    let mut field = |bit: u32| -> io::Result<Option<u64>> {
+                        match present & (1 << bit) {
+                            0 => Ok(None),
+                            _ => record.varint().map(Some),
+                        }
+                    };
+                    event.usable_size = field(0)?;
+                    event.old_addr = field(1)?;
+                    event.old_size = field(2)?;
+                    event.zeroed = (present & (1 << 3) != 0).then_some(present & (1 << 4) != 0);
+                    event.timestamp_ns = field(5)?.map(|zigzag| {
+                        let delta = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+                        self.timestamp_ns = self.timestamp_ns.wrapping_add(delta as u64);
+                        self.timestamp_ns
+                    });
+                    event.span_id = field(6)?;
+                    event.sample_rate = field(7)?;
+                    event.sample_interval = field(8)?;
+                    event.count = field(9)?;
+                    event.age_ns = field(10)?;
+                    event.age_events = field(11)?;
+                    let caller = field(12)?;
+                    event.align = field(13)?;
+                    let tag = field(14)?;
+                    let caller = match caller {
+                        None => None,
+                        Some(id) => Some(
+                            self.callers
+                                .get(&id)
+                                .cloned()
+                                .ok_or_else(|| invalid("undefined caller"))?,
+                        ),
+                    };
+                    let tag = match tag {
+                        None => None,
+                        Some(id) => Some(
+                            self.tags
+                                .get(&id)
+                                .cloned()
+                                .ok_or_else(|| invalid("undefined tag"))?,
+                        ),
+                    };
+                    return Ok(Some(BinaryRecord { event, caller, tag }));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads the length prefix of the next record, or `None` if the stream
+    /// has ended.
+    fn read_len(&mut self) -> io::Result<Option<usize>> {
+        let mut len = 0u64;
+        for (index, shift) in (0..64).step_by(7).enumerate() {
+            let mut byte = [0];
+            if self.reader.read(&mut byte)? == 0 {
+                return match index {
+                    0 => Ok(None),
+                    _ => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+            len |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(len as usize));
+            }
+        }
+        Err(invalid("record length is too long"))
+    }
+}
+
+impl<R: Read> Iterator for BinaryReader<R> {
+    type Item = io::Result<BinaryRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.read().transpose();
+        self.done = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+/// The unread bytes of a record.
+struct Cursor<'r>(&'r [u8]);
+
+impl Cursor<'_> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let (&byte, rest) = self
+            .0
+            .split_first()
+            .ok_or_else(|| invalid("truncated record"))?;
+        self.0 = rest;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("varint is too long"))
+    }
+}
+
+/// Appends `value` to `bytes` as a varint.
+fn varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// An error for malformed input.
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A [`Layer`] that writes the allocator operations described by the
+/// events it observes with a [`BinaryWriter`], together with their
+/// callers, if known (see [`Detail::Caller`](crate::Detail::Caller)), and
+/// their tags.
+///
+/// Writes are buffered; the buffer is flushed when the layer and all
+/// [`BinaryHandle`]s to it are dropped, or by [`BinaryHandle::flush`].
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::BinaryLayer;
+///
+/// let file = std::fs::File::create("allocations.bin").unwrap();
+/// let layer = BinaryLayer::new(file);
+/// let events = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// events.flush().unwrap();
+/// ```
+#[cfg(feature = "tracing-subscriber")]
+#[derive(Clone, Debug)]
+pub struct BinaryLayer {
+    handle: BinaryHandle,
+}
+
+#[cfg(feature = "tracing-subscriber")]
+impl BinaryLayer {
+    /// Constructs a new `BinaryLayer`, which writes to `writer`.
+    pub fn new<W>(writer: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        let state = crate::disable_in_scope(|| State {
+            writer: BinaryWriter::new(BufWriter::new(Box::new(writer))),
+            caller: String::new(),
+            tag: String::new(),
+        });
+        Self {
+            handle: BinaryHandle {
+                state: Arc::new(Mutex::new(state)),
+            },
+        }
+    }
+
+    /// A handle through which to flush the events written by this layer.
+    pub fn handle(&self) -> BinaryHandle {
+        self.handle.clone()
+    }
+}
+
+#[cfg(feature = "tracing-subscriber")]
+impl<S> Layer<S> for BinaryLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(decoded) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let mut state = self.handle.state();
+            let State {
+                writer,
+                caller,
+                tag,
+            } = &mut *state;
+            caller.clear();
+            tag.clear();
+            let caller = crate::event::caller_of(event, caller).then_some(caller.as_str());
+            let tag = crate::event::tag_of(event, tag).then_some(tag.as_str());
+            let _ = writer.write_tagged(&decoded, caller, tag);
+        });
+    }
+}
+
+/// A handle to the events written by a [`BinaryLayer`].
+///
+/// Handles are cheap to clone, and all clones refer to the same writer.
+#[cfg(feature = "tracing-subscriber")]
+#[derive(Clone)]
+pub struct BinaryHandle {
+    state: Arc<Mutex<State>>,
+}
+
+#[cfg(feature = "tracing-subscriber")]
+impl BinaryHandle {
+    /// Writes the buffered events.
+    pub fn flush(&self) -> io::Result<()> {
+        crate::disable_in_scope(|| self.state().writer.flush())
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "tracing-subscriber")]
+impl core::fmt::Debug for BinaryHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BinaryHandle").finish_non_exhaustive()
+    }
+}
+
+/// The writer of a [`BinaryLayer`].
+#[cfg(feature = "tracing-subscriber")]
+struct State {
+    writer: BinaryWriter<BufWriter<Box<dyn Write + Send>>>,
+    /// The caller of the event being written.
+    caller: String,
+    /// The tag of the event being written.
+    tag: String,
+}
+
+#[cfg(feature = "tracing-subscriber")]
+impl Drop for State {
+    fn drop(&mut self) {
+        crate::disable_in_scope(|| {
+            let _ = self.writer.flush();
+        });
+    }
+}
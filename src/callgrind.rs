@@ -0,0 +1,264 @@
+//! Allocation profiles in the format of Callgrind.
+//!
+//! [`CallgrindLayer`] captures the stack of each allocation described by the
+//! events it observes, and writes the costs of each function, and of each
+//! call between functions, in the profile format of Callgrind, so that the
+//! allocations of a program may be navigated with KCachegrind or QCachegrind.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    event::{AllocationEvent, AllocationKind},
+    stack::{self, Line, Profile, Symbolizer},
+};
+
+/// The events of each cost, in the order in which they are written.
+const EVENTS: &str = "AllocBytes AllocBlocks LiveBytes LiveBlocks";
+
+/// A [`Layer`] that profiles the allocations described by the events it
+/// observes, by the stack that requested them.
+///
+/// Profiles may be written at any time, from any thread, through the
+/// [`CallgrindHandle`] returned by [`CallgrindLayer::handle`], as cost lines
+/// of the following events, charged to the source lines of the stacks of the
+/// observed allocations (and reallocations):
+/// - **`AllocBytes`**  
+///   the number of bytes allocated
+/// - **`AllocBlocks`**  
+///   the number of blocks allocated
+/// - **`LiveBytes`**  
+///   the number of bytes allocated that no observed deallocation has freed
+/// - **`LiveBlocks`**  
+///   the number of blocks allocated that no observed deallocation has freed
+///
+/// The self cost of a function is that of the allocations it requested
+/// directly; its inclusive cost also includes that of the functions it
+/// called. Sampled and coalesced events are scaled by
+/// [`AllocationEvent::weight`].
+///
+/// Requires the `callgrind` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::CallgrindLayer;
+///
+/// let layer = CallgrindLayer::new();
+/// let profile = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// let file = std::fs::File::create("callgrind.out.allocations").unwrap();
+/// profile.write_to(file).unwrap();
+/// ```
+///
+/// ```sh
+/// kcachegrind callgrind.out.allocations
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CallgrindLayer {
+    handle: CallgrindHandle,
+}
+
+impl CallgrindLayer {
+    /// Constructs a new `CallgrindLayer`, with an empty profile.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle through which to write the profile of this layer.
+    pub fn handle(&self) -> CallgrindHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for CallgrindLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let stack = (event.kind != AllocationKind::Dealloc).then(stack::capture);
+            self.handle
+                .profile
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(&event, stack);
+        });
+    }
+}
+
+/// A handle to the profile of a [`CallgrindLayer`].
+///
+/// Handles are cheap to clone, and all clones write the same profile.
+#[derive(Clone, Default)]
+pub struct CallgrindHandle {
+    profile: Arc<Mutex<Profile>>,
+    symbolizer: Arc<Mutex<Symbolizer>>,
+}
+
+impl CallgrindHandle {
+    /// Writes the profile of the allocations observed so far to `writer`, in
+    /// the format of Callgrind.
+    ///
+    /// Stacks are symbolized as they are written, which may be slow the first
+    /// time each stack frame is encountered.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        crate::disable_in_scope(|| {
+            let stacks = self
+                .profile
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .stacks
+                .clone();
+            let mut symbolizer = self
+                .symbolizer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let mut functions: BTreeMap<Function, Costs> = BTreeMap::new();
+            for totals in &stacks {
+                let cost = [
+                    totals.alloc_space,
+                    totals.alloc_objects,
+                    totals.inuse_space,
+                    totals.inuse_objects,
+                ];
+                // the source lines of the stack, outermost first
+                let mut lines: Vec<(Function, u32)> = Vec::new();
+                for &ip in symbolizer.trim(&totals.stack).iter().rev() {
+                    for line in symbolizer.frame(ip).lines.iter().rev() {
+                        lines.push((Function::of(line), line.line.unwrap_or(0)));
+                    }
+                }
+                if lines.is_empty() {
+                    lines.push((Function::unknown(), 0));
+                }
+                for pair in lines.windows(2) {
+                    let [(caller, line), (callee, target)] = pair else {
+                        unreachable!()
+                    };
+                    let call = functions
+                        .entry(caller.clone())
+                        .or_default()
+                        .calls
+                        .entry((*line, callee.clone(), *target))
+                        .or_default();
+                    call.0 += totals.alloc_objects;
+                    add(&mut call.1, &cost);
+                }
+                let (function, line) = lines.pop().expect("stacks are not empty");
+                add(
+                    functions
+                        .entry(function)
+                        .or_default()
+                        .own
+                        .entry(line)
+                        .or_default(),
+                    &cost,
+                );
+            }
+
+            writeln!(writer, "# callgrind format")?;
+            writeln!(writer, "version: 1")?;
+            writeln!(writer, "creator: tracing-allocations")?;
+            writeln!(writer, "pid: {}", std::process::id())?;
+            writeln!(writer, "positions: line")?;
+            writeln!(writer, "events: {}", EVENTS)?;
+            let mut totals = [0; 4];
+            for costs in functions.values() {
+                for cost in costs.own.values() {
+                    add(&mut totals, cost);
+                }
+            }
+            writeln!(
+                writer,
+                "summary: {} {} {} {}",
+                totals[0], totals[1], totals[2], totals[3]
+            )?;
+            for (function, costs) in &functions {
+                writeln!(writer)?;
+                writeln!(writer, "fl={}", function.file)?;
+                writeln!(writer, "fn={}", function.name)?;
+                for (line, cost) in &costs.own {
+                    writeln!(
+                        writer,
+                        "{} {} {} {} {}",
+                        line, cost[0], cost[1], cost[2], cost[3]
+                    )?;
+                }
+                for ((line, callee, target), (calls, cost)) in &costs.calls {
+                    writeln!(writer, "cfi={}", callee.file)?;
+                    writeln!(writer, "cfn={}", callee.name)?;
+                    writeln!(writer, "calls={} {}", calls, target)?;
+                    writeln!(
+                        writer,
+                        "{} {} {} {} {}",
+                        line, cost[0], cost[1], cost[2], cost[3]
+                    )?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl core::fmt::Debug for CallgrindHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CallgrindHandle").finish_non_exhaustive()
+    }
+}
+
+/// A function, as identified in a Callgrind profile.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Function {
+    file: String,
+    name: String,
+}
+
+impl Function {
+    /// The function of `line`.
+    fn of(line: &Line) -> Self {
+        Self {
+            file: line.file.clone().unwrap_or_else(|| String::from("???")),
+            name: line.function.clone(),
+        }
+    }
+
+    /// The function of a stack that could not be symbolized.
+    fn unknown() -> Self {
+        Self {
+            file: String::from("???"),
+            name: String::from("[unknown]"),
+        }
+    }
+}
+
+/// The costs of a function.
+#[derive(Default)]
+struct Costs {
+    /// The self cost of each of its lines.
+    own: BTreeMap<u32, [u64; 4]>,
+    /// The number and inclusive cost of the calls from each of its lines to
+    /// each line of another function, by its line, the callee, and the line
+    /// of the callee.
+    calls: BTreeMap<(u32, Function, u32), (u64, [u64; 4])>,
+}
+
+/// Adds `cost` to `total`.
+fn add(total: &mut [u64; 4], cost: &[u64; 4]) {
+    for (total, cost) in total.iter_mut().zip(cost) {
+        *total += cost;
+    }
+}
@@ -0,0 +1,340 @@
+//! Identification of the code that requested an allocator operation.
+//!
+//! The caller of an allocator operation is the innermost frame of the stack
+//! that belongs neither to the standard library nor to this crate. Finding it
+//! requires walking and symbolizing the stack; symbolization is expensive, so
+//! the classification of each instruction pointer is cached.
+//!
+//! If [`TracingAllocator::with_callsite_stats`] is enabled, the allocations of
+//! each caller are also totalled, for [`top_callsites`]. If
+//! [`TracingAllocator::with_callsite_rollup`] is enabled, the operations of
+//! each caller are rolled up, for periodic emission.
+//!
+//! [`TracingAllocator::with_callsite_stats`]: crate::TracingAllocator::with_callsite_stats
+//! [`TracingAllocator::with_callsite_rollup`]: crate::TracingAllocator::with_callsite_rollup
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::event::{AllocationEvent, AllocationKind};
+
+/// The greatest number of frames inspected when searching for the caller.
+const MAX_DEPTH: usize = 64;
+
+/// Symbol prefixes of frames that belong to the allocator machinery, rather
+/// than to its callers.
+pub(crate) const INTERNAL_PREFIXES: &[&str] = &[
+    "alloc::",
+    "core::",
+    "std::",
+    "backtrace::",
+    "tracing_allocations::",
+    "__rust",
+    "__rdl_",
+    "__rg_",
+];
+
+/// The classification of each instruction pointer encountered so far; `None`
+/// if the frame belongs to the allocator machinery.
+static FRAMES: Mutex<BTreeMap<usize, Option<&'static Callsite>>> = Mutex::new(BTreeMap::new());
+
+/// The totals of each caller, keyed by the address of its `Callsite`, or zero
+/// for operations whose caller could not be found.
+static TOTALS: Mutex<BTreeMap<usize, Totals>> = Mutex::new(BTreeMap::new());
+
+/// The operations of each caller rolled up since the last emission of the
+/// rollup, keyed as `TOTALS`.
+static ROLLUP: Mutex<BTreeMap<usize, Rolled>> = Mutex::new(BTreeMap::new());
+
+/// The time, per [`monotonic_nanos`](crate::monotonic_nanos), at which the
+/// rollup was last taken.
+static ROLLED_AT: AtomicU64 = AtomicU64::new(0);
+
+/// The location of code that requested an allocator operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Callsite {
+    /// The demangled name of the calling function, without its hash.
+    pub(crate) symbol: Option<&'static str>,
+    /// The source file of the call, if known.
+    pub(crate) file: Option<&'static str>,
+    /// The source line of the call, if known.
+    pub(crate) line: Option<u32>,
+}
+
+impl Callsite {
+    /// Whether `pattern` matches this callsite; i.e., whether the path of the
+    /// calling function begins with `pattern`, or the path of its source file
+    /// contains `pattern`.
+    pub(crate) fn matches(&self, pattern: &str) -> bool {
+        let symbol = self.symbol.map(|symbol| symbol.trim_start_matches('<'));
+        symbol.is_some_and(|symbol| symbol.starts_with(pattern))
+            || self.file.is_some_and(|file| file.contains(pattern))
+    }
+
+    /// Resolves the frame at `frame`; `None` if it belongs to the allocator
+    /// machinery, or cannot be resolved.
+    fn resolve(frame: &backtrace::Frame) -> Option<Self> {
+        let mut callsite = None;
+        backtrace::resolve_frame(frame, |symbol| {
+            if callsite.is_some() {
+                return;
+            }
+            let mut name = String::new();
+            if let Some(symbol) = symbol.name() {
+                // the alternate form omits the hash
+                let _ = write!(name, "{:#}", symbol);
+            }
+            let file = symbol
+                .filename()
+                .and_then(|file| file.to_str())
+                .map(String::from);
+            callsite = Some((name, file, symbol.lineno()));
+        });
+        let (name, file, line) = callsite?;
+        let path = name.trim_start_matches('<');
+        if (path.is_empty() && file.is_none())
+            || INTERNAL_PREFIXES.iter().any(|prefix| path.starts_with(prefix))
+            // the standard library's sources are remapped to `/rustc/<hash>/`
+            || file.as_deref().is_some_and(|file| file.starts_with("/rustc/"))
+        {
+            return None;
+        }
+        Some(Self {
+            symbol: (!name.is_empty()).then(|| &*Box::leak(name.into_boxed_str())),
+            file: file.map(|file| &*Box::leak(file.into_boxed_str())),
+            line,
+        })
+    }
+}
+
+impl fmt::Display for Callsite {
+    /// Formats this callsite as `symbol (file:line)`, omitting whatever is
+    /// unknown.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol.unwrap_or("<unknown>"))?;
+        if let Some(file) = self.file {
+            write!(f, " ({}", file)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+/// The caller of the current allocator operation, if it can be found.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+pub(crate) fn caller() -> Option<&'static Callsite> {
+    let mut caller = None;
+    let mut depth = 0;
+    backtrace::trace(|frame| {
+        depth += 1;
+        let ip = frame.ip() as usize;
+        let Ok(mut frames) = FRAMES.lock() else {
+            return false;
+        };
+        let callsite = *frames
+            .entry(ip)
+            .or_insert_with(|| Callsite::resolve(frame).map(|c| &*Box::leak(Box::new(c))));
+        caller = callsite;
+        caller.is_none() && depth < MAX_DEPTH
+    });
+    caller
+}
+
+/// The allocations attributed to a caller.
+#[derive(Clone, Copy)]
+struct Totals {
+    caller: Option<&'static Callsite>,
+    allocations: u64,
+    bytes_allocated: u64,
+}
+
+/// Attributes an allocation of `size` bytes, standing for `weight`
+/// operations, to the caller of the current allocator operation.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+pub(crate) fn attribute(size: u64, weight: f64) {
+    let caller = caller();
+    let key = caller.map_or(0, |caller| caller as *const Callsite as usize);
+    let Ok(mut totals) = TOTALS.lock() else {
+        return;
+    };
+    let totals = totals.entry(key).or_insert(Totals {
+        caller,
+        allocations: 0,
+        bytes_allocated: 0,
+    });
+    totals.allocations += weight.round() as u64;
+    totals.bytes_allocated += (size as f64 * weight).round() as u64;
+}
+
+/// The operations of a caller, rolled up over an interval.
+#[derive(Clone, Copy)]
+pub(crate) struct Rolled {
+    pub(crate) caller: Option<&'static Callsite>,
+    /// The number of allocations, zeroed or not, and reallocations.
+    pub(crate) allocations: u64,
+    pub(crate) bytes_allocated: u64,
+    pub(crate) deallocations: u64,
+    pub(crate) bytes_freed: u64,
+}
+
+/// Rolls up the operations described by `event` with the other operations of
+/// the caller of the current allocator operation.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+pub(crate) fn roll_up(event: &AllocationEvent) {
+    let caller = caller();
+    let key = caller.map_or(0, |caller| caller as *const Callsite as usize);
+    let weight = event.weight();
+    let scale = |n: u64| (n as f64 * weight).round() as u64;
+    let Ok(mut rollup) = ROLLUP.lock() else {
+        return;
+    };
+    let rolled = rollup.entry(key).or_insert(Rolled {
+        caller,
+        allocations: 0,
+        bytes_allocated: 0,
+        deallocations: 0,
+        bytes_freed: 0,
+    });
+    match event.kind {
+        AllocationKind::Alloc | AllocationKind::AllocZeroed => {
+            rolled.allocations += scale(1);
+            rolled.bytes_allocated += scale(event.size);
+        }
+        AllocationKind::Dealloc => {
+            rolled.deallocations += scale(1);
+            rolled.bytes_freed += scale(event.size);
+        }
+        AllocationKind::Realloc => {
+            rolled.allocations += scale(1);
+            rolled.bytes_allocated += scale(event.size);
+            rolled.bytes_freed += scale(event.old_size.unwrap_or(0));
+        }
+    }
+}
+
+/// The operations rolled up since the rollup was last taken, if that was at
+/// least `interval` ago; the rollup begins anew.
+///
+/// Only one thread takes the rollup of each interval.
+pub(crate) fn take_rollup(interval: Duration) -> Option<BTreeMap<usize, Rolled>> {
+    let now = crate::monotonic_nanos();
+    let rolled_at = ROLLED_AT.load(Ordering::Relaxed);
+    if now.saturating_sub(rolled_at) < interval.as_nanos() as u64 {
+        return None;
+    }
+    ROLLED_AT
+        .compare_exchange(rolled_at, now, Ordering::Relaxed, Ordering::Relaxed)
+        .ok()?;
+    let mut rollup = ROLLUP.lock().ok()?;
+    Some(mem::take(&mut *rollup))
+}
+
+/// The allocations requested by a caller. See [`top_callsites`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct CallsiteStats {
+    /// The demangled name of the calling function, if known.
+    pub symbol: Option<&'static str>,
+    /// The source file of the call, if known.
+    pub file: Option<&'static str>,
+    /// The source line of the call, if known.
+    pub line: Option<u32>,
+    /// The number of allocations, zeroed or not, and reallocations requested.
+    pub allocations: u64,
+    /// The total size of the blocks allocated, or reallocated, by the
+    /// requested operations.
+    pub bytes_allocated: u64,
+}
+
+/// The `n` callers that have allocated the most bytes, in descending order,
+/// since [`TracingAllocator::with_callsite_stats`] was enabled or
+/// [`reset_callsite_stats`] was last called.
+///
+/// Callers are identified as described for
+/// [`TracingAllocator::with_ignored_callers`]. Allocations whose callers could
+/// not be identified are totalled together, as a caller whose location is
+/// entirely unknown. Allocations are totalled as their events are emitted, so
+/// sampled events are scaled to estimate the allocations they stand for, and
+/// allocations that emit no events are not totalled.
+///
+/// Requires the `backtrace` feature.
+///
+/// ## Usage
+/// ```
+/// use std::alloc::System;
+/// use tracing_allocations::{top_callsites, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> =
+///     TracingAllocator::new(System).with_callsite_stats(true);
+///
+/// fn main() {
+///     /* your code here */
+///
+///     for callsite in top_callsites(10) {
+///         println!(
+///             "{} bytes in {} allocations: {:?} ({:?}:{:?})",
+///             callsite.bytes_allocated,
+///             callsite.allocations,
+///             callsite.symbol,
+///             callsite.file,
+///             callsite.line,
+///         );
+///     }
+/// }
+/// ```
+///
+/// [`TracingAllocator::with_callsite_stats`]: crate::TracingAllocator::with_callsite_stats
+/// [`TracingAllocator::with_ignored_callers`]: crate::TracingAllocator::with_ignored_callers
+pub fn top_callsites(n: usize) -> Vec<CallsiteStats> {
+    crate::disable_in_scope(|| {
+        let Ok(totals) = TOTALS.lock() else {
+            return Vec::new();
+        };
+        let mut callsites: Vec<CallsiteStats> = totals
+            .values()
+            .map(|totals| CallsiteStats {
+                symbol: totals.caller.and_then(|caller| caller.symbol),
+                file: totals.caller.and_then(|caller| caller.file),
+                line: totals.caller.and_then(|caller| caller.line),
+                allocations: totals.allocations,
+                bytes_allocated: totals.bytes_allocated,
+            })
+            .collect();
+        drop(totals);
+        callsites.sort_by_key(|callsite| Reverse(callsite.bytes_allocated));
+        callsites.truncate(n);
+        callsites
+    })
+}
+
+/// Discards the totals reported by [`top_callsites`].
+///
+/// Requires the `backtrace` feature.
+pub fn reset_callsite_stats() {
+    crate::disable_in_scope(|| {
+        if let Ok(mut totals) = TOTALS.lock() {
+            totals.clear();
+        }
+    });
+}
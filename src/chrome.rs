@@ -0,0 +1,242 @@
+//! Traces of allocator operations in Chrome's trace event format.
+//!
+//! [`ChromeTraceLayer`] writes the events it observes as counter and instant
+//! events in the JSON array format of Chrome's tracing, as read by Perfetto
+//! and `about://tracing`, so that the heap of each thread may be viewed
+//! alongside other trace data.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufWriter, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    event::{AllocationEvent, AllocationKind},
+    json::JsonStr,
+};
+
+/// A [`Layer`] that writes the allocator operations described by the events
+/// it observes as a trace, in the JSON array format of Chrome's trace events.
+///
+/// For each thread, the trace has a counter track, named `live bytes
+/// (<thread>)`, of the number of bytes allocated by that thread less the
+/// number freed by it, which is updated upon each of its operations.
+/// Allocations (and reallocations) of at least 1 MiB, or of the size given to
+/// [`ChromeTraceLayer::with_instant_threshold`], are also written as instant
+/// events, named after their kind. Sampled and coalesced events are scaled by
+/// [`AllocationEvent::weight`].
+///
+/// Trace events are buffered. The trace is completed when the layer and all
+/// [`ChromeTraceHandle`]s to it are dropped, or when
+/// [`ChromeTraceHandle::finish`] is called; as trace viewers accept traces
+/// whose array is unterminated, a trace that is merely flushed with
+/// [`ChromeTraceHandle::flush`] is also readable.
+///
+/// Requires the `chrome` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::ChromeTraceLayer;
+///
+/// let file = std::fs::File::create("trace.json").unwrap();
+/// let layer = ChromeTraceLayer::new(file);
+/// let trace = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// trace.finish().unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ChromeTraceLayer {
+    handle: ChromeTraceHandle,
+}
+
+impl ChromeTraceLayer {
+    /// Constructs a new `ChromeTraceLayer`, which writes its trace to
+    /// `writer`.
+    pub fn new<W>(writer: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        let trace = crate::disable_in_scope(|| Trace {
+            writer: BufWriter::new(Box::new(writer)),
+            pid: std::process::id(),
+            threshold: 1 << 20,
+            events: 0,
+            finished: false,
+            threads: HashMap::new(),
+        });
+        Self {
+            handle: ChromeTraceHandle {
+                trace: Arc::new(Mutex::new(trace)),
+            },
+        }
+    }
+
+    /// Write allocations (and reallocations) of at least `size` bytes as
+    /// instant events, rather than those of at least 1 MiB.
+    pub fn with_instant_threshold(self, size: u64) -> Self {
+        self.handle.trace().threshold = size;
+        self
+    }
+
+    /// A handle through which to flush or finish the trace of this layer.
+    pub fn handle(&self) -> ChromeTraceHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for ChromeTraceLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let _ = self.handle.trace().record(&event);
+        });
+    }
+}
+
+/// A handle to the trace of a [`ChromeTraceLayer`].
+///
+/// Handles are cheap to clone, and all clones refer to the same trace.
+#[derive(Clone)]
+pub struct ChromeTraceHandle {
+    trace: Arc<Mutex<Trace>>,
+}
+
+impl ChromeTraceHandle {
+    /// Writes the buffered trace events.
+    pub fn flush(&self) -> io::Result<()> {
+        crate::disable_in_scope(|| self.trace().writer.flush())
+    }
+
+    /// Terminates and flushes the trace; subsequent events are not written.
+    pub fn finish(&self) -> io::Result<()> {
+        crate::disable_in_scope(|| self.trace().finish())
+    }
+
+    fn trace(&self) -> std::sync::MutexGuard<'_, Trace> {
+        self.trace.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl core::fmt::Debug for ChromeTraceHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ChromeTraceHandle").finish_non_exhaustive()
+    }
+}
+
+/// The trace written by a [`ChromeTraceLayer`].
+struct Trace {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    /// The ID of this process.
+    pid: u32,
+    /// The least size of the allocations written as instant events.
+    threshold: u64,
+    /// The number of trace events written so far.
+    events: u64,
+    /// Whether the trace has been terminated.
+    finished: bool,
+    /// The live bytes of each thread, by its number.
+    threads: HashMap<u64, i64>,
+}
+
+impl Trace {
+    /// Writes the trace events for the operation described by `event`, which
+    /// was performed by the current thread.
+    fn record(&mut self, event: &AllocationEvent) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let tid = crate::thread::number();
+        let nanos = event.timestamp_ns.unwrap_or_else(crate::monotonic_nanos);
+        let ts = format!("{}.{:03}", nanos / 1000, nanos % 1000);
+        let pid = self.pid;
+
+        let weight = event.weight();
+        let scale = |n: u64| (n as f64 * weight).round() as u64;
+        let (allocated, freed) = match event.kind {
+            AllocationKind::Alloc | AllocationKind::AllocZeroed => (event.size, 0),
+            AllocationKind::Dealloc => (0, event.size),
+            AllocationKind::Realloc => (event.size, event.old_size.unwrap_or(0)),
+        };
+        let (allocated, freed) = (scale(allocated), scale(freed));
+
+        let thread = std::thread::current();
+        let name = match thread.name() {
+            Some(name) => String::from(name),
+            None => format!("thread {}", tid),
+        };
+        if !self.threads.contains_key(&tid) {
+            self.event(format_args!(
+                r#"{{"name":"thread_name","ph":"M","pid":{},"tid":{},"args":{{"name":{}}}}}"#,
+                pid,
+                tid,
+                JsonStr(&name),
+            ))?;
+        }
+        let live = self.threads.entry(tid).or_insert(0);
+        *live = live
+            .wrapping_add(allocated as i64)
+            .wrapping_sub(freed as i64);
+        let live = *live;
+
+        self.event(format_args!(
+            r#"{{"name":{},"ph":"C","ts":{},"pid":{},"tid":{},"args":{{"bytes":{}}}}}"#,
+            JsonStr(&format!("live bytes ({})", name)),
+            ts,
+            pid,
+            tid,
+            live,
+        ))?;
+        if event.kind != AllocationKind::Dealloc && event.size >= self.threshold {
+            self.event(format_args!(
+                r#"{{"name":"{}","cat":"allocation","ph":"i","s":"t","ts":{},"pid":{},"tid":{},"args":{{"size":{},"addr":{}}}}}"#,
+                event.kind.as_str(),
+                ts,
+                pid,
+                tid,
+                event.size,
+                event.addr,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Writes a trace event.
+    fn event(&mut self, event: core::fmt::Arguments<'_>) -> io::Result<()> {
+        let separator = if self.events == 0 { "[\n" } else { ",\n" };
+        self.events += 1;
+        self.writer.write_all(separator.as_bytes())?;
+        self.writer.write_fmt(event)
+    }
+
+    /// Terminates and flushes the trace.
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.finished = true;
+            let terminator = if self.events == 0 { "[]\n" } else { "\n]\n" };
+            self.writer.write_all(terminator.as_bytes())?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        crate::disable_in_scope(|| {
+            let _ = self.finish();
+        });
+    }
+}
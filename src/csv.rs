@@ -0,0 +1,148 @@
+//! Allocation events as CSV.
+//!
+//! [`CsvLayer`] writes one row per allocation event, with fixed columns, so
+//! that traces may be loaded straight into pandas, DuckDB or a spreadsheet.
+
+use core::fmt::Write as _;
+use std::{
+    io::Write,
+    sync::{Mutex, PoisonError},
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::event::AllocationEvent;
+
+/// The header row.
+const HEADER: &str = "seq,kind,addr,size,align,thread,caller\n";
+
+/// A [`Layer`] that writes the allocation events it observes to a writer, as
+/// CSV, with the following columns:
+/// - **`seq`**  
+///   the number of the event, in the order in which they were written,
+///   starting from 0
+/// - **`kind`**  
+///   "alloc", "alloc_zeroed", "dealloc" or "realloc"
+/// - **`addr`**  
+///   the address of the block, in decimal; for reallocations, of the new
+///   block
+/// - **`size`**  
+///   the size of the block; for reallocations, of the new block
+/// - **`align`**  
+///   the alignment of the block
+/// - **`thread`**  
+///   the number of the thread that performed the operation, in the order in
+///   which threads first performed a traced operation, starting from 1
+/// - **`caller`**  
+///   the code that requested the operation; empty unless requested (with
+///   `Detail::Caller`)
+///
+/// The header row is written before the first event. Events that are not
+/// allocation events are ignored.
+///
+/// Requires the `csv` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::CsvLayer;
+///
+/// let file = std::fs::File::create("allocations.csv").unwrap();
+/// tracing_subscriber::registry().with(CsvLayer::new(file)).init();
+/// ```
+///
+/// ```sql
+/// SELECT caller, sum(size) FROM 'allocations.csv'
+/// WHERE kind = 'alloc' GROUP BY caller ORDER BY 2 DESC;
+/// ```
+#[derive(Debug)]
+pub struct CsvLayer<W> {
+    state: Mutex<State<W>>,
+}
+
+/// The writer of a [`CsvLayer`].
+#[derive(Debug)]
+struct State<W> {
+    writer: W,
+    /// The number of events written so far.
+    seq: u64,
+    /// The row being formatted.
+    row: String,
+    /// The caller of the event being written.
+    caller: String,
+}
+
+impl<W> CsvLayer<W>
+where
+    W: Write + Send + 'static,
+{
+    /// Constructs a new `CsvLayer`, which writes to `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            state: Mutex::new(State {
+                writer,
+                seq: 0,
+                row: String::new(),
+                caller: String::new(),
+            }),
+        }
+    }
+}
+
+impl<S, W> Layer<S> for CsvLayer<W>
+where
+    S: Subscriber,
+    W: Write + Send + 'static,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(decoded) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let State {
+                writer,
+                seq,
+                row,
+                caller,
+            } = &mut *state;
+            row.clear();
+            if *seq == 0 {
+                row.push_str(HEADER);
+            }
+            let _ = write!(
+                row,
+                "{},{},{},{},",
+                seq, decoded.kind, decoded.addr, decoded.size
+            );
+            if let Some(align) = decoded.align {
+                let _ = write!(row, "{}", align);
+            }
+            let _ = write!(row, ",{},", crate::thread::number());
+            caller.clear();
+            if crate::event::caller_of(event, caller) {
+                quote(row, caller);
+            }
+            row.push('\n');
+            if writer.write_all(row.as_bytes()).is_ok() {
+                *seq += 1;
+            }
+        });
+    }
+}
+
+/// Appends `field` to `row`, quoted if necessary.
+fn quote(row: &mut String, field: &str) {
+    if !field.contains([',', '"', '\n', '\r']) {
+        return row.push_str(field);
+    }
+    row.push('"');
+    for c in field.chars() {
+        if c == '"' {
+            row.push('"');
+        }
+        row.push(c);
+    }
+    row.push('"');
+}
@@ -0,0 +1,113 @@
+//! Additional detail on the events emitted within a scope.
+//!
+//! Some detail (such as a backtrace) is too expensive to collect for every
+//! event. [`with_detail_in_scope`] collects it only for the operations
+//! performed by a closure, on the current thread, so that the cost is confined
+//! to the code under investigation.
+
+use core::cell::Cell;
+use std::backtrace::Backtrace;
+
+use tracing::field::{display, DisplayValue};
+
+thread_local! {
+    /// The set of details requested on this thread, as a bit set.
+    static DETAILS: Cell<u8> = const { Cell::new(0) };
+}
+
+/// Additional detail on allocation events. See [`with_detail_in_scope`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Detail {
+    /// A `backtrace` field, with the backtrace of each operation.
+    Backtrace,
+    /// A `caller` field, with the code that requested each operation.
+    ///
+    /// Requires the `backtrace` feature.
+    #[cfg(feature = "backtrace")]
+    Caller,
+}
+
+impl Detail {
+    /// This detail's bit in a set of details.
+    const fn bit(self) -> u8 {
+        match self {
+            Detail::Backtrace => 1 << 0,
+            #[cfg(feature = "backtrace")]
+            Detail::Caller => 1 << 1,
+        }
+    }
+}
+
+/// Whether `detail` is requested on the current thread.
+fn requested(detail: Detail) -> bool {
+    DETAILS
+        .try_with(|details| details.get() & detail.bit() != 0)
+        .unwrap_or(false)
+}
+
+/// The `backtrace` field of emitted events, if requested.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+pub(crate) fn backtrace() -> Option<DisplayValue<Backtrace>> {
+    requested(Detail::Backtrace).then(|| display(Backtrace::force_capture()))
+}
+
+/// The value of the `caller` field of emitted events.
+#[cfg(feature = "backtrace")]
+pub(crate) type CallerValue = DisplayValue<&'static crate::callsite::Callsite>;
+
+/// The value of the `caller` field of emitted events.
+#[cfg(not(feature = "backtrace"))]
+pub(crate) type CallerValue = DisplayValue<&'static str>;
+
+/// The `caller` field of emitted events, if requested.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+#[cfg(feature = "backtrace")]
+pub(crate) fn caller() -> Option<CallerValue> {
+    if !requested(Detail::Caller) {
+        return None;
+    }
+    crate::callsite::caller().map(display)
+}
+
+/// The `caller` field of emitted events, which is never present without the
+/// `backtrace` feature.
+#[cfg(not(feature = "backtrace"))]
+pub(crate) fn caller() -> Option<CallerValue> {
+    None
+}
+
+/// Run the given function with `detail` added to the allocation events it
+/// causes to be emitted on the current thread.
+///
+/// Calls may be nested, to request several details at once.
+///
+/// ## Usage
+/// ```
+/// use tracing_allocations::{with_detail_in_scope, Detail};
+///
+/// # fn suspicious_function() {}
+/// with_detail_in_scope(Detail::Backtrace, || {
+///     suspicious_function();
+/// });
+/// ```
+pub fn with_detail_in_scope<F, R>(detail: Detail, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    /// Restores the previous set of details, even if `f` panics.
+    struct Restore(u8);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let _ = DETAILS.try_with(|details| details.set(self.0));
+        }
+    }
+
+    let _restore = Restore(DETAILS.with(|details| details.replace(details.get() | detail.bit())));
+    f()
+}
@@ -0,0 +1,347 @@
+//! Heap profiles in the JSON format of DHAT.
+//!
+//! [`DhatLayer`] captures the stack of each allocation described by the
+//! events it observes, tracks the lifetime of each block, and writes the
+//! totals of each stack in the JSON format of DHAT, as read by the online
+//! DHAT viewer (`dh_view.html`).
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    event::{AllocationEvent, AllocationKind},
+    json::JsonStr,
+    stack::{self, Symbolizer},
+};
+
+/// A [`Layer`] that profiles the allocations described by the events it
+/// observes, by the stack that requested them, in the manner of DHAT.
+///
+/// Each block lives from the observed allocation (or reallocation) that
+/// produced it until its observed deallocation, or else until the profile is
+/// written, and is attributed to the stack of the former. For each stack, the
+/// profile has the number of blocks and bytes allocated, the total lifetime
+/// of those blocks, the greatest number of them that were live at once, the
+/// number that were live when the heap was at its greatest, and the number
+/// that are still live. Profiles may be written at any time, from any
+/// thread, through the [`DhatHandle`] returned by [`DhatLayer::handle`].
+/// Sampled and coalesced events are scaled by [`AllocationEvent::weight`].
+///
+/// Accesses to blocks are not observed, so profiles have no access counts.
+///
+/// Requires the `dhat` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::DhatLayer;
+///
+/// let layer = DhatLayer::new();
+/// let profile = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// let file = std::fs::File::create("dhat-heap.json").unwrap();
+/// profile.write_to(file).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct DhatLayer {
+    handle: DhatHandle,
+}
+
+impl DhatLayer {
+    /// Constructs a new `DhatLayer`, with an empty profile.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle through which to write the profile of this layer.
+    pub fn handle(&self) -> DhatHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for DhatLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let stack = (event.kind != AllocationKind::Dealloc).then(stack::capture);
+            self.handle
+                .profile
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(&event, stack);
+        });
+    }
+}
+
+/// A handle to the profile of a [`DhatLayer`].
+///
+/// Handles are cheap to clone, and all clones write the same profile.
+#[derive(Clone, Default)]
+pub struct DhatHandle {
+    profile: Arc<Mutex<Profile>>,
+    symbolizer: Arc<Mutex<Symbolizer>>,
+}
+
+impl DhatHandle {
+    /// Writes a DHAT profile of the allocations observed so far to `writer`.
+    ///
+    /// Stacks are symbolized as the profile is written, which may be slow the
+    /// first time each stack frame is encountered.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        crate::disable_in_scope(|| {
+            let now = crate::monotonic_nanos();
+            let (sites, began_ns, peak_ns) = {
+                let profile = self.profile.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut sites = profile.sites.clone();
+                if profile.at_peak {
+                    for site in &mut sites {
+                        site.peak_bytes = site.live_bytes;
+                        site.peak_blocks = site.live_blocks;
+                    }
+                }
+                // the lifetimes of live blocks extend to the end of the profile
+                for block in profile.live.values() {
+                    sites[block.site].lifetimes_ns +=
+                        now.saturating_sub(block.allocated_ns) * block.blocks;
+                }
+                (sites, profile.began_ns, profile.peak_ns)
+            };
+            let mut symbolizer = self
+                .symbolizer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let micros = |ns: u64| ns.saturating_sub(began_ns) / 1000;
+
+            let cmd: Vec<String> = std::env::args().collect();
+            write!(
+                writer,
+                r#"{{"dhatFileVersion":2,"mode":"rust-heap","verb":"Allocated","bklt":true,"bkacc":false,"tu":"µs","Mtu":"s","tuth":10,"cmd":{},"pid":{},"tg":{},"te":{},"pps":["#,
+                JsonStr(&cmd.join(" ")),
+                std::process::id(),
+                micros(peak_ns),
+                micros(now),
+            )?;
+            let mut frames = vec![String::from("[root]")];
+            let mut indices = HashMap::new();
+            for (index, site) in sites.iter().enumerate() {
+                let separator = if index == 0 { "" } else { "," };
+                write!(
+                    writer,
+                    r#"{}{{"tb":{},"tbk":{},"tl":{},"mb":{},"mbk":{},"gb":{},"gbk":{},"eb":{},"ebk":{},"fs":["#,
+                    separator,
+                    site.total_bytes,
+                    site.total_blocks,
+                    site.lifetimes_ns / 1000,
+                    site.max_bytes,
+                    site.max_blocks,
+                    site.peak_bytes,
+                    site.peak_blocks,
+                    site.live_bytes,
+                    site.live_blocks,
+                )?;
+                let mut first = true;
+                for &ip in symbolizer.trim(&site.stack) {
+                    for line in &symbolizer.frame(ip).lines {
+                        let frame = match (&line.file, line.line) {
+                            (Some(file), Some(number)) => {
+                                format!("{:#x}: {} ({}:{})", ip, line.function, file, number)
+                            }
+                            _ => format!("{:#x}: {}", ip, line.function),
+                        };
+                        let index = *indices.entry(frame.clone()).or_insert_with(|| {
+                            frames.push(frame);
+                            frames.len() - 1
+                        });
+                        let separator = if first { "" } else { "," };
+                        first = false;
+                        write!(writer, "{}{}", separator, index)?;
+                    }
+                }
+                write!(writer, "]}}")?;
+            }
+            write!(writer, r#"],"ftbl":["#)?;
+            for (index, frame) in frames.iter().enumerate() {
+                let separator = if index == 0 { "" } else { "," };
+                write!(writer, "{}{}", separator, JsonStr(frame))?;
+            }
+            writeln!(writer, "]}}")
+        })
+    }
+}
+
+impl core::fmt::Debug for DhatHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DhatHandle").finish_non_exhaustive()
+    }
+}
+
+/// The allocations observed by a [`DhatLayer`].
+struct Profile {
+    /// When the layer was constructed, per
+    /// [`monotonic_nanos`](crate::monotonic_nanos).
+    began_ns: u64,
+    /// The index in `sites` of each stack.
+    indices: HashMap<Vec<usize>, usize>,
+    /// The totals of each stack.
+    sites: Vec<Site>,
+    /// The live blocks, by address.
+    live: HashMap<u64, Block>,
+    /// The total size of the live blocks.
+    live_bytes: u64,
+    /// The greatest value of `live_bytes`.
+    peak_bytes: u64,
+    /// When `live_bytes` last reached `peak_bytes`.
+    peak_ns: u64,
+    /// Whether `live_bytes` is `peak_bytes`, and the live blocks of each site
+    /// are yet to be recorded as those at the peak.
+    at_peak: bool,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        let now = crate::monotonic_nanos();
+        Self {
+            began_ns: now,
+            indices: HashMap::new(),
+            sites: Vec::new(),
+            live: HashMap::new(),
+            live_bytes: 0,
+            peak_bytes: 0,
+            peak_ns: now,
+            at_peak: false,
+        }
+    }
+}
+
+/// The allocations requested by a stack; DHAT's "program point".
+#[derive(Clone, Default)]
+struct Site {
+    /// The instruction pointers of the stack, innermost first.
+    stack: Vec<usize>,
+    total_bytes: u64,
+    total_blocks: u64,
+    /// The sum of the lifetimes of the blocks that have been freed.
+    lifetimes_ns: u64,
+    live_bytes: u64,
+    live_blocks: u64,
+    /// The greatest value of `live_bytes`, and the value of `live_blocks`
+    /// then.
+    max_bytes: u64,
+    max_blocks: u64,
+    /// The values of `live_bytes` and `live_blocks` when the heap was last at
+    /// its peak, unless it is at its peak now.
+    peak_bytes: u64,
+    peak_blocks: u64,
+}
+
+/// A live block.
+struct Block {
+    /// The index of the site that allocated the block.
+    site: usize,
+    /// The number of blocks this stands for.
+    blocks: u64,
+    /// The number of bytes this stands for.
+    bytes: u64,
+    /// When the block was allocated, per
+    /// [`monotonic_nanos`](crate::monotonic_nanos).
+    allocated_ns: u64,
+}
+
+impl Profile {
+    /// Records the operation described by `event`, which was requested by
+    /// `stack` unless it is a deallocation.
+    fn record(&mut self, event: &AllocationEvent, stack: Option<Vec<usize>>) {
+        // events' timestamps may be of another clock
+        let now = crate::monotonic_nanos();
+        if let Some(old_addr) = event.old_addr {
+            self.free(old_addr, now);
+        }
+        let Some(stack) = stack else {
+            return self.free(event.addr, now);
+        };
+        if event.addr == 0 {
+            return;
+        }
+        let weight = event.weight();
+        let scale = |n: u64| (n as f64 * weight).round() as u64;
+        let (blocks, bytes) = (scale(1), scale(event.size));
+        let index = match self.indices.get(&stack) {
+            Some(&index) => index,
+            None => {
+                let index = self.sites.len();
+                self.indices.insert(stack.clone(), index);
+                self.sites.push(Site {
+                    stack,
+                    ..Site::default()
+                });
+                index
+            }
+        };
+        let site = &mut self.sites[index];
+        site.total_bytes += bytes;
+        site.total_blocks += blocks;
+        site.live_bytes += bytes;
+        site.live_blocks += blocks;
+        if site.live_bytes >= site.max_bytes {
+            site.max_bytes = site.live_bytes;
+            site.max_blocks = site.live_blocks;
+        }
+        let block = Block {
+            site: index,
+            blocks,
+            bytes,
+            allocated_ns: now,
+        };
+        // a block at the same address must have been freed unobserved
+        if let Some(stale) = self.live.insert(event.addr, block) {
+            self.release(&stale, now);
+        }
+        self.live_bytes += bytes;
+        if self.live_bytes >= self.peak_bytes {
+            self.peak_bytes = self.live_bytes;
+            self.peak_ns = now;
+            self.at_peak = true;
+        }
+    }
+
+    /// Records the deallocation, at `now`, of the block at `addr`, if it is
+    /// live.
+    fn free(&mut self, addr: u64, now: u64) {
+        if let Some(block) = self.live.remove(&addr) {
+            self.release(&block, now);
+        }
+    }
+
+    /// Deducts `block`, freed at `now`, from the live totals.
+    fn release(&mut self, block: &Block, now: u64) {
+        // the heap is leaving its peak, so the live blocks of each site are
+        // those at the peak
+        if self.at_peak {
+            self.at_peak = false;
+            for site in &mut self.sites {
+                site.peak_bytes = site.live_bytes;
+                site.peak_blocks = site.live_blocks;
+            }
+        }
+        let site = &mut self.sites[block.site];
+        site.live_bytes = site.live_bytes.saturating_sub(block.bytes);
+        site.live_blocks = site.live_blocks.saturating_sub(block.blocks);
+        site.lifetimes_ns += now.saturating_sub(block.allocated_ns) * block.blocks;
+        self.live_bytes = self.live_bytes.saturating_sub(block.bytes);
+    }
+}
@@ -0,0 +1,444 @@
+//! The schema of the events emitted by [`TracingAllocator`].
+//!
+//! [`AllocationEvent`] is the canonical description of a single allocator
+//! operation. [`TracingAllocator`] builds an `AllocationEvent` for each
+//! operation it traces, and emits it as a tracing event;
+//! [`AllocationEvent::from_event`] performs the reverse conversion, so that
+//! layers and offline tools need not duplicate field names.
+//!
+//! With the `serde` feature enabled, [`AllocationEvent`] implements
+//! `Serialize` and `Deserialize`. With the `valuable` feature enabled, it
+//! implements `Valuable`.
+//!
+//! [`TracingAllocator`]: crate::TracingAllocator
+
+use core::fmt::{self, Write as _};
+
+use tracing::field::{Field, Visit};
+
+/// The version of the [`AllocationEvent`] schema.
+///
+/// This is incremented whenever a field of [`AllocationEvent`] (or of the
+/// emitted tracing events) is renamed, removed, or changes meaning. Adding
+/// fields does not change the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The kind of allocator operation described by an [`AllocationEvent`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub enum AllocationKind {
+    /// An allocation produced by [`GlobalAlloc::alloc`].
+    ///
+    /// [`GlobalAlloc::alloc`]: core::alloc::GlobalAlloc::alloc
+    Alloc,
+    /// An allocation produced by [`GlobalAlloc::alloc_zeroed`].
+    ///
+    /// [`GlobalAlloc::alloc_zeroed`]: core::alloc::GlobalAlloc::alloc_zeroed
+    AllocZeroed,
+    /// A deallocation performed by [`GlobalAlloc::dealloc`].
+    ///
+    /// [`GlobalAlloc::dealloc`]: core::alloc::GlobalAlloc::dealloc
+    Dealloc,
+    /// A reallocation performed by [`GlobalAlloc::realloc`].
+    ///
+    /// [`GlobalAlloc::realloc`]: core::alloc::GlobalAlloc::realloc
+    Realloc,
+}
+
+impl AllocationKind {
+    /// The name of this kind, as it appears in the `kind` field of emitted
+    /// events; e.g., "alloc_zeroed".
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            AllocationKind::Alloc => "alloc",
+            AllocationKind::AllocZeroed => "alloc_zeroed",
+            AllocationKind::Dealloc => "dealloc",
+            AllocationKind::Realloc => "realloc",
+        }
+    }
+
+    /// This kind's index among all kinds.
+    pub(crate) const fn index(self) -> usize {
+        self.bit().trailing_zeros() as usize
+    }
+
+    /// This kind's bit in a set of kinds.
+    pub(crate) const fn bit(self) -> u8 {
+        match self {
+            AllocationKind::Alloc => 1 << 0,
+            AllocationKind::AllocZeroed => 1 << 1,
+            AllocationKind::Dealloc => 1 << 2,
+            AllocationKind::Realloc => 1 << 3,
+        }
+    }
+
+    /// The kind named `name`, if any.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "alloc" => Some(AllocationKind::Alloc),
+            "alloc_zeroed" => Some(AllocationKind::AllocZeroed),
+            "dealloc" => Some(AllocationKind::Dealloc),
+            "realloc" => Some(AllocationKind::Realloc),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AllocationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A single allocator operation.
+///
+/// Addresses and sizes are represented as [`u64`] regardless of the target's
+/// pointer width, so that a single consumer can process traces from any
+/// target.
+///
+/// For reallocations, `addr`, `size` and `usable_size` describe the *new*
+/// block; on emitted events, these are named `new_addr`, `new_size` and
+/// `new_usable_size`. The existing block is described by `old_addr` and
+/// `old_size`, which are absent for all other kinds of operation.
+///
+/// Addresses are reported as configured by the emitting allocator's
+/// [`AddressMode`](crate::AddressMode).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct AllocationEvent {
+    /// The kind of operation.
+    pub kind: AllocationKind,
+    /// The address of the allocated or deallocated block.
+    pub addr: u64,
+    /// The size of the allocated or deallocated block.
+    pub size: u64,
+    /// The alignment of the allocated or deallocated block (for
+    /// reallocations, of both the existing and the new block), if known.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub align: Option<u64>,
+    /// The usable size of the allocated or deallocated block, if known.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub usable_size: Option<u64>,
+    /// For reallocations, the address of the existing block.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub old_addr: Option<u64>,
+    /// For reallocations, the size of the existing block.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub old_size: Option<u64>,
+    /// For allocations, whether the block was zeroed, if known.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub zeroed: Option<bool>,
+    /// The time of the operation, in nanoseconds, if known.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub timestamp_ns: Option<u64>,
+    /// The ID of the span that was current during the operation, if known.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub span_id: Option<u64>,
+    /// If events are sampled 1-in-N, N.
+    ///
+    /// Each sampled event stands for `sample_rate` operations.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub sample_rate: Option<u64>,
+    /// If events are sampled by bytes, the mean number of bytes between
+    /// samples.
+    ///
+    /// A sampled event of `size` bytes stands for approximately
+    /// `size / (1 - exp(-size / sample_interval))` bytes of operations.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub sample_interval: Option<u64>,
+    /// If runs of identical operations are coalesced, the number of
+    /// operations this event stands for.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub count: Option<u64>,
+    /// For deallocations, the number of nanoseconds the block lived, if known.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub age_ns: Option<u64>,
+    /// For deallocations, the number of allocations performed while the block
+    /// lived, if known.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub age_events: Option<u64>,
+}
+
+impl AllocationEvent {
+    /// Constructs an event of the given `kind`, describing a block of `size`
+    /// bytes at `addr`, with all optional fields absent.
+    pub const fn new(kind: AllocationKind, addr: u64, size: u64) -> Self {
+        Self {
+            kind,
+            addr,
+            size,
+            align: None,
+            usable_size: None,
+            old_addr: None,
+            old_size: None,
+            zeroed: None,
+            timestamp_ns: None,
+            span_id: None,
+            sample_rate: None,
+            sample_interval: None,
+            count: None,
+            age_ns: None,
+            age_events: None,
+        }
+    }
+
+    /// For reallocations, the change in size; positive if the block grew,
+    /// negative if it shrank.
+    pub fn delta(&self) -> Option<i64> {
+        self.old_size
+            .map(|old_size| self.size as i64 - old_size as i64)
+    }
+
+    /// For reallocations, whether the block was moved.
+    pub fn moved(&self) -> Option<bool> {
+        self.old_addr.map(|old_addr| old_addr != self.addr)
+    }
+
+    /// The estimated number of operations this event stands for, accounting
+    /// for coalescing and sampling.
+    pub fn weight(&self) -> f64 {
+        let mut weight = self.count.unwrap_or(1) as f64 * self.sample_rate.unwrap_or(1) as f64;
+        if let Some(interval) = self.sample_interval.filter(|&interval| interval > 0) {
+            let probability = 1.0 - (-(self.size as f64) / interval as f64).exp();
+            if probability > 0.0 {
+                weight /= probability;
+            }
+        }
+        weight
+    }
+
+    /// Reconstructs the `AllocationEvent` described by a tracing event emitted
+    /// by [`TracingAllocator`](crate::TracingAllocator).
+    ///
+    /// Returns `None` if `event` does not describe an allocator operation
+    /// (including `large_alloc` warnings, which duplicate the information of
+    /// an accompanying operation event), or if its target is neither
+    /// "tracing::allocator" nor that of a [size class](crate::SizeClass::target),
+    /// as other events may have fields of the same names.
+    pub fn from_event(event: &tracing::Event<'_>) -> Option<Self> {
+        let target = event.metadata().target();
+        if !matches!(
+            target,
+            "tracing::allocator" | "alloc::small" | "alloc::medium" | "alloc::large"
+        ) {
+            return None;
+        }
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        visitor.finish()
+    }
+}
+
+/// Collects the fields of an emitted event.
+#[derive(Default)]
+struct EventVisitor {
+    kind: Option<AllocationKind>,
+    addr: Option<u64>,
+    size: Option<u64>,
+    align: Option<u64>,
+    usable_size: Option<u64>,
+    old_addr: Option<u64>,
+    old_size: Option<u64>,
+    zeroed: Option<bool>,
+    timestamp_ns: Option<u64>,
+    span_id: Option<u64>,
+    sample_rate: Option<u64>,
+    sample_interval: Option<u64>,
+    count: Option<u64>,
+    age_ns: Option<u64>,
+    age_events: Option<u64>,
+    large_alloc: bool,
+}
+
+impl EventVisitor {
+    fn finish(self) -> Option<AllocationEvent> {
+        if self.large_alloc {
+            return None;
+        }
+        Some(AllocationEvent {
+            kind: self.kind?,
+            addr: self.addr?,
+            size: self.size?,
+            align: self.align,
+            usable_size: self.usable_size,
+            old_addr: self.old_addr,
+            old_size: self.old_size,
+            zeroed: self.zeroed,
+            timestamp_ns: self.timestamp_ns,
+            span_id: self.span_id,
+            sample_rate: self.sample_rate,
+            sample_interval: self.sample_interval,
+            count: self.count,
+            age_ns: self.age_ns,
+            age_events: self.age_events,
+        })
+    }
+}
+
+impl Visit for EventVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "addr" | "new_addr" => self.addr = Some(value),
+            "size" | "new_size" => self.size = Some(value),
+            "align" => self.align = Some(value),
+            "usable_size" | "new_usable_size" => self.usable_size = Some(value),
+            "old_addr" => self.old_addr = Some(value),
+            "old_size" => self.old_size = Some(value),
+            "timestamp_ns" => self.timestamp_ns = Some(value),
+            "span_id" => self.span_id = Some(value),
+            "sample_rate" => self.sample_rate = Some(value),
+            "sample_interval" => self.sample_interval = Some(value),
+            "count" => self.count = Some(value),
+            "age_ns" => self.age_ns = Some(value),
+            "age_events" => self.age_events = Some(value),
+            _ => {}
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "zeroed" {
+            self.zeroed = Some(value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "kind" {
+            self.kind = AllocationKind::from_name(value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let mut buf = Buf::default();
+        // values that don't fit in the buffer are of no interest
+        if write!(buf, "{:?}", value).is_err() {
+            return;
+        }
+        let value = buf.as_str();
+        match field.name() {
+            "message" => self.large_alloc = value == "large_alloc",
+            "addr_hex" | "new_addr_hex" if self.addr.is_none() => self.addr = parse_hex(value),
+            "old_addr_hex" if self.old_addr.is_none() => self.old_addr = parse_hex(value),
+            _ => {}
+        }
+    }
+}
+
+/// Parses a `0x`-prefixed hexadecimal address.
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.strip_prefix("0x")?, 16).ok()
+}
+
+/// A small, fixed-capacity string buffer, for inspecting debug-formatted field
+/// values without allocating.
+#[derive(Default)]
+struct Buf {
+    bytes: [u8; 32],
+    len: usize,
+}
+
+impl Buf {
+    fn as_str(&self) -> &str {
+        // only whole `str`s are ever written
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Write for Buf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        let dst = self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?;
+        dst.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Writes the `caller` field of an emitted event, if it has one, to `caller`,
+/// and returns whether it did.
+#[cfg(feature = "tracing-subscriber")]
+pub(crate) fn caller_of(event: &tracing::Event<'_>, caller: &mut String) -> bool {
+    field_of(event, "caller", caller)
+}
+
+/// Writes the `tag` field of an emitted event, if it has one, to `tag`, and
+/// returns whether it did.
+#[cfg(feature = "tracing-subscriber")]
+pub(crate) fn tag_of(event: &tracing::Event<'_>, tag: &mut String) -> bool {
+    field_of(event, "tag", tag)
+}
+
+/// Writes the field `name` of an emitted event, if it has one, to `value`,
+/// and returns whether it did.
+#[cfg(feature = "tracing-subscriber")]
+fn field_of(event: &tracing::Event<'_>, name: &str, value: &mut String) -> bool {
+    /// Collects a field of an emitted event.
+    struct FieldVisitor<'n, 'v> {
+        name: &'n str,
+        value: &'v mut String,
+        found: bool,
+    }
+
+    impl Visit for FieldVisitor<'_, '_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == self.name {
+                self.value.push_str(value);
+                self.found = true;
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == self.name {
+                self.found = write!(self.value, "{:?}", value).is_ok();
+            }
+        }
+    }
+
+    let mut visitor = FieldVisitor {
+        name,
+        value,
+        found: false,
+    };
+    event.record(&mut visitor);
+    visitor.found
+}
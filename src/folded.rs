@@ -0,0 +1,177 @@
+//! Allocation profiles as folded stacks, for flame graphs.
+//!
+//! [`FoldedLayer`] captures the stack of each allocation described by the
+//! events it observes, and writes the stacks in the folded format of
+//! `stackcollapse` scripts, ready to be piped into `flamegraph.pl` or
+//! `inferno-flamegraph`.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    event::{AllocationEvent, AllocationKind},
+    stack::{self, Profile, Symbolizer, Totals},
+};
+
+/// A [`Layer`] that profiles the allocations described by the events it
+/// observes, by the stack that requested them.
+///
+/// Profiles may be written at any time, from any thread, through the
+/// [`FoldedHandle`] returned by [`FoldedLayer::handle`], as one line per
+/// stack, of the functions of the stack (outermost first, separated by
+/// semicolons) followed by the number of bytes they allocated, e.g.:
+///
+/// ```text
+/// main;app::load;alloc::vec::Vec<T,A>::reserve 4096
+/// ```
+///
+/// A line totals the observed allocations and reallocations of its stack;
+/// those written by [`FoldedHandle::write_live_to`] total only the blocks not
+/// yet freed by an observed deallocation. Sampled and coalesced events are
+/// scaled by [`AllocationEvent::weight`].
+///
+/// Requires the `folded` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::FoldedLayer;
+///
+/// let layer = FoldedLayer::new();
+/// let profile = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// let file = std::fs::File::create("allocations.folded").unwrap();
+/// profile.write_to(file).unwrap();
+/// ```
+///
+/// ```sh
+/// inferno-flamegraph --countname bytes allocations.folded > allocations.svg
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FoldedLayer {
+    handle: FoldedHandle,
+}
+
+impl FoldedLayer {
+    /// Constructs a new `FoldedLayer`, with an empty profile.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle through which to write the profile of this layer.
+    pub fn handle(&self) -> FoldedHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for FoldedLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let stack = (event.kind != AllocationKind::Dealloc).then(stack::capture);
+            self.handle
+                .profile
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(&event, stack);
+        });
+    }
+}
+
+/// A handle to the profile of a [`FoldedLayer`].
+///
+/// Handles are cheap to clone, and all clones write the same profile.
+#[derive(Clone, Default)]
+pub struct FoldedHandle {
+    profile: Arc<Mutex<Profile>>,
+    symbolizer: Arc<Mutex<Symbolizer>>,
+}
+
+impl FoldedHandle {
+    /// Writes the folded stacks of the allocations observed so far to
+    /// `writer`, weighted by the number of bytes each stack allocated.
+    ///
+    /// Stacks are symbolized as they are written, which may be slow the first
+    /// time each stack frame is encountered.
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.write_weighted(writer, |totals| totals.alloc_space)
+    }
+
+    /// Writes the folded stacks of the allocations observed so far to
+    /// `writer`, weighted by the number of bytes allocated by each stack that
+    /// are still live.
+    ///
+    /// Stacks are symbolized as they are written, which may be slow the first
+    /// time each stack frame is encountered.
+    pub fn write_live_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.write_weighted(writer, |totals| totals.inuse_space)
+    }
+
+    /// Writes the folded stacks with a non-zero `weight` to `writer`.
+    fn write_weighted<W: Write>(
+        &self,
+        mut writer: W,
+        weight: fn(&Totals) -> u64,
+    ) -> io::Result<()> {
+        crate::disable_in_scope(|| {
+            let stacks = self
+                .profile
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .stacks
+                .clone();
+            let mut symbolizer = self
+                .symbolizer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            // distinct stacks may fold to the same functions
+            let mut folded: BTreeMap<String, u64> = BTreeMap::new();
+            for totals in &stacks {
+                let weight = weight(totals);
+                if weight == 0 {
+                    continue;
+                }
+                let mut line = String::new();
+                for &ip in symbolizer.trim(&totals.stack).iter().rev() {
+                    for frame in symbolizer.frame(ip).lines.iter().rev() {
+                        if !line.is_empty() {
+                            line.push(';');
+                        }
+                        // semicolons separate frames, but may appear in names
+                        // (e.g., of array types)
+                        line.push_str(&frame.function.replace(';', ":"));
+                    }
+                }
+                if line.is_empty() {
+                    line.push_str("[unknown]");
+                }
+                *folded.entry(line).or_default() += weight;
+            }
+            for (line, weight) in folded {
+                writeln!(writer, "{} {}", line, weight)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl core::fmt::Debug for FoldedHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FoldedHandle").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,191 @@
+//! Detection of allocations in regions in which they are forbidden.
+//!
+//! A global allocator must not unwind, so [`TracingAllocator`] cannot panic
+//! upon a forbidden allocation. Instead, it records the allocation in
+//! thread-local storage (or emits an event immediately), and the panic is
+//! deferred until the end of the forbidding region.
+//!
+//! [`TracingAllocator`]: crate::TracingAllocator
+
+use core::{cell::Cell, fmt, marker::PhantomData};
+
+use crate::event::AllocationKind;
+
+thread_local! {
+    /// The state of the innermost region in which allocations are forbidden on
+    /// this thread, if any.
+    static FORBIDDEN: Cell<Option<Forbidden>> = const { Cell::new(None) };
+}
+
+/// What to do about allocations performed while they are forbidden. See
+/// [`forbid_allocations`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OnAllocation {
+    /// Panic when the region in which allocations are forbidden ends.
+    Panic,
+    /// Emit an [`ERROR`]-level event, with target "tracing::allocator", upon
+    /// each forbidden allocation.
+    ///
+    /// [`ERROR`]: tracing::Level::ERROR
+    Error,
+}
+
+/// The state of a region in which allocations are forbidden.
+#[derive(Clone, Copy)]
+struct Forbidden {
+    /// What to do about forbidden allocations.
+    on_allocation: OnAllocation,
+    /// The number of forbidden allocations performed so far.
+    count: u64,
+    /// The total size of the forbidden allocations performed so far.
+    bytes: u64,
+    /// The kind and size of the first forbidden allocation, if any.
+    first: Option<(AllocationKind, usize)>,
+}
+
+/// Whether allocations are forbidden on the current thread.
+pub(crate) fn forbidding() -> bool {
+    FORBIDDEN
+        .try_with(|forbidden| forbidden.get().is_some())
+        .unwrap_or(false)
+}
+
+/// Records an allocation of `size` bytes, if allocations are forbidden on the
+/// current thread; returns whether it must be reported with [`report`].
+pub(crate) fn record(kind: AllocationKind, size: usize) -> bool {
+    FORBIDDEN
+        .try_with(|forbidden| {
+            let Some(mut current) = forbidden.get() else {
+                return false;
+            };
+            current.count += 1;
+            current.bytes = current.bytes.saturating_add(size as u64);
+            current.first = current.first.or(Some((kind, size)));
+            forbidden.set(Some(current));
+            current.on_allocation == OnAllocation::Error
+        })
+        .unwrap_or(false)
+}
+
+/// Emits an event describing a forbidden allocation.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+pub(crate) fn report(kind: AllocationKind, size: usize) {
+    tracing::error!(
+        target: "tracing::allocator",
+        kind = kind.as_str(),
+        size = size as u64,
+        "allocation while allocations are forbidden"
+    );
+}
+
+/// Forbid allocations on the current thread, until the returned guard is
+/// dropped.
+///
+/// Allocations, zeroed allocations and reallocations are forbidden;
+/// deallocations are permitted. They are detected by
+/// [`TracingAllocator`](crate::TracingAllocator), which must be the global
+/// allocator, whether or not they are traced; allocations performed by the
+/// instrumentation itself are permitted. If guards are nested, the innermost
+/// guard governs.
+///
+/// With [`OnAllocation::Panic`], dropping the guard panics if any allocation
+/// was performed while it was held (unless the thread is already panicking).
+///
+/// ## Usage
+/// ```
+/// use std::alloc::System;
+/// use tracing_allocations::{forbid_allocations, OnAllocation, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// fn process(samples: &mut [f32]) {
+///     let _guard = forbid_allocations(OnAllocation::Error);
+///     for sample in samples {
+///         *sample *= 0.5;
+///     }
+/// }
+/// ```
+pub fn forbid_allocations(on_allocation: OnAllocation) -> ForbidGuard {
+    let forbidden = Forbidden {
+        on_allocation,
+        count: 0,
+        bytes: 0,
+        first: None,
+    };
+    ForbidGuard {
+        outer: FORBIDDEN.with(|current| current.replace(Some(forbidden))),
+        _not_send: PhantomData,
+    }
+}
+
+/// Run the given function, and panic if it performs any allocations on the
+/// current thread.
+///
+/// This is shorthand for holding the guard of
+/// [`forbid_allocations`]`(`[`OnAllocation::Panic`]`)` for the duration of
+/// the function.
+///
+/// ## Usage
+#[cfg_attr(feature = "off", doc = "```ignore")]
+#[cfg_attr(not(feature = "off"), doc = "```should_panic")]
+/// use std::alloc::System;
+/// use tracing_allocations::{assert_no_alloc, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// fn main() {
+///     let sum = assert_no_alloc(|| [1, 2, 3].iter().sum::<i32>());
+///     assert_eq!(sum, 6);
+///
+///     // panics
+///     assert_no_alloc(|| vec![1, 2, 3]);
+/// }
+/// ```
+pub fn assert_no_alloc<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = forbid_allocations(OnAllocation::Panic);
+    f()
+}
+
+/// A guard that forbids allocations on the current thread until it is
+/// dropped. See [`forbid_allocations`].
+#[must_use = "allocations are permitted again as soon as the guard is dropped"]
+pub struct ForbidGuard {
+    /// The state of the enclosing region, if any.
+    outer: Option<Forbidden>,
+    /// The guard restores the state of the thread it was created on.
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl Drop for ForbidGuard {
+    fn drop(&mut self) {
+        let outer = self.outer.take();
+        let Ok(Some(forbidden)) = FORBIDDEN.try_with(|current| current.replace(outer)) else {
+            return;
+        };
+        if let (OnAllocation::Panic, Some((kind, size))) =
+            (forbidden.on_allocation, forbidden.first)
+        {
+            if !std::thread::panicking() {
+                panic!(
+                    "{} allocation(s) of {} bytes in total were performed while allocations \
+                     were forbidden; the first was {} of {} bytes",
+                    forbidden.count, forbidden.bytes, kind, size,
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ForbidGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ForbidGuard").finish_non_exhaustive()
+    }
+}
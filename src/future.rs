@@ -0,0 +1,108 @@
+//! Allocation tracing scopes that follow futures across `.await`s.
+//!
+//! [`disable_in_scope`](crate::disable_in_scope) and friends affect only the
+//! current thread, but a task may resume on another thread after each
+//! `.await`. The combinators of [`AllocationTracingExt`] instead apply their
+//! scope around every poll (and the drop) of the future they wrap, in the
+//! manner of [`tracing::Instrument`].
+
+use core::{
+    future::Future,
+    mem::ManuallyDrop,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Extension methods that scope allocation tracing to a future.
+pub trait AllocationTracingExt: Future + Sized {
+    /// Disable allocation tracing whenever this future is polled or dropped.
+    ///
+    /// ## Usage
+    /// ```
+    /// use tracing_allocations::future::AllocationTracingExt;
+    ///
+    /// # async fn flush_metrics() {}
+    /// # async fn example() {
+    /// flush_metrics().untraced().await;
+    /// # }
+    /// ```
+    fn untraced(self) -> Untraced<Self> {
+        Untraced {
+            inner: ManuallyDrop::new(self),
+        }
+    }
+
+    /// Enable allocation tracing whenever this future is polled or dropped,
+    /// even within a broader region in which it is disabled.
+    ///
+    /// ## Usage
+    /// ```
+    /// use tracing_allocations::future::AllocationTracingExt;
+    ///
+    /// # async fn handle_request() {}
+    /// # async fn example() {
+    /// handle_request().traced().await;
+    /// # }
+    /// ```
+    fn traced(self) -> Traced<Self> {
+        Traced {
+            inner: ManuallyDrop::new(self),
+        }
+    }
+}
+
+impl<F: Future> AllocationTracingExt for F {}
+
+/// A future with allocation tracing disabled. See
+/// [`AllocationTracingExt::untraced`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Untraced<F> {
+    inner: ManuallyDrop<F>,
+}
+
+impl<F: Future> Future for Untraced<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _guard = crate::disabled();
+        // safety: `inner` is structurally pinned; it is never moved
+        let inner = unsafe { self.map_unchecked_mut(|this| &mut *this.inner) };
+        inner.poll(cx)
+    }
+}
+
+impl<F> Drop for Untraced<F> {
+    fn drop(&mut self) {
+        let _guard = crate::disabled();
+        // safety: `inner` is dropped in place, and never used again
+        unsafe { ManuallyDrop::drop(&mut self.inner) }
+    }
+}
+
+/// A future with allocation tracing enabled. See
+/// [`AllocationTracingExt::traced`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Traced<F> {
+    inner: ManuallyDrop<F>,
+}
+
+impl<F: Future> Future for Traced<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _guard = crate::enabled();
+        // safety: `inner` is structurally pinned; it is never moved
+        let inner = unsafe { self.map_unchecked_mut(|this| &mut *this.inner) };
+        inner.poll(cx)
+    }
+}
+
+impl<F> Drop for Traced<F> {
+    fn drop(&mut self) {
+        let _guard = crate::enabled();
+        // safety: `inner` is dropped in place, and never used again
+        unsafe { ManuallyDrop::drop(&mut self.inner) }
+    }
+}
@@ -0,0 +1,504 @@
+//! Process-wide counters of allocator operations.
+//!
+//! [`TracingAllocator`] counts every operation it performs, on every thread,
+//! whether or not it is traced. [`Region`] reports how these counters changed
+//! over a stretch of the program, in the manner of the `stats_alloc` crate;
+//! [`checkpoint`] and [`diff`] do the same for named points of the program.
+//! [`peak_bytes`] reports the high-water mark of heap usage, which cannot be
+//! reconstructed from sampled events, and [`stats`] reports the counters
+//! themselves.
+//!
+//! The counters, and thus the live bytes, are divided into stripes, so that
+//! threads allocating concurrently seldom contend for them. The peak is
+//! tracked approximately, by sampling the live bytes as each thread
+//! allocates, unless [exact peak
+//! tracking](crate::TracingAllocator::with_exact_peak) is enabled.
+//!
+//! [`TracingAllocator`]: crate::TracingAllocator
+
+use core::{
+    cell::Cell,
+    fmt,
+    sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+};
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::event::AllocationKind;
+
+/// The number of stripes into which the process-wide counts are divided.
+const STRIPES: usize = 32;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZEROED: Stripe = Stripe(Counters::new());
+
+/// The counts of all operations performed so far, divided into stripes so
+/// that threads allocating concurrently seldom contend for the same counters;
+/// the counts are the sum of those of all stripes.
+static COUNTERS: [Stripe; STRIPES] = [ZEROED; STRIPES];
+
+/// The number of threads that have been assigned a stripe.
+static STRIPED: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of bytes each thread allocates between its samples of the live
+/// bytes, unless exact peak tracking is enabled.
+const PEAK_SAMPLE_BYTES: u64 = 64 * 1024;
+
+thread_local! {
+    /// The index of the stripe of this thread, if it has been assigned one.
+    static STRIPE: Cell<Option<usize>> = const { Cell::new(None) };
+
+    /// The number of bytes this thread has allocated since it last sampled
+    /// the live bytes.
+    static UNSAMPLED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The number of bytes currently allocated, by all threads, if exact peak
+/// tracking is enabled.
+///
+/// Unlike the counts, this is not striped, so that its peak may be tracked
+/// exactly; every thread that allocates or frees contends for it.
+static EXACT_LIVE_BYTES: AtomicI64 = AtomicI64::new(0);
+
+/// The greatest number of bytes allocated at once since the peak was last
+/// reset.
+static PEAK_BYTES: AtomicI64 = AtomicI64::new(0);
+
+/// The greatest number of bytes allocated at once since the last periodic
+/// report. See [`start_reporter`](crate::start_reporter).
+static REPORTED_PEAK: AtomicI64 = AtomicI64::new(0);
+
+/// The counts at each named checkpoint.
+static CHECKPOINTS: Mutex<BTreeMap<&'static str, AllocationCounts>> = Mutex::new(BTreeMap::new());
+
+/// Counts of allocator operations.
+///
+/// Reallocations are counted neither as allocations nor as deallocations, but
+/// contribute the size of the new block to `bytes_allocated`, and the size of
+/// the existing block to `bytes_freed`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct AllocationCounts {
+    /// The number of allocations, zeroed or not.
+    pub allocations: u64,
+    /// The number of deallocations.
+    pub deallocations: u64,
+    /// The number of reallocations.
+    pub reallocations: u64,
+    /// The total size of all allocated blocks.
+    pub bytes_allocated: u64,
+    /// The total size of all freed blocks.
+    pub bytes_freed: u64,
+}
+
+impl AllocationCounts {
+    /// The number of bytes allocated, less the number of bytes freed.
+    pub fn net_bytes(&self) -> i64 {
+        self.bytes_allocated.wrapping_sub(self.bytes_freed) as i64
+    }
+
+    /// The number of allocations, less the number of deallocations.
+    pub fn net_allocations(&self) -> i64 {
+        self.allocations.wrapping_sub(self.deallocations) as i64
+    }
+
+    /// The sum of the counts of `self` and `other`.
+    fn plus(&self, other: &Self) -> Self {
+        Self {
+            allocations: self.allocations.wrapping_add(other.allocations),
+            deallocations: self.deallocations.wrapping_add(other.deallocations),
+            reallocations: self.reallocations.wrapping_add(other.reallocations),
+            bytes_allocated: self.bytes_allocated.wrapping_add(other.bytes_allocated),
+            bytes_freed: self.bytes_freed.wrapping_add(other.bytes_freed),
+        }
+    }
+
+    /// The counts accrued between `earlier` and `self`.
+    fn since(&self, earlier: &Self) -> Self {
+        Self {
+            allocations: self.allocations.wrapping_sub(earlier.allocations),
+            deallocations: self.deallocations.wrapping_sub(earlier.deallocations),
+            reallocations: self.reallocations.wrapping_sub(earlier.reallocations),
+            bytes_allocated: self.bytes_allocated.wrapping_sub(earlier.bytes_allocated),
+            bytes_freed: self.bytes_freed.wrapping_sub(earlier.bytes_freed),
+        }
+    }
+}
+
+/// The process-wide counts of allocator operations. See [`stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct GlobalStats {
+    /// The counts of all operations performed so far.
+    pub counts: AllocationCounts,
+    /// The number of bytes currently allocated.
+    pub live_bytes: u64,
+    /// The greatest number of bytes that were allocated at once; see
+    /// [`peak_bytes`].
+    pub peak_bytes: u64,
+}
+
+/// Atomic counts of allocator operations.
+#[derive(Default)]
+pub(crate) struct Counters {
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+    reallocations: AtomicU64,
+    bytes_allocated: AtomicU64,
+    bytes_freed: AtomicU64,
+}
+
+impl Counters {
+    /// Zeroed counters.
+    pub(crate) const fn new() -> Self {
+        Self {
+            allocations: AtomicU64::new(0),
+            deallocations: AtomicU64::new(0),
+            reallocations: AtomicU64::new(0),
+            bytes_allocated: AtomicU64::new(0),
+            bytes_freed: AtomicU64::new(0),
+        }
+    }
+
+    /// The current counts.
+    pub(crate) fn load(&self) -> AllocationCounts {
+        AllocationCounts {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            reallocations: self.reallocations.load(Ordering::Relaxed),
+            bytes_allocated: self.bytes_allocated.load(Ordering::Relaxed),
+            bytes_freed: self.bytes_freed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resets all counts to zero.
+    pub(crate) fn reset(&self) {
+        for count in [
+            &self.allocations,
+            &self.deallocations,
+            &self.reallocations,
+            &self.bytes_allocated,
+            &self.bytes_freed,
+        ] {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Adds `operations` operations of the given `kind`, which allocated
+    /// `allocated` bytes and freed `freed` bytes in total.
+    pub(crate) fn add(&self, kind: AllocationKind, operations: u64, allocated: u64, freed: u64) {
+        let count = match kind {
+            AllocationKind::Alloc | AllocationKind::AllocZeroed => &self.allocations,
+            AllocationKind::Dealloc => &self.deallocations,
+            AllocationKind::Realloc => &self.reallocations,
+        };
+        count.fetch_add(operations, Ordering::Relaxed);
+        if allocated > 0 {
+            self.bytes_allocated.fetch_add(allocated, Ordering::Relaxed);
+        }
+        if freed > 0 {
+            self.bytes_freed.fetch_add(freed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counters, aligned so that no two stripes share a cache line.
+#[repr(align(128))]
+struct Stripe(Counters);
+
+/// The stripe of the counts of the current thread.
+fn stripe() -> &'static Counters {
+    let index = STRIPE
+        .try_with(|stripe| match stripe.get() {
+            Some(index) => index,
+            None => {
+                let index = STRIPED.fetch_add(1, Ordering::Relaxed) % STRIPES;
+                stripe.set(Some(index));
+                index
+            }
+        })
+        // threads whose thread-local storage has been destroyed share a stripe
+        .unwrap_or(0);
+    &COUNTERS[index].0
+}
+
+/// The counts of all operations performed so far.
+fn counts() -> AllocationCounts {
+    COUNTERS
+        .iter()
+        .fold(AllocationCounts::default(), |counts, Stripe(counters)| {
+            counts.plus(&counters.load())
+        })
+}
+
+/// The number of bytes currently allocated, by all threads; the sum of those
+/// of all stripes.
+fn live_bytes() -> i64 {
+    counts().net_bytes()
+}
+
+/// Records an operation that allocated `allocated` bytes and freed `freed`
+/// bytes, tracking the peak exactly if `exact_peak`.
+pub(crate) fn record(kind: AllocationKind, allocated: usize, freed: usize, exact_peak: bool) {
+    stripe().add(kind, 1, allocated as u64, freed as u64);
+    if exact_peak {
+        let delta = (allocated as i64).wrapping_sub(freed as i64);
+        if delta == 0 {
+            return;
+        }
+        let live = EXACT_LIVE_BYTES
+            .fetch_add(delta, Ordering::Relaxed)
+            .wrapping_add(delta);
+        if delta > 0 {
+            raise_peak(live);
+        }
+    } else if allocated > 0 && sample_due(allocated as u64) {
+        raise_peak(live_bytes());
+    }
+}
+
+/// Whether the current thread, having allocated `allocated` more bytes, should
+/// sample the live bytes.
+fn sample_due(allocated: u64) -> bool {
+    UNSAMPLED
+        .try_with(|unsampled| {
+            let total = unsampled.get().saturating_add(allocated);
+            let due = total >= PEAK_SAMPLE_BYTES;
+            unsampled.set(if due { 0 } else { total });
+            due
+        })
+        // threads whose thread-local storage has been destroyed always sample
+        .unwrap_or(true)
+}
+
+/// Raises the peaks to `live` bytes, if they are lower.
+fn raise_peak(live: i64) {
+    // the peak is written only when it is exceeded, so that threads seldom
+    // contend for it once it has been reached
+    for peak in [&PEAK_BYTES, &REPORTED_PEAK] {
+        if live > peak.load(Ordering::Relaxed) {
+            peak.fetch_max(live, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The greatest number of bytes that were allocated at once since this was
+/// last called, after which the peak is reset to the number of bytes that are
+/// currently allocated.
+pub(crate) fn take_reported_peak() -> u64 {
+    let live = live_bytes();
+    REPORTED_PEAK.swap(live, Ordering::Relaxed).max(live).max(0) as u64
+}
+
+/// The counts of the allocator operations performed so far, by all threads.
+///
+/// Operations are counted by [`TracingAllocator`](crate::TracingAllocator),
+/// which must be the global allocator, whether or not they are traced and
+/// whether or not a subscriber is installed. The counts are maintained in
+/// atomics, so reading them is cheap enough to do frequently (e.g., to feed a
+/// dashboard every second), unlike consuming the event stream. The counts are
+/// divided into stripes, each shared by a fraction of all threads, and summed
+/// when read; threads allocating concurrently on many cores thus seldom
+/// contend for them.
+///
+/// ## Usage
+#[cfg_attr(feature = "off", doc = "```ignore")]
+#[cfg_attr(not(feature = "off"), doc = "```")]
+/// use std::alloc::System;
+/// use tracing_allocations::TracingAllocator;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// fn main() {
+///     let v = vec![0u8; 64];
+///     let stats = tracing_allocations::stats();
+///     assert!(stats.counts.allocations >= 1);
+///     assert!(stats.live_bytes >= 64);
+///     # drop(v);
+/// }
+/// ```
+pub fn stats() -> GlobalStats {
+    GlobalStats {
+        counts: counts(),
+        live_bytes: live_bytes().max(0) as u64,
+        peak_bytes: peak_bytes(),
+    }
+}
+
+/// The greatest number of bytes that were allocated at once, by all threads,
+/// since the program began or [`reset_peak`] was last called.
+///
+/// Operations are counted by [`TracingAllocator`](crate::TracingAllocator),
+/// which must be the global allocator, whether or not they are traced.
+///
+/// By default, the peak is approximate: each thread samples the live bytes
+/// once for every 64 KiB it allocates, so a peak reached by small allocations
+/// may be underestimated by up to 64 KiB for each thread that allocates. With
+/// [`TracingAllocator::with_exact_peak`](crate::TracingAllocator::with_exact_peak),
+/// the peak is exact, but every operation updates one shared counter.
+///
+/// ## Usage
+#[cfg_attr(feature = "off", doc = "```ignore")]
+#[cfg_attr(not(feature = "off"), doc = "```")]
+/// use std::alloc::System;
+/// use tracing_allocations::{peak_bytes, reset_peak, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> =
+///     TracingAllocator::new(System).with_exact_peak(true);
+///
+/// fn main() {
+///     reset_peak();
+///     drop(vec![0u8; 4096]);
+///     assert!(peak_bytes() >= 4096);
+/// }
+/// ```
+pub fn peak_bytes() -> u64 {
+    PEAK_BYTES.load(Ordering::Relaxed).max(0) as u64
+}
+
+/// Resets the peak reported by [`peak_bytes`] to the number of bytes that are
+/// currently allocated.
+pub fn reset_peak() {
+    PEAK_BYTES.store(live_bytes(), Ordering::Relaxed);
+}
+
+/// A stretch of the program, over which to measure the allocator operations
+/// performed by all threads.
+///
+/// Operations are counted by [`TracingAllocator`](crate::TracingAllocator),
+/// which must be the global allocator, whether or not they are traced; no
+/// subscriber is required. Unlike
+/// [`count_allocations`](crate::count_allocations), this includes operations
+/// performed by other threads, and by the instrumentation itself.
+///
+/// ## Usage
+#[cfg_attr(feature = "off", doc = "```ignore")]
+#[cfg_attr(not(feature = "off"), doc = "```")]
+/// use std::alloc::System;
+/// use tracing_allocations::{Region, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// fn main() {
+///     let region = Region::new();
+///     let v = vec![0u8; 64];
+///     let change = region.change();
+///     assert!(change.allocations >= 1);
+///     assert!(change.net_bytes() >= 64);
+///     # drop(v);
+/// }
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    /// The counts when this region began.
+    initial: AllocationCounts,
+}
+
+impl Region {
+    /// Begins a region at the current point of the program.
+    pub fn new() -> Self {
+        Self { initial: counts() }
+    }
+
+    /// The counts of the operations performed since this region began.
+    pub fn change(&self) -> AllocationCounts {
+        counts().since(&self.initial)
+    }
+
+    /// The counts of the operations performed since this region began, after
+    /// which the region begins anew.
+    pub fn change_and_reset(&mut self) -> AllocationCounts {
+        let current = counts();
+        let change = current.since(&self.initial);
+        self.initial = current;
+        change
+    }
+
+    /// Begins this region anew, at the current point of the program.
+    pub fn reset(&mut self) {
+        self.initial = counts();
+    }
+}
+
+impl Default for Region {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Record the counts of the allocator operations performed so far under
+/// `name`, for later comparison with [`diff`].
+///
+/// Recording a checkpoint under an existing name replaces it. The
+/// bookkeeping of checkpoints is performed as instrumentation, and the
+/// counts are read once the checkpoint has a place in which to be recorded,
+/// so that consecutive checkpoints do not differ.
+///
+/// ## Usage
+#[cfg_attr(feature = "off", doc = "```ignore")]
+#[cfg_attr(not(feature = "off"), doc = "```")]
+/// use std::alloc::System;
+/// use tracing_allocations::{checkpoint, diff, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// # fn handle_request() -> Vec<u8> { vec![0; 64] }
+/// fn main() {
+///     checkpoint("before_request");
+///     let response = handle_request();
+///     checkpoint("after_request");
+///
+///     let growth = diff("before_request", "after_request").unwrap();
+///     assert!(growth.net_bytes() >= 64);
+///     # drop(response);
+/// }
+/// ```
+pub fn checkpoint(name: &'static str) {
+    crate::as_instrumentation(|| {
+        let mut checkpoints = CHECKPOINTS.lock().unwrap_or_else(PoisonError::into_inner);
+        // insert the checkpoint before reading the counts, so that they
+        // include any allocation made to hold it
+        let checkpoint = checkpoints.entry(name).or_default();
+        *checkpoint = counts();
+    });
+}
+
+/// The counts of the allocator operations performed between the checkpoints
+/// named `from` and `to`; `None` if either has not been recorded. See
+/// [`checkpoint`].
+pub fn diff(from: &str, to: &str) -> Option<AllocationCounts> {
+    crate::as_instrumentation(|| {
+        let checkpoints = CHECKPOINTS.lock().unwrap_or_else(PoisonError::into_inner);
+        let (from, to) = (checkpoints.get(from)?, checkpoints.get(to)?);
+        Some(to.since(from))
+    })
+}
+
+/// A number of bytes, displayed in binary units.
+pub(crate) struct Bytes(pub(crate) u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{:.1} {}", value, UNITS[unit])
+    }
+}
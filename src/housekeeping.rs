@@ -0,0 +1,328 @@
+//! Configurable housekeeping. See [`housekeeping`](crate::housekeeping()).
+//!
+//! [`builder`] extends the housekeeping performed at the start of `main` with
+//! application-specific initializers, which run with allocation tracing
+//! disabled. This lets applications list, in one place, the lazily
+//! initialized values that would otherwise deadlock if first initialized
+//! while an allocation is being traced. It also opts into reports of the
+//! heap when a thread panics, and at the end of `main`.
+
+use core::{
+    fmt::{self, Write as _},
+    marker::PhantomData,
+    sync::atomic::Ordering,
+};
+use std::{collections::BTreeMap, io::Write as _};
+
+use crate::{global::Bytes, HeapSnapshot};
+
+/// The number of tags, and of callers, listed in reports of the heap.
+const TOP: usize = 5;
+
+/// Begins configuring housekeeping.
+///
+/// ## Usage
+/// ```
+/// use std::alloc::System;
+/// use tracing_allocations::TracingAllocator;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// # fn my_logger() {}
+/// fn main() {
+///     let _guard = tracing_allocations::housekeeping::builder()
+///         .init(|| {
+///             let _ = my_logger();
+///         })
+///         .finish();
+///     /* your code here */
+/// }
+/// ```
+pub fn builder<'a>() -> Builder<'a> {
+    Builder {
+        initializers: Vec::new(),
+        suspend_during_panics: false,
+        heap_stats_on_panic: false,
+        report_on_exit: false,
+    }
+}
+
+/// A builder for housekeeping. See [`builder`].
+#[must_use = "housekeeping is only performed by `Builder::finish`"]
+pub struct Builder<'a> {
+    /// The initializers supplied by the application, in order.
+    initializers: Vec<Box<dyn FnOnce() + 'a>>,
+    /// Whether to suspend allocation tracing on panicking threads.
+    suspend_during_panics: bool,
+    /// Whether to report the heap when a thread panics.
+    heap_stats_on_panic: bool,
+    /// Whether to report the heap when the guard is dropped.
+    report_on_exit: bool,
+}
+
+impl<'a> Builder<'a> {
+    /// Runs `f` with allocation tracing disabled, after the standard
+    /// housekeeping is performed.
+    ///
+    /// Initializers run in the order they are supplied.
+    pub fn init<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce() + 'a,
+    {
+        self.initializers.push(Box::new(f));
+        self
+    }
+
+    /// If `suspend`, suspend allocation tracing on each thread for as long as
+    /// it is panicking; i.e., while the panic hook runs, and while the thread
+    /// unwinds.
+    ///
+    /// Formatting panic messages and capturing backtraces allocate heavily,
+    /// and the resulting events commonly re-enter the subscriber that panicked.
+    /// By default, tracing is not suspended.
+    ///
+    /// ## Usage
+    /// ```
+    /// let _guard = tracing_allocations::housekeeping::builder()
+    ///     .suspend_during_panics(true)
+    ///     .finish();
+    /// ```
+    pub fn suspend_during_panics(mut self, suspend: bool) -> Self {
+        self.suspend_during_panics = suspend;
+        self
+    }
+
+    /// If `report`, report the heap to standard error whenever a thread
+    /// panics, after the panic hook that was set when housekeeping was
+    /// performed, e.g.:
+    ///
+    /// ```text
+    /// heap at panic: 1.9 GiB live, peak 2.0 GiB
+    ///   top tags by live bytes:
+    ///     1.2 GiB  image-cache
+    ///   top callers by live bytes:
+    ///     1.1 GiB  app::cache::insert (src/cache.rs:42)
+    /// ```
+    ///
+    /// When a process dies of memory pressure, the panic message is often all
+    /// that remains of it. The live and peak bytes are those of
+    /// [`stats`](crate::stats()); the tags and callers holding the most live
+    /// bytes, up to five of each, are read from a [snapshot](crate::snapshot())
+    /// of the live table, so they are listed only if the [live
+    /// table](crate::TracingAllocator::with_live_table) is enabled. The report
+    /// never waits for a lock on the live table: parts of it held by other
+    /// threads are left out, and the report notes how many. Failures to
+    /// allocate that abort the process, rather than panic, are not reported.
+    /// By default, the heap is not reported.
+    ///
+    /// ## Usage
+    /// ```
+    /// let _guard = tracing_allocations::housekeeping::builder()
+    ///     .heap_stats_on_panic(true)
+    ///     .finish();
+    /// ```
+    pub fn heap_stats_on_panic(mut self, report: bool) -> Self {
+        self.heap_stats_on_panic = report;
+        self
+    }
+
+    /// If `report`, report the heap to standard error when the guard
+    /// returned by [`Builder::finish`] is dropped, at the end of `main`, e.g.:
+    ///
+    /// ```text
+    /// heap at exit: 18204 allocations, 96.3 MiB allocated, peak 12.5 MiB, 2.0 KiB not freed
+    ///   top callers by bytes allocated:
+    ///     64.0 MiB in 12 allocations  app::read_input (src/main.rs:17)
+    ///   top callers by live bytes:
+    ///     2.0 KiB  app::intern (src/intern.rs:8)
+    /// ```
+    ///
+    /// This summarizes a run of a program without configuring any layers.
+    /// The counts, the peak, and the bytes not yet freed are those of
+    /// [`stats`](crate::stats()). With the `backtrace` feature, up to five
+    /// callers that allocated the most bytes are listed, per
+    /// [`top_callsites`](crate::top_callsites), if [callsite
+    /// statistics](crate::TracingAllocator::with_callsite_stats) are
+    /// enabled; and, as for [`Builder::heap_stats_on_panic`], up to five tags
+    /// and callers holding the most live bytes are listed, if the [live
+    /// table](crate::TracingAllocator::with_live_table) is enabled. By
+    /// default, the heap is not reported.
+    ///
+    /// ## Usage
+    /// ```
+    /// let _guard = tracing_allocations::housekeeping::builder()
+    ///     .report_on_exit(true)
+    ///     .finish();
+    /// ```
+    pub fn report_on_exit(mut self, report: bool) -> Self {
+        self.report_on_exit = report;
+        self
+    }
+
+    /// Performs housekeeping, and returns a guard that must be held until the
+    /// end of `main`; see [`housekeeping`](crate::housekeeping()).
+    #[must_use]
+    pub fn finish(self) -> impl Drop {
+        if self.suspend_during_panics {
+            crate::SUSPEND_DURING_PANICS.store(true, Ordering::Relaxed);
+        }
+        crate::disable_in_scope(|| {
+            let _ = std::io::stdout();
+            let _ = std::io::stderr();
+            // the handle and name of the current thread are lazily allocated
+            let _ = std::thread::current().name();
+            for initializer in self.initializers {
+                initializer();
+            }
+            if self.heap_stats_on_panic {
+                let previous = std::panic::take_hook();
+                std::panic::set_hook(Box::new(move |info| {
+                    previous(info);
+                    crate::disable_in_scope(|| {
+                        let mut report = String::new();
+                        heap_stats(&mut report);
+                        let _ = std::io::stderr().write_all(report.as_bytes());
+                    });
+                }));
+            }
+            Guard {
+                report_on_exit: self.report_on_exit,
+                _thread: PhantomData,
+            }
+        })
+    }
+}
+
+impl fmt::Debug for Builder<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("initializers", &self.initializers.len())
+            .field("suspend_during_panics", &self.suspend_during_panics)
+            .field("heap_stats_on_panic", &self.heap_stats_on_panic)
+            .field("report_on_exit", &self.report_on_exit)
+            .finish()
+    }
+}
+
+/// The guard returned by [`Builder::finish`].
+struct Guard {
+    /// Whether to report the heap when dropped.
+    report_on_exit: bool,
+    _thread: PhantomData<*mut ()>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        crate::flush_coalesced();
+        if self.report_on_exit {
+            crate::disable_in_scope(|| {
+                let mut report = String::new();
+                exit_report(&mut report);
+                let _ = std::io::stderr().write_all(report.as_bytes());
+            });
+        }
+        // disable tracing so `std::io::cleanup()` doesn't panic
+        crate::disable_for_thread();
+    }
+}
+
+/// Writes the live and peak bytes, and the tags and callers holding the most
+/// live bytes, to `report`.
+///
+/// This never blocks: the shards of the live table held by other threads,
+/// which may be deadlocked or may never release them, are left out, and the
+/// report says so.
+fn heap_stats(report: &mut String) {
+    let stats = crate::stats();
+    let _ = writeln!(
+        report,
+        "heap at panic: {} live, peak {}",
+        Bytes(stats.live_bytes),
+        Bytes(stats.peak_bytes)
+    );
+    let (snapshot, busy) = crate::snapshot::try_snapshot();
+    top_groups(report, &snapshot);
+    if busy > 0 {
+        let _ = writeln!(
+            report,
+            "  ({} shards of the live table were busy, and are not included)",
+            busy
+        );
+    }
+}
+
+/// Writes the counts and peak of the run, the bytes not yet freed, and the
+/// callers that allocated the most, to `report`.
+fn exit_report(report: &mut String) {
+    let stats = crate::stats();
+    let _ = writeln!(
+        report,
+        "heap at exit: {} allocations, {} allocated, peak {}, {} not freed",
+        stats.counts.allocations,
+        Bytes(stats.counts.bytes_allocated),
+        Bytes(stats.peak_bytes),
+        Bytes(stats.live_bytes)
+    );
+    #[cfg(feature = "backtrace")]
+    {
+        let callsites = crate::top_callsites(TOP);
+        if !callsites.is_empty() {
+            let _ = writeln!(report, "  top callers by bytes allocated:");
+        }
+        for callsite in callsites {
+            let _ = writeln!(
+                report,
+                "    {} in {} allocations  {}",
+                Bytes(callsite.bytes_allocated),
+                callsite.allocations,
+                caller(callsite.symbol, callsite.file, callsite.line)
+            );
+        }
+    }
+    top_groups(report, &crate::snapshot());
+}
+
+/// Writes the tags and callers holding the most live bytes in `snapshot` of
+/// the live table to `report`, if it holds any.
+fn top_groups(report: &mut String, snapshot: &HeapSnapshot) {
+    let mut tags: BTreeMap<&str, u64> = BTreeMap::new();
+    let mut callers: BTreeMap<String, u64> = BTreeMap::new();
+    for group in &snapshot.groups {
+        if let Some(tag) = &group.tag {
+            *tags.entry(tag).or_default() += group.bytes;
+        }
+        let caller = caller(group.symbol.as_deref(), group.file.as_deref(), group.line);
+        *callers.entry(caller).or_default() += group.bytes;
+    }
+    let mut list = |title: &str, mut groups: Vec<(&str, u64)>| {
+        if groups.is_empty() {
+            return;
+        }
+        let _ = writeln!(report, "  top {} by live bytes:", title);
+        groups.sort_by_key(|&(_, bytes)| core::cmp::Reverse(bytes));
+        for (name, bytes) in groups.into_iter().take(TOP) {
+            let _ = writeln!(report, "    {}  {}", Bytes(bytes), name);
+        }
+    };
+    list("tags", tags.into_iter().collect());
+    list(
+        "callers",
+        callers
+            .iter()
+            .map(|(caller, &bytes)| (caller.as_str(), bytes))
+            .collect(),
+    );
+}
+
+/// A caller, formatted as `symbol (file:line)`, omitting whatever is unknown.
+fn caller(symbol: Option<&str>, file: Option<&str>, line: Option<u32>) -> String {
+    let mut caller = String::from(symbol.unwrap_or("<unknown>"));
+    if let Some(file) = file {
+        let _ = match line {
+            Some(line) => write!(caller, " ({}:{})", file, line),
+            None => write!(caller, " ({})", file),
+        };
+    }
+    caller
+}
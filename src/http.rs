@@ -0,0 +1,274 @@
+//! Live heap statistics over HTTP.
+//!
+//! [`HeapEndpoint`] answers requests for the process-wide counters, for a
+//! snapshot of the live heap, and for a heap profile, in the manner of Go's
+//! `/debug/pprof/heap`. It is independent of any HTTP framework; with the
+//! `hyper` feature, [`HeapEndpoint::serve`] also serves it on a Tokio
+//! listener.
+
+use core::fmt::Write as _;
+#[cfg(feature = "hyper")]
+use std::{convert::Infallible, io};
+
+#[cfg(feature = "hyper")]
+use bytes::Bytes;
+#[cfg(feature = "hyper")]
+use http_body_util::Full;
+#[cfg(feature = "hyper")]
+use hyper::{
+    body::Incoming, header::CONTENT_TYPE, server::conn::http1, service::service_fn, Request,
+    Response,
+};
+#[cfg(feature = "hyper")]
+use hyper_util::rt::TokioIo;
+#[cfg(feature = "hyper")]
+use tokio::net::TcpListener;
+
+use crate::json::JsonStr;
+#[cfg(feature = "pprof")]
+use crate::PprofHandle;
+#[cfg(feature = "prometheus")]
+use crate::PrometheusHandle;
+
+/// An HTTP handler of live heap statistics.
+///
+/// [`HeapEndpoint::respond`] maps the path of a request to the status and
+/// body of its response, so that the endpoint may be mounted in any HTTP
+/// framework. It serves the following paths:
+/// - **`/heap/stats`**  
+///   the process-wide counts of allocator operations (see
+///   [`stats`](crate::stats())), and the live and peak bytes, as a JSON
+///   object
+/// - **`/heap/snapshot`**  
+///   a [snapshot](crate::snapshot()) of the live heap, by caller and tag, as
+///   a JSON object; empty unless the [live
+///   table](crate::TracingAllocator::with_live_table) is enabled
+/// - **`/heap/profile`**, or **`/debug/pprof/heap`**  
+///   the gzipped `profile.proto` heap profile of the [`PprofHandle`] given
+///   to [`HeapEndpoint::with_profile`], as read by `go tool pprof`; not
+///   found without one (which requires the `pprof` feature)
+/// - **`/metrics`**  
+///   the metrics of the [`PrometheusHandle`] given to
+///   [`HeapEndpoint::with_metrics`], in Prometheus's text exposition format;
+///   not found without one (which requires the `prometheus` feature)
+///
+/// The query of the path, if any, is ignored, and other paths are not found.
+/// Statistics are served with the content type `application/json`, profiles
+/// with `application/octet-stream`, and metrics with `text/plain;
+/// version=0.0.4`.
+///
+/// Requires the `http` feature.
+///
+/// ## Usage
+/// ```
+/// use tracing_allocations::HeapEndpoint;
+///
+/// let endpoint = HeapEndpoint::new();
+///
+/// let (status, body) = endpoint.respond("/heap/stats");
+/// assert_eq!(status, 200);
+/// assert!(body.starts_with(b"{"));
+/// ```
+///
+/// ```sh
+/// curl localhost:6060/heap/stats
+/// go tool pprof -http=: localhost:6060/debug/pprof/heap
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct HeapEndpoint {
+    #[cfg(feature = "pprof")]
+    profile: Option<PprofHandle>,
+    #[cfg(feature = "prometheus")]
+    metrics: Option<PrometheusHandle>,
+}
+
+impl HeapEndpoint {
+    /// Constructs a new `HeapEndpoint`, without a heap profile or metrics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve the heap profile of `profile` at `/heap/profile` and
+    /// `/debug/pprof/heap`.
+    ///
+    /// Requires the `pprof` feature.
+    #[cfg(feature = "pprof")]
+    pub fn with_profile(mut self, profile: PprofHandle) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Serve the metrics of `metrics` at `/metrics`.
+    ///
+    /// Requires the `prometheus` feature.
+    #[cfg(feature = "prometheus")]
+    pub fn with_metrics(mut self, metrics: PrometheusHandle) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// The status and body of the response to a `GET` request for `path`.
+    ///
+    /// Profiles are symbolized as they are written, which may be slow the
+    /// first time each stack frame is encountered.
+    pub fn respond(&self, path: &str) -> (u16, Vec<u8>) {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        match path {
+            "/heap/stats" => (200, stats_json().into_bytes()),
+            "/heap/snapshot" => (200, snapshot_json().into_bytes()),
+            "/heap/profile" | "/debug/pprof/heap" => self.profile(),
+            "/metrics" => self.metrics(),
+            _ => (404, b"not found\n".to_vec()),
+        }
+    }
+
+    /// The response to a request for the heap profile.
+    #[cfg(feature = "pprof")]
+    fn profile(&self) -> (u16, Vec<u8>) {
+        let Some(profile) = &self.profile else {
+            return (404, b"no heap profile is configured\n".to_vec());
+        };
+        let mut body = Vec::new();
+        match profile.write_to(&mut body) {
+            Ok(()) => (200, body),
+            Err(error) => (500, format!("{}\n", error).into_bytes()),
+        }
+    }
+
+    #[cfg(not(feature = "pprof"))]
+    fn profile(&self) -> (u16, Vec<u8>) {
+        (404, b"heap profiles require the `pprof` feature\n".to_vec())
+    }
+
+    /// The response to a request for the metrics.
+    #[cfg(feature = "prometheus")]
+    fn metrics(&self) -> (u16, Vec<u8>) {
+        let Some(metrics) = &self.metrics else {
+            return (404, b"no metrics are configured\n".to_vec());
+        };
+        let mut body = Vec::new();
+        match metrics.write_to(&mut body) {
+            Ok(()) => (200, body),
+            Err(error) => (500, format!("{}\n", error).into_bytes()),
+        }
+    }
+
+    #[cfg(not(feature = "prometheus"))]
+    fn metrics(&self) -> (u16, Vec<u8>) {
+        (404, b"metrics require the `prometheus` feature\n".to_vec())
+    }
+
+    /// Serves this endpoint over HTTP/1 to the connections accepted by
+    /// `listener`, each on a task of its own, until accepting fails.
+    ///
+    /// Each response is written on Tokio's blocking thread pool, with tracing
+    /// [disabled](crate::disable_in_scope), so that reading a snapshot or
+    /// writing a profile neither stalls the runtime nor is itself traced.
+    ///
+    /// Must be called within a Tokio runtime. Requires the `hyper`
+    /// feature.
+    ///
+    /// ## Usage
+    /// ```no_run
+    /// use tracing_allocations::HeapEndpoint;
+    ///
+    /// # async fn run() -> std::io::Result<()> {
+    /// let listener = tokio::net::TcpListener::bind("127.0.0.1:6060").await?;
+    /// tokio::spawn(HeapEndpoint::new().serve(listener));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "hyper")]
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let endpoint = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request: Request<Incoming>| {
+                    let endpoint = endpoint.clone();
+                    let path = request.uri().path().to_owned();
+                    async move {
+                        let content_type = content_type(&path);
+                        // snapshots and profiles take locks and may be slow to
+                        // write, so are kept off the runtime's workers
+                        let (status, body) = tokio::task::spawn_blocking(move || {
+                            crate::disable_in_scope(|| endpoint.respond(&path))
+                        })
+                        .await
+                        .unwrap_or_else(|error| (500, format!("{}\n", error).into_bytes()));
+                        let response = Response::builder()
+                            .status(status)
+                            .header(CONTENT_TYPE, content_type)
+                            .body(Full::new(Bytes::from(body)));
+                        Ok::<_, Infallible>(response.expect("a valid response"))
+                    }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    }
+}
+
+/// The content type of the response to a request for `path`.
+#[cfg(feature = "hyper")]
+fn content_type(path: &str) -> &'static str {
+    match path {
+        "/heap/stats" | "/heap/snapshot" => "application/json",
+        "/heap/profile" | "/debug/pprof/heap" => "application/octet-stream",
+        "/metrics" => "text/plain; version=0.0.4",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+/// The process-wide counters, as a JSON object.
+fn stats_json() -> String {
+    let stats = crate::stats();
+    let counts = &stats.counts;
+    format!(
+        "{{\"allocations\":{},\"deallocations\":{},\"reallocations\":{},\
+         \"bytes_allocated\":{},\"bytes_freed\":{},\"live_bytes\":{},\"peak_bytes\":{}}}\n",
+        counts.allocations,
+        counts.deallocations,
+        counts.reallocations,
+        counts.bytes_allocated,
+        counts.bytes_freed,
+        stats.live_bytes,
+        stats.peak_bytes,
+    )
+}
+
+/// A snapshot of the live heap, as a JSON object.
+fn snapshot_json() -> String {
+    let snapshot = crate::snapshot();
+    let mut json = format!(
+        "{{\"total_blocks\":{},\"total_bytes\":{},\"groups\":[",
+        snapshot.total_blocks(),
+        snapshot.total_bytes()
+    );
+    let string = |s: &Option<String>| match s {
+        Some(s) => JsonStr(s).to_string(),
+        None => String::from("null"),
+    };
+    for (i, group) in snapshot.groups.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        let line = match group.line {
+            Some(line) => line.to_string(),
+            None => String::from("null"),
+        };
+        let _ = write!(
+            json,
+            "{}{{\"symbol\":{},\"file\":{},\"line\":{},\"tag\":{},\"blocks\":{},\"bytes\":{}}}",
+            separator,
+            string(&group.symbol),
+            string(&group.file),
+            line,
+            string(&group.tag),
+            group.blocks,
+            group.bytes
+        );
+    }
+    json.push_str("]}\n");
+    json
+}
@@ -0,0 +1,116 @@
+//! Per-thread summaries of allocator operations over fixed intervals.
+//!
+//! If [`TracingAllocator::with_interval_summaries`] is enabled, the operations
+//! that would otherwise emit events are instead totalled on each thread, and
+//! the totals are emitted once per interval.
+//!
+//! [`TracingAllocator::with_interval_summaries`]: crate::TracingAllocator::with_interval_summaries
+
+use core::{cell::Cell, time::Duration};
+
+use crate::event::{AllocationEvent, AllocationKind};
+
+thread_local! {
+    /// The totals of the current interval of this thread.
+    static INTERVAL: Cell<Interval> = const { Cell::new(Interval::new()) };
+}
+
+/// The totals of the operations of a thread over an interval.
+#[derive(Clone, Copy)]
+pub(crate) struct Interval {
+    /// When the interval began, per [`monotonic_nanos`](crate::monotonic_nanos);
+    /// `None` if the thread has yet to perform an operation.
+    began_ns: Option<u64>,
+    /// The length of the interval, in nanoseconds; set when it ends.
+    pub(crate) elapsed_ns: u64,
+    /// The number of allocations, zeroed or not, and reallocations.
+    pub(crate) allocations: u64,
+    pub(crate) bytes_allocated: u64,
+    pub(crate) bytes_freed: u64,
+    /// The number of bytes allocated by the thread, less the number freed by
+    /// it, since it began.
+    live: i64,
+    /// The value of `live` when the interval began.
+    initial: i64,
+    /// The greatest value of `live` over the interval.
+    peak: i64,
+}
+
+impl Interval {
+    /// The state of a thread that has yet to perform an operation.
+    const fn new() -> Self {
+        Self {
+            began_ns: None,
+            elapsed_ns: 0,
+            allocations: 0,
+            bytes_allocated: 0,
+            bytes_freed: 0,
+            live: 0,
+            initial: 0,
+            peak: 0,
+        }
+    }
+
+    /// The number of bytes allocated over the interval, less the number
+    /// freed.
+    pub(crate) fn net_bytes(&self) -> i64 {
+        self.live.wrapping_sub(self.initial)
+    }
+
+    /// The greatest number of bytes that the thread had allocated and not
+    /// freed over the interval, relative to the number when it began.
+    pub(crate) fn peak_bytes(&self) -> u64 {
+        self.peak.wrapping_sub(self.initial).max(0) as u64
+    }
+
+    /// The interval that follows this one, beginning at `now`.
+    fn next(&self, now: u64) -> Self {
+        Self {
+            began_ns: Some(now),
+            live: self.live,
+            initial: self.live,
+            peak: self.live,
+            ..Self::new()
+        }
+    }
+}
+
+/// Totals the operations described by `event` in the current interval of the
+/// current thread; returns the totals of the interval if, as of this
+/// operation, it has lasted at least `length`.
+pub(crate) fn record(event: &AllocationEvent, length: Duration) -> Option<Interval> {
+    let now = crate::monotonic_nanos();
+    INTERVAL
+        .try_with(|interval| {
+            let mut current = interval.get();
+            let began_ns = *current.began_ns.get_or_insert(now);
+            let weight = event.weight();
+            let scale = |n: u64| (n as f64 * weight).round() as u64;
+            let (allocated, freed) = match event.kind {
+                AllocationKind::Alloc | AllocationKind::AllocZeroed => (event.size, 0),
+                AllocationKind::Dealloc => (0, event.size),
+                AllocationKind::Realloc => (event.size, event.old_size.unwrap_or(0)),
+            };
+            let (allocated, freed) = (scale(allocated), scale(freed));
+            if event.kind != AllocationKind::Dealloc {
+                current.allocations += scale(1);
+            }
+            current.bytes_allocated += allocated;
+            current.bytes_freed += freed;
+            current.live = current
+                .live
+                .wrapping_add(allocated as i64)
+                .wrapping_sub(freed as i64);
+            current.peak = current.peak.max(current.live);
+            let elapsed_ns = now.saturating_sub(began_ns);
+            if elapsed_ns < length.as_nanos() as u64 {
+                interval.set(current);
+                return None;
+            }
+            interval.set(current.next(now));
+            current.elapsed_ns = elapsed_ns;
+            Some(current)
+        })
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,35 @@
+//! The little JSON that exporters write by hand.
+
+use core::fmt::{self, Write as _};
+
+/// Displays a string as a JSON string literal, quotes included.
+pub(crate) struct JsonStr<'s>(pub(crate) &'s str);
+
+impl fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        Escape(&mut *f).write_str(self.0)?;
+        f.write_str("\"")
+    }
+}
+
+/// Escapes the strings written through it for use within a JSON string
+/// literal, without allocating.
+pub(crate) struct Escape<W>(pub(crate) W);
+
+impl<W: fmt::Write> fmt::Write for Escape<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if c.is_control() => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Allocation events as JSON Lines, written without allocating.
+//!
+//! The JSON formatter of `tracing_subscriber` allocates for each event it
+//! writes, which, for allocation events, both distorts the trace and risks
+//! recursion. [`JsonLinesLayer`] instead formats each event into a buffer of
+//! the thread that emitted it, which is allocated once and then reused.
+
+use core::{
+    cell::RefCell,
+    fmt::{self, Write as _},
+};
+use std::{
+    io::Write,
+    sync::{Mutex, PoisonError},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{event::AllocationEvent, json::Escape};
+
+/// The initial capacity of the buffer of each thread, which suffices for any
+/// event without a `backtrace` field.
+const CAPACITY: usize = 1024;
+
+thread_local! {
+    /// The line being formatted on this thread.
+    static LINE: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A [`Layer`] that writes the allocation events it observes to a writer, as
+/// JSON Lines; one JSON object per event, of its fields, e.g.:
+///
+/// ```text
+/// {"kind":"alloc","addr":94251034710528,"size":64,"timestamp_ns":1204113}
+/// ```
+///
+/// Each event is formatted into a buffer of the thread that emitted it, which
+/// is allocated when the thread first emits an event and then reused, so that
+/// writing an event performs no allocation unless the event is larger than
+/// any before it on that thread. Each line is written to the writer with a
+/// single call to [`Write::write_all`], while the writer is locked. Events
+/// that are not allocation events are ignored.
+///
+/// Requires the `json` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::JsonLinesLayer;
+///
+/// let file = std::fs::File::create("allocations.jsonl").unwrap();
+/// tracing_subscriber::registry()
+///     .with(JsonLinesLayer::new(file))
+///     .init();
+/// ```
+#[derive(Debug)]
+pub struct JsonLinesLayer<W> {
+    writer: Mutex<W>,
+}
+
+impl<W> JsonLinesLayer<W>
+where
+    W: Write + Send + 'static,
+{
+    /// Constructs a new `JsonLinesLayer`, which writes to `writer`.
+    ///
+    /// Lines are not buffered beyond the line being written; writers that
+    /// buffer (e.g., [`BufWriter`](std::io::BufWriter)) may not be flushed
+    /// until the layer is dropped.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<S, W> Layer<S> for JsonLinesLayer<W>
+where
+    S: Subscriber,
+    W: Write + Send + 'static,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if AllocationEvent::from_event(event).is_none() {
+            return;
+        }
+        crate::disable_in_scope(|| {
+            let _ = LINE.try_with(|line| {
+                // a writer that emits events may reenter this layer
+                let Ok(mut line) = line.try_borrow_mut() else {
+                    return;
+                };
+                line.clear();
+                line.reserve(CAPACITY);
+                let mut visitor = Visitor {
+                    line: Line(&mut line),
+                    first: true,
+                };
+                event.record(&mut visitor);
+                line.extend_from_slice(b"}\n");
+                let _ = self
+                    .writer
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .write_all(&line);
+            });
+        });
+    }
+}
+
+/// Writes the fields of an event as the members of a JSON object, less its
+/// closing brace.
+struct Visitor<'l> {
+    line: Line<'l>,
+    /// Whether no field has yet been written.
+    first: bool,
+}
+
+impl<'l> Visitor<'l> {
+    /// Writes the name of `field`, and returns the line, to which its value
+    /// is to be written.
+    fn name(&mut self, field: &Field) -> &mut Line<'l> {
+        let opening = if self.first { "{\"" } else { ",\"" };
+        self.first = false;
+        let _ = self.line.write_str(opening);
+        let _ = Escape(&mut self.line).write_str(field.name());
+        let _ = self.line.write_str("\":");
+        &mut self.line
+    }
+}
+
+impl Visit for Visitor<'_> {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        let _ = write!(self.name(field), "{}", value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        let _ = write!(self.name(field), "{}", value);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        let line = self.name(field);
+        let _ = match value.is_finite() {
+            true => write!(line, "{}", value),
+            false => line.write_str("null"),
+        };
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        let _ = write!(self.name(field), "{}", value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let line = self.name(field);
+        let _ = line.write_str("\"");
+        let _ = Escape(&mut *line).write_str(value);
+        let _ = line.write_str("\"");
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // the message merely repeats the kind
+        if field.name() == "message" {
+            return;
+        }
+        let line = self.name(field);
+        let _ = line.write_str("\"");
+        let _ = write!(Escape(&mut *line), "{:?}", value);
+        let _ = line.write_str("\"");
+    }
+}
+
+/// A line being formatted.
+struct Line<'l>(&'l mut Vec<u8>);
+
+impl fmt::Write for Line<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,676 @@
+//! Aggregate counters maintained from emitted allocation events.
+//!
+//! The process-wide counters of [`Region`](crate::Region) count every
+//! operation performed by [`TracingAllocator`], traced or not. [`StatsLayer`]
+//! instead counts the operations described by the events that reach a
+//! subscriber, and so respects the allocator's sampling, filtering and scoping
+//! along with any filters of the subscriber itself. [`SpanStatsLayer`]
+//! attributes the same counts to the spans in which the operations were
+//! performed, and [`TargetStatsLayer`] to the targets of those spans. The
+//! counts of each span are also stored in its extensions, as
+//! [`SpanAllocations`], for the use of other layers.
+//!
+//! [`TracingAllocator`]: crate::TracingAllocator
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+
+use tracing::{field::Value, span, Event, Level, Metadata, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::{
+    event::{AllocationEvent, AllocationKind},
+    global::{AllocationCounts, Counters},
+    stats::AllocationStats,
+};
+
+/// A [`Layer`] that counts the allocator operations described by the events
+/// it observes.
+///
+/// Sampled and coalesced events are scaled by
+/// [`AllocationEvent::weight`], so that the counts estimate the operations
+/// that were performed. The counts may be read at any time, from any thread,
+/// through the [`StatsHandle`] returned by [`StatsLayer::handle`].
+///
+/// ## Usage
+/// ```
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::StatsLayer;
+///
+/// let layer = StatsLayer::new();
+/// let stats = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// let counts = stats.counts();
+/// println!("{} bytes live in {} allocations", counts.net_bytes(), counts.net_allocations());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct StatsLayer {
+    handle: StatsHandle,
+}
+
+impl StatsLayer {
+    /// Constructs a new `StatsLayer`, with all counts zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle through which to read the counts of this layer.
+    pub fn handle(&self) -> StatsHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for StatsLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if let Some(event) = AllocationEvent::from_event(event) {
+            record(&self.handle.counters, &event);
+        }
+    }
+}
+
+/// A handle to the counts of a [`StatsLayer`].
+///
+/// Handles are cheap to clone, and all clones read the same counts.
+#[derive(Clone, Default)]
+pub struct StatsHandle {
+    counters: Arc<Counters>,
+}
+
+impl StatsHandle {
+    /// The counts of the operations observed so far.
+    ///
+    /// Live bytes and live allocations are given by
+    /// [`AllocationCounts::net_bytes`] and
+    /// [`AllocationCounts::net_allocations`].
+    pub fn counts(&self) -> AllocationCounts {
+        self.counters.load()
+    }
+}
+
+impl core::fmt::Debug for StatsHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("StatsHandle")
+            .field("counts", &self.counts())
+            .finish()
+    }
+}
+
+/// A [`Layer`] that counts the allocator operations described by the events
+/// it observes, separately for each open span.
+///
+/// An operation is attributed to every span that the thread performing it was
+/// inside of: the span it was immediately inside of, and all of that span's
+/// ancestors. The counts of a span therefore roll up those of its descendants,
+/// so that the counts of a top-level span (e.g., of a request) give the full
+/// cost of everything beneath it. The operations performed immediately inside
+/// of each span are counted separately. As with [`StatsLayer`], sampled and
+/// coalesced events are scaled by [`AllocationEvent::weight`]. The counts of
+/// each span may be read, until the span closes, through the
+/// [`SpanStatsHandle`] returned by [`SpanStatsLayer::handle`].
+///
+/// With [`SpanStatsLayer::with_summaries`], the layer also emits a summary of
+/// the counts of each span when it closes; with
+/// [`SpanStatsLayer::with_span_fields`], it records them on the span itself.
+/// Other layers may read the counts of each span from its extensions; see
+/// [`SpanAllocations`].
+///
+/// ## Usage
+/// ```
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::SpanStatsLayer;
+///
+/// let layer = SpanStatsLayer::new();
+/// let stats = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// let span = tracing::info_span!("request");
+/// span.in_scope(|| { /* your code here */ });
+///
+/// if let Some(counts) = span.id().and_then(|id| stats.get(&id)) {
+///     println!("request allocated {} bytes", counts.bytes_allocated);
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SpanStatsLayer {
+    handle: SpanStatsHandle,
+    summaries: Option<Level>,
+    span_fields: bool,
+    #[cfg(feature = "tracing-opentelemetry")]
+    otel_attributes: bool,
+}
+
+impl SpanStatsLayer {
+    /// Constructs a new `SpanStatsLayer`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle through which to read the counts of each open span.
+    pub fn handle(&self) -> SpanStatsHandle {
+        self.handle.clone()
+    }
+
+    /// Emit an event at the given `level`, with target "tracing::allocator",
+    /// summarizing the counts of each span when it closes; `None` (the
+    /// default) disables summaries.
+    ///
+    /// The event is emitted outside of the closed span. Its counts include
+    /// the operations performed inside the span's descendants, and it has the
+    /// following fields:
+    /// - **`span`: [`str`]**  
+    ///   the name of the closed span
+    /// - **`span_id`: [`u64`]**  
+    ///   the ID of the closed span
+    /// - **`allocated_bytes`: [`u64`]**  
+    ///   the total size of the blocks allocated inside the span
+    /// - **`freed_bytes`: [`u64`]**  
+    ///   the total size of the blocks freed inside the span
+    /// - **`net_bytes`: [`i64`]**  
+    ///   `allocated_bytes`, less `freed_bytes`
+    /// - **`alloc_count`: [`u64`]**  
+    ///   the number of allocations performed inside the span
+    ///
+    /// ## Usage
+    /// ```
+    /// use tracing::Level;
+    /// use tracing_subscriber::prelude::*;
+    /// use tracing_allocations::SpanStatsLayer;
+    ///
+    /// tracing_subscriber::registry()
+    ///     .with(SpanStatsLayer::new().with_summaries(Some(Level::INFO)))
+    ///     .with(tracing_subscriber::fmt::layer())
+    ///     .init();
+    /// ```
+    pub fn with_summaries(mut self, level: Option<Level>) -> Self {
+        self.summaries = level;
+        self
+    }
+
+    /// Record the counts of each span as fields of the span when it closes
+    /// (default: `false`), so that they are reported by any layer that
+    /// observes the span's fields, such as the `fmt` layer.
+    ///
+    /// Of the following fields, only those that the span declares are
+    /// recorded; as with [`Span::record`](tracing::Span::record), a field
+    /// whose value is not yet known may be declared as
+    /// [`Empty`](tracing::field::Empty):
+    /// - **`allocated_bytes`: [`u64`]**  
+    ///   the total size of the blocks allocated inside the span
+    /// - **`freed_bytes`: [`u64`]**  
+    ///   the total size of the blocks freed inside the span
+    /// - **`net_bytes`: [`i64`]**  
+    ///   `allocated_bytes`, less `freed_bytes`
+    /// - **`alloc_count`: [`u64`]**  
+    ///   the number of allocations performed inside the span
+    ///
+    /// As with summaries, the counts include the operations performed inside
+    /// the span's descendants. Layers observe the fields only if they are
+    /// notified of the span's closure after this layer, i.e., if they are
+    /// added to the subscriber after it.
+    ///
+    /// ## Usage
+    /// ```
+    /// use tracing::field::Empty;
+    /// use tracing_subscriber::{fmt::format::FmtSpan, prelude::*};
+    /// use tracing_allocations::SpanStatsLayer;
+    ///
+    /// tracing_subscriber::registry()
+    ///     .with(SpanStatsLayer::new().with_span_fields(true))
+    ///     .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE))
+    ///     .init();
+    ///
+    /// let span = tracing::info_span!("request", allocated_bytes = Empty, alloc_count = Empty);
+    /// span.in_scope(|| { /* your code here */ });
+    /// ```
+    pub fn with_span_fields(mut self, enabled: bool) -> Self {
+        self.span_fields = enabled;
+        self
+    }
+
+    /// Attach the counts of each span to the OpenTelemetry span built for it
+    /// by `tracing-opentelemetry` when it closes (default: `false`), as the
+    /// following attributes:
+    /// - **`alloc.bytes`: [`i64`]**  
+    ///   the total size of the blocks allocated inside the span
+    /// - **`alloc.count`: [`i64`]**  
+    ///   the number of allocations performed inside the span
+    ///
+    /// As with summaries, the counts include the operations performed inside
+    /// the span's descendants. The span is exported by
+    /// `tracing_opentelemetry::OpenTelemetryLayer` when it is notified of the
+    /// span's closure, so that layer must be added to the subscriber after
+    /// this one.
+    ///
+    /// Requires the `tracing-opentelemetry` feature.
+    ///
+    /// ## Usage
+    /// ```no_run
+    /// use opentelemetry::trace::TracerProvider as _;
+    /// use tracing_subscriber::prelude::*;
+    /// use tracing_allocations::SpanStatsLayer;
+    ///
+    /// # let provider = opentelemetry::trace::noop::NoopTracerProvider::new();
+    /// let tracer = provider.tracer("my-service");
+    ///
+    /// tracing_subscriber::registry()
+    ///     .with(SpanStatsLayer::new().with_otel_attributes(true))
+    ///     .with(tracing_opentelemetry::layer().with_tracer(tracer))
+    ///     .init();
+    /// ```
+    #[cfg(feature = "tracing-opentelemetry")]
+    pub fn with_otel_attributes(mut self, enabled: bool) -> Self {
+        self.otel_attributes = enabled;
+        self
+    }
+}
+
+/// The counts of a span.
+#[derive(Default)]
+struct SpanCounters {
+    /// The operations performed inside the span, or any of its descendants.
+    total: Tally,
+    /// The operations performed immediately inside the span.
+    own: Tally,
+}
+
+/// Counts of allocator operations, and the peak of the bytes they allocated.
+#[derive(Default)]
+pub(crate) struct Tally {
+    counters: Counters,
+    /// The number of bytes allocated, less the number freed.
+    live: AtomicI64,
+    /// The greatest value of `live`.
+    peak: AtomicI64,
+}
+
+impl Tally {
+    /// Counts the operations described by `event`.
+    pub(crate) fn record(&self, event: &AllocationEvent) {
+        let delta = record(&self.counters, event);
+        let live = self
+            .live
+            .fetch_add(delta, Ordering::Relaxed)
+            .wrapping_add(delta);
+        if delta > 0 {
+            self.peak.fetch_max(live, Ordering::Relaxed);
+        }
+    }
+
+    /// The counts of the operations recorded so far.
+    pub(crate) fn stats(&self) -> AllocationStats {
+        let counts = self.counters.load();
+        AllocationStats {
+            allocations: counts.allocations,
+            deallocations: counts.deallocations,
+            reallocations: counts.reallocations,
+            bytes_allocated: counts.bytes_allocated,
+            bytes_freed: counts.bytes_freed,
+            peak_bytes: self.peak.load(Ordering::Relaxed).max(0) as u64,
+        }
+    }
+}
+
+/// The counts of a span, stored in its [extensions] by [`SpanStatsLayer`].
+///
+/// Other layers, such as exporters, samplers and tail-based filters, may read
+/// the allocation cost of a span from its extensions for as long as the span
+/// exists, including when it closes. The counts include the operations
+/// performed inside the span's descendants.
+///
+/// ## Usage
+/// ```
+/// use tracing::{span, Subscriber};
+/// use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+/// use tracing_allocations::SpanAllocations;
+///
+/// /// Reports spans that held more than 1 MiB at once.
+/// struct HeavySpans;
+///
+/// impl<S> Layer<S> for HeavySpans
+/// where
+///     S: Subscriber + for<'a> LookupSpan<'a>,
+/// {
+///     fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+///         let Some(span) = ctx.span(&id) else { return };
+///         if let Some(allocations) = span.extensions().get::<SpanAllocations>() {
+///             let stats = allocations.stats();
+///             if stats.peak_bytes > 1 << 20 {
+///                 println!("{} held {} bytes", span.name(), stats.peak_bytes);
+///             }
+///         }
+///     }
+/// }
+/// ```
+///
+/// [extensions]: tracing_subscriber::registry::SpanRef::extensions
+pub struct SpanAllocations(Arc<SpanCounters>);
+
+impl SpanAllocations {
+    /// The counts of the operations performed inside the span, or any of its
+    /// descendants, so far.
+    ///
+    /// `peak_bytes` is the greatest number of bytes that were allocated and
+    /// not yet freed inside the span at once, by all threads.
+    pub fn stats(&self) -> AllocationStats {
+        self.0.total.stats()
+    }
+
+    /// The counts of the operations performed immediately inside the span so
+    /// far, excluding those performed inside its descendants.
+    pub fn own_stats(&self) -> AllocationStats {
+        self.0.own.stats()
+    }
+}
+
+impl core::fmt::Debug for SpanAllocations {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SpanAllocations")
+            .field("stats", &self.stats())
+            .field("own_stats", &self.own_stats())
+            .finish()
+    }
+}
+
+impl<S> Layer<S> for SpanStatsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        // the events of these allocations would otherwise be attributed to
+        // the enclosing spans, and would contend for the lock we hold
+        crate::disable_in_scope(|| {
+            let counters = Arc::new(SpanCounters::default());
+            span.extensions_mut()
+                .insert(SpanAllocations(Arc::clone(&counters)));
+            self.handle
+                .spans
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(id.clone(), counters);
+        });
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(scope) = ctx.event_scope(event) else {
+            return;
+        };
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        for (depth, span) in scope.enumerate() {
+            if let Some(SpanAllocations(counters)) = span.extensions().get::<SpanAllocations>() {
+                counters.total.record(&event);
+                if depth == 0 {
+                    counters.own.record(&event);
+                }
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        crate::disable_in_scope(|| {
+            let counters = self
+                .handle
+                .spans
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&id);
+            let (Some(counters), Some(span)) = (counters, ctx.span(&id)) else {
+                return;
+            };
+            let counts = counters.total.counters.load();
+            if let Some(level) = self.summaries {
+                summarize(level, span.name(), &id, &counts);
+            }
+            if self.span_fields {
+                record_fields(&id, span.metadata(), &counts);
+            }
+            #[cfg(feature = "tracing-opentelemetry")]
+            if self.otel_attributes {
+                if let Some(data) = span
+                    .extensions_mut()
+                    .get_mut::<tracing_opentelemetry::OtelData>()
+                {
+                    record_otel_attributes(data, &counts);
+                }
+            }
+        });
+    }
+}
+
+/// A handle to the counts of a [`SpanStatsLayer`].
+///
+/// Handles are cheap to clone, and all clones read the same counts.
+#[derive(Clone, Default)]
+pub struct SpanStatsHandle {
+    spans: Arc<RwLock<HashMap<span::Id, Arc<SpanCounters>>>>,
+}
+
+impl SpanStatsHandle {
+    /// The counts of the operations performed inside the span with the given
+    /// `id`, or any of its descendants, so far; `None` if no such span is
+    /// open.
+    pub fn get(&self, id: &span::Id) -> Option<AllocationCounts> {
+        let spans = self.spans.read().unwrap_or_else(PoisonError::into_inner);
+        spans.get(id).map(|counters| counters.total.counters.load())
+    }
+
+    /// The counts of the operations performed immediately inside the span
+    /// with the given `id` so far, excluding those performed inside its
+    /// descendants; `None` if no such span is open.
+    pub fn get_own(&self, id: &span::Id) -> Option<AllocationCounts> {
+        let spans = self.spans.read().unwrap_or_else(PoisonError::into_inner);
+        spans.get(id).map(|counters| counters.own.counters.load())
+    }
+}
+
+impl core::fmt::Debug for SpanStatsHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SpanStatsHandle").finish_non_exhaustive()
+    }
+}
+
+/// A [`Layer`] that counts the allocator operations described by the events
+/// it observes, separately for the target of each span.
+///
+/// An operation is attributed to the target (typically, the module path) of
+/// the span that the thread performing it was immediately inside of;
+/// operations performed outside of any span are not counted. This gives a
+/// breakdown of the cost of each component of a program (e.g.,
+/// `myapp::db` and `myapp::render`) without capturing backtraces. As with
+/// [`StatsLayer`], sampled and coalesced events are scaled by
+/// [`AllocationEvent::weight`]. The counts may be read at any time, from any
+/// thread, through the [`TargetStatsHandle`] returned by
+/// [`TargetStatsLayer::handle`].
+///
+/// ## Usage
+/// ```
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::TargetStatsLayer;
+///
+/// let layer = TargetStatsLayer::new();
+/// let stats = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// for (target, counts) in stats.counts() {
+///     println!("{target} → {} bytes", counts.net_bytes());
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TargetStatsLayer {
+    handle: TargetStatsHandle,
+}
+
+impl TargetStatsLayer {
+    /// Constructs a new `TargetStatsLayer`, with all counts zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle through which to read the counts of each target.
+    pub fn handle(&self) -> TargetStatsHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for TargetStatsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        record(&self.handle.counters(span.metadata().target()), &event);
+    }
+}
+
+/// A handle to the counts of a [`TargetStatsLayer`].
+///
+/// Handles are cheap to clone, and all clones read the same counts.
+#[derive(Clone, Default)]
+pub struct TargetStatsHandle {
+    targets: Arc<RwLock<HashMap<&'static str, Arc<Counters>>>>,
+}
+
+impl TargetStatsHandle {
+    /// The counts of the operations performed inside spans with the given
+    /// `target` so far; `None` if no such operations have been observed.
+    pub fn get(&self, target: &str) -> Option<AllocationCounts> {
+        let targets = self.targets.read().unwrap_or_else(PoisonError::into_inner);
+        targets.get(target).map(|counters| counters.load())
+    }
+
+    /// The counts of the operations performed inside spans of each target so
+    /// far.
+    pub fn counts(&self) -> BTreeMap<&'static str, AllocationCounts> {
+        let targets = self.targets.read().unwrap_or_else(PoisonError::into_inner);
+        targets
+            .iter()
+            .map(|(&target, counters)| (target, counters.load()))
+            .collect()
+    }
+
+    /// The counters of the given `target`, creating them if necessary.
+    fn counters(&self, target: &'static str) -> Arc<Counters> {
+        let targets = self.targets.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(counters) = targets.get(target) {
+            return Arc::clone(counters);
+        }
+        drop(targets);
+        // the events of these allocations would otherwise contend for the
+        // lock we hold
+        crate::disable_in_scope(|| {
+            let mut targets = self.targets.write().unwrap_or_else(PoisonError::into_inner);
+            Arc::clone(targets.entry(target).or_default())
+        })
+    }
+}
+
+impl core::fmt::Debug for TargetStatsHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TargetStatsHandle")
+            .field("counts", &self.counts())
+            .finish()
+    }
+}
+
+/// Emits a summary of the `counts` of the closed span with the given `name`
+/// and `id`.
+fn summarize(level: Level, name: &str, id: &span::Id, counts: &AllocationCounts) {
+    macro_rules! summary {
+        ($level:expr) => {
+            tracing::event!(
+                target: "tracing::allocator",
+                parent: None,
+                $level,
+                span = name,
+                span_id = id.into_u64(),
+                allocated_bytes = counts.bytes_allocated,
+                freed_bytes = counts.bytes_freed,
+                net_bytes = counts.net_bytes(),
+                alloc_count = counts.allocations,
+                "span closed"
+            )
+        };
+    }
+    match level {
+        Level::TRACE => summary!(Level::TRACE),
+        Level::DEBUG => summary!(Level::DEBUG),
+        Level::INFO => summary!(Level::INFO),
+        Level::WARN => summary!(Level::WARN),
+        _ => summary!(Level::ERROR),
+    }
+}
+
+/// Records the `counts` of the span with the given `id` and `metadata` as
+/// fields of the span, if it declares them.
+fn record_fields(id: &span::Id, metadata: &'static Metadata<'static>, counts: &AllocationCounts) {
+    let fields = metadata.fields();
+    let values: [(&str, &dyn Value); 4] = [
+        ("allocated_bytes", &counts.bytes_allocated),
+        ("freed_bytes", &counts.bytes_freed),
+        ("net_bytes", &counts.net_bytes()),
+        ("alloc_count", &counts.allocations),
+    ];
+    tracing::dispatcher::get_default(|dispatch| {
+        for (name, value) in values {
+            if let Some(field) = fields.field(name) {
+                let values = [(&field, Some(value))];
+                dispatch.record(id, &span::Record::new(&fields.value_set(&values)));
+            }
+        }
+    });
+}
+
+/// Attaches the `counts` of a span as attributes of the OpenTelemetry span
+/// built for it.
+#[cfg(feature = "tracing-opentelemetry")]
+fn record_otel_attributes(data: &mut tracing_opentelemetry::OtelData, counts: &AllocationCounts) {
+    use opentelemetry::KeyValue;
+
+    data.builder
+        .attributes
+        .get_or_insert_with(Vec::new)
+        .extend([
+            KeyValue::new("alloc.bytes", counts.bytes_allocated as i64),
+            KeyValue::new("alloc.count", counts.allocations as i64),
+        ]);
+}
+
+/// Counts the operations described by `event`; returns the number of bytes
+/// they allocated, less the number they freed.
+fn record(counters: &Counters, event: &AllocationEvent) -> i64 {
+    let weight = event.weight();
+    let (allocated, freed) = match event.kind {
+        AllocationKind::Alloc | AllocationKind::AllocZeroed => (event.size, 0),
+        AllocationKind::Dealloc => (0, event.size),
+        AllocationKind::Realloc => (event.size, event.old_size.unwrap_or(0)),
+    };
+    let scale = |n: u64| (n as f64 * weight).round() as u64;
+    let (allocated, freed) = (scale(allocated), scale(freed));
+    counters.add(event.kind, scale(1), allocated, freed);
+    allocated.wrapping_sub(freed) as i64
+}
@@ -0,0 +1,41 @@
+//! Levels, encoded as integers for use as the `LEVEL` parameter of
+//! [`TracingAllocator`].
+//!
+//! [`tracing::Level`] cannot be used as a const generic parameter, so these
+//! constants stand in for it.
+//!
+//! [`TracingAllocator`]: crate::TracingAllocator
+
+use tracing::{level_filters::STATIC_MAX_LEVEL, Level};
+
+/// Encodes [`Level::TRACE`].
+pub const TRACE: u8 = 0;
+/// Encodes [`Level::DEBUG`].
+pub const DEBUG: u8 = 1;
+/// Encodes [`Level::INFO`].
+pub const INFO: u8 = 2;
+/// Encodes [`Level::WARN`].
+pub const WARN: u8 = 3;
+/// Encodes [`Level::ERROR`].
+pub const ERROR: u8 = 4;
+
+/// Encodes `level`.
+pub(crate) const fn encode(level: Level) -> u8 {
+    match level {
+        Level::TRACE => TRACE,
+        Level::DEBUG => DEBUG,
+        Level::INFO => INFO,
+        Level::WARN => WARN,
+        _ => ERROR,
+    }
+}
+
+/// Whether events at the encoded `level` survive `tracing`'s `max_level_*`
+/// and `release_max_level_*` features.
+pub(crate) const fn statically_enabled(level: u8) -> bool {
+    match STATIC_MAX_LEVEL.into_level() {
+        // lower encodings are more verbose
+        Some(max) => level >= encode(max),
+        None => false,
+    }
+}
@@ -15,33 +15,2331 @@
 //!     /* your code here */
 //! }
 //! ```
+//!
+//! ## Features
+//! - **`serde`**: implements `Serialize` and `Deserialize` for
+//!   [`event::AllocationEvent`], and for reports such as [`HeapSnapshot`].
+//! - **`valuable`**: implements `Valuable` for [`event::AllocationEvent`], and
+//!   for reports such as [`HeapSnapshot`].
+//! - **`backtrace`**: enables filters on the code that requested each
+//!   allocator operation, such as `TracingAllocator::with_ignored_callers`
+//!   and `TracingAllocator::with_caller_filters`, and the totals of each
+//!   caller reported by `top_callsites`.
+//! - **`tracing-subscriber`**: provides layers that cooperate with
+//!   [`TracingAllocator`], such as `MarkedSpans`, and layers that aggregate
+//!   its events, such as `StatsLayer`, `SpanStatsLayer`, `TargetStatsLayer`
+//!   and `SummaryLayer`, and layers that write them, such as `BinaryLayer`.
+//! - **`json`**: provides `JsonLinesLayer`, which writes allocation events as
+//!   JSON Lines without allocating. Implies `tracing-subscriber`.
+//! - **`csv`**: provides `CsvLayer`, which writes allocation events as CSV.
+//!   Implies `tracing-subscriber`.
+//! - **`arrow`**: provides `ArrowLayer`, which writes allocation events as
+//!   Apache Arrow IPC streams. Implies `tracing-subscriber`.
+//! - **`parquet`**: provides `ParquetLayer`, which writes allocation events as
+//!   Parquet files. Implies `arrow`.
+//! - **`sqlite`**: provides `SqliteLayer`, which writes allocation events
+//!   into SQLite databases. Implies `tracing-subscriber`.
+//! - **`socket`**: provides `SocketLayer`, which streams allocation events
+//!   in the binary format of `BinaryWriter` to a collector process over a
+//!   Unix domain socket or, in length-prefixed frames, over TCP, and
+//!   `FrameReader`, which reads those frames. Implies `tracing-subscriber`.
+//! - **`ring`**: provides `RingLayer`, which writes allocation events into a
+//!   ring buffer in shared memory, without locks or system calls, and
+//!   `RingReader`, which reads them from another process. Implies
+//!   `tracing-subscriber`.
+//! - **`measureme`**: provides `MeasuremeLayer`, which records allocation
+//!   events in the format of rustc's self-profiler. Implies
+//!   `tracing-subscriber`.
+//! - **`sentry`**: provides `SentryIntegration`, which attaches allocation
+//!   statistics to the events reported to Sentry.
+//! - **`prometheus`**: provides `PrometheusLayer`, which maintains metrics of
+//!   allocation events, and writes them in the text format of Prometheus.
+//!   Implies `tracing-subscriber`.
+//! - **`metrics`**: provides `MetricsLayer`, which publishes metrics of
+//!   allocation events through the `metrics` facade. Implies
+//!   `tracing-subscriber`.
+//! - **`opentelemetry`**: provides `OtelMetricsLayer`, which records allocation
+//!   events with OpenTelemetry instruments. Implies `tracing-subscriber`.
+//! - **`tracing-opentelemetry`**: provides
+//!   `SpanStatsLayer::with_otel_attributes`, which attaches the allocations of
+//!   each span to the span exported by `tracing-opentelemetry`. Implies
+//!   `tracing-subscriber`.
+//! - **`pprof`**: provides `PprofLayer`, which writes heap profiles in the
+//!   format of pprof. Implies `backtrace` and `tracing-subscriber`.
+//! - **`massif`**: provides `MassifLayer`, which writes snapshots of the heap
+//!   over time in the format of Valgrind's massif. Implies `backtrace` and
+//!   `tracing-subscriber`.
+//! - **`speedscope`**: provides `SpeedscopeLayer`, which writes allocation
+//!   profiles in the file format of speedscope. Implies `backtrace` and
+//!   `tracing-subscriber`.
+//! - **`callgrind`**: provides `CallgrindLayer`, which writes allocation
+//!   profiles in the format of Callgrind, for KCachegrind. Implies
+//!   `backtrace` and `tracing-subscriber`.
+//! - **`folded`**: provides `FoldedLayer`, which writes allocation profiles as
+//!   folded stacks, for flame graphs. Implies `backtrace` and
+//!   `tracing-subscriber`.
+//! - **`dhat`**: provides `DhatLayer`, which writes heap profiles in the JSON
+//!   format of DHAT, including the lifetimes of blocks. Implies `backtrace`
+//!   and `tracing-subscriber`.
+//! - **`timeline`**: provides `TimelineLayer`, which records time series of
+//!   live bytes, in total, by thread and by tag, and writes them as CSV or
+//!   JSON. Implies `tracing-subscriber`.
+//! - **`chrome`**: provides `ChromeTraceLayer`, which writes traces of the
+//!   heap of each thread in the format of Chrome's trace events, as read by
+//!   Perfetto. Implies `tracing-subscriber`.
+//! - **`http`**: provides `HeapEndpoint`, which serves live heap statistics,
+//!   snapshots and heap profiles over HTTP, independently of any framework.
+//! - **`hyper`**: provides `HeapEndpoint::serve`, which serves a
+//!   `HeapEndpoint` with hyper on a Tokio listener. Implies `http`.
+//! - **`macros`**: provides attribute macros that scope allocation tracing to
+//!   functions (`#[trace_allocations]` and `#[untraced]`), that record the
+//!   allocations of functions on spans (`#[instrument_allocations]`), and that
+//!   set up `main` (`#[tracing_allocations::main]`).
+//! - **`analyzer`**: builds the `tracing-allocations` binary, which reads
+//!   traces written by `JsonLinesLayer` or `BinaryLayer` and reports leaks,
+//!   top callers, and histograms of sizes and lifetimes, and compares the
+//!   live heaps of two traces. Install it with `cargo install
+//!   tracing-allocations --features analyzer`.
+//! - **`tui`**: builds the `tracing-allocations-top` binary, which listens on
+//!   a Unix socket for the streams of `SocketLayer`s in running processes,
+//!   and shows their live bytes, allocation rates, and top callers as they
+//!   change. Install it with `cargo install tracing-allocations --features
+//!   tui`.
+//! - **`off`**: compiles out all instrumentation, so that [`TracingAllocator`]
+//!   merely forwards to the allocator it wraps. Configuration methods remain
+//!   available, but have no effect; [`count_allocations`] counts nothing, and
+//!   [`forbid_allocations`] detects nothing. This allows production builds to
+//!   keep the same `#[global_allocator]` declaration as instrumented builds.
+//!   It must not be combined (e.g., by `--all-features`) with tests that
+//!   assert on what is counted, such as by [`count_allocations`],
+//!   [`assert_alloc_budget`], [`assert_no_alloc`], [`stats`] or [`Region`],
+//!   which fail when nothing is counted; the examples of this crate that do
+//!   so are ignored when it is enabled.
 
 use core::{
     alloc::{GlobalAlloc, Layout},
-    cell::{RefCell, RefMut},
+    cell::{Cell, RefCell, RefMut},
+    fmt,
+    marker::PhantomData,
+    ops::RangeInclusive,
+};
+
+use std::{
+    collections::hash_map::{DefaultHasher, RandomState},
+    hash::{BuildHasher, Hash, Hasher},
+    panic::catch_unwind,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::{
+    field::{display, DisplayValue},
+    Level,
+};
+
+#[cfg(feature = "arrow")]
+mod arrow;
+mod binary;
+#[cfg(feature = "callgrind")]
+mod callgrind;
+#[cfg(feature = "backtrace")]
+mod callsite;
+#[cfg(feature = "chrome")]
+mod chrome;
+#[cfg(feature = "csv")]
+mod csv;
+mod detail;
+#[cfg(feature = "dhat")]
+mod dhat;
+pub mod event;
+#[cfg(feature = "folded")]
+mod folded;
+mod forbid;
+pub mod future;
+mod global;
+pub mod housekeeping;
+#[cfg(feature = "http")]
+mod http;
+mod interval;
+#[cfg(any(
+    feature = "chrome",
+    feature = "dhat",
+    feature = "http",
+    feature = "json",
+    feature = "speedscope",
+    feature = "timeline"
+))]
+mod json;
+#[cfg(feature = "json")]
+mod jsonl;
+#[cfg(feature = "tracing-subscriber")]
+mod layer;
+pub mod level;
+mod live;
+#[cfg(feature = "tracing-subscriber")]
+mod marked;
+#[cfg(feature = "massif")]
+mod massif;
+#[cfg(feature = "measureme")]
+mod measureme;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "opentelemetry")]
+mod opentelemetry;
+#[cfg(feature = "parquet")]
+mod parquet;
+mod per_thread;
+#[cfg(feature = "pprof")]
+mod pprof;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod reporter;
+#[cfg(feature = "ring")]
+mod ring;
+#[cfg(feature = "sentry")]
+mod sentry;
+mod snapshot;
+#[cfg(feature = "socket")]
+mod socket;
+#[cfg(feature = "speedscope")]
+mod speedscope;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "backtrace")]
+mod stack;
+mod stats;
+#[cfg(feature = "tracing-subscriber")]
+mod summary;
+mod tag;
+pub mod thread;
+#[cfg(feature = "timeline")]
+mod timeline;
+
+#[cfg(feature = "arrow")]
+pub use arrow::{ArrowHandle, ArrowLayer};
+#[cfg(feature = "tracing-subscriber")]
+pub use binary::{BinaryHandle, BinaryLayer};
+pub use binary::{BinaryReader, BinaryRecord, BinaryWriter};
+#[cfg(feature = "callgrind")]
+pub use callgrind::{CallgrindHandle, CallgrindLayer};
+#[cfg(feature = "backtrace")]
+pub use callsite::{reset_callsite_stats, top_callsites, CallsiteStats};
+#[cfg(feature = "chrome")]
+pub use chrome::{ChromeTraceHandle, ChromeTraceLayer};
+#[cfg(feature = "csv")]
+pub use csv::CsvLayer;
+pub use detail::{with_detail_in_scope, Detail};
+#[cfg(feature = "dhat")]
+pub use dhat::{DhatHandle, DhatLayer};
+use event::{AllocationEvent, AllocationKind};
+#[cfg(feature = "folded")]
+pub use folded::{FoldedHandle, FoldedLayer};
+pub use forbid::{assert_no_alloc, forbid_allocations, ForbidGuard, OnAllocation};
+pub use global::{
+    checkpoint, diff, peak_bytes, reset_peak, stats, AllocationCounts, GlobalStats, Region,
 };
+#[cfg(feature = "http")]
+pub use http::HeapEndpoint;
+#[cfg(feature = "json")]
+pub use jsonl::JsonLinesLayer;
+#[cfg(feature = "tracing-subscriber")]
+pub use layer::{
+    SpanAllocations, SpanStatsHandle, SpanStatsLayer, StatsHandle, StatsLayer, TargetStatsHandle,
+    TargetStatsLayer,
+};
+pub use live::{
+    churn_hotspots, fragmentation, live_blocks, ChurnStats, ChurnWindow, Fragmentation, LiveBlock,
+};
+#[cfg(feature = "tracing-subscriber")]
+pub use marked::MarkedSpans;
+#[cfg(feature = "massif")]
+pub use massif::{MassifHandle, MassifLayer};
+#[cfg(feature = "measureme")]
+pub use measureme::MeasuremeLayer;
+#[cfg(feature = "metrics")]
+pub use metrics::MetricsLayer;
+#[cfg(feature = "opentelemetry")]
+pub use opentelemetry::OtelMetricsLayer;
+#[cfg(feature = "parquet")]
+pub use parquet::{ParquetHandle, ParquetLayer};
+pub use per_thread::{reset_thread_peak, thread_counts, thread_peak_bytes, ThreadCounts};
+#[cfg(feature = "pprof")]
+pub use pprof::{PprofHandle, PprofLayer};
+#[cfg(feature = "prometheus")]
+pub use prometheus::{PrometheusHandle, PrometheusLayer};
+pub use reporter::{start_reporter, Reporter};
+#[cfg(feature = "ring")]
+pub use ring::{RingLayer, RingReader};
+#[cfg(feature = "sentry")]
+pub use sentry::SentryIntegration;
+pub use snapshot::{snapshot, GroupChange, HeapDiff, HeapGroup, HeapGroupDiff, HeapSnapshot};
+#[cfg(feature = "socket")]
+pub use socket::{FrameReader, SocketHandle, SocketLayer};
+#[cfg(feature = "speedscope")]
+pub use speedscope::{SpeedscopeHandle, SpeedscopeLayer};
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteHandle, SqliteLayer};
+#[doc(hidden)]
+pub use stats::{__count_in_span, __count_in_span_async};
+pub use stats::{assert_alloc_budget, count_allocations, AllocationStats};
+#[cfg(feature = "tracing-subscriber")]
+pub use summary::SummaryLayer;
+pub use tag::tag_in_scope;
+pub use thread::spawn;
+#[cfg(feature = "timeline")]
+pub use timeline::{TimelineHandle, TimelineLayer};
+#[cfg(feature = "macros")]
+pub use tracing_allocations_macros::{instrument_allocations, main, trace_allocations, untraced};
 
-use std::panic::catch_unwind;
+#[doc(hidden)]
+pub use tracing as __tracing;
 
 /// A global allocator that emits tracing events.
 ///
-/// This allocator emits [`TRACE`]-level events. See method documentation for
-/// more information:
+/// By default, this allocator emits [`TRACE`]-level events; see
+/// [`TracingAllocator::with_level`]. See method documentation for more
+/// information:
 /// - [`TracingAllocator::alloc`]
 /// - [`TracingAllocator::dealloc`]
 /// - [`TracingAllocator::alloc_zeroed`]
 /// - [`TracingAllocator::realloc`]
 ///
+/// The presence of the address fields (e.g., `addr` and `addr_hex`) on these
+/// events depends on the configured [`AddressFormat`].
+///
+/// Optionally, allocations exceeding a [configurable
+/// threshold][TracingAllocator::with_large_alloc_threshold] additionally emit
+/// a [`WARN`]-level `large_alloc` event.
+///
+/// ## Static level filtering
+/// The `LEVEL` parameter is the least verbose [level] at which this allocator
+/// may emit events; [`with_level`][TracingAllocator::with_level] and
+/// [`set_level`][TracingAllocator::set_level] cannot select a less verbose
+/// level. If `LEVEL` is disabled by `tracing`'s `max_level_*` or
+/// `release_max_level_*` features, the allocator's instrumentation (including
+/// `large_alloc` events) is compiled out entirely, and it merely forwards to
+/// the allocator it wraps.
+///
+/// By default, `LEVEL` is [`level::ERROR`], and any level may be selected at
+/// runtime.
+///
+/// ```
+/// use std::alloc::System;
+/// use tracing::Level;
+/// use tracing_allocations::{level, TracingAllocator};
+///
+/// // with `tracing/release_max_level_info`, release builds emit nothing
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System, { level::DEBUG }> =
+///     TracingAllocator::new(System).with_level(Level::DEBUG);
+/// # fn main() {}
+/// ```
+///
 /// [`TRACE`]: tracing::Level::TRACE
+/// [`WARN`]: tracing::Level::WARN
+/// [level]: tracing::Level
 #[non_exhaustive]
-pub struct TracingAllocator<A> {
+pub struct TracingAllocator<A, const LEVEL: u8 = { level::ERROR }> {
     /// The underlying allocator, which `TracingAllocator` delegates allocations
     /// and deallocations to.
     pub allocator: A,
+    config: Config,
+}
+
+impl<A: Default, const LEVEL: u8> Default for TracingAllocator<A, LEVEL> {
+    /// Constructs a tracing allocator wrapping the default `A`; see
+    /// [`TracingAllocator::new`].
+    fn default() -> Self {
+        Self::new(A::default())
+    }
+}
+
+/// A [`TracingAllocator`] wrapping the [`System`](std::alloc::System)
+/// allocator.
+pub type TracingSystem = TracingAllocator<std::alloc::System>;
+
+/// A [`TracingSystem`] with the default configuration, for use as the global
+/// allocator.
+///
+/// ## Usage
+/// ```
+/// use tracing_allocations::{TracingSystem, SYSTEM};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingSystem = SYSTEM;
+///
+/// fn main() {
+///     let _guard = tracing_allocations::housekeeping();
+///     /* your code here */
+/// }
+/// ```
+// each use of this constant produces a distinct allocator; this is intended
+#[allow(clippy::declare_interior_mutable_const)]
+pub const SYSTEM: TracingSystem = TracingAllocator::new(std::alloc::System);
+
+/// Emits an event at the level encoded (by [`level::encode`]) in `$level`, to
+/// the target of the [`SizeClass`] (if any) in `$class`, and with the explicit
+/// `parent`, if given.
+///
+/// An event's target and level must be known statically, so this expands to
+/// one callsite per combination of target and level.
+macro_rules! event_at {
+    (parent: $parent:expr, $class:expr, $level:expr, $($fields:tt)*) => {
+        event_at!(@class [parent: $parent,] $class, $level, $($fields)*)
+    };
+    ($class:expr, $level:expr, $($fields:tt)*) => {
+        event_at!(@class [] $class, $level, $($fields)*)
+    };
+    (@class [$($parent:tt)*] $class:expr, $level:expr, $($fields:tt)*) => {
+        match $class {
+            None => {
+                event_at!(@level [target: "tracing::allocator", $($parent)*] $level, $($fields)*)
+            }
+            Some(SizeClass::Small) => {
+                event_at!(@level [target: "alloc::small", $($parent)*] $level, $($fields)*)
+            }
+            Some(SizeClass::Medium) => {
+                event_at!(@level [target: "alloc::medium", $($parent)*] $level, $($fields)*)
+            }
+            Some(SizeClass::Large) => {
+                event_at!(@level [target: "alloc::large", $($parent)*] $level, $($fields)*)
+            }
+        }
+    };
+    (@level [$($target:tt)*] $level:expr, $($fields:tt)*) => {
+        match $level {
+            level::TRACE => tracing::event!($($target)* Level::TRACE, $($fields)*),
+            level::DEBUG => tracing::event!($($target)* Level::DEBUG, $($fields)*),
+            level::INFO => tracing::event!($($target)* Level::INFO, $($fields)*),
+            level::WARN => tracing::event!($($target)* Level::WARN, $($fields)*),
+            _ => tracing::event!($($target)* Level::ERROR, $($fields)*),
+        }
+    };
+}
+
+/// Settings consulted by [`TracingAllocator`]'s instrumented methods.
+struct Config {
+    /// The set of [`AllocationKind`]s that emit events.
+    kinds: AtomicU8,
+    /// The set of methods, identified by the [`AllocationKind`]s of their
+    /// operations, that are instrumented.
+    wrapped: u8,
+    /// The level of emitted events, encoded by [`level::encode`].
+    level: AtomicU8,
+    /// Allocations larger than this many bytes emit a `large_alloc` warning.
+    large_alloc_threshold: AtomicUsize,
+    /// Operations on blocks smaller than this many bytes emit no events.
+    min_size: AtomicUsize,
+    /// Operations on blocks larger than this many bytes emit no events.
+    max_size: AtomicUsize,
+    /// Only one in this many operations on each thread emits events.
+    sample_rate: AtomicU64,
+    /// On average, one event is emitted per this many bytes operated upon; or
+    /// zero, if byte-weighted sampling is disabled.
+    sample_interval: AtomicU64,
+    /// The maximum number of events emitted per second; or zero, if events
+    /// are not rate-limited.
+    rate_limit: AtomicU64,
+    /// Whether the rate limit applies to each thread, or to the process.
+    rate_limit_scope: RateLimitScope,
+    /// The state of the rate limit, if it applies to the process.
+    global_rate_limiter: GlobalRateLimiter,
+    /// The state of adaptive downsampling.
+    adaptive: AdaptiveSampler,
+    /// Patterns matching callers whose operations emit no events.
+    #[cfg(feature = "backtrace")]
+    ignored_callers: &'static [&'static str],
+    /// Directives selecting the callers whose operations emit events.
+    #[cfg(feature = "backtrace")]
+    caller_filters: &'static [CallerFilter],
+    /// Whether the allocations of each caller are totalled.
+    #[cfg(feature = "backtrace")]
+    callsite_stats: bool,
+    /// The interval at which the totals of each caller are emitted in place
+    /// of events, if operations are rolled up.
+    #[cfg(feature = "backtrace")]
+    callsite_rollup: Option<Duration>,
+    /// How addresses are reported on emitted events.
+    address_mode: AddressMode,
+    /// How addresses are formatted on emitted events.
+    address_format: AddressFormat,
+    /// Whether `alloc_zeroed` emits `alloc` events with a `zeroed` field.
+    unify_zeroed: bool,
+    /// The clock used to timestamp emitted events, if any.
+    clock: Option<fn() -> u64>,
+    /// Contributes extra fields to emitted events, if any.
+    extra: Option<fn(&mut ExtraFields<'_, '_>)>,
+    /// Whether emitted events record the ID of the current span.
+    span_ids: bool,
+    /// Whether traced allocations are recorded in the live table.
+    live_table: bool,
+    /// The lifetime within which allocations are deemed short-lived, if churn
+    /// is detected.
+    churn_window: Option<ChurnWindow>,
+    /// Whether runs of identical operations are coalesced into one event.
+    coalesce: bool,
+    /// Whether the process-wide peak of live bytes is tracked exactly.
+    exact_peak: bool,
+    /// The interval at which each thread emits the totals of its operations
+    /// in place of events, if operations are summarized.
+    interval_summaries: Option<Duration>,
+    /// The greatest size of [`SizeClass::Small`] blocks, and the least size of
+    /// [`SizeClass::Large`] blocks, if events are routed by size class.
+    size_classes: Option<(usize, usize)>,
+    /// Whether only operations inside of marked spans emit events.
+    #[cfg(feature = "tracing-subscriber")]
+    marked_spans_only: bool,
+    /// Queries the usable size of allocated blocks, if supported.
+    usable_size: Option<unsafe fn(*mut u8, Layout) -> usize>,
+}
+
+impl Config {
+    /// Whether the method performing operations of the given `kind` is
+    /// instrumented.
+    fn wraps(&self, kind: AllocationKind) -> bool {
+        self.wrapped & kind.bit() != 0
+    }
+
+    /// Whether operations of the given `kind` should emit events.
+    fn traces(&self, kind: AllocationKind) -> bool {
+        self.kinds.load(Ordering::Relaxed) & kind.bit() != 0
+    }
+
+    /// Whether operations on blocks of `size` bytes should emit events.
+    fn admits(&self, size: usize) -> bool {
+        let min_size = self.min_size.load(Ordering::Relaxed);
+        let max_size = self.max_size.load(Ordering::Relaxed);
+        (min_size..=max_size).contains(&size)
+    }
+
+    /// The size class of a block of `size` bytes, if size classes are
+    /// configured.
+    fn size_class(&self, size: u64) -> Option<SizeClass> {
+        let (small, large) = self.size_classes?;
+        Some(if size <= small as u64 {
+            SizeClass::Small
+        } else if size >= large as u64 {
+            SizeClass::Large
+        } else {
+            SizeClass::Medium
+        })
+    }
+
+    /// Whether the current operation occurs inside of a marked span, if that
+    /// is required.
+    fn admits_span(&self) -> bool {
+        #[cfg(feature = "tracing-subscriber")]
+        if self.marked_spans_only {
+            return marked::in_marked_span();
+        }
+        true
+    }
+
+    /// Whether the caller of the current operation is neither ignored nor
+    /// excluded by the caller filters.
+    fn admits_caller(&self) -> bool {
+        #[cfg(feature = "backtrace")]
+        if !self.ignored_callers.is_empty() || !self.caller_filters.is_empty() {
+            let caller = callsite::caller();
+            let matches = |pattern: &str| caller.is_some_and(|caller| caller.matches(pattern));
+            if self.ignored_callers.iter().any(|pattern| matches(pattern)) {
+                return false;
+            }
+            return CallerFilter::admits(self.caller_filters, matches);
+        }
+        true
+    }
+
+    /// Whether the current operation is within the rate limit. Emits an
+    /// `events_dropped` event if operations have recently exceeded it.
+    fn within_rate_limit(&self) -> bool {
+        let rate_limit = self.rate_limit.load(Ordering::Relaxed);
+        if rate_limit == 0 {
+            return true;
+        }
+        let now = monotonic_nanos();
+        let interval = NANOS_PER_SEC / rate_limit;
+        let (admitted, dropped) = match self.rate_limit_scope {
+            RateLimitScope::Thread => RATE_LIMITER
+                .try_with(|limiter| {
+                    let mut state = limiter.get();
+                    let result = state.admit(now, interval);
+                    limiter.set(state);
+                    result
+                })
+                .unwrap_or((false, 0)),
+            RateLimitScope::Global => self.global_rate_limiter.admit(now, interval),
+        };
+        if dropped > 0 {
+            tracing::warn! {
+                target: "tracing::allocator",
+                dropped = dropped,
+                rate_limit = rate_limit,
+                "events_dropped",
+            };
+        }
+        admitted
+    }
+
+    /// Whether the current operation is selected by 1-in-N sampling.
+    fn sample(&self) -> bool {
+        let Some(sample_rate) = self.sample_rate() else {
+            return true;
+        };
+        SAMPLE_COUNTER
+            .try_with(|counter| {
+                let count = counter.get();
+                counter.set(count.wrapping_add(1));
+                count % sample_rate == 0
+            })
+            .unwrap_or(false)
+    }
+
+    /// The effective rate of 1-in-N sampling, if any; i.e., the configured
+    /// rate, multiplied by the factor of adaptive downsampling.
+    fn sample_rate(&self) -> Option<u64> {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed).max(1);
+        let sample_rate = sample_rate.saturating_mul(self.adaptive.factor());
+        (sample_rate > 1).then_some(sample_rate)
+    }
+
+    /// The mean interval of byte-weighted sampling, if enabled.
+    fn sample_interval(&self) -> Option<u64> {
+        let sample_interval = self.sample_interval.load(Ordering::Relaxed);
+        (sample_interval > 0).then_some(sample_interval)
+    }
+
+    /// Whether an operation on `size` bytes is selected by byte-weighted
+    /// sampling.
+    fn sample_bytes(&self, size: usize) -> bool {
+        let Some(interval) = self.sample_interval() else {
+            return true;
+        };
+        BYTE_SAMPLER
+            .try_with(|sampler| {
+                let mut state = sampler.get();
+                let sampled = state.sample(size as u64, interval);
+                sampler.set(state);
+                sampled
+            })
+            .unwrap_or(false)
+    }
+
+    /// The address of `ptr`, as it should be reported on emitted events.
+    fn addr(&self, ptr: *mut u8) -> u64 {
+        self.address_mode.apply(ptr as usize)
+    }
+
+    /// The usable size of the block at `ptr`, if the allocator supports
+    /// introspection, queried with allocation tracing disabled, as
+    /// [`UsableSize`] promises, or `None` if the query panics.
+    ///
+    /// ## Safety
+    /// `ptr` must be null, or denote a block currently allocated with `layout`.
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> Option<u64> {
+        match self.usable_size {
+            Some(usable_size) if !ptr.is_null() => {
+                // safety: global allocators must not unwind
+                catch_unwind(|| as_instrumentation(|| usable_size(ptr, layout) as u64)).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Records the allocation of the block at `ptr` in the live table, if it
+    /// is enabled.
+    ///
+    /// ## Safety
+    /// `ptr` must be null, or denote a block currently allocated with `layout`.
+    unsafe fn track(&self, ptr: *mut u8, layout: Layout) {
+        if self.live_table && !ptr.is_null() {
+            let usable_size = self.usable_size(ptr, layout).map(|size| size as usize);
+            live::track(
+                ptr as usize,
+                layout.size(),
+                usable_size,
+                self.churn_window.is_some(),
+            );
+        }
+    }
+
+    /// Takes the block at `ptr`, which is about to be reallocated, out of the
+    /// live table, if it is enabled and the block was recorded.
+    ///
+    /// Like [`Config::untrack`], this is bookkeeping rather than tracing, so
+    /// it is done whether or not the operation is traced.
+    fn take(&self, ptr: *mut u8) -> Option<live::Block> {
+        if !self.live_table {
+            return None;
+        }
+        // safety: global allocators must not unwind
+        catch_unwind(|| as_instrumentation(|| live::take(ptr as usize)))
+            .ok()
+            .flatten()
+    }
+
+    /// Returns `block`, [taken](Config::take) from the live table, to the
+    /// table: at `new_ptr`, resized to `new_size` bytes, if the reallocation
+    /// succeeded, or else at `old_ptr`.
+    ///
+    /// ## Safety
+    /// `new_ptr` must be null, or denote a block currently allocated with
+    /// `new_size` bytes and `old_layout`'s alignment.
+    unsafe fn retrack(
+        &self,
+        block: live::Block,
+        old_ptr: *mut u8,
+        old_layout: Layout,
+        new_ptr: *mut u8,
+        new_size: usize,
+    ) {
+        // safety: global allocators must not unwind
+        let _ = catch_unwind(|| {
+            as_instrumentation(|| {
+                if new_ptr.is_null() {
+                    return live::restore(old_ptr as usize, block);
+                }
+                let usable_size = Layout::from_size_align(new_size, old_layout.align())
+                    .ok()
+                    .and_then(|new_layout| self.usable_size(new_ptr, new_layout))
+                    .map(|size| size as usize);
+                live::retrack(block, new_ptr as usize, new_size, usable_size);
+            })
+        });
+    }
+
+    /// Records that the block at `ptr`, which is about to be freed, was freed
+    /// in the live table, if it is enabled, returning the lifetime of the
+    /// block if it was recorded.
+    ///
+    /// This must be called before the block is freed, lest another thread be
+    /// given its address, and record it, first. The table is bookkeeping
+    /// rather than tracing, so this is done whether or not the operation is
+    /// traced; otherwise, blocks freed untraced would linger in the table.
+    fn untrack(&self, ptr: *mut u8) -> Option<live::Age> {
+        if !self.live_table {
+            return None;
+        }
+        // safety: global allocators must not unwind
+        catch_unwind(|| as_instrumentation(|| live::untrack(ptr as usize, self.churn_window)))
+            .ok()
+            .flatten()
+    }
+
+    /// Describes an operation of the given `kind` on the block at `ptr`,
+    /// populating the fields shared by all kinds of operation.
+    fn event(&self, kind: AllocationKind, ptr: *mut u8, size: usize) -> AllocationEvent {
+        let mut event = AllocationEvent::new(kind, self.addr(ptr), size as u64);
+        event.timestamp_ns = self.clock.map(|clock| clock());
+        event.span_id = self
+            .span_ids
+            .then(|| tracing::Span::current().id())
+            .flatten()
+            .map(|id| id.into_u64());
+        event.sample_rate = self.sample_rate();
+        event.sample_interval = self.sample_interval();
+        event
+    }
+
+    /// The kind of event emitted for an allocation produced by `alloc` or, if
+    /// `zeroed`, `alloc_zeroed`.
+    fn alloc_kind(&self, zeroed: bool) -> AllocationKind {
+        if zeroed && !self.unify_zeroed {
+            AllocationKind::AllocZeroed
+        } else {
+            AllocationKind::Alloc
+        }
+    }
+
+    /// Describes an allocation produced by `alloc` or, if `zeroed`,
+    /// `alloc_zeroed`.
+    ///
+    /// ## Safety
+    /// `ptr` must be null, or denote a block currently allocated with `layout`.
+    unsafe fn alloc_event(&self, ptr: *mut u8, layout: Layout, zeroed: bool) -> AllocationEvent {
+        let mut event = self.event(self.alloc_kind(zeroed), ptr, layout.size());
+        event.align = Some(layout.align() as u64);
+        event.usable_size = self.usable_size(ptr, layout);
+        event.zeroed = self.unify_zeroed.then_some(zeroed);
+        event
+    }
+
+    /// Describes a deallocation of a block that lived for `age`, if known.
+    fn dealloc_event(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        usable_size: Option<u64>,
+        age: Option<live::Age>,
+    ) -> AllocationEvent {
+        let mut event = self.event(AllocationKind::Dealloc, ptr, layout.size());
+        event.align = Some(layout.align() as u64);
+        event.usable_size = usable_size;
+        event.age_ns = age.map(|age| age.ns);
+        event.age_events = age.map(|age| age.allocations);
+        event
+    }
+
+    /// Describes a reallocation.
+    ///
+    /// ## Safety
+    /// `new_ptr` must be null, or denote a block currently allocated with
+    /// `new_size` bytes and `old_layout`'s alignment.
+    unsafe fn realloc_event(
+        &self,
+        old_ptr: *mut u8,
+        old_layout: Layout,
+        new_ptr: *mut u8,
+        new_size: usize,
+    ) -> AllocationEvent {
+        let mut event = self.event(AllocationKind::Realloc, new_ptr, new_size);
+        event.align = Some(old_layout.align() as u64);
+        event.usable_size = Layout::from_size_align(new_size, old_layout.align())
+            .ok()
+            .and_then(|new_layout| self.usable_size(new_ptr, new_layout));
+        event.old_addr = Some(self.addr(old_ptr));
+        event.old_size = Some(old_layout.size() as u64);
+        event
+    }
+
+    /// Emits `event`, or the run of identical events that it ends, if
+    /// coalescing is enabled.
+    fn emit(&self, event: &AllocationEvent) {
+        self.adaptive.record();
+        #[cfg(feature = "backtrace")]
+        if self.callsite_stats && event.kind != AllocationKind::Dealloc {
+            callsite::attribute(event.size, event.weight());
+        }
+        if let Some(length) = self.interval_summaries {
+            if let Some(interval) = interval::record(event, length) {
+                self.dispatch_interval(&interval);
+            }
+            return;
+        }
+        #[cfg(feature = "backtrace")]
+        if let Some(interval) = self.callsite_rollup {
+            callsite::roll_up(event);
+            if let Some(rollup) = callsite::take_rollup(interval) {
+                for rolled in rollup.values() {
+                    self.dispatch_rollup(rolled, interval);
+                }
+            }
+            return;
+        }
+        if !self.coalesce {
+            return self.emission(event).dispatch(event);
+        }
+        let key = RunKey::of(event);
+        let ended = RUNS.try_with(|runs| {
+            let run = &mut runs.borrow_mut().0[event.kind.index()];
+            match run {
+                Some(run) if run.key == key => {
+                    run.count += 1;
+                    None
+                }
+                _ => run.replace(Run::new(event.clone(), key, self.emission(event))),
+            }
+        });
+        match ended {
+            Ok(Some(ended)) => ended.finish(),
+            Ok(None) => {}
+            Err(_) => self.emission(event).dispatch(event),
+        }
+    }
+
+    /// How, and in what context, `event` is to be emitted, as of now.
+    fn emission(&self, event: &AllocationEvent) -> Emission {
+        Emission {
+            level: self.level.load(Ordering::Relaxed),
+            class: self.size_class(event.size),
+            address_format: self.address_format,
+            extra: self.extra,
+            large_alloc_threshold: self.large_alloc_threshold.load(Ordering::Relaxed),
+            tag: tag::current(),
+            backtrace: detail::backtrace(),
+            caller: detail::caller(),
+            span: tracing::Span::current().id(),
+        }
+    }
+
+    /// Emits an `interval_summary` event reporting the operations of the
+    /// current thread over an `interval`.
+    fn dispatch_interval(&self, interval: &interval::Interval) {
+        let level = self.level.load(Ordering::Relaxed);
+        event_at! {
+            None,
+            level,
+            allocations = interval.allocations,
+            bytes_allocated = interval.bytes_allocated,
+            bytes_freed = interval.bytes_freed,
+            net_bytes = interval.net_bytes(),
+            peak_bytes = interval.peak_bytes(),
+            interval_ns = interval.elapsed_ns,
+            "interval_summary",
+        }
+    }
+
+    /// Emits a `callsite_rollup` event reporting the operations `rolled` up
+    /// over the last `interval`.
+    #[cfg(feature = "backtrace")]
+    fn dispatch_rollup(&self, rolled: &callsite::Rolled, interval: Duration) {
+        let level = self.level.load(Ordering::Relaxed);
+        let caller = rolled.caller;
+        event_at! {
+            None,
+            level,
+            symbol = caller.and_then(|caller| caller.symbol),
+            file = caller.and_then(|caller| caller.file),
+            line = caller.and_then(|caller| caller.line),
+            allocations = rolled.allocations,
+            bytes_allocated = rolled.bytes_allocated,
+            deallocations = rolled.deallocations,
+            bytes_freed = rolled.bytes_freed,
+            interval_ns = interval.as_nanos() as u64,
+            "callsite_rollup",
+        }
+    }
+}
+
+/// How, and in what context, the event of an operation is emitted.
+///
+/// This is captured as the operation is performed, so that the event standing
+/// for a coalesced run reports the context of the run's first operation,
+/// rather than that of whichever operation ends the run.
+struct Emission {
+    /// The level of the event, encoded by [`level::encode`].
+    level: u8,
+    /// The size class of the block, if size classes are configured.
+    class: Option<SizeClass>,
+    /// How addresses are formatted.
+    address_format: AddressFormat,
+    /// Contributes extra fields to the event, if any.
+    extra: Option<fn(&mut ExtraFields<'_, '_>)>,
+    /// Allocations larger than this many bytes emit a `large_alloc` warning.
+    large_alloc_threshold: usize,
+    /// The tag of the enclosing [`tag_in_scope`], if any.
+    tag: Option<&'static str>,
+    /// The backtrace of the operation, if requested.
+    backtrace: Option<DisplayValue<std::backtrace::Backtrace>>,
+    /// The code that requested the operation, if requested.
+    caller: Option<detail::CallerValue>,
+    /// The ID of the span that was current, as the parent of the event.
+    ///
+    /// Only the ID is held, rather than the span, lest a coalesced run keep
+    /// the span open until the run's event is emitted; if the span has closed
+    /// by then, subscribers will not find the event's parent.
+    span: Option<tracing::span::Id>,
+}
+
+impl Emission {
+    /// Emits `event`, and a `large_alloc` event if warranted.
+    fn dispatch(&self, event: &AllocationEvent) {
+        let (tag, backtrace, caller) = (self.tag, &self.backtrace, &self.caller);
+        match event.kind {
+            AllocationKind::Alloc => event_at! {
+                parent: self.span.clone(),
+                self.class,
+                self.level,
+                kind = "alloc",
+                addr = self.decimal(event.addr),
+                addr_hex = self.hex(event.addr),
+                size = event.size,
+                align = event.align,
+                usable_size = event.usable_size,
+                zeroed = event.zeroed,
+                timestamp_ns = event.timestamp_ns,
+                span_id = event.span_id,
+                sample_rate = event.sample_rate,
+                sample_interval = event.sample_interval,
+                count = event.count,
+                tag = tag,
+                extra = self.extra(),
+                backtrace = backtrace,
+                caller = caller,
+                "alloc",
+            },
+            AllocationKind::AllocZeroed => event_at! {
+                parent: self.span.clone(),
+                self.class,
+                self.level,
+                kind = "alloc_zeroed",
+                addr = self.decimal(event.addr),
+                addr_hex = self.hex(event.addr),
+                size = event.size,
+                align = event.align,
+                usable_size = event.usable_size,
+                timestamp_ns = event.timestamp_ns,
+                span_id = event.span_id,
+                sample_rate = event.sample_rate,
+                sample_interval = event.sample_interval,
+                count = event.count,
+                tag = tag,
+                extra = self.extra(),
+                backtrace = backtrace,
+                caller = caller,
+                "alloc_zeroed",
+            },
+            AllocationKind::Dealloc => event_at! {
+                parent: self.span.clone(),
+                self.class,
+                self.level,
+                kind = "dealloc",
+                addr = self.decimal(event.addr),
+                addr_hex = self.hex(event.addr),
+                size = event.size,
+                align = event.align,
+                usable_size = event.usable_size,
+                timestamp_ns = event.timestamp_ns,
+                span_id = event.span_id,
+                sample_rate = event.sample_rate,
+                sample_interval = event.sample_interval,
+                count = event.count,
+                age_ns = event.age_ns,
+                age_events = event.age_events,
+                tag = tag,
+                extra = self.extra(),
+                backtrace = backtrace,
+                caller = caller,
+                "dealloc",
+            },
+            AllocationKind::Realloc => event_at! {
+                parent: self.span.clone(),
+                self.class,
+                self.level,
+                kind = "realloc",
+                old_addr = event.old_addr.and_then(|addr| self.decimal(addr)),
+                old_addr_hex = event.old_addr.and_then(|addr| self.hex(addr)),
+                old_size = event.old_size,
+                new_addr = self.decimal(event.addr),
+                new_addr_hex = self.hex(event.addr),
+                new_size = event.size,
+                align = event.align,
+                new_usable_size = event.usable_size,
+                delta = event.delta(),
+                moved = event.moved(),
+                timestamp_ns = event.timestamp_ns,
+                span_id = event.span_id,
+                sample_rate = event.sample_rate,
+                sample_interval = event.sample_interval,
+                count = event.count,
+                tag = tag,
+                extra = self.extra(),
+                backtrace = backtrace,
+                caller = caller,
+                "realloc",
+            },
+        }
+        self.warn_if_large(event);
+    }
+
+    /// Emits a `large_alloc` event if `event` allocated more bytes than the
+    /// configured threshold.
+    fn warn_if_large(&self, event: &AllocationEvent) {
+        let threshold = self.large_alloc_threshold;
+        let grew = match event.kind {
+            AllocationKind::Dealloc => false,
+            AllocationKind::Realloc => event.delta() > Some(0),
+            _ => true,
+        };
+        if grew && event.size > threshold as u64 {
+            tracing::warn! {
+                target: "tracing::allocator",
+                parent: self.span.clone(),
+                kind = event.kind.as_str(),
+                addr = self.decimal(event.addr),
+                addr_hex = self.hex(event.addr),
+                size = event.size,
+                threshold = threshold,
+                timestamp_ns = event.timestamp_ns,
+                span_id = event.span_id,
+                sample_rate = event.sample_rate,
+                sample_interval = event.sample_interval,
+                count = event.count,
+                tag = self.tag,
+                extra = self.extra(),
+                backtrace = &self.backtrace,
+                caller = &self.caller,
+                "large_alloc",
+            };
+        }
+    }
+
+    /// The reported address `addr`, if it should be emitted in decimal.
+    fn decimal(&self, addr: u64) -> Option<u64> {
+        match self.address_format {
+            AddressFormat::Decimal | AddressFormat::Both => Some(addr),
+            AddressFormat::Hex => None,
+        }
+    }
+
+    /// The reported address `addr`, if it should be emitted in hexadecimal.
+    fn hex(&self, addr: u64) -> Option<DisplayValue<Hex>> {
+        match self.address_format {
+            AddressFormat::Hex | AddressFormat::Both => Some(display(Hex(addr))),
+            AddressFormat::Decimal => None,
+        }
+    }
+
+    /// The `extra` field of the event, if an extra fields callback is
+    /// configured.
+    fn extra(&self) -> Option<DisplayValue<Extra>> {
+        self.extra.map(|callback| display(Extra(callback)))
+    }
+}
+
+/// How [`TracingAllocator`] reports addresses on the events it emits.
+///
+/// Raw heap addresses may be undesirable in traces that leave the machine:
+/// they defeat ASLR and can leak information about the process. The redacting
+/// modes replace each address with a hash of it. Because the hash of a given
+/// address is the same for the allocation and the deallocation of a block,
+/// allocation and deallocation events can still be correlated.
+///
+/// In every mode, a null address (i.e., a failed allocation) is reported as
+/// `0`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AddressMode {
+    /// Report addresses as-is. This is the default.
+    Raw,
+    /// Replace addresses with a hash of the address and the given `salt`.
+    ///
+    /// The same salt produces the same hashes across runs of the same build,
+    /// which makes traces from separate runs comparable.
+    Hashed {
+        /// The salt mixed into every hashed address.
+        salt: u64,
+    },
+    /// Replace addresses with a hash of the address and a salt chosen at
+    /// random once per process.
+    ///
+    /// Hashed addresses are opaque identifiers that are only meaningful within
+    /// a single run.
+    Opaque,
+}
+
+/// A class of block sizes, each of which is reported to its own target. See
+/// [`TracingAllocator::with_size_classes`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SizeClass {
+    /// Blocks no larger than the `small` threshold.
+    Small,
+    /// Blocks between the `small` and `large` thresholds.
+    Medium,
+    /// Blocks no smaller than the `large` threshold.
+    Large,
+}
+
+impl SizeClass {
+    /// The target of events reporting operations on blocks of this class;
+    /// e.g., "alloc::large".
+    pub const fn target(self) -> &'static str {
+        match self {
+            SizeClass::Small => "alloc::small",
+            SizeClass::Medium => "alloc::medium",
+            SizeClass::Large => "alloc::large",
+        }
+    }
+}
+
+/// Whether [`TracingAllocator`]'s [rate
+/// limit][TracingAllocator::with_rate_limit] applies to each thread, or to the
+/// process as a whole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RateLimitScope {
+    /// Each thread may emit up to the limit.
+    Thread,
+    /// All threads together may emit up to the limit.
+    Global,
+}
+
+/// A directive selecting callers whose operations emit events. See
+/// [`TracingAllocator::with_caller_filters`].
+#[cfg(feature = "backtrace")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CallerFilter {
+    /// Operations of callers matching this pattern emit events.
+    Allow(&'static str),
+    /// Operations of callers matching this pattern emit no events.
+    Deny(&'static str),
+}
+
+#[cfg(feature = "backtrace")]
+impl CallerFilter {
+    /// Whether `filters` admit a caller that matches the patterns for which
+    /// `matches` returns `true`.
+    fn admits(filters: &[Self], matches: impl Fn(&str) -> bool) -> bool {
+        let mut verdict: Option<(usize, bool)> = None;
+        for filter in filters {
+            let (pattern, allow) = match *filter {
+                CallerFilter::Allow(pattern) => (pattern, true),
+                CallerFilter::Deny(pattern) => (pattern, false),
+            };
+            if !matches(pattern) {
+                continue;
+            }
+            match verdict {
+                Some((len, _)) if pattern.len() < len || (pattern.len() == len && allow) => {}
+                _ => verdict = Some((pattern.len(), allow)),
+            }
+        }
+        match verdict {
+            Some((_, allow)) => allow,
+            None => !filters
+                .iter()
+                .any(|filter| matches!(filter, CallerFilter::Allow(_))),
+        }
+    }
+}
+
+/// How [`TracingAllocator`] formats addresses on the events it emits.
+///
+/// Each address field (e.g., `addr`) may be accompanied by a `_hex`-suffixed
+/// counterpart (e.g., `addr_hex`) whose value is the same address formatted as
+/// a `0x`-prefixed hexadecimal string, which is easier to correlate with
+/// debugger output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AddressFormat {
+    /// Emit only decimal address fields (e.g., `addr`). This is the default.
+    Decimal,
+    /// Emit only hexadecimal address fields (e.g., `addr_hex`).
+    Hex,
+    /// Emit both decimal and hexadecimal address fields.
+    Both,
+}
+
+/// Formats an address as a `0x`-prefixed hexadecimal number, without
+/// allocating.
+struct Hex(u64);
+
+impl fmt::Display for Hex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// Formats the fields contributed by an extra fields callback, without
+/// allocating.
+struct Extra(fn(&mut ExtraFields<'_, '_>));
+
+impl fmt::Display for Extra {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = ExtraFields {
+            f,
+            result: Ok(()),
+            empty: true,
+        };
+        (self.0)(&mut fields);
+        fields.result
+    }
+}
+
+/// The fields contributed to an event by an [extra fields
+/// callback][TracingAllocator::with_extra_fields].
+///
+/// Fields are formatted as space-separated `name=value` pairs, and recorded in
+/// the `extra` field of the event.
+pub struct ExtraFields<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+    result: fmt::Result,
+    empty: bool,
+}
+
+impl ExtraFields<'_, '_> {
+    /// Contributes a field with the given `name` and `value`.
+    pub fn field(&mut self, name: &str, value: impl fmt::Display) -> &mut Self {
+        if self.result.is_ok() {
+            let separator = if self.empty { "" } else { " " };
+            self.result = write!(self.f, "{}{}={}", separator, name, value);
+            self.empty = false;
+        }
+        self
+    }
+}
+
+impl AddressMode {
+    /// Maps the raw address `addr` according to this mode.
+    fn apply(self, addr: usize) -> u64 {
+        if addr == 0 {
+            return 0;
+        }
+        match self {
+            AddressMode::Raw => addr as u64,
+            AddressMode::Hashed { salt } => {
+                let mut hasher = DefaultHasher::new();
+                salt.hash(&mut hasher);
+                addr.hash(&mut hasher);
+                hasher.finish()
+            }
+            AddressMode::Opaque => {
+                static STATE: OnceLock<RandomState> = OnceLock::new();
+                STATE.get_or_init(RandomState::new).hash_one(addr)
+            }
+        }
+    }
+}
+
+impl<A, const LEVEL: u8> TracingAllocator<A, LEVEL> {
+    /// Whether this allocator counts operations for [`count_allocations`].
+    const COUNTED: bool = !cfg!(feature = "off");
+
+    /// Whether this allocator's instrumentation is compiled in.
+    const INSTRUMENTED: bool = Self::COUNTED && level::statically_enabled(LEVEL);
+
+    /// Constructs a tracing allocator.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+    ///
+    /// fn main() {
+    ///     let _guard = tracing_allocations::housekeeping();
+    ///     /* your code here */
+    /// }
+    /// ```
+    pub const fn new(allocator: A) -> Self {
+        Self {
+            allocator,
+            config: Config {
+                kinds: AtomicU8::new(u8::MAX),
+                wrapped: u8::MAX,
+                level: AtomicU8::new(level::TRACE),
+                large_alloc_threshold: AtomicUsize::new(usize::MAX),
+                min_size: AtomicUsize::new(0),
+                max_size: AtomicUsize::new(usize::MAX),
+                sample_rate: AtomicU64::new(1),
+                sample_interval: AtomicU64::new(0),
+                rate_limit: AtomicU64::new(0),
+                rate_limit_scope: RateLimitScope::Thread,
+                global_rate_limiter: GlobalRateLimiter::new(),
+                adaptive: AdaptiveSampler::new(),
+                #[cfg(feature = "backtrace")]
+                ignored_callers: &[],
+                #[cfg(feature = "backtrace")]
+                caller_filters: &[],
+                #[cfg(feature = "backtrace")]
+                callsite_stats: false,
+                #[cfg(feature = "backtrace")]
+                callsite_rollup: None,
+                address_mode: AddressMode::Raw,
+                address_format: AddressFormat::Decimal,
+                unify_zeroed: false,
+                clock: None,
+                extra: None,
+                span_ids: false,
+                live_table: false,
+                churn_window: None,
+                coalesce: false,
+                exact_peak: false,
+                interval_summaries: None,
+                size_classes: None,
+                #[cfg(feature = "tracing-subscriber")]
+                marked_spans_only: false,
+                usable_size: None,
+            },
+        }
+    }
+
+    /// Enable or disable events for operations of the given `kind`.
+    ///
+    /// All kinds are enabled by default. If [unified alloc
+    /// events][Self::with_unified_alloc_events] are enabled, allocations
+    /// produced by `alloc_zeroed` are of kind [`AllocationKind::Alloc`].
+    ///
+    /// See [`TracingAllocator::set_kind_enabled`] to change this setting at
+    /// runtime.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::{event::AllocationKind, TracingAllocator};
+    ///
+    /// // only trace reallocations
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System)
+    ///     .with_kind_enabled(AllocationKind::Alloc, false)
+    ///     .with_kind_enabled(AllocationKind::AllocZeroed, false)
+    ///     .with_kind_enabled(AllocationKind::Dealloc, false);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_kind_enabled(mut self, kind: AllocationKind, enabled: bool) -> Self {
+        let kinds = self.config.kinds.into_inner();
+        self.config.kinds = AtomicU8::new(if enabled {
+            kinds | kind.bit()
+        } else {
+            kinds & !kind.bit()
+        });
+        self
+    }
+
+    /// Enable or disable events for operations of the given `kind`, at
+    /// runtime.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::{event::AllocationKind, TracingAllocator};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+    ///
+    /// fn main() {
+    ///     let _guard = tracing_allocations::housekeeping();
+    ///     // suppress deallocation events
+    ///     ALLOCATOR.set_kind_enabled(AllocationKind::Dealloc, false);
+    /// }
+    /// ```
+    pub fn set_kind_enabled(&self, kind: AllocationKind, enabled: bool) {
+        if enabled {
+            self.config.kinds.fetch_or(kind.bit(), Ordering::Relaxed);
+        } else {
+            self.config.kinds.fetch_and(!kind.bit(), Ordering::Relaxed);
+        }
+    }
+
+    /// Whether events are enabled for operations of the given `kind`.
+    pub fn is_kind_enabled(&self, kind: AllocationKind) -> bool {
+        self.config.traces(kind)
+    }
+
+    /// Choose whether the [`GlobalAlloc`] method that performs operations of
+    /// the given `kind` is instrumented.
+    ///
+    /// All methods are instrumented by default. An uninstrumented method
+    /// forwards directly to the allocator this wraps, and does none of the
+    /// work of instrumentation (such as consulting filters), so it is cheaper
+    /// than a method whose kind is [disabled][Self::with_kind_enabled]. Unlike
+    /// disabled kinds, uninstrumented methods cannot be re-enabled at runtime.
+    ///
+    /// Reallocations performed by an uninstrumented `realloc` are invisible,
+    /// so a block may be allocated at one address and deallocated at another.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::{event::AllocationKind, TracingAllocator};
+    ///
+    /// // only instrument `alloc` and `dealloc`
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System)
+    ///     .with_method_wrapped(AllocationKind::AllocZeroed, false)
+    ///     .with_method_wrapped(AllocationKind::Realloc, false);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_method_wrapped(mut self, kind: AllocationKind, wrapped: bool) -> Self {
+        self.config.wrapped = if wrapped {
+            self.config.wrapped | kind.bit()
+        } else {
+            self.config.wrapped & !kind.bit()
+        };
+        self
+    }
+
+    /// Emit a [`WARN`]-level `large_alloc` event, in addition to the usual
+    /// [`TRACE`]-level event, for every allocation larger than `bytes`.
+    ///
+    /// The `large_alloc` event has the following metadata:
+    /// - **`target`**  
+    ///   "tracing::allocator"
+    /// - **`kind`: [`str`]**  
+    ///   the kind of operation that produced the allocation; i.e., "alloc",
+    ///   "alloc_zeroed", or "realloc"
+    /// - **`addr`: [`u64`]**  
+    ///   the address of the allocation
+    /// - **`addr_hex`: [`str`]**  
+    ///   the address of the allocation, in hexadecimal
+    /// - **`size`: [`u64`]**  
+    ///   the size of the allocation
+    /// - **`threshold`: [`u64`]**  
+    ///   the configured threshold
+    /// - **`timestamp_ns`: [`u64`]**  
+    ///   the time of the allocation; only present if a
+    ///   [clock][TracingAllocator::with_clock] is configured
+    /// - **`span_id`: [`u64`]**  
+    ///   the ID of the current span; only present if [span
+    ///   IDs][TracingAllocator::with_span_ids] are enabled
+    /// - **`sample_rate`: [`u64`]**  
+    ///   the rate at which events are sampled; only present if
+    ///   [sampling][TracingAllocator::with_sample_rate] is enabled
+    /// - **`sample_interval`: [`u64`]**  
+    ///   the mean number of bytes between samples; only present if [byte
+    ///   sampling][TracingAllocator::with_byte_sampling] is enabled
+    /// - **`count`: [`u64`]**  
+    ///   the number of identical operations this event stands for; only
+    ///   present if [coalescing][TracingAllocator::with_coalescing] is enabled
+    ///   and the operation was repeated
+    /// - **`tag`: [`str`]**  
+    ///   the tag of the enclosing [`tag_in_scope`]; only present within one
+    /// - **`extra`: [`str`]**  
+    ///   fields contributed by the [extra fields
+    ///   callback][TracingAllocator::with_extra_fields]; only present if one is
+    ///   configured
+    /// - **`backtrace`: [`str`]**  
+    ///   the backtrace of the operation; only present if
+    ///   [requested][Detail::Backtrace]
+    /// - **`caller`: [`str`]**  
+    ///   the code that requested the operation; only present if requested
+    ///   (with `Detail::Caller`)
+    ///
+    /// Whether `addr` and/or `addr_hex` are present depends on the configured
+    /// [`AddressFormat`]. Reallocations that grow a block beyond `bytes` also
+    /// emit this event.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_large_alloc_threshold(1 << 20);
+    /// # fn main() {}
+    /// ```
+    ///
+    /// [`TRACE`]: tracing::Level::TRACE
+    /// [`WARN`]: tracing::Level::WARN
+    pub const fn with_large_alloc_threshold(mut self, bytes: usize) -> Self {
+        self.config.large_alloc_threshold = AtomicUsize::new(bytes);
+        self
+    }
+
+    /// Set the [large allocation
+    /// threshold][TracingAllocator::with_large_alloc_threshold] at runtime, or
+    /// disable `large_alloc` events if `bytes` is `None`.
+    pub fn set_large_alloc_threshold(&self, bytes: Option<usize>) {
+        let bytes = bytes.unwrap_or(usize::MAX);
+        self.config
+            .large_alloc_threshold
+            .store(bytes, Ordering::Relaxed);
+    }
+
+    /// Emit no events for operations on blocks smaller than `bytes`.
+    ///
+    /// Reallocations emit events if either the existing or the new block is at
+    /// least `bytes` large. Filtering in the allocator is considerably cheaper
+    /// than filtering in a subscriber, because the event is never constructed.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_min_size(4096);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_min_size(mut self, bytes: usize) -> Self {
+        self.config.min_size = AtomicUsize::new(bytes);
+        self
+    }
+
+    /// Set the [minimum size][TracingAllocator::with_min_size] of traced
+    /// blocks at runtime.
+    ///
+    /// Setting this to [`usize::MAX`] effectively disables tracing, which
+    /// allows a process to start with tracing off and enable it while
+    /// investigating an incident.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_min_size(usize::MAX);
+    ///
+    /// fn main() {
+    ///     let _guard = tracing_allocations::housekeeping();
+    ///     /* later, while investigating: */
+    ///     ALLOCATOR.set_min_size(4096);
+    /// }
+    /// ```
+    pub fn set_min_size(&self, bytes: usize) {
+        self.config.min_size.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Emit events only for operations on blocks whose size lies within
+    /// `range`.
+    ///
+    /// Reallocations emit events if either the existing or the new block's size
+    /// lies within `range`. This supersedes any previously configured
+    /// [minimum size][TracingAllocator::with_min_size].
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// // only trace allocations of roughly 1 MiB
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_size_range(943_718..=1_153_433);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_size_range(mut self, range: RangeInclusive<usize>) -> Self {
+        self.config.min_size = AtomicUsize::new(*range.start());
+        self.config.max_size = AtomicUsize::new(*range.end());
+        self
+    }
+
+    /// Set the [size range][TracingAllocator::with_size_range] of traced
+    /// blocks at runtime.
+    ///
+    /// The bounds are updated independently, so an operation performed
+    /// concurrently with this call may be filtered by one old and one new
+    /// bound.
+    pub fn set_size_range(&self, range: RangeInclusive<usize>) {
+        self.config
+            .min_size
+            .store(*range.start(), Ordering::Relaxed);
+        self.config.max_size.store(*range.end(), Ordering::Relaxed);
+    }
+
+    /// Emit events for only one in every `n` operations on each thread.
+    ///
+    /// Sampled events carry a `sample_rate` field recording `n`, so that
+    /// totals can be statistically reconstructed by scaling each event by its
+    /// rate. Because deallocations are sampled independently of the
+    /// allocations they free, sampled events cannot be reliably paired.
+    ///
+    /// Sampling is applied after [size filtering][Self::with_size_range];
+    /// i.e., one in every `n` *admitted* operations emits events.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_sample_rate(100);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_sample_rate(mut self, n: u64) -> Self {
+        self.config.sample_rate = AtomicU64::new(n);
+        self
+    }
+
+    /// Set the [1-in-N sampling rate][TracingAllocator::with_sample_rate] at
+    /// runtime. A rate of `1` disables sampling.
+    pub fn set_sample_rate(&self, n: u64) {
+        self.config.sample_rate.store(n, Ordering::Relaxed);
+    }
+
+    /// Automatically sample events more sparsely while more than
+    /// `events_per_second` events are emitted, and relax again once the rate
+    /// of events drops.
+    ///
+    /// The rate of events is measured across the process over windows of 100
+    /// milliseconds. Whenever it exceeds `events_per_second`, the effective
+    /// [1-in-N sampling rate][TracingAllocator::with_sample_rate] is doubled;
+    /// whenever it falls below a quarter of `events_per_second`, the effective
+    /// rate is halved, down to the configured rate. The `sample_rate` field of
+    /// emitted events records the effective rate.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_adaptive_sampling(50_000);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_adaptive_sampling(mut self, events_per_second: u64) -> Self {
+        self.config.adaptive.threshold = AtomicU64::new(events_per_second);
+        self
+    }
+
+    /// Set the threshold of [adaptive
+    /// downsampling][TracingAllocator::with_adaptive_sampling] at runtime, or
+    /// disable adaptive downsampling if `events_per_second` is `None`.
+    pub fn set_adaptive_sampling(&self, events_per_second: Option<u64>) {
+        let events_per_second = events_per_second.unwrap_or(0);
+        self.config.adaptive.set_threshold(events_per_second);
+    }
+
+    /// Coalesce runs of identical operations on each thread into a single
+    /// event, with a `count` field recording the length of the run.
+    ///
+    /// Operations are identical if they are of the same kind and size (and,
+    /// with the `backtrace` feature, were requested by the same caller);
+    /// addresses are disregarded. Operations of other kinds do not interrupt a
+    /// run, so a loop that allocates and frees a buffer in each iteration is
+    /// reported as one `alloc` event and one `dealloc` event.
+    ///
+    /// A run's event is emitted, bearing the address, timestamp, tag, caller
+    /// and span of its first operation, once an operation of the same kind
+    /// ends it, when the thread exits, when the [`housekeeping`] guard is
+    /// dropped, or when [`flush_coalesced`] is called. The span that was
+    /// current at the first operation is not held open until then; if it
+    /// closes first, subscribers will not find the parent of the run's event.
+    /// Events are therefore delayed, and may be emitted out of order;
+    /// consumers that pair allocations with deallocations should not enable
+    /// coalescing.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_coalescing(true);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_coalescing(mut self, coalesce: bool) -> Self {
+        self.config.coalesce = coalesce;
+        self
+    }
+
+    /// Route events to a target according to the [`SizeClass`] of the block
+    /// they describe: blocks of at most `small` bytes are reported to
+    /// "alloc::small", blocks of at least `large` bytes to "alloc::large", and
+    /// all others to "alloc::medium". For reallocations, the new size decides.
+    ///
+    /// Combined with per-target filtering, this allows large allocations to
+    /// remain visible while the noise of small allocations is discarded. The
+    /// `large_alloc` event is not affected.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    /// use tracing_subscriber::EnvFilter;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_size_classes(256, 64 * 1024);
+    ///
+    /// fn main() {
+    ///     tracing_subscriber::fmt()
+    ///         .with_env_filter(EnvFilter::new("alloc::large=trace"))
+    ///         .init();
+    ///     let _guard = tracing_allocations::housekeeping();
+    ///     /* your code here */
+    /// }
+    /// ```
+    ///
+    /// ## Panics
+    /// Panics if `small` is not less than `large`; in the initializer of a
+    /// `static`, this is an error at compile time:
+    /// ```compile_fail
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_size_classes(64 * 1024, 256);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_size_classes(mut self, small: usize, large: usize) -> Self {
+        assert!(small < large, "`small` must be less than `large`");
+        self.config.size_classes = Some((small, large));
+        self
+    }
+
+    /// If `only`, emit events only for operations performed inside of spans
+    /// marked with a `trace_alloc = true` field, such as those created by
+    /// [`traced_span!`].
+    ///
+    /// This flips the model of [`disable_in_scope`] from opt-out to opt-in.
+    /// Operations inside of spans nested within a marked span also emit
+    /// events. Marked spans are recognized by the [`MarkedSpans`] layer, which
+    /// must be installed; otherwise, no events are emitted.
+    ///
+    /// Requires the `tracing-subscriber` feature.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_subscriber::prelude::*;
+    /// use tracing_allocations::{traced_span, MarkedSpans, TracingAllocator};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_marked_spans_only(true);
+    ///
+    /// fn main() {
+    ///     tracing_subscriber::registry()
+    ///         .with(MarkedSpans::new())
+    ///         .with(tracing_subscriber::fmt::layer())
+    ///         .init();
+    ///
+    ///     let _guard = tracing_allocations::housekeeping();
+    ///
+    ///     // untraced
+    ///     let _ = vec![0u8; 64];
+    ///
+    ///     traced_span!(tracing::Level::INFO, "investigation").in_scope(|| {
+    ///         // traced
+    ///         let _ = vec![0u8; 64];
+    ///     });
+    /// }
+    /// ```
+    #[cfg(feature = "tracing-subscriber")]
+    pub const fn with_marked_spans_only(mut self, only: bool) -> Self {
+        self.config.marked_spans_only = only;
+        self
+    }
+
+    /// Emit no events for operations requested by callers that match any of
+    /// the given `patterns`.
+    ///
+    /// The caller of an operation is the innermost frame of the stack outside
+    /// of the standard library and this crate. A pattern matches the caller if
+    /// the path of the calling function begins with the pattern (e.g.,
+    /// `"hashbrown"`, or `"my_crate::noisy"`), or the path of its source file
+    /// contains the pattern (e.g., `"src/noisy.rs"`).
+    ///
+    /// Finding the caller requires walking the stack, so this filter is
+    /// applied after all others. Resolved callers are cached, and are never
+    /// freed.
+    ///
+    /// Requires the `backtrace` feature.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_ignored_callers(&["hashbrown", "src/noisy.rs"]);
+    /// # fn main() {}
+    /// ```
+    #[cfg(feature = "backtrace")]
+    pub const fn with_ignored_callers(mut self, patterns: &'static [&'static str]) -> Self {
+        self.config.ignored_callers = patterns;
+        self
+    }
+
+    /// Emit events only for operations whose callers are selected by the
+    /// given `filters`; the allocator-side analogue of `EnvFilter`'s module
+    /// directives.
+    ///
+    /// Callers are identified, and patterns matched, as described for
+    /// [`with_ignored_callers`][TracingAllocator::with_ignored_callers]. The
+    /// filter with the longest pattern matching an operation's caller decides
+    /// whether it emits events; if an [`Allow`][CallerFilter::Allow] and a
+    /// [`Deny`][CallerFilter::Deny] filter are equally long, the operation
+    /// emits no events. If no filter matches, the operation emits events only
+    /// if there are no `Allow` filters. Operations whose callers cannot be
+    /// identified (e.g., for lack of debug info) match no filter.
+    ///
+    /// `#[track_caller]` cannot identify the callers of a global allocator,
+    /// which are invoked through compiler-generated shims; callers are instead
+    /// identified by walking the stack.
+    ///
+    /// Requires the `backtrace` feature.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::{CallerFilter, TracingAllocator};
+    ///
+    /// // trace allocations from `src/render/`, except its texture cache
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System)
+    ///     .with_caller_filters(&[
+    ///         CallerFilter::Allow("src/render/"),
+    ///         CallerFilter::Deny("src/render/textures.rs"),
+    ///     ]);
+    /// # fn main() {}
+    /// ```
+    #[cfg(feature = "backtrace")]
+    pub const fn with_caller_filters(mut self, filters: &'static [CallerFilter]) -> Self {
+        self.config.caller_filters = filters;
+        self
+    }
+
+    /// Total the allocations of each caller, for [`top_callsites`].
+    ///
+    /// Callers are identified as described for
+    /// [`with_ignored_callers`][TracingAllocator::with_ignored_callers]. Only
+    /// operations that emit events are totalled, so this is cheapest when
+    /// combined with [sampling][TracingAllocator::with_byte_sampling].
+    ///
+    /// Requires the `backtrace` feature.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System)
+    ///     .with_byte_sampling(512 * 1024)
+    ///     .with_callsite_stats(true);
+    /// # fn main() {}
+    /// ```
+    #[cfg(feature = "backtrace")]
+    pub const fn with_callsite_stats(mut self, enabled: bool) -> Self {
+        self.config.callsite_stats = enabled;
+        self
+    }
+
+    /// Roll up the operations of each caller, and emit their totals once per
+    /// `interval` in place of an event per operation; `None` (the default)
+    /// emits an event per operation.
+    ///
+    /// Callers are identified as described for
+    /// [`with_ignored_callers`][TracingAllocator::with_ignored_callers].
+    /// Operations that would otherwise emit events are instead totalled by
+    /// caller; at the end of each interval, the first operation to be totalled
+    /// emits a `callsite_rollup` event for each caller with operations in the
+    /// interval, at the configured [level][TracingAllocator::with_level].
+    /// This reduces the volume of events by orders of magnitude, while
+    /// preserving their attribution. Sampled operations are scaled to estimate
+    /// the operations they stand for. Each `callsite_rollup` event has the
+    /// following fields:
+    /// - **`symbol`: [`Option<&str>`]**  
+    ///   the demangled name of the calling function, if known
+    /// - **`file`: [`Option<&str>`]**  
+    ///   the source file of the call, if known
+    /// - **`line`: [`Option<u32>`]**  
+    ///   the source line of the call, if known
+    /// - **`allocations`: [`u64`]**  
+    ///   the number of allocations, zeroed or not, and reallocations
+    /// - **`bytes_allocated`: [`u64`]**  
+    ///   the total size of the blocks allocated, or reallocated
+    /// - **`deallocations`: [`u64`]**  
+    ///   the number of deallocations
+    /// - **`bytes_freed`: [`u64`]**  
+    ///   the total size of the blocks freed, or reallocated from
+    /// - **`interval_ns`: [`u64`]**  
+    ///   the length of the interval, in nanoseconds
+    ///
+    /// Requires the `backtrace` feature.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::{alloc::System, time::Duration};
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_callsite_rollup(Some(Duration::from_secs(10)));
+    /// # fn main() {}
+    /// ```
+    #[cfg(feature = "backtrace")]
+    pub const fn with_callsite_rollup(mut self, interval: Option<Duration>) -> Self {
+        self.config.callsite_rollup = interval;
+        self
+    }
+
+    /// Summarize the operations of each thread, and emit their totals once per
+    /// `interval` in place of an event per operation; `None` (the default)
+    /// emits an event per operation.
+    ///
+    /// This suits continuous, low-cost telemetry in production, rather than
+    /// detailed tracing. Operations that would otherwise emit events are
+    /// instead totalled on the thread that performs them; at the end of each
+    /// interval, the next such operation on the thread emits an
+    /// `interval_summary` event, at the configured
+    /// [level][TracingAllocator::with_level]. Threads that perform no
+    /// operations emit no summaries. Sampled operations are scaled to estimate
+    /// the operations they stand for. Each `interval_summary` event has the
+    /// following fields:
+    /// - **`allocations`: [`u64`]**  
+    ///   the number of allocations, zeroed or not, and reallocations
+    /// - **`bytes_allocated`: [`u64`]**  
+    ///   the total size of the blocks allocated, or reallocated
+    /// - **`bytes_freed`: [`u64`]**  
+    ///   the total size of the blocks freed, or reallocated from
+    /// - **`net_bytes`: [`i64`]**  
+    ///   `bytes_allocated`, less `bytes_freed`
+    /// - **`peak_bytes`: [`u64`]**  
+    ///   the greatest value that `net_bytes` reached over the interval
+    /// - **`interval_ns`: [`u64`]**  
+    ///   the length of the interval, in nanoseconds
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::{alloc::System, time::Duration};
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_interval_summaries(Some(Duration::from_millis(500)));
+    /// # fn main() {}
+    /// ```
+    pub const fn with_interval_summaries(mut self, interval: Option<Duration>) -> Self {
+        self.config.interval_summaries = interval;
+        self
+    }
+
+    /// Emit events for, on average, one operation per `interval` bytes
+    /// operated upon by each thread.
+    ///
+    /// This is the sampling strategy of heap profilers such as tcmalloc and
+    /// jemalloc: the number of bytes between consecutive samples is drawn from
+    /// an exponential distribution with mean `interval`, so the probability
+    /// that an operation on `size` bytes is sampled is
+    /// `1 - exp(-size / interval)`. Unlike [1-in-N
+    /// sampling][Self::with_sample_rate], this is not biased towards small
+    /// allocations. Sampled events carry a `sample_interval` field recording
+    /// `interval`; see [`AllocationEvent::sample_interval`] for how to
+    /// reconstruct totals.
+    ///
+    /// Reallocations are weighted by the size of the new block.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// // sample, on average, every 512 KiB
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_byte_sampling(512 * 1024);
+    /// # fn main() {}
+    /// ```
+    ///
+    /// [`AllocationEvent::sample_interval`]: event::AllocationEvent::sample_interval
+    pub const fn with_byte_sampling(mut self, interval: u64) -> Self {
+        self.config.sample_interval = AtomicU64::new(interval);
+        self
+    }
+
+    /// Set the [byte-weighted sampling
+    /// interval][TracingAllocator::with_byte_sampling] at runtime, or disable
+    /// byte-weighted sampling if `interval` is `None`.
+    ///
+    /// Threads adopt a new interval after taking their next sample.
+    pub fn set_byte_sampling(&self, interval: Option<u64>) {
+        let interval = interval.unwrap_or(0);
+        self.config
+            .sample_interval
+            .store(interval, Ordering::Relaxed);
+    }
+
+    /// Emit at most `per_second` events per second, either on each thread or
+    /// across the process, as determined by `scope`.
+    ///
+    /// Events are admitted by a token bucket that holds up to one second's
+    /// worth of events, so short bursts are emitted in full. Operations that
+    /// exceed the limit emit no events (including `large_alloc` events); at
+    /// most once per second, the number of such operations is reported by a
+    /// [`WARN`]-level `events_dropped` event with the following fields:
+    /// - **`dropped`: [`u64`]**  
+    ///   the number of operations that emitted no events since the last report
+    /// - **`rate_limit`: [`u64`]**  
+    ///   the configured limit, in events per second
+    ///
+    /// The rate limit is applied after
+    /// [sampling][TracingAllocator::with_sample_rate].
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::{RateLimitScope, TracingAllocator};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_rate_limit(10_000, RateLimitScope::Global);
+    /// # fn main() {}
+    /// ```
+    ///
+    /// [`WARN`]: tracing::Level::WARN
+    pub const fn with_rate_limit(mut self, per_second: u64, scope: RateLimitScope) -> Self {
+        self.config.rate_limit = AtomicU64::new(per_second);
+        self.config.rate_limit_scope = scope;
+        self
+    }
+
+    /// Set the [rate limit][TracingAllocator::with_rate_limit] at runtime, or
+    /// disable rate limiting if `per_second` is `None`.
+    ///
+    /// The limit's scope cannot be changed at runtime.
+    pub fn set_rate_limit(&self, per_second: Option<u64>) {
+        let per_second = per_second.unwrap_or(0);
+        self.config.rate_limit.store(per_second, Ordering::Relaxed);
+    }
+
+    /// Emit events for allocator operations at the given `level`, rather than
+    /// [`TRACE`].
+    ///
+    /// This does not affect the level of `large_alloc` events, which are
+    /// always [`WARN`]. If `level` is less verbose than this allocator's
+    /// [`LEVEL`][TracingAllocator#static-level-filtering] parameter, events
+    /// are emitted at `LEVEL` instead.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing::Level;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_level(Level::DEBUG);
+    /// # fn main() {}
+    /// ```
+    ///
+    /// [`TRACE`]: tracing::Level::TRACE
+    /// [`WARN`]: tracing::Level::WARN
+    pub const fn with_level(mut self, level: Level) -> Self {
+        self.config.level = AtomicU8::new(Self::clamp(level));
+        self
+    }
+
+    /// Set the [level][TracingAllocator::with_level] of emitted events at
+    /// runtime.
+    pub fn set_level(&self, level: Level) {
+        self.config
+            .level
+            .store(Self::clamp(level), Ordering::Relaxed);
+    }
+
+    /// Encodes `level`, or `LEVEL` if `level` is less verbose.
+    const fn clamp(level: Level) -> u8 {
+        let level = level::encode(level);
+        if level > LEVEL {
+            LEVEL
+        } else {
+            level
+        }
+    }
+
+    /// Set how addresses are reported on emitted events. See [`AddressMode`]
+    /// for more information.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::{AddressMode, TracingAllocator};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_address_mode(AddressMode::Opaque);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_address_mode(mut self, mode: AddressMode) -> Self {
+        self.config.address_mode = mode;
+        self
+    }
+
+    /// Set how addresses are formatted on emitted events. See
+    /// [`AddressFormat`] for more information.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::{AddressFormat, TracingAllocator};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_address_format(AddressFormat::Both);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_address_format(mut self, format: AddressFormat) -> Self {
+        self.config.address_format = format;
+        self
+    }
+
+    /// If `unified`, [`alloc_zeroed`][TracingAllocator::alloc_zeroed] emits
+    /// the same "alloc" event as [`alloc`][TracingAllocator::alloc], and both
+    /// carry a `zeroed` field indicating which method produced the allocation.
+    ///
+    /// This lets downstream accounting treat all allocations with a single
+    /// code path.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_unified_alloc_events(true);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_unified_alloc_events(mut self, unified: bool) -> Self {
+        self.config.unify_zeroed = unified;
+        self
+    }
+
+    /// Timestamp every emitted event with a `timestamp_ns` field, using the
+    /// given `clock`.
+    ///
+    /// The timestamp is taken on the allocating thread immediately after the
+    /// underlying allocator returns, so it is considerably more precise than
+    /// timestamps assigned by a subscriber. The clock is expected to be
+    /// monotonic and to report nanoseconds; [`monotonic_nanos`] is a suitable
+    /// default. Tests may substitute a deterministic clock.
+    ///
+    /// The clock is invoked with allocation tracing disabled, so it may
+    /// allocate, but should be cheap.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::{monotonic_nanos, TracingAllocator};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_clock(monotonic_nanos);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        self.config.clock = Some(clock);
+        self
+    }
+
+    /// If `enabled`, every emitted event records the numeric ID of the
+    /// [current span][tracing::Span::current] in a `span_id` field.
+    ///
+    /// This allows consumers that do not reconstruct span context (e.g.,
+    /// offline tools reading serialized events) to attribute allocations to
+    /// spans after the fact. Events emitted outside of any span omit the field.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_span_ids(true);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_span_ids(mut self, enabled: bool) -> Self {
+        self.config.span_ids = enabled;
+        self
+    }
+
+    /// Record each traced allocation in a table of live blocks, along with
+    /// when it was performed, by which caller, and under which
+    /// [tag](tag_in_scope), until the block is freed. The table may be read
+    /// with [`live_blocks`].
+    ///
+    /// This reveals the lifetime of each block, which is reported by the
+    /// `age_ns` and `age_events` fields of `dealloc` events. The table is
+    /// sharded, so threads seldom contend for it, but it keeps an entry for
+    /// each live block; and, with the `backtrace` feature, each allocation
+    /// walks the stack to identify its caller. Blocks that are freed by a
+    /// thread on which tracing is [permanently disabled](disable_for_thread)
+    /// remain in the table until their addresses are reused.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_live_table(true);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_live_table(mut self, enabled: bool) -> Self {
+        self.config.live_table = enabled;
+        self
+    }
+
+    /// Track the process-wide peak of live bytes, reported by [`peak_bytes`],
+    /// exactly.
+    ///
+    /// By default, the live bytes are divided into stripes, which are summed
+    /// when read, and the peak is sampled once for every 64 KiB that each
+    /// thread allocates, so that threads allocating concurrently seldom
+    /// contend. With exact tracking, every operation updates one counter
+    /// shared by all threads, which scales poorly on many cores.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::TracingAllocator;
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_exact_peak(true);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_exact_peak(mut self, exact: bool) -> Self {
+        self.config.exact_peak = exact;
+        self
+    }
+
+    /// Detect churn: count, for each caller, the traced allocations that are
+    /// freed within `window`, for [`churn_hotspots`].
+    ///
+    /// This enables the [live table][TracingAllocator::with_live_table].
+    /// Callers are identified as described for `with_ignored_callers`, which
+    /// requires the `backtrace` feature; without it, all allocations are
+    /// attributed to a single caller of unknown location.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::{alloc::System, time::Duration};
+    /// use tracing_allocations::{ChurnWindow, TracingAllocator};
+    ///
+    /// // blocks freed within a millisecond are short-lived
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System)
+    ///     .with_churn_window(ChurnWindow::Time(Duration::from_millis(1)));
+    /// # fn main() {}
+    /// ```
+    pub const fn with_churn_window(mut self, window: ChurnWindow) -> Self {
+        self.config.live_table = true;
+        self.config.churn_window = Some(window);
+        self
+    }
+
+    /// Contribute extra fields to every emitted event, using the given
+    /// `callback`; e.g., the ID of the current request, or the current frame
+    /// number, read from a thread-local.
+    ///
+    /// The fields are recorded as an `extra` field, formatted as
+    /// space-separated `name=value` pairs. The callback is only invoked if a
+    /// subscriber records the field; it runs with tracing disabled, so it may
+    /// allocate, but it should be cheap. If [coalescing][Self::with_coalescing]
+    /// is enabled, it runs when the event is emitted, rather than when the
+    /// operation is performed.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::{alloc::System, cell::Cell};
+    /// use tracing_allocations::{ExtraFields, TracingAllocator};
+    ///
+    /// thread_local! {
+    ///     static FRAME: Cell<u64> = const { Cell::new(0) };
+    /// }
+    ///
+    /// fn frame(fields: &mut ExtraFields<'_, '_>) {
+    ///     fields.field("frame", FRAME.with(Cell::get));
+    /// }
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_extra_fields(frame);
+    /// # fn main() {}
+    /// ```
+    pub const fn with_extra_fields(mut self, callback: fn(&mut ExtraFields<'_, '_>)) -> Self {
+        self.config.extra = Some(callback);
+        self
+    }
 }
 
-impl<A> TracingAllocator<A> {
-    /// Constructs a tracing allocator.
+impl<A: UsableSize, const LEVEL: u8> TracingAllocator<A, LEVEL> {
+    /// Record the [usable size][UsableSize] of allocated blocks in a
+    /// `usable_size` field (or, for reallocations, `new_usable_size`).
+    ///
+    /// The usable size may exceed the requested size; comparing the two
+    /// measures internal fragmentation.
     ///
     /// ## Usage
     /// ```
@@ -49,18 +2347,110 @@ impl<A> TracingAllocator<A> {
     /// use tracing_allocations::TracingAllocator;
     ///
     /// #[global_allocator]
-    /// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
-    ///
-    /// fn main() {
-    ///     let _guard = tracing_allocations::housekeeping();
-    ///     /* your code here */
-    /// }
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_usable_size();
+    /// # fn main() {}
     /// ```
-    pub const fn new(allocator: A) -> Self {
-        Self { allocator }
+    pub const fn with_usable_size(mut self) -> Self {
+        self.config.usable_size = Some(A::usable_size);
+        self
+    }
+}
+
+/// Allocators that can report the number of bytes actually reserved for an
+/// allocated block.
+///
+/// Allocators commonly round requests up to a size class; the *usable size* of
+/// a block is the size it was rounded to. This trait corresponds to, e.g.,
+/// `malloc_usable_size`, jemalloc's `sallocx`, or mimalloc's `mi_usable_size`.
+///
+/// This trait is implemented for [`System`][std::alloc::System]. Where the
+/// platform's allocator offers no such introspection, that implementation
+/// reports the requested size.
+pub trait UsableSize {
+    /// The usable size of the block at `ptr`, which was allocated with
+    /// `layout`.
+    ///
+    /// This function is invoked with allocation tracing disabled. It must not
+    /// panic.
+    ///
+    /// ## Safety
+    /// `ptr` must denote a block currently allocated by this allocator with
+    /// `layout`.
+    unsafe fn usable_size(ptr: *mut u8, layout: Layout) -> usize;
+}
+
+impl UsableSize for std::alloc::System {
+    #[allow(unused_variables)]
+    unsafe fn usable_size(ptr: *mut u8, layout: Layout) -> usize {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            extern "C" {
+                fn malloc_usable_size(ptr: *mut core::ffi::c_void) -> usize;
+            }
+            malloc_usable_size(ptr.cast())
+        }
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        {
+            extern "C" {
+                fn malloc_size(ptr: *const core::ffi::c_void) -> usize;
+            }
+            malloc_size(ptr.cast())
+        }
+        #[cfg(not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "ios"
+        )))]
+        {
+            layout.size()
+        }
     }
 }
 
+/// Nanoseconds elapsed since this function was first called, according to a
+/// monotonic clock.
+///
+/// This is the default clock for [`TracingAllocator::with_clock`].
+pub fn monotonic_nanos() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+/// Declare a [`TracingAllocator`] wrapping the given allocator as the global
+/// allocator.
+///
+/// `install!(System)` declares a static named `ALLOCATOR`; `install!(NAME =
+/// System)` declares one named `NAME`, so that it may be configured at
+/// runtime. [`housekeeping`] must still be called at the start of `main`; the
+/// `#[tracing_allocations::main]` attribute (which requires the `macros`
+/// feature) does both.
+///
+/// ## Usage
+/// ```
+/// use std::alloc::System;
+///
+/// tracing_allocations::install!(System);
+///
+/// fn main() {
+///     let _guard = tracing_allocations::housekeeping();
+///     ALLOCATOR.set_level(tracing::Level::DEBUG);
+///     /* your code here */
+/// }
+/// ```
+#[macro_export]
+macro_rules! install {
+    ($name:ident = $allocator:path) => {
+        #[global_allocator]
+        static $name: $crate::TracingAllocator<$allocator> =
+            $crate::TracingAllocator::new($allocator);
+    };
+    ($allocator:path) => {
+        $crate::install!(ALLOCATOR = $allocator);
+    };
+}
+
 /// **Call this function at the start of `main`.**
 ///
 /// This routine performs housekeeping tasks that help you avoid deadlocking
@@ -88,101 +2478,756 @@ impl<A> TracingAllocator<A> {
 /// potential source of deadlocks: If the initilization of `Stdout` occurs
 /// *after* allocation tracing is enabled, and the tracing subscriber
 /// consequently attempts prints to stdout, that attempt to output will
-/// deadlock.
+/// deadlock. For the same reason, it also invokes [`std::io::stderr()`], and
+/// initializes the [handle][std::thread::current] of the current thread, which
+/// subscribers consult for its name.
 ///
 /// If you are aware of other types in the standard library that pose similar
 /// risks, please [file an issue][issue-tracker]. If your application uses types
 /// outside the standard library that pose such an issue, you can safely
-/// initialize them with [`disable_in_scope`].
+/// initialize them with [`disable_in_scope`], or list them with
+/// [`housekeeping::builder`].
 ///
-/// When dropped, the guard produced by this function disables allocation on the
-/// current thread for the remainder of the program's execution. This avoids a
-/// potential panic that can occur *after* `main` (see [rust-lang/rust#95126]).
+/// When dropped, the guard produced by this function [disables allocation
+/// tracing][disable_for_thread] on the current thread for the remainder of the
+/// program's execution. This avoids a potential panic that can occur *after*
+/// `main` (see [rust-lang/rust#95126]).
+/// To also print a summary of the program's allocations when the guard is
+/// dropped, see [`housekeeping::Builder::report_on_exit`].
 ///
 /// [issue-tracker]: https://github.com/jswrenn/tracing-allocations
 /// [rust-lang/rust#95126]: https://github.com/rust-lang/rust/issues/95126
 #[must_use]
 pub fn housekeeping() -> impl Drop {
-    use core::marker::PhantomData;
+    housekeeping::builder().finish()
+}
 
-    struct Guard(PhantomData<*mut ()>);
+/// Flag controlling whether to emit tracing events for allocation-related
+/// routines on any thread.
+static GLOBALLY_ENABLED: AtomicBool = AtomicBool::new(true);
 
-    impl Drop for Guard {
-        fn drop(&mut self) {
-            // disable tracing so `std::io::cleanup()` doesn't panic
-            maybe_with_guard(|mut trace| *trace = false);
-        }
-    }
+/// Flag controlling whether to suspend allocation tracing on threads that are
+/// panicking. See [`housekeeping::Builder::suspend_during_panics`].
+static SUSPEND_DURING_PANICS: AtomicBool = AtomicBool::new(false);
+
+/// Whether allocation tracing is suspended because the current thread is
+/// panicking.
+fn suspended_for_panic() -> bool {
+    SUSPEND_DURING_PANICS.load(Ordering::Relaxed) && std::thread::panicking()
+}
+
+/// Disable allocation tracing on all threads, until [`enable_globally`] is
+/// called.
+///
+/// This takes precedence over thread-local settings, like
+/// [`disable_in_scope`]. It is cheap and async-signal-safe, so it may be
+/// called from a signal handler.
+pub fn disable_globally() {
+    GLOBALLY_ENABLED.store(false, Ordering::Relaxed);
+}
 
-    disable_in_scope(|| {
-        let _ = std::io::stdout();
-        Guard(PhantomData)
-    })
+/// Re-enable allocation tracing on all threads, after a call to
+/// [`disable_globally`].
+///
+/// Tracing is globally enabled by default. This does not override
+/// thread-local settings; e.g., tracing remains disabled within
+/// [`disable_in_scope`].
+pub fn enable_globally() {
+    GLOBALLY_ENABLED.store(true, Ordering::Relaxed);
 }
 
 thread_local! {
     /// Flag controlling whether to emit tracing events for allocation-related
     /// routines on this thread.
-    static TRACE_ALLOCATOR: RefCell<bool> = RefCell::new(true);
+    static TRACE_ALLOCATOR: RefCell<bool> = const { RefCell::new(true) };
+
+    /// Flag recording whether allocation tracing has been permanently disabled
+    /// on this thread.
+    static DISABLED_FOR_THREAD: Cell<bool> = const { Cell::new(false) };
+
+    /// The number of operations considered for 1-in-N sampling on this thread.
+    static SAMPLE_COUNTER: Cell<u64> = const { Cell::new(0) };
+
+    /// The state of byte-weighted sampling on this thread.
+    static BYTE_SAMPLER: Cell<ByteSampler> = const { Cell::new(ByteSampler::new()) };
+
+    /// The current run of identical events of each kind on this thread.
+    static RUNS: RefCell<Runs> = const { RefCell::new(Runs([None, None, None, None])) };
+
+    /// The state of the per-thread rate limit on this thread.
+    static RATE_LIMITER: Cell<RateLimiter> = const { Cell::new(RateLimiter::new()) };
+}
+
+/// Per-thread state for byte-weighted sampling.
+#[derive(Clone, Copy)]
+struct ByteSampler {
+    /// The number of bytes remaining until the next sample.
+    remaining: u64,
+    /// The state of a xorshift random number generator; zero until seeded.
+    rng: u64,
+}
+
+impl ByteSampler {
+    const fn new() -> Self {
+        Self {
+            remaining: 0,
+            rng: 0,
+        }
+    }
+
+    /// Whether an operation on `size` bytes is sampled, given a mean sampling
+    /// `interval`.
+    fn sample(&mut self, size: u64, interval: u64) -> bool {
+        if self.rng == 0 {
+            self.rng = RandomState::new().hash_one(0u8) | 1;
+            self.remaining = self.next_interval(interval);
+        }
+        if size < self.remaining {
+            self.remaining -= size;
+            false
+        } else {
+            self.remaining = self.next_interval(interval);
+            true
+        }
+    }
+
+    /// Draws the number of bytes until the next sample from an exponential
+    /// distribution with the given mean.
+    fn next_interval(&mut self, mean: u64) -> u64 {
+        // xorshift64*
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let bits = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        // uniformly distributed in (0, 1]
+        let uniform = (bits + 1) as f64 / (1u64 << 53) as f64;
+        ((-uniform.ln() * mean as f64) as u64).max(1)
+    }
+}
+
+/// The current run of identical events of each kind on a thread, which are
+/// emitted when the thread exits.
+struct Runs([Option<Run>; 4]);
+
+impl Runs {
+    /// Emits the event standing for each run.
+    fn flush(&mut self) {
+        let runs = core::mem::take(&mut self.0);
+        as_instrumentation(|| runs.into_iter().flatten().for_each(Run::finish));
+    }
+}
+
+impl Drop for Runs {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// A run of identical events, of which only the first has been retained.
+struct Run {
+    event: AllocationEvent,
+    key: RunKey,
+    count: u64,
+    /// How, and in what context, the first event of the run is emitted.
+    emission: Emission,
+}
+
+impl Run {
+    fn new(event: AllocationEvent, key: RunKey, emission: Emission) -> Self {
+        Self {
+            event,
+            key,
+            count: 1,
+            emission,
+        }
+    }
+
+    /// Emits the event standing for this run.
+    fn finish(self) {
+        let mut event = self.event;
+        event.count = (self.count > 1).then_some(self.count);
+        self.emission.dispatch(&event);
+    }
+}
+
+/// Emits the events standing for the runs of identical operations that
+/// [coalescing](TracingAllocator::with_coalescing) has retained on the current
+/// thread.
+///
+/// Runs are otherwise emitted once an operation of the same kind ends them, or
+/// when the thread exits; call this before inspecting what subscribers have
+/// recorded. The [`housekeeping`] guard calls it when dropped.
+pub fn flush_coalesced() {
+    let _ = RUNS.try_with(|runs| {
+        if let Ok(mut runs) = runs.try_borrow_mut() {
+            runs.flush();
+        }
+    });
+}
+
+/// The properties that identical events of the same kind share.
+#[derive(PartialEq, Eq)]
+struct RunKey {
+    size: u64,
+    old_size: Option<u64>,
+    zeroed: Option<bool>,
+    #[cfg(feature = "backtrace")]
+    caller: Option<&'static callsite::Callsite>,
+}
+
+impl RunKey {
+    fn of(event: &AllocationEvent) -> Self {
+        Self {
+            size: event.size,
+            old_size: event.old_size,
+            zeroed: event.zeroed,
+            #[cfg(feature = "backtrace")]
+            caller: callsite::caller(),
+        }
+    }
+}
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// A per-thread token bucket, implemented as a generic cell rate algorithm.
+#[derive(Clone, Copy)]
+struct RateLimiter {
+    /// The theoretical arrival time of the next event.
+    tat: u64,
+    /// The number of operations dropped since the last report.
+    dropped: u64,
+    /// The time of the last report of dropped operations.
+    last_report: u64,
+}
+
+impl RateLimiter {
+    const fn new() -> Self {
+        Self {
+            tat: 0,
+            dropped: 0,
+            last_report: 0,
+        }
+    }
+
+    /// Whether an operation at `now` is admitted, given a mean `interval`
+    /// between events; and the number of dropped operations to report, if a
+    /// report is due.
+    fn admit(&mut self, now: u64, interval: u64) -> (bool, u64) {
+        let Some(tat) = next_tat(self.tat, now, interval) else {
+            self.dropped += 1;
+            return (false, 0);
+        };
+        self.tat = tat;
+        if self.dropped > 0 && now.saturating_sub(self.last_report) >= NANOS_PER_SEC {
+            self.last_report = now;
+            return (true, core::mem::take(&mut self.dropped));
+        }
+        (true, 0)
+    }
+}
+
+/// A process-wide token bucket, implemented as a generic cell rate algorithm.
+struct GlobalRateLimiter {
+    /// The theoretical arrival time of the next event.
+    tat: AtomicU64,
+    /// The number of operations dropped since the last report.
+    dropped: AtomicU64,
+    /// The time of the last report of dropped operations.
+    last_report: AtomicU64,
+}
+
+impl GlobalRateLimiter {
+    const fn new() -> Self {
+        Self {
+            tat: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            last_report: AtomicU64::new(0),
+        }
+    }
+
+    /// Like [`RateLimiter::admit`].
+    fn admit(&self, now: u64, interval: u64) -> (bool, u64) {
+        let admitted = self
+            .tat
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tat| {
+                next_tat(tat, now, interval)
+            })
+            .is_ok();
+        if !admitted {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return (false, 0);
+        }
+        let last_report = self.last_report.load(Ordering::Relaxed);
+        if self.dropped.load(Ordering::Relaxed) > 0
+            && now.saturating_sub(last_report) >= NANOS_PER_SEC
+            && self
+                .last_report
+                .compare_exchange(last_report, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            return (true, self.dropped.swap(0, Ordering::Relaxed));
+        }
+        (true, 0)
+    }
+}
+
+/// The length of the windows over which adaptive downsampling measures the
+/// rate of events.
+const ADAPTIVE_WINDOW_NANOS: u64 = NANOS_PER_SEC / 10;
+
+/// The largest factor by which adaptive downsampling multiplies the sampling
+/// rate.
+const MAX_ADAPTIVE_FACTOR: u64 = 1 << 20;
+
+/// The state of adaptive downsampling.
+struct AdaptiveSampler {
+    /// The number of events per second above which sampling is made sparser;
+    /// or zero, if adaptive downsampling is disabled.
+    threshold: AtomicU64,
+    /// The factor by which the configured sampling rate is multiplied.
+    factor: AtomicU64,
+    /// The time at which the current window began.
+    window_start: AtomicU64,
+    /// The number of events emitted in the current window.
+    window_events: AtomicU64,
+}
+
+impl AdaptiveSampler {
+    const fn new() -> Self {
+        Self {
+            threshold: AtomicU64::new(0),
+            factor: AtomicU64::new(1),
+            window_start: AtomicU64::new(0),
+            window_events: AtomicU64::new(0),
+        }
+    }
+
+    /// The factor by which the configured sampling rate is multiplied.
+    fn factor(&self) -> u64 {
+        self.factor.load(Ordering::Relaxed)
+    }
+
+    fn set_threshold(&self, threshold: u64) {
+        self.threshold.store(threshold, Ordering::Relaxed);
+        if threshold == 0 {
+            self.factor.store(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the emission of an event, and adjusts the factor at the end of
+    /// each window.
+    fn record(&self) {
+        let threshold = self.threshold.load(Ordering::Relaxed);
+        if threshold == 0 {
+            return;
+        }
+        let events = self.window_events.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = monotonic_nanos();
+        let window_start = self.window_start.load(Ordering::Relaxed);
+        let elapsed = now.saturating_sub(window_start);
+        if elapsed < ADAPTIVE_WINDOW_NANOS
+            || self
+                .window_start
+                .compare_exchange(window_start, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        self.window_events.store(0, Ordering::Relaxed);
+        let rate = (events as u128 * NANOS_PER_SEC as u128 / elapsed as u128) as u64;
+        let factor = self.factor();
+        if rate > threshold {
+            let factor = factor.saturating_mul(2).min(MAX_ADAPTIVE_FACTOR);
+            self.factor.store(factor, Ordering::Relaxed);
+        } else if rate < threshold / 4 && factor > 1 {
+            self.factor.store(factor / 2, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The theoretical arrival time following an event admitted at `now`, or
+/// `None` if an event at `now` would exceed the burst of one second's worth
+/// of events.
+fn next_tat(tat: u64, now: u64, interval: u64) -> Option<u64> {
+    let tat = tat.max(now);
+    (tat - now <= NANOS_PER_SEC.saturating_sub(interval)).then_some(tat + interval)
+}
+
+/// Constructs a span marked with a `trace_alloc = true` field, inside of which
+/// operations emit events even if the allocator is configured to [trace
+/// marked spans only][TracingAllocator::with_marked_spans_only].
+///
+/// Accepts the same arguments as [`tracing::span!`], except for `parent:`.
+///
+/// ## Usage
+/// ```
+/// use tracing::Level;
+/// use tracing_allocations::traced_span;
+///
+/// let span = traced_span!(Level::INFO, "render", frame = 7);
+/// let _guard = span.enter();
+/// ```
+#[macro_export]
+macro_rules! traced_span {
+    (target: $target:expr, $level:expr, $name:expr $(, $($fields:tt)*)?) => {
+        $crate::__tracing::span!(target: $target, $level, $name, trace_alloc = true $(, $($fields)*)?)
+    };
+    ($level:expr, $name:expr $(, $($fields:tt)*)?) => {
+        $crate::__tracing::span!($level, $name, trace_alloc = true $(, $($fields)*)?)
+    };
 }
 
 /// Run the given function with allocation tracing disabled on the current
 /// thread.
+///
+/// This does not survive an `.await`, after which a task may resume on another
+/// thread; use [`future::AllocationTracingExt::untraced`] instead.
 pub fn disable_in_scope<F, R>(f: F) -> R
 where
-    F: FnOnce() -> R
+    F: FnOnce() -> R,
+{
+    let _guard = disabled();
+    f()
+}
+
+/// Disable allocation tracing on the current thread, until the returned guard
+/// is dropped.
+///
+/// This is the RAII counterpart of [`disable_in_scope`], in the manner of
+/// [`tracing::Span::enter`]; it is convenient in code with early returns. When
+/// the guard is dropped, the previous state of the current thread is restored.
+///
+/// ## Usage
+/// ```
+/// fn load(path: &str) -> std::io::Result<String> {
+///     let _guard = tracing_allocations::disabled();
+///     let contents = std::fs::read_to_string(path)?;
+///     Ok(contents.to_uppercase())
+/// }
+/// ```
+pub fn disabled() -> DisableGuard {
+    DisableGuard {
+        prev: replace_enabled(false),
+        _not_send: PhantomData,
+    }
+}
+
+/// A guard that disables allocation tracing on the current thread until it is
+/// dropped. See [`disabled`].
+#[must_use = "tracing is re-enabled as soon as the guard is dropped"]
+pub struct DisableGuard {
+    /// Whether tracing was enabled before this guard was created.
+    prev: bool,
+    /// The guard restores the state of the thread it was created on.
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl Drop for DisableGuard {
+    fn drop(&mut self) {
+        replace_enabled(self.prev);
+    }
+}
+
+impl fmt::Debug for DisableGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DisableGuard").finish_non_exhaustive()
+    }
+}
+
+/// Run the given function with allocation tracing enabled on the current
+/// thread, even within a broader region in which it is disabled.
+///
+/// Allocations performed by the instrumentation itself (e.g., by subscribers
+/// handling allocation events) are never traced, regardless.
+pub fn enable_in_scope<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
 {
-    let prev = TRACE_ALLOCATOR.try_with(|guard| {
-        guard.replace(false)
-    }).unwrap_or(false);
+    let _guard = enabled();
+    f()
+}
+
+/// Enable allocation tracing on the current thread, until the returned guard
+/// is dropped.
+///
+/// This is the RAII counterpart of [`enable_in_scope`]. When the guard is
+/// dropped, the previous state of the current thread is restored.
+///
+/// ## Usage
+/// ```
+/// tracing_allocations::disable_in_scope(|| {
+///     /* untraced */
+///     let _guard = tracing_allocations::enabled();
+///     /* traced */
+/// });
+/// ```
+pub fn enabled() -> EnableGuard {
+    EnableGuard {
+        prev: replace_enabled(true),
+        _not_send: PhantomData,
+    }
+}
+
+/// A guard that enables allocation tracing on the current thread until it is
+/// dropped. See [`enabled`].
+#[must_use = "tracing is restored to its previous state as soon as the guard is dropped"]
+pub struct EnableGuard {
+    /// Whether tracing was enabled before this guard was created.
+    prev: bool,
+    /// The guard restores the state of the thread it was created on.
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl Drop for EnableGuard {
+    fn drop(&mut self) {
+        replace_enabled(self.prev);
+    }
+}
+
+impl fmt::Debug for EnableGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EnableGuard").finish_non_exhaustive()
+    }
+}
+
+/// Permanently disable allocation tracing on the current thread.
+///
+/// Unlike [`disable_in_scope`] and [`disabled`], this cannot be undone; not
+/// even by [`enable_in_scope`]. It is useful for background threads, owned by
+/// third-party libraries, whose allocations are noise: call it from a thread
+/// start hook, such as tokio's `on_thread_start`.
+///
+/// The guard returned by [`housekeeping`] calls this when it is dropped.
+///
+/// ## Usage
+/// ```
+/// std::thread::spawn(|| {
+///     tracing_allocations::disable_for_thread();
+///     assert!(!tracing_allocations::is_enabled());
+/// })
+/// .join()
+/// .unwrap();
+/// ```
+pub fn disable_for_thread() {
+    let _ = DISABLED_FOR_THREAD.try_with(|disabled| disabled.set(true));
+}
+
+/// Whether allocation tracing has been permanently disabled on the current
+/// thread, or its thread-local storage has been destroyed.
+fn disabled_for_thread() -> bool {
+    DISABLED_FOR_THREAD.try_with(Cell::get).unwrap_or(true)
+}
 
-    let res = f();
+/// Whether allocation tracing is currently enabled on the current thread.
+///
+/// This is cheap, so library code may use it to skip work that only matters
+/// to traces (e.g., building expensive tags). It reflects [`disable_in_scope`],
+/// [`enable_in_scope`], [`disable_globally`] and the like, but not the filters
+/// of any particular [`TracingAllocator`]. It returns `false` inside of the
+/// instrumentation itself (e.g., while a subscriber handles an allocation
+/// event).
+///
+/// ## Usage
+/// ```
+/// assert!(tracing_allocations::is_enabled());
+/// tracing_allocations::disable_in_scope(|| {
+///     assert!(!tracing_allocations::is_enabled());
+/// });
+/// ```
+pub fn is_enabled() -> bool {
+    GLOBALLY_ENABLED.load(Ordering::Relaxed)
+        && !disabled_for_thread()
+        && !suspended_for_panic()
+        && scope_enabled()
+}
+
+/// Whether allocation tracing is enabled on the current thread by
+/// [`disable_in_scope`], [`enable_in_scope`] and the like; `false` while the
+/// thread is inside of an instrumented method.
+fn scope_enabled() -> bool {
+    TRACE_ALLOCATOR
+        .try_with(|guard| guard.try_borrow().is_ok_and(|guard| *guard))
+        .unwrap_or(false)
+}
 
-    let _ = TRACE_ALLOCATOR.try_with(|guard| {
-        guard.replace(prev)
+/// Sets whether allocation tracing is enabled on the current thread, and
+/// returns whether it was previously enabled.
+///
+/// Has no effect (and returns `false`) while the thread is inside of an
+/// instrumented method, or after its thread-local storage is destroyed.
+fn replace_enabled(enabled: bool) -> bool {
+    TRACE_ALLOCATOR
+        .try_with(|guard| {
+            guard
+                .try_borrow_mut()
+                .map(|mut guard| core::mem::replace(&mut *guard, enabled))
+                .unwrap_or(false)
+        })
+        .unwrap_or(false)
+}
+
+/// Runs `f` as part of the instrumentation: the allocator operations it
+/// performs on the current thread are neither traced, nor recorded in the live
+/// table, nor counted by [`count_allocations`] and the like.
+///
+/// This permits `f` to allocate while holding locks that the instrumentation
+/// would otherwise acquire.
+fn as_instrumentation<R>(f: impl FnOnce() -> R) -> R {
+    let mut f = Some(f);
+    let result = TRACE_ALLOCATOR.try_with(|guard| {
+        let _guard = guard.try_borrow_mut();
+        f.take().map(|f| f())
     });
+    match (result, f) {
+        (Ok(Some(result)), _) => result,
+        // the thread-local storage has been destroyed, so nothing is traced
+        (_, Some(f)) => f(),
+        (_, None) => unreachable!(),
+    }
+}
 
-    res
+/// Records an operation with the process-wide counters (see [`Region`]). Then,
+/// unless the operation was performed by the instrumentation itself, records it
+/// with any call to [`count_allocations`] (or [`assert_alloc_budget`]) in
+/// progress on the current thread, and checks it against any
+/// [`forbid_allocations`] guard.
+fn account(config: &Config, kind: AllocationKind, allocated: usize, freed: usize) {
+    global::record(kind, allocated, freed, config.exact_peak);
+    per_thread::record(kind, allocated, freed);
+    let (counting, forbidding, naming) = (
+        stats::counting(),
+        forbid::forbidding(),
+        per_thread::naming(),
+    );
+    let instrumenting = || {
+        TRACE_ALLOCATOR
+            .try_with(|guard| guard.try_borrow().is_err())
+            .unwrap_or(true)
+    };
+    if !(counting || forbidding || naming) || instrumenting() {
+        return;
+    }
+    if naming {
+        // safety: global allocators must not unwind
+        let _ = catch_unwind(|| maybe_with_guard(|_| per_thread::name_thread()));
+    }
+    if counting && stats::record(kind, allocated, freed) {
+        // safety: global allocators must not unwind
+        let _ = catch_unwind(|| maybe_with_guard(|_| stats::record_offender(kind, allocated)));
+    }
+    if forbidding && kind != AllocationKind::Dealloc && forbid::record(kind, allocated) {
+        // safety: global allocators must not unwind
+        let _ = catch_unwind(|| maybe_with_guard(|_| forbid::report(kind, allocated)));
+    }
 }
 
 fn maybe_with_guard<F>(f: F)
 where
     F: for<'a> FnOnce(RefMut<'a, bool>),
 {
+    if disabled_for_thread() || suspended_for_panic() {
+        return;
+    }
     let _ = TRACE_ALLOCATOR.try_with(|guard| guard.try_borrow_mut().map(f));
 }
 
-unsafe impl<A> GlobalAlloc for TracingAllocator<A>
+unsafe impl<A, const LEVEL: u8> GlobalAlloc for TracingAllocator<A, LEVEL>
 where
     A: GlobalAlloc,
 {
     /// Allocate memory as described by the given `layout`.
     /// [Read more.][GlobalAlloc::alloc]
     ///
-    /// Emits [`TRACE`]-level events with the following metadata:
+    /// Emits [`TRACE`]-level events (unless [configured
+    /// otherwise][TracingAllocator::with_level]) with the following metadata:
     /// - **`name`**  
     ///   "alloc"
     /// - **`target`**  
-    ///   "tracing::allocator"
-    /// - **`addr`: [`usize`]**  
+    ///   "tracing::allocator"; or, if [size
+    ///   classes][TracingAllocator::with_size_classes] are configured, the
+    ///   [target][SizeClass::target] of the block's size class
+    /// - **`kind`: [`str`]**  
+    ///   "alloc"
+    /// - **`addr`: [`u64`]**  
     ///   the address of the allocation
-    /// - **`size`: [`usize`]**  
+    /// - **`addr_hex`: [`str`]**  
+    ///   the address of the allocation, in hexadecimal
+    /// - **`size`: [`u64`]**  
     ///   the size of the allocation
+    /// - **`align`: [`u64`]**  
+    ///   the alignment of the allocation
+    /// - **`usable_size`: [`u64`]**  
+    ///   the usable size of the allocation; only present if [usable
+    ///   sizes][TracingAllocator::with_usable_size] are enabled
+    /// - **`zeroed`: [`bool`]**  
+    ///   whether the allocation was produced by `alloc_zeroed`; only present if
+    ///   [unified alloc events][TracingAllocator::with_unified_alloc_events]
+    ///   are enabled
+    /// - **`timestamp_ns`: [`u64`]**  
+    ///   the time of the allocation; only present if a
+    ///   [clock][TracingAllocator::with_clock] is configured
+    /// - **`span_id`: [`u64`]**  
+    ///   the ID of the current span; only present if [span
+    ///   IDs][TracingAllocator::with_span_ids] are enabled
+    /// - **`sample_rate`: [`u64`]**  
+    ///   the rate at which events are sampled; only present if
+    ///   [sampling][TracingAllocator::with_sample_rate] is enabled
+    /// - **`sample_interval`: [`u64`]**  
+    ///   the mean number of bytes between samples; only present if [byte
+    ///   sampling][TracingAllocator::with_byte_sampling] is enabled
+    /// - **`count`: [`u64`]**  
+    ///   the number of identical operations this event stands for; only
+    ///   present if [coalescing][TracingAllocator::with_coalescing] is enabled
+    ///   and the operation was repeated
+    /// - **`tag`: [`str`]**  
+    ///   the tag of the enclosing [`tag_in_scope`]; only present within one
+    /// - **`extra`: [`str`]**  
+    ///   fields contributed by the [extra fields
+    ///   callback][TracingAllocator::with_extra_fields]; only present if one is
+    ///   configured
+    /// - **`backtrace`: [`str`]**  
+    ///   the backtrace of the operation; only present if
+    ///   [requested][Detail::Backtrace]
+    /// - **`caller`: [`str`]**  
+    ///   the code that requested the operation; only present if requested
+    ///   (with `Detail::Caller`)
     ///
     /// [`TRACE`]: tracing::Level::TRACE
     #[track_caller]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !Self::COUNTED || !self.config.wraps(AllocationKind::Alloc) {
+            return self.allocator.alloc(layout);
+        }
+
         let ptr = self.allocator.alloc(layout);
 
+        if !ptr.is_null() {
+            account(&self.config, AllocationKind::Alloc, layout.size(), 0);
+        }
+
+        if !Self::INSTRUMENTED {
+            return ptr;
+        }
+
+        let config = &self.config;
+
         // safety: global allocators must not unwind
         let _ = catch_unwind(|| {
             maybe_with_guard(|trace_allocations| {
-                if *trace_allocations {
-                    tracing::trace! {
-                        addr = ptr as usize,
-                        size = layout.size(),
-                        "alloc",
-                    };
+                let traced = *trace_allocations && GLOBALLY_ENABLED.load(Ordering::Relaxed);
+                if traced {
+                    config.track(ptr, layout);
+                }
+                if traced
+                    && config.traces(AllocationKind::Alloc)
+                    && config.admits(layout.size())
+                    && config.admits_span()
+                    && config.sample()
+                    && config.sample_bytes(layout.size())
+                    && config.admits_caller()
+                    && config.within_rate_limit()
+                {
+                    config.emit(&config.alloc_event(ptr, layout, false));
                 }
             })
         });
@@ -194,30 +3239,100 @@ where
     /// `layout`.
     /// [Read more.][GlobalAlloc::dealloc]
     ///
-    /// Emits [`TRACE`]-level events with the following metadata:
+    /// Emits [`TRACE`]-level events (unless [configured
+    /// otherwise][TracingAllocator::with_level]) with the following metadata:
     /// - **`name`**  
     ///   "dealloc"
     /// - **`target`**  
-    ///   "tracing::allocator"
-    /// - **`addr`: [`usize`]**  
+    ///   "tracing::allocator"; or, if [size
+    ///   classes][TracingAllocator::with_size_classes] are configured, the
+    ///   [target][SizeClass::target] of the block's size class
+    /// - **`kind`: [`str`]**  
+    ///   "dealloc"
+    /// - **`addr`: [`u64`]**  
     ///   the address of the deallocation
-    /// - **`size`: [`usize`]**  
+    /// - **`addr_hex`: [`str`]**  
+    ///   the address of the deallocation, in hexadecimal
+    /// - **`size`: [`u64`]**  
     ///   the size of the deallocation
+    /// - **`align`: [`u64`]**  
+    ///   the alignment of the deallocation
+    /// - **`usable_size`: [`u64`]**  
+    ///   the usable size of the deallocated block; only present if [usable
+    ///   sizes][TracingAllocator::with_usable_size] are enabled
+    /// - **`timestamp_ns`: [`u64`]**  
+    ///   the time of the deallocation; only present if a
+    ///   [clock][TracingAllocator::with_clock] is configured
+    /// - **`span_id`: [`u64`]**  
+    ///   the ID of the current span; only present if [span
+    ///   IDs][TracingAllocator::with_span_ids] are enabled
+    /// - **`sample_rate`: [`u64`]**  
+    ///   the rate at which events are sampled; only present if
+    ///   [sampling][TracingAllocator::with_sample_rate] is enabled
+    /// - **`sample_interval`: [`u64`]**  
+    ///   the mean number of bytes between samples; only present if [byte
+    ///   sampling][TracingAllocator::with_byte_sampling] is enabled
+    /// - **`count`: [`u64`]**  
+    ///   the number of identical operations this event stands for; only
+    ///   present if [coalescing][TracingAllocator::with_coalescing] is enabled
+    ///   and the operation was repeated
+    /// - **`age_ns`: [`u64`]**  
+    ///   the number of nanoseconds the block lived; only present if the [live
+    ///   table][TracingAllocator::with_live_table] is enabled, and the block's
+    ///   allocation was recorded in it
+    /// - **`age_events`: [`u64`]**  
+    ///   the number of allocations recorded in the live table while the block
+    ///   lived; present alongside `age_ns`
+    /// - **`tag`: [`str`]**  
+    ///   the tag of the enclosing [`tag_in_scope`]; only present within one
+    /// - **`extra`: [`str`]**  
+    ///   fields contributed by the [extra fields
+    ///   callback][TracingAllocator::with_extra_fields]; only present if one is
+    ///   configured
+    /// - **`backtrace`: [`str`]**  
+    ///   the backtrace of the operation; only present if
+    ///   [requested][Detail::Backtrace]
+    /// - **`caller`: [`str`]**  
+    ///   the code that requested the operation; only present if requested
+    ///   (with `Detail::Caller`)
     ///
     /// [`TRACE`]: tracing::Level::TRACE
     #[track_caller]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !Self::COUNTED || !self.config.wraps(AllocationKind::Dealloc) {
+            return self.allocator.dealloc(ptr, layout);
+        }
+
+        if !Self::INSTRUMENTED {
+            self.allocator.dealloc(ptr, layout);
+            account(&self.config, AllocationKind::Dealloc, 0, layout.size());
+            return;
+        }
+
+        let config = &self.config;
+
+        // the usable size can only be queried before the block is freed
+        let usable_size = config.usable_size(ptr, layout);
+        let age = config.untrack(ptr);
+
         self.allocator.dealloc(ptr, layout);
 
+        account(&self.config, AllocationKind::Dealloc, 0, layout.size());
+
         // safety: global allocators must not unwind
         let _ = catch_unwind(|| {
             maybe_with_guard(|trace_allocations| {
-                if *trace_allocations {
-                    tracing::trace! {
-                        addr = ptr as usize,
-                        size = layout.size(),
-                        "dealloc",
-                    };
+                if *trace_allocations
+                    && GLOBALLY_ENABLED.load(Ordering::Relaxed)
+                    && config.traces(AllocationKind::Dealloc)
+                    && config.admits(layout.size())
+                    && config.admits_span()
+                    && config.sample()
+                    && config.sample_bytes(layout.size())
+                    && config.admits_caller()
+                    && config.within_rate_limit()
+                {
+                    config.emit(&config.dealloc_event(ptr, layout, usable_size, age));
                 }
             })
         });
@@ -227,30 +3342,96 @@ where
     /// before being returned.
     /// [Read more.][GlobalAlloc::alloc_zeroed]
     ///
-    /// Emits [`TRACE`]-level events with the following metadata:
+    /// If [unified alloc events][TracingAllocator::with_unified_alloc_events]
+    /// are enabled, emits the same events as [`alloc`][TracingAllocator::alloc]
+    /// (with `zeroed` set to `true`). Otherwise:
+    ///
+    /// Emits [`TRACE`]-level events (unless [configured
+    /// otherwise][TracingAllocator::with_level]) with the following metadata:
     /// - **`name`**  
     ///   "alloc_zeroed"
     /// - **`target`**  
-    ///   "tracing::allocator"
-    /// - **`addr`: [`usize`]**  
+    ///   "tracing::allocator"; or, if [size
+    ///   classes][TracingAllocator::with_size_classes] are configured, the
+    ///   [target][SizeClass::target] of the block's size class
+    /// - **`kind`: [`str`]**  
+    ///   "alloc_zeroed"
+    /// - **`addr`: [`u64`]**  
     ///   the address of the allocation
-    /// - **`size`: [`usize`]**  
+    /// - **`addr_hex`: [`str`]**  
+    ///   the address of the allocation, in hexadecimal
+    /// - **`size`: [`u64`]**  
     ///   the size of the allocation
+    /// - **`align`: [`u64`]**  
+    ///   the alignment of the allocation
+    /// - **`usable_size`: [`u64`]**  
+    ///   the usable size of the allocation; only present if [usable
+    ///   sizes][TracingAllocator::with_usable_size] are enabled
+    /// - **`timestamp_ns`: [`u64`]**  
+    ///   the time of the allocation; only present if a
+    ///   [clock][TracingAllocator::with_clock] is configured
+    /// - **`span_id`: [`u64`]**  
+    ///   the ID of the current span; only present if [span
+    ///   IDs][TracingAllocator::with_span_ids] are enabled
+    /// - **`sample_rate`: [`u64`]**  
+    ///   the rate at which events are sampled; only present if
+    ///   [sampling][TracingAllocator::with_sample_rate] is enabled
+    /// - **`sample_interval`: [`u64`]**  
+    ///   the mean number of bytes between samples; only present if [byte
+    ///   sampling][TracingAllocator::with_byte_sampling] is enabled
+    /// - **`count`: [`u64`]**  
+    ///   the number of identical operations this event stands for; only
+    ///   present if [coalescing][TracingAllocator::with_coalescing] is enabled
+    ///   and the operation was repeated
+    /// - **`tag`: [`str`]**  
+    ///   the tag of the enclosing [`tag_in_scope`]; only present within one
+    /// - **`extra`: [`str`]**  
+    ///   fields contributed by the [extra fields
+    ///   callback][TracingAllocator::with_extra_fields]; only present if one is
+    ///   configured
+    /// - **`backtrace`: [`str`]**  
+    ///   the backtrace of the operation; only present if
+    ///   [requested][Detail::Backtrace]
+    /// - **`caller`: [`str`]**  
+    ///   the code that requested the operation; only present if requested
+    ///   (with `Detail::Caller`)
     ///
     /// [`TRACE`]: tracing::Level::TRACE
     #[track_caller]
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if !Self::COUNTED || !self.config.wraps(AllocationKind::AllocZeroed) {
+            return self.allocator.alloc_zeroed(layout);
+        }
+
         let ptr = self.allocator.alloc_zeroed(layout);
 
+        if !ptr.is_null() {
+            account(&self.config, AllocationKind::AllocZeroed, layout.size(), 0);
+        }
+
+        if !Self::INSTRUMENTED {
+            return ptr;
+        }
+
+        let config = &self.config;
+
         // safety: global allocators must not unwind
         let _ = catch_unwind(|| {
             maybe_with_guard(|trace_allocations| {
-                if *trace_allocations {
-                    tracing::trace! {
-                        addr = ptr as usize,
-                        size = layout.size(),
-                        "alloc_zeroed",
-                    }
+                let traced = *trace_allocations && GLOBALLY_ENABLED.load(Ordering::Relaxed);
+                if traced {
+                    config.track(ptr, layout);
+                }
+                if traced
+                    && config.traces(config.alloc_kind(true))
+                    && config.admits(layout.size())
+                    && config.admits_span()
+                    && config.sample()
+                    && config.sample_bytes(layout.size())
+                    && config.admits_caller()
+                    && config.within_rate_limit()
+                {
+                    config.emit(&config.alloc_event(ptr, layout, true));
                 }
             })
         });
@@ -262,36 +3443,117 @@ where
     /// described by the given `old_ptr` pointer and `old_layout` layout.
     /// [Read more.][GlobalAlloc::realloc]
     ///
-    /// Emits [`TRACE`]-level events with the following metadata:
+    /// Emits [`TRACE`]-level events (unless [configured
+    /// otherwise][TracingAllocator::with_level]) with the following metadata:
     /// - **`name`**  
     ///   "realloc"
     /// - **`target`**  
-    ///   "tracing::allocator"
-    /// - **`old_addr`: [`usize`]**  
+    ///   "tracing::allocator"; or, if [size
+    ///   classes][TracingAllocator::with_size_classes] are configured, the
+    ///   [target][SizeClass::target] of the block's size class
+    /// - **`kind`: [`str`]**  
+    ///   "realloc"
+    /// - **`old_addr`: [`u64`]**  
     ///   the address of the existing allocation
-    /// - **`old_size`: [`usize`]**  
+    /// - **`old_addr_hex`: [`str`]**  
+    ///   the address of the existing allocation, in hexadecimal
+    /// - **`old_size`: [`u64`]**  
     ///   the size of the existing allocation
-    /// - **`new_addr`: [`usize`]**  
+    /// - **`new_addr`: [`u64`]**  
     ///   the address of the new allocation
-    /// - **`new_size`: [`usize`]**  
+    /// - **`new_addr_hex`: [`str`]**  
+    ///   the address of the new allocation, in hexadecimal
+    /// - **`new_size`: [`u64`]**  
     ///   the size of the new allocation
+    /// - **`align`: [`u64`]**  
+    ///   the alignment of both the existing and the new allocation
+    /// - **`new_usable_size`: [`u64`]**  
+    ///   the usable size of the new allocation; only present if [usable
+    ///   sizes][TracingAllocator::with_usable_size] are enabled
+    /// - **`delta`: [`i64`]**  
+    ///   the change in size; positive if the block grew, negative if it shrank
+    /// - **`moved`: [`bool`]**  
+    ///   whether the block was moved (i.e., `old_addr != new_addr`)
+    /// - **`timestamp_ns`: [`u64`]**  
+    ///   the time of the reallocation; only present if a
+    ///   [clock][TracingAllocator::with_clock] is configured
+    /// - **`span_id`: [`u64`]**  
+    ///   the ID of the current span; only present if [span
+    ///   IDs][TracingAllocator::with_span_ids] are enabled
+    /// - **`sample_rate`: [`u64`]**  
+    ///   the rate at which events are sampled; only present if
+    ///   [sampling][TracingAllocator::with_sample_rate] is enabled
+    /// - **`sample_interval`: [`u64`]**  
+    ///   the mean number of bytes between samples; only present if [byte
+    ///   sampling][TracingAllocator::with_byte_sampling] is enabled
+    /// - **`count`: [`u64`]**  
+    ///   the number of identical operations this event stands for; only
+    ///   present if [coalescing][TracingAllocator::with_coalescing] is enabled
+    ///   and the operation was repeated
+    /// - **`tag`: [`str`]**  
+    ///   the tag of the enclosing [`tag_in_scope`]; only present within one
+    /// - **`extra`: [`str`]**  
+    ///   fields contributed by the [extra fields
+    ///   callback][TracingAllocator::with_extra_fields]; only present if one is
+    ///   configured
+    /// - **`backtrace`: [`str`]**  
+    ///   the backtrace of the operation; only present if
+    ///   [requested][Detail::Backtrace]
+    /// - **`caller`: [`str`]**  
+    ///   the code that requested the operation; only present if requested
+    ///   (with `Detail::Caller`)
     ///
     /// [`TRACE`]: tracing::Level::TRACE
     #[track_caller]
     unsafe fn realloc(&self, old_ptr: *mut u8, old_layout: Layout, new_size: usize) -> *mut u8 {
+        if !Self::COUNTED || !self.config.wraps(AllocationKind::Realloc) {
+            return self.allocator.realloc(old_ptr, old_layout, new_size);
+        }
+
+        // the block is taken out of the live table before it is moved, lest
+        // another thread be given its address, and record it, first
+        let block = if Self::INSTRUMENTED {
+            self.config.take(old_ptr)
+        } else {
+            None
+        };
+
         let new_ptr = self.allocator.realloc(old_ptr, old_layout, new_size);
 
+        if let Some(block) = block {
+            self.config
+                .retrack(block, old_ptr, old_layout, new_ptr, new_size);
+        }
+
+        if !new_ptr.is_null() {
+            account(
+                &self.config,
+                AllocationKind::Realloc,
+                new_size,
+                old_layout.size(),
+            );
+        }
+
+        if !Self::INSTRUMENTED {
+            return new_ptr;
+        }
+
+        let config = &self.config;
+
         // safety: global allocators must not unwind
         let _ = catch_unwind(|| {
             maybe_with_guard(|trace_allocations| {
-                if *trace_allocations {
-                    tracing::trace! {
-                        old_addr = old_ptr as usize,
-                        old_size = old_layout.size(),
-                        new_addr = new_ptr as usize,
-                        new_size = new_size,
-                        "realloc",
-                    }
+                if *trace_allocations
+                    && GLOBALLY_ENABLED.load(Ordering::Relaxed)
+                    && config.traces(AllocationKind::Realloc)
+                    && (config.admits(old_layout.size()) || config.admits(new_size))
+                    && config.admits_span()
+                    && config.sample()
+                    && config.sample_bytes(new_size)
+                    && config.admits_caller()
+                    && config.within_rate_limit()
+                {
+                    config.emit(&config.realloc_event(old_ptr, old_layout, new_ptr, new_size));
                 }
             })
         });
@@ -0,0 +1,657 @@
+//! A table of the blocks currently allocated.
+//!
+//! If [`TracingAllocator::with_live_table`] is enabled, each traced allocation
+//! is recorded in a table, along with when it was performed, by which caller
+//! and under which [tag](crate::tag_in_scope), until the block is freed. This
+//! reveals the lifetime of each block, and so whether the allocations of a
+//! caller are short-lived (see [`TracingAllocator::with_churn_window`]).
+//! Together with the usable size of each block, it also reveals how fragmented
+//! the heap is (see [`fragmentation`]).
+//!
+//! The table is split into shards, each guarded by its own lock, so that
+//! threads operating on unrelated blocks rarely contend. A shard allocates as
+//! it grows, while its lock is held; the table is only updated while the
+//! allocator holds the current thread's guard, so these allocations are
+//! neither traced nor recorded, and never reenter the table.
+//!
+//! [`TracingAllocator::with_live_table`]: crate::TracingAllocator::with_live_table
+//! [`TracingAllocator::with_churn_window`]: crate::TracingAllocator::with_churn_window
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, OnceLock, TryLockError},
+    time::Instant,
+};
+
+#[cfg(feature = "backtrace")]
+use crate::callsite::{self, Callsite};
+
+/// The number of shards of the table.
+const SHARDS: usize = 64;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Mutex<BTreeMap<usize, Block>> = Mutex::new(BTreeMap::new());
+
+/// The blocks currently allocated, keyed by address, and sharded by [`shard`].
+static LIVE: [Mutex<BTreeMap<usize, Block>>; SHARDS] = [EMPTY; SHARDS];
+
+/// The total size of the blocks in the table, as requested.
+static REQUESTED: AtomicU64 = AtomicU64::new(0);
+
+/// The total size of the blocks in the table, as reserved by the allocator.
+static RESERVED: AtomicU64 = AtomicU64::new(0);
+
+/// The number of blocks in the table.
+static BLOCKS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of allocations recorded so far, which numbers each recorded
+/// allocation.
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The instant from which allocation times are measured.
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// The churn of each caller, keyed by [`Caller::key`].
+static CHURN: Mutex<BTreeMap<usize, Churn>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// The number of locks of the table that the current thread holds.
+    static HOLDING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Calls `f`, during which the current thread is deemed to hold a lock of
+/// the table.
+///
+/// Blocks freed while a lock is held (e.g., the nodes of a shard) are freed
+/// by the table itself, which must not then lock the table again to untrack
+/// them; they were never tracked.
+fn holding<R>(f: impl FnOnce() -> R) -> R {
+    let _ = HOLDING.try_with(|holding| holding.set(holding.get() + 1));
+    let result = f();
+    let _ = HOLDING.try_with(|holding| holding.set(holding.get() - 1));
+    result
+}
+
+/// Whether the current thread holds a lock of the table.
+fn held() -> bool {
+    HOLDING
+        .try_with(|holding| holding.get() > 0)
+        .unwrap_or(false)
+}
+
+/// The shard of the table that holds the block at `addr`.
+fn shard(addr: usize) -> &'static Mutex<BTreeMap<usize, Block>> {
+    // blocks are aligned, so the lowest bits of their addresses are alike
+    &LIVE[((addr >> 4) ^ (addr >> 12)) % SHARDS]
+}
+
+/// Inserts `block` at `addr`.
+fn insert(addr: usize, block: Block) {
+    let Ok(replaced) = holding(|| {
+        shard(addr)
+            .lock()
+            .map(|mut shard| shard.insert(addr, block))
+    }) else {
+        return;
+    };
+    REQUESTED.fetch_add(block.size as u64, Ordering::Relaxed);
+    RESERVED.fetch_add(block.reserved() as u64, Ordering::Relaxed);
+    BLOCKS.fetch_add(1, Ordering::Relaxed);
+    if let Some(replaced) = replaced {
+        // the block previously at this address was freed untraced
+        forget(&replaced);
+    }
+}
+
+/// Removes the block at `addr`, if any.
+fn remove(addr: usize) -> Option<Block> {
+    let block = holding(|| shard(addr).lock().ok()?.remove(&addr))?;
+    forget(&block);
+    Some(block)
+}
+
+/// Deducts `block` from the totals of the table.
+fn forget(block: &Block) {
+    REQUESTED.fetch_sub(block.size as u64, Ordering::Relaxed);
+    RESERVED.fetch_sub(block.reserved() as u64, Ordering::Relaxed);
+    BLOCKS.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// A block in the live table.
+#[derive(Clone, Copy)]
+pub(crate) struct Block {
+    /// The size of the block, as requested.
+    pub(crate) size: usize,
+    /// The usable size of the block, if known.
+    pub(crate) usable_size: Option<usize>,
+    /// When the block was allocated, in nanoseconds since the table was first
+    /// used.
+    pub(crate) born_ns: u64,
+    /// The number of the allocation of the block.
+    pub(crate) born_allocations: u64,
+    /// The caller that allocated the block.
+    pub(crate) caller: Caller,
+    /// The tag under which the block was allocated, if any.
+    pub(crate) tag: Option<&'static str>,
+}
+
+impl Block {
+    /// The size of the block, as reserved by the allocator; its usable size if
+    /// known, and otherwise its requested size.
+    fn reserved(&self) -> usize {
+        self.usable_size.unwrap_or(self.size)
+    }
+}
+
+/// The lifetime of a freed block.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Age {
+    /// The number of nanoseconds the block lived.
+    pub(crate) ns: u64,
+    /// The number of allocations recorded while the block lived.
+    pub(crate) allocations: u64,
+}
+
+/// The caller of an allocator operation, which can only be identified with
+/// the `backtrace` feature.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Caller(#[cfg(feature = "backtrace")] Option<&'static Callsite>);
+
+impl Caller {
+    /// The caller of the current allocator operation.
+    ///
+    /// This allocates, and so must only be called while allocator operations
+    /// on the current thread are untraced.
+    fn current() -> Self {
+        #[cfg(feature = "backtrace")]
+        return Self(callsite::caller());
+        #[cfg(not(feature = "backtrace"))]
+        return Self::default();
+    }
+
+    /// A key identifying this caller; callers that could not be identified
+    /// share the key zero.
+    pub(crate) fn key(self) -> usize {
+        #[cfg(feature = "backtrace")]
+        return self
+            .0
+            .map_or(0, |callsite| callsite as *const Callsite as usize);
+        #[cfg(not(feature = "backtrace"))]
+        return 0;
+    }
+
+    /// The location of this caller, as far as it is known.
+    pub(crate) fn location(self) -> Location {
+        #[cfg(feature = "backtrace")]
+        if let Some(callsite) = self.0 {
+            return Location {
+                symbol: callsite.symbol,
+                file: callsite.file,
+                line: callsite.line,
+            };
+        }
+        Location::default()
+    }
+}
+
+/// The location of a caller.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Location {
+    pub(crate) symbol: Option<&'static str>,
+    pub(crate) file: Option<&'static str>,
+    pub(crate) line: Option<u32>,
+}
+
+/// The number of nanoseconds since the table was first used.
+fn now_ns() -> u64 {
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+/// Calls `f` with the address and description of each block in the table.
+///
+/// Each shard is locked while its blocks are visited, so `f` may only
+/// allocate as part of the instrumentation.
+pub(crate) fn for_each(mut f: impl FnMut(usize, &Block)) {
+    holding(|| {
+        for shard in &LIVE {
+            let Ok(shard) = shard.lock() else {
+                continue;
+            };
+            for (&addr, block) in shard.iter() {
+                f(addr, block);
+            }
+        }
+    })
+}
+
+/// Calls `f` with the address and description of each block in the shards of
+/// the table that no thread holds, and returns the number of shards skipped
+/// because a thread held them.
+///
+/// Unlike [`for_each`], this never waits for a shard, so it may be called
+/// where the thread holding one might never release it, such as a panic hook.
+pub(crate) fn try_for_each(mut f: impl FnMut(usize, &Block)) -> usize {
+    holding(|| {
+        let mut busy = 0;
+        for shard in &LIVE {
+            let shard = match shard.try_lock() {
+                Ok(shard) => shard,
+                Err(TryLockError::WouldBlock) => {
+                    busy += 1;
+                    continue;
+                }
+                Err(TryLockError::Poisoned(_)) => continue,
+            };
+            for (&addr, block) in shard.iter() {
+                f(addr, block);
+            }
+        }
+        busy
+    })
+}
+
+/// Records the allocation of `size` bytes at `addr`, of which `usable_size`
+/// are usable if known, and counts it towards the churn of its caller if
+/// `churn` is being detected.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+pub(crate) fn track(addr: usize, size: usize, usable_size: Option<usize>, churn: bool) {
+    let caller = Caller::current();
+    let block = Block {
+        size,
+        usable_size,
+        born_ns: now_ns(),
+        born_allocations: ALLOCATIONS.fetch_add(1, Ordering::Relaxed),
+        caller,
+        tag: crate::tag::current(),
+    };
+    insert(addr, block);
+    if churn {
+        holding(|| {
+            if let Ok(mut churn) = CHURN.lock() {
+                let churn = churn.entry(caller.key()).or_insert(Churn {
+                    location: caller.location(),
+                    allocations: 0,
+                    short_lived: 0,
+                    short_lived_bytes: 0,
+                });
+                churn.allocations += 1;
+            }
+        });
+    }
+}
+
+/// Takes the block at `addr` out of the table, before it is moved by a
+/// reallocation, so that no other thread is given its address while it is
+/// still recorded; it must then be [retracked](retrack), or
+/// [restored](restore) if the reallocation fails.
+///
+/// Blocks freed by the table itself are never recorded, and are not looked
+/// up.
+pub(crate) fn take(addr: usize) -> Option<Block> {
+    if held() {
+        return None;
+    }
+    remove(addr)
+}
+
+/// Records that `block`, [taken](take) from the table, was moved to
+/// `new_addr`, and resized to `new_size` bytes, of which `new_usable_size`
+/// are usable if known; its lifetime continues.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+pub(crate) fn retrack(
+    mut block: Block,
+    new_addr: usize,
+    new_size: usize,
+    new_usable_size: Option<usize>,
+) {
+    block.size = new_size;
+    block.usable_size = new_usable_size;
+    insert(new_addr, block);
+}
+
+/// Returns `block`, [taken](take) from the table, to `addr`, where it
+/// remains, because its reallocation failed.
+pub(crate) fn restore(addr: usize, block: Block) {
+    insert(addr, block);
+}
+
+/// Records that the block at `addr` is being freed, returning its lifetime if
+/// it was recorded. If it was freed within `churn_window`, it is counted as a
+/// short-lived allocation of its caller.
+///
+/// This must be called before the block is freed, so that no other thread is
+/// given its address while it is still recorded. Blocks freed by the table
+/// itself are never recorded, and are not looked up.
+pub(crate) fn untrack(addr: usize, churn_window: Option<ChurnWindow>) -> Option<Age> {
+    if held() {
+        return None;
+    }
+    let block = remove(addr)?;
+    let age = Age {
+        ns: now_ns().saturating_sub(block.born_ns),
+        allocations: ALLOCATIONS
+            .load(Ordering::Relaxed)
+            .saturating_sub(block.born_allocations + 1),
+    };
+    if churn_window.is_some_and(|window| window.contains(age)) {
+        holding(|| {
+            if let Ok(mut churn) = CHURN.lock() {
+                // the block was allocated before churn detection was enabled
+                // if its caller has no entry
+                if let Some(churn) = churn.get_mut(&block.caller.key()) {
+                    churn.short_lived += 1;
+                    churn.short_lived_bytes += block.size as u64;
+                }
+            }
+        });
+    }
+    Some(age)
+}
+
+/// A block in the live table. See [`live_blocks`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct LiveBlock {
+    /// The address of the block.
+    pub addr: u64,
+    /// The size of the block, as requested.
+    pub size: u64,
+    /// The usable size of the block, if known.
+    pub usable_size: Option<u64>,
+    /// The number of nanoseconds since the block was allocated.
+    pub age_ns: u64,
+    /// The demangled name of the function that allocated the block, if known.
+    pub symbol: Option<&'static str>,
+    /// The source file of the allocation, if known.
+    pub file: Option<&'static str>,
+    /// The source line of the allocation, if known.
+    pub line: Option<u32>,
+    /// The [tag](crate::tag_in_scope) under which the block was allocated, if
+    /// any.
+    pub tag: Option<&'static str>,
+}
+
+/// The blocks currently recorded in the live table, in no particular order.
+///
+/// Allocations are recorded if the [live
+/// table](crate::TracingAllocator::with_live_table) is enabled and they are
+/// traced. Their callers are identified as described for
+/// [`TracingAllocator::with_ignored_callers`], which requires the `backtrace`
+/// feature; without it, the locations of all blocks are unknown.
+///
+/// [`TracingAllocator::with_ignored_callers`]: crate::TracingAllocator::with_ignored_callers
+///
+/// ## Usage
+/// ```
+/// use std::alloc::System;
+/// use tracing_allocations::{live_blocks, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> =
+///     TracingAllocator::new(System).with_live_table(true);
+///
+/// fn main() {
+///     /* your code here */
+///
+///     // blocks that have lived for over a minute may have leaked
+///     for block in live_blocks() {
+///         if block.age_ns > 60_000_000_000 {
+///             println!("{:?}", block);
+///         }
+///     }
+/// }
+/// ```
+pub fn live_blocks() -> Vec<LiveBlock> {
+    let now = now_ns();
+    crate::as_instrumentation(|| {
+        let mut blocks = Vec::new();
+        for_each(|addr, block| {
+            let location = block.caller.location();
+            blocks.push(LiveBlock {
+                addr: addr as u64,
+                size: block.size as u64,
+                usable_size: block.usable_size.map(|size| size as u64),
+                age_ns: now.saturating_sub(block.born_ns),
+                symbol: location.symbol,
+                file: location.file,
+                line: location.line,
+                tag: block.tag,
+            });
+        });
+        blocks
+    })
+}
+
+/// An estimate of the fragmentation of the heap. See [`fragmentation`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct Fragmentation {
+    /// The number of live blocks.
+    pub blocks: u64,
+    /// The total size of the live blocks, as requested.
+    pub requested_bytes: u64,
+    /// The total size of the live blocks, as reserved by the allocator; i.e.,
+    /// their usable sizes, where known.
+    pub reserved_bytes: u64,
+    /// The distance from the start of the lowest live block to the end of the
+    /// highest.
+    pub address_span: u64,
+}
+
+impl Fragmentation {
+    /// The fraction of reserved bytes that were not requested; the waste due
+    /// to the rounding of block sizes by the allocator.
+    pub fn internal(&self) -> f64 {
+        if self.reserved_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.requested_bytes as f64 / self.reserved_bytes as f64
+    }
+
+    /// The fraction of the address span not reserved by live blocks; the
+    /// waste due to gaps between blocks.
+    ///
+    /// This overestimates external fragmentation if the allocator serves
+    /// blocks from several distant regions (e.g., per-thread arenas, or
+    /// `mmap` for large blocks).
+    pub fn external(&self) -> f64 {
+        if self.address_span == 0 {
+            return 0.0;
+        }
+        1.0 - (self.reserved_bytes as f64 / self.address_span as f64).min(1.0)
+    }
+}
+
+/// An estimate of the current fragmentation of the heap, computed from the
+/// blocks in the live table; `None` if the table is empty.
+///
+/// Only allocations recorded in the [live
+/// table](crate::TracingAllocator::with_live_table) are considered. Internal
+/// fragmentation can only be estimated if [usable
+/// sizes](crate::TracingAllocator::with_usable_size) are enabled; otherwise,
+/// each block is assumed to reserve exactly its requested size. Computing the
+/// estimate is cheap, so it may be polled periodically.
+///
+/// ## Usage
+/// ```
+/// use std::alloc::System;
+/// use tracing_allocations::{fragmentation, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> =
+///     TracingAllocator::new(System).with_live_table(true);
+///
+/// fn main() {
+///     /* your code here */
+///
+///     if let Some(fragmentation) = fragmentation() {
+///         println!(
+///             "{:.1}% internal, {:.1}% external fragmentation",
+///             fragmentation.internal() * 100.0,
+///             fragmentation.external() * 100.0,
+///         );
+///     }
+/// }
+/// ```
+pub fn fragmentation() -> Option<Fragmentation> {
+    let mut extent: Option<(usize, usize)> = None;
+    holding(|| {
+        for shard in &LIVE {
+            let Ok(shard) = shard.lock() else {
+                continue;
+            };
+            let (Some((&lowest, _)), Some((&highest, block))) =
+                (shard.first_key_value(), shard.last_key_value())
+            else {
+                continue;
+            };
+            let end = highest + block.reserved();
+            extent = Some(match extent {
+                Some((start, stop)) => (start.min(lowest), stop.max(end)),
+                None => (lowest, end),
+            });
+        }
+    });
+    let (start, end) = extent?;
+    Some(Fragmentation {
+        blocks: BLOCKS.load(Ordering::Relaxed),
+        requested_bytes: REQUESTED.load(Ordering::Relaxed),
+        reserved_bytes: RESERVED.load(Ordering::Relaxed),
+        address_span: (end - start) as u64,
+    })
+}
+
+/// The lifetime within which an allocation is deemed short-lived. See
+/// [`TracingAllocator::with_churn_window`](crate::TracingAllocator::with_churn_window).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChurnWindow {
+    /// Blocks freed within this duration of their allocation are short-lived.
+    Time(Duration),
+    /// Blocks freed before this many further allocations have been recorded
+    /// are short-lived.
+    Allocations(u64),
+}
+
+impl ChurnWindow {
+    /// Whether a block that lived for `age` is short-lived.
+    fn contains(self, age: Age) -> bool {
+        match self {
+            ChurnWindow::Time(window) => u128::from(age.ns) <= window.as_nanos(),
+            ChurnWindow::Allocations(window) => age.allocations <= window,
+        }
+    }
+}
+
+/// The churn of a caller.
+#[derive(Clone, Copy)]
+struct Churn {
+    location: Location,
+    allocations: u64,
+    short_lived: u64,
+    short_lived_bytes: u64,
+}
+
+/// The short-lived allocations of a caller. See [`churn_hotspots`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct ChurnStats {
+    /// The demangled name of the calling function, if known.
+    pub symbol: Option<&'static str>,
+    /// The source file of the call, if known.
+    pub file: Option<&'static str>,
+    /// The source line of the call, if known.
+    pub line: Option<u32>,
+    /// The number of allocations recorded.
+    pub allocations: u64,
+    /// The number of those allocations that were freed within the churn
+    /// window.
+    pub short_lived: u64,
+    /// The total size of the short-lived allocations.
+    pub short_lived_bytes: u64,
+}
+
+impl ChurnStats {
+    /// The fraction of allocations that were short-lived.
+    pub fn churn_ratio(&self) -> f64 {
+        if self.allocations == 0 {
+            return 0.0;
+        }
+        self.short_lived as f64 / self.allocations as f64
+    }
+}
+
+/// The `n` callers with the most short-lived allocations, in descending order;
+/// these are the prime candidates for pooling, or for conversion to arenas.
+///
+/// Allocations are recorded, and deemed short-lived, as configured by
+/// [`TracingAllocator::with_churn_window`]. Callers are identified as
+/// described for [`TracingAllocator::with_ignored_callers`], which requires
+/// the `backtrace` feature; without it, all allocations are attributed to a
+/// single caller of unknown location.
+///
+/// ## Usage
+/// ```
+/// use std::{alloc::System, time::Duration};
+/// use tracing_allocations::{churn_hotspots, ChurnWindow, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System)
+///     .with_churn_window(ChurnWindow::Time(Duration::from_millis(1)));
+///
+/// fn main() {
+///     /* your code here */
+///
+///     for hotspot in churn_hotspots(10) {
+///         println!(
+///             "{:?} ({:?}:{:?}): {:.0}% of {} allocations short-lived",
+///             hotspot.symbol,
+///             hotspot.file,
+///             hotspot.line,
+///             hotspot.churn_ratio() * 100.0,
+///             hotspot.allocations,
+///         );
+///     }
+/// }
+/// ```
+///
+/// [`TracingAllocator::with_churn_window`]: crate::TracingAllocator::with_churn_window
+/// [`TracingAllocator::with_ignored_callers`]: crate::TracingAllocator::with_ignored_callers
+pub fn churn_hotspots(n: usize) -> Vec<ChurnStats> {
+    crate::as_instrumentation(|| {
+        let mut hotspots: Vec<ChurnStats> = holding(|| {
+            let Ok(churn) = CHURN.lock() else {
+                return Vec::new();
+            };
+            churn
+                .values()
+                .map(|churn| ChurnStats {
+                    symbol: churn.location.symbol,
+                    file: churn.location.file,
+                    line: churn.location.line,
+                    allocations: churn.allocations,
+                    short_lived: churn.short_lived,
+                    short_lived_bytes: churn.short_lived_bytes,
+                })
+                .collect()
+        });
+        hotspots.sort_by_key(|hotspot| core::cmp::Reverse(hotspot.short_lived));
+        hotspots.truncate(n);
+        hotspots
+    })
+}
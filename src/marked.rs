@@ -0,0 +1,109 @@
+//! Tracking of entry into spans marked for allocation tracing.
+//!
+//! A [`TracingAllocator`] cannot observe the fields of spans, so
+//! [`MarkedSpans`] observes them on its behalf, and records in a thread-local
+//! counter how many marked spans the current thread has entered.
+//!
+//! [`TracingAllocator`]: crate::TracingAllocator
+
+use core::{cell::Cell, fmt};
+
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// The name of the field that marks a span.
+const MARKER: &str = "trace_alloc";
+
+thread_local! {
+    /// The number of marked spans this thread is currently inside of.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Whether the current thread is inside of a marked span.
+pub(crate) fn in_marked_span() -> bool {
+    DEPTH.try_with(|depth| depth.get() > 0).unwrap_or(false)
+}
+
+/// A [`Layer`] that tracks entry into spans marked with `trace_alloc = true`,
+/// for allocators configured with
+/// [`TracingAllocator::with_marked_spans_only`](crate::TracingAllocator::with_marked_spans_only).
+///
+/// Spans created with [`traced_span!`](crate::traced_span) are marked. The
+/// field must be given when the span is created; recording it later has no
+/// effect, since the span may already have been entered without being
+/// counted.
+///
+/// ## Usage
+/// ```
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::MarkedSpans;
+///
+/// tracing_subscriber::registry()
+///     .with(MarkedSpans::new())
+///     .with(tracing_subscriber::fmt::layer())
+///     .init();
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MarkedSpans {
+    _priv: (),
+}
+
+impl MarkedSpans {
+    /// Constructs a new `MarkedSpans` layer.
+    pub const fn new() -> Self {
+        Self { _priv: () }
+    }
+}
+
+/// The extension attached to marked spans.
+struct Marked;
+
+impl<S> Layer<S> for MarkedSpans
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut visitor = MarkerVisitor(false);
+        attrs.record(&mut visitor);
+        if let (true, Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(Marked);
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if is_marked(id, &ctx) {
+            let _ = DEPTH.try_with(|depth| depth.set(depth.get() + 1));
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if is_marked(id, &ctx) {
+            let _ = DEPTH.try_with(|depth| depth.set(depth.get().saturating_sub(1)));
+        }
+    }
+}
+
+/// Whether the span with the given `id` is marked.
+fn is_marked<S>(id: &span::Id, ctx: &Context<'_, S>) -> bool
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    ctx.span(id)
+        .is_some_and(|span| span.extensions().get::<Marked>().is_some())
+}
+
+/// Detects a `trace_alloc = true` field.
+struct MarkerVisitor(bool);
+
+impl Visit for MarkerVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == MARKER {
+            self.0 = value;
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+}
@@ -0,0 +1,328 @@
+//! Heap profiles over time in the format of Valgrind's massif.
+//!
+//! [`MassifLayer`] periodically snapshots the live blocks described by the
+//! events it observes, by the stack that allocated them, and writes the
+//! snapshots in the format of `massif.out` files, as read by `ms_print` and
+//! massif-visualizer.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    event::{AllocationEvent, AllocationKind},
+    stack::{self, Profile, Symbolizer, Totals},
+};
+
+/// The greatest number of snapshots retained; once exceeded, every other
+/// snapshot is discarded and the interval between snapshots is doubled.
+const MAX_SNAPSHOTS: usize = 100;
+
+/// The share of the heap, in percent, below which the callers of a node of a
+/// heap tree are collapsed into a single entry, as by massif's `--threshold`.
+const THRESHOLD: f64 = 1.0;
+
+/// A [`Layer`] that records the heap over time, in snapshots of the live
+/// blocks by the stack that allocated them.
+///
+/// Each snapshot is a heap tree, as drawn by massif's `ms_print`: the blocks
+/// live at that moment (allocated, or reallocated, by an observed event and
+/// not yet freed by one), by the frames of the stacks that allocated them.
+/// The first event observed after each interval (by default, 100ms) takes a
+/// snapshot; at most 100 snapshots are retained, after which every other
+/// snapshot is discarded and the interval is doubled, so that the snapshots
+/// always span the whole run. Snapshots may be written at any time,
+/// from any thread, through the [`MassifHandle`] returned by
+/// [`MassifLayer::handle`]. Sampled and coalesced events are scaled by
+/// [`AllocationEvent::weight`].
+///
+/// Requires the `massif` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use std::time::Duration;
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::MassifLayer;
+///
+/// let layer = MassifLayer::new().with_interval(Duration::from_millis(10));
+/// let massif = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// let file = std::fs::File::create("massif.out.tracing").unwrap();
+/// massif.write_to(file).unwrap();
+/// ```
+///
+/// ```sh
+/// ms_print massif.out.tracing
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MassifLayer {
+    handle: MassifHandle,
+}
+
+impl MassifLayer {
+    /// Constructs a new `MassifLayer`, which snapshots the heap every 100ms.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Snapshot the heap every `interval`, rather than every 100ms.
+    pub fn with_interval(self, interval: Duration) -> Self {
+        crate::disable_in_scope(|| self.handle.state().interval = interval);
+        self
+    }
+
+    /// A handle through which to write the snapshots of this layer.
+    pub fn handle(&self) -> MassifHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for MassifLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let stack = (event.kind != AllocationKind::Dealloc).then(stack::capture);
+            let mut state = self.handle.state();
+            state.profile.record(&event, stack);
+            let now = state.profile.began.elapsed();
+            if now >= state.next {
+                state.snapshot(now);
+            }
+        });
+    }
+}
+
+/// A handle to the snapshots of a [`MassifLayer`].
+///
+/// Handles are cheap to clone, and all clones write the same snapshots.
+#[derive(Clone, Default)]
+pub struct MassifHandle {
+    state: Arc<Mutex<State>>,
+    symbolizer: Arc<Mutex<Symbolizer>>,
+}
+
+impl MassifHandle {
+    /// Writes the snapshots taken so far, followed by a snapshot of the heap
+    /// as it is now, to `writer`, in the format of `massif.out` files.
+    ///
+    /// Each snapshot has a detailed heap tree, and the greatest of them is
+    /// marked as the peak. Stacks are symbolized as the snapshots are
+    /// written, which may be slow the first time each stack frame is
+    /// encountered.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        crate::disable_in_scope(|| {
+            let (snapshots, stacks) = {
+                let state = self.state();
+                let mut snapshots = state.snapshots.clone();
+                snapshots.push(state.take(state.profile.began.elapsed()));
+                (snapshots, state.profile.stacks.clone())
+            };
+            let mut symbolizer = self
+                .symbolizer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let peak = snapshots
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, snapshot)| snapshot.heap_bytes)
+                .map_or(0, |(index, _)| index);
+
+            writeln!(writer, "desc: (none)")?;
+            let cmd: Vec<String> = std::env::args().collect();
+            writeln!(writer, "cmd: {}", cmd.join(" "))?;
+            writeln!(writer, "time_unit: ms")?;
+            for (index, snapshot) in snapshots.iter().enumerate() {
+                writeln!(writer, "#-----------")?;
+                writeln!(writer, "snapshot={}", index)?;
+                writeln!(writer, "#-----------")?;
+                writeln!(writer, "time={}", snapshot.time.as_millis())?;
+                writeln!(writer, "mem_heap_B={}", snapshot.heap_bytes)?;
+                writeln!(writer, "mem_heap_extra_B=0")?;
+                writeln!(writer, "mem_stacks_B=0")?;
+                let kind = if index == peak { "peak" } else { "detailed" };
+                writeln!(writer, "heap_tree={}", kind)?;
+                let mut root = Node::default();
+                for &(stack, bytes) in &snapshot.stacks {
+                    root.insert(symbolizer.trim(&stacks[stack].stack), bytes);
+                }
+                root.write(&mut writer, &mut symbolizer, None, 0, snapshot.heap_bytes)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl core::fmt::Debug for MassifHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MassifHandle").finish_non_exhaustive()
+    }
+}
+
+/// The allocations and snapshots of a [`MassifLayer`].
+struct State {
+    profile: Profile,
+    /// The interval between snapshots.
+    interval: Duration,
+    /// When the next snapshot is due, relative to `profile.began`.
+    next: Duration,
+    snapshots: Vec<Snapshot>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            profile: Profile::default(),
+            interval: Duration::from_millis(100),
+            next: Duration::ZERO,
+            snapshots: Vec::new(),
+        }
+    }
+}
+
+impl State {
+    /// Snapshots the heap at `now`, relative to `profile.began`, and
+    /// schedules the next snapshot.
+    fn snapshot(&mut self, now: Duration) {
+        let snapshot = self.take(now);
+        self.snapshots.push(snapshot);
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            let mut index = 0;
+            self.snapshots.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+            self.interval *= 2;
+        }
+        self.next = now + self.interval;
+    }
+
+    /// A snapshot of the heap at `now`, relative to `profile.began`.
+    fn take(&self, now: Duration) -> Snapshot {
+        Snapshot {
+            time: now,
+            heap_bytes: self.profile.live_bytes,
+            stacks: self
+                .profile
+                .stacks
+                .iter()
+                .enumerate()
+                .filter(|(_, totals)| totals.inuse_space > 0)
+                .map(|(index, Totals { inuse_space, .. })| (index, *inuse_space))
+                .collect(),
+        }
+    }
+}
+
+/// The live blocks at a point in time.
+#[derive(Clone)]
+struct Snapshot {
+    /// When the snapshot was taken, relative to when the layer was
+    /// constructed.
+    time: Duration,
+    /// The total size of the live blocks.
+    heap_bytes: u64,
+    /// The total size of the live blocks allocated by each stack, by the
+    /// index of the stack in the profile.
+    stacks: Vec<(usize, u64)>,
+}
+
+/// A node of a heap tree: the live bytes allocated through a frame, by the
+/// frames that called it.
+#[derive(Default)]
+struct Node {
+    bytes: u64,
+    children: HashMap<usize, Node>,
+}
+
+impl Node {
+    /// Attributes `bytes` to the path through the tree given by `stack`,
+    /// innermost first.
+    fn insert(&mut self, stack: &[usize], bytes: u64) {
+        self.bytes += bytes;
+        if let Some((&ip, callers)) = stack.split_first() {
+            self.children.entry(ip).or_default().insert(callers, bytes);
+        }
+    }
+
+    /// Writes the tree rooted at this node, which is the frame at `ip` (or,
+    /// if `None`, the allocator), at the given `depth`; callers that account
+    /// for less than [`THRESHOLD`] percent of `heap_bytes` are collapsed.
+    fn write<W: Write>(
+        &self,
+        writer: &mut W,
+        symbolizer: &mut Symbolizer,
+        ip: Option<usize>,
+        depth: usize,
+        heap_bytes: u64,
+    ) -> io::Result<()> {
+        let threshold = heap_bytes as f64 * THRESHOLD / 100.0;
+        let mut children: Vec<_> = self.children.iter().collect();
+        children.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(b.0)));
+        let significant = children
+            .iter()
+            .take_while(|(_, child)| child.bytes as f64 >= threshold)
+            .count();
+        let (significant, insignificant) = children.split_at(significant);
+        let entries = significant.len() + usize::from(!insignificant.is_empty());
+
+        write!(writer, "{:depth$}n{}: {} ", "", entries, self.bytes)?;
+        match ip {
+            None => writeln!(
+                writer,
+                "(heap allocation functions) malloc/new/new[], --alloc-fns, etc."
+            )?,
+            Some(ip) => {
+                write!(writer, "0x{:X}: ", ip)?;
+                match symbolizer.frame(ip).lines.first() {
+                    None => writeln!(writer, "???")?,
+                    Some(line) => match (&line.file, line.line) {
+                        (Some(file), Some(number)) => {
+                            writeln!(writer, "{} ({}:{})", line.function, file, number)?
+                        }
+                        _ => writeln!(writer, "{}", line.function)?,
+                    },
+                }
+            }
+        }
+
+        for (&ip, child) in significant {
+            child.write(writer, symbolizer, Some(ip), depth + 1, heap_bytes)?;
+        }
+        if !insignificant.is_empty() {
+            let bytes: u64 = insignificant.iter().map(|(_, child)| child.bytes).sum();
+            let places = match insignificant.len() {
+                1 => String::from("1 place, below"),
+                n => format!("{} places, all below", n),
+            };
+            writeln!(
+                writer,
+                "{:indent$}n0: {} in {} massif's threshold ({:.2}%)",
+                "",
+                bytes,
+                places,
+                THRESHOLD,
+                indent = depth + 1,
+            )?;
+        }
+        Ok(())
+    }
+}
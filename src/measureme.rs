@@ -0,0 +1,129 @@
+//! Allocation events in the format of `measureme`.
+//!
+//! [`MeasuremeLayer`] records the events it observes with a
+//! [`measureme::Profiler`], so that they may be analyzed with the tools of
+//! rustc's self-profiler, such as `summarize` and `crox`.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use measureme::{EventId, Profiler, StringId};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::event::{AllocationEvent, AllocationKind};
+
+/// A [`Layer`] that records the allocation events it observes in a
+/// `measureme` profile.
+///
+/// Each allocation event is recorded as an integer event, whose event kind
+/// is the kind of operation ("alloc", "alloc_zeroed", "dealloc" or
+/// "realloc"), whose label is the code that requested the operation if known
+/// (see [`Detail::Caller`](crate::Detail::Caller)) and otherwise the kind of
+/// operation, whose thread ID is the number of the thread that performed it,
+/// and whose value is the size of the block (for reallocations, of the new
+/// block). The profile is completed when the layer is dropped.
+///
+/// Requires the `measureme` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::MeasuremeLayer;
+///
+/// let layer = MeasuremeLayer::new("allocations").unwrap();
+/// let subscriber = tracing_subscriber::registry().with(layer);
+///
+/// tracing::subscriber::with_default(subscriber, || {
+///     /* your code here */
+/// });
+/// ```
+///
+/// ```sh
+/// summarize summarize allocations.mm_profdata
+/// ```
+#[derive(Clone)]
+pub struct MeasuremeLayer {
+    profiler: Arc<Profiler>,
+    /// The event kind of each kind of operation, by its index.
+    kinds: [StringId; 4],
+    /// The labels of the callers seen so far.
+    callers: Arc<Mutex<Callers>>,
+}
+
+/// The labels of callers, and the name of the caller of the event being
+/// recorded.
+#[derive(Default)]
+struct Callers {
+    labels: HashMap<String, StringId>,
+    caller: String,
+}
+
+impl MeasuremeLayer {
+    /// Constructs a new `MeasuremeLayer`, which writes its profile to
+    /// `path_stem`, with the extension `.mm_profdata`.
+    pub fn new<P: AsRef<Path>>(path_stem: P) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        crate::disable_in_scope(|| {
+            let profiler = Profiler::new(path_stem)?;
+            let kinds = [
+                AllocationKind::Alloc,
+                AllocationKind::AllocZeroed,
+                AllocationKind::Dealloc,
+                AllocationKind::Realloc,
+            ]
+            .map(|kind| profiler.alloc_string(kind.as_str()));
+            Ok(Self {
+                profiler: Arc::new(profiler),
+                kinds,
+                callers: Arc::default(),
+            })
+        })
+    }
+}
+
+impl<S> Layer<S> for MeasuremeLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(decoded) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let kind = self.kinds[decoded.kind.index()];
+            let label = {
+                let mut callers = self.callers.lock().unwrap_or_else(PoisonError::into_inner);
+                let Callers { labels, caller } = &mut *callers;
+                caller.clear();
+                if crate::event::caller_of(event, caller) {
+                    match labels.get(caller.as_str()) {
+                        Some(&label) => label,
+                        None => {
+                            let label = self.profiler.alloc_string(caller.as_str());
+                            labels.insert(caller.clone(), label);
+                            label
+                        }
+                    }
+                } else {
+                    kind
+                }
+            };
+            self.profiler.record_integer_event(
+                kind,
+                EventId::from_label(label),
+                crate::thread::number() as u32,
+                decoded.size,
+            );
+        });
+    }
+}
+
+impl core::fmt::Debug for MeasuremeLayer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MeasuremeLayer").finish_non_exhaustive()
+    }
+}
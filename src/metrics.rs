@@ -0,0 +1,161 @@
+//! Allocation metrics through the `metrics` facade.
+//!
+//! [`MetricsLayer`] publishes counters, gauges and a histogram of sizes from
+//! the events it observes through the macros of the `metrics` crate, so that
+//! they are exported by whichever recorder the application installs (e.g.,
+//! for Prometheus, StatsD, or OTLP).
+
+use std::sync::Once;
+
+use metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
+};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::event::{AllocationEvent, AllocationKind};
+
+/// Whether the metrics have been described to the recorder.
+static DESCRIBED: Once = Once::new();
+
+/// A [`Layer`] that publishes metrics of the allocator operations described
+/// by the events it observes through the `metrics` facade.
+///
+/// The layer publishes the following metrics, with the same names as those of
+/// [`PrometheusLayer`](crate::PrometheusLayer), prefixed by
+/// `tracing_allocations_`:
+/// - **`live_bytes`**, **`live_blocks`**  
+///   gauges, incremented by each allocation and decremented by each
+///   deallocation; unlike those of `PrometheusLayer`, they do not pair
+///   deallocations with the allocations of the same blocks, and so also count
+///   the deallocations of blocks whose allocation was not observed
+/// - **`allocations_total`**, **`deallocations_total`**,
+///   **`reallocations_total`**  
+///   counters of the operations of each kind
+/// - **`allocated_bytes_total`**, **`freed_bytes_total`**  
+///   counters of the bytes allocated and freed; reallocations count the size
+///   of the new block as allocated, and that of the existing block as freed
+/// - **`allocation_size_bytes`**  
+///   a histogram of the sizes of the blocks allocated (and reallocated)
+///
+/// Metrics are published as each event is observed, and described to the
+/// recorder when the first is observed; the recorder should therefore be
+/// installed before any allocation event is observed. Sampled and coalesced
+/// events are scaled by [`AllocationEvent::weight`].
+///
+/// Requires the `metrics` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::MetricsLayer;
+///
+/// // install a recorder, e.g., with `metrics-exporter-prometheus`
+///
+/// tracing_subscriber::registry().with(MetricsLayer::new()).init();
+///
+/// /* your code here */
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MetricsLayer {
+    _private: (),
+}
+
+impl MetricsLayer {
+    /// Constructs a new `MetricsLayer`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        // recorders may allocate, e.g., to register a metric
+        crate::disable_in_scope(|| {
+            DESCRIBED.call_once(describe);
+            record(&event);
+        });
+    }
+}
+
+/// Publishes the operation described by `event`.
+fn record(event: &AllocationEvent) {
+    let weight = event.weight();
+    let scale = |n: u64| (n as f64 * weight).round() as u64;
+    let objects = scale(1);
+    match event.kind {
+        AllocationKind::Alloc | AllocationKind::AllocZeroed => {
+            counter!("tracing_allocations_allocations_total").increment(objects);
+            gauge!("tracing_allocations_live_blocks").increment(objects as f64);
+        }
+        AllocationKind::Dealloc => {
+            let size = scale(event.size);
+            counter!("tracing_allocations_deallocations_total").increment(objects);
+            counter!("tracing_allocations_freed_bytes_total").increment(size);
+            gauge!("tracing_allocations_live_blocks").decrement(objects as f64);
+            gauge!("tracing_allocations_live_bytes").decrement(size as f64);
+            return;
+        }
+        AllocationKind::Realloc => {
+            let old_size = scale(event.old_size.unwrap_or(0));
+            counter!("tracing_allocations_reallocations_total").increment(objects);
+            counter!("tracing_allocations_freed_bytes_total").increment(old_size);
+            gauge!("tracing_allocations_live_bytes").decrement(old_size as f64);
+        }
+    }
+    let size = scale(event.size);
+    counter!("tracing_allocations_allocated_bytes_total").increment(size);
+    gauge!("tracing_allocations_live_bytes").increment(size as f64);
+    histogram!("tracing_allocations_allocation_size_bytes")
+        .record_many(event.size as f64, objects as usize);
+}
+
+/// Describes the metrics to the recorder.
+fn describe() {
+    describe_gauge!(
+        "tracing_allocations_live_bytes",
+        Unit::Bytes,
+        "Bytes allocated and not yet freed."
+    );
+    describe_gauge!(
+        "tracing_allocations_live_blocks",
+        Unit::Count,
+        "Blocks allocated and not yet freed."
+    );
+    describe_counter!(
+        "tracing_allocations_allocations_total",
+        Unit::Count,
+        "Allocations, zeroed or not."
+    );
+    describe_counter!(
+        "tracing_allocations_deallocations_total",
+        Unit::Count,
+        "Deallocations."
+    );
+    describe_counter!(
+        "tracing_allocations_reallocations_total",
+        Unit::Count,
+        "Reallocations."
+    );
+    describe_counter!(
+        "tracing_allocations_allocated_bytes_total",
+        Unit::Bytes,
+        "Bytes allocated."
+    );
+    describe_counter!(
+        "tracing_allocations_freed_bytes_total",
+        Unit::Bytes,
+        "Bytes freed."
+    );
+    describe_histogram!(
+        "tracing_allocations_allocation_size_bytes",
+        Unit::Bytes,
+        "Sizes of the blocks allocated."
+    );
+}
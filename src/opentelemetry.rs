@@ -0,0 +1,163 @@
+//! Allocation metrics as OpenTelemetry instruments.
+//!
+//! [`OtelMetricsLayer`] records the events it observes with instruments of an
+//! OpenTelemetry [`Meter`], so that allocation telemetry is exported by the
+//! metrics pipeline of the application, alongside its other metrics.
+
+use opentelemetry::metrics::{Counter, Histogram, Meter, UpDownCounter};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::event::{AllocationEvent, AllocationKind};
+
+/// A [`Layer`] that records the allocator operations described by the events
+/// it observes with OpenTelemetry instruments.
+///
+/// The layer creates the following instruments with the [`Meter`] given to
+/// [`OtelMetricsLayer::new`]:
+/// - **`tracing_allocations.live`**, **`tracing_allocations.live_blocks`**  
+///   up-down counters of the bytes and blocks allocated and not yet freed,
+///   incremented by each allocation and decremented by each deallocation,
+///   whether or not the allocation of its block was observed
+/// - **`tracing_allocations.allocations`**,
+///   **`tracing_allocations.deallocations`**,
+///   **`tracing_allocations.reallocations`**  
+///   counters of the operations of each kind
+/// - **`tracing_allocations.allocated`**, **`tracing_allocations.freed`**  
+///   counters of the bytes allocated and freed, whose rates are the rates of
+///   allocation and deallocation; reallocations count the size of the new
+///   block as allocated, and that of the existing block as freed
+/// - **`tracing_allocations.allocation_size`**  
+///   a histogram of the sizes of the blocks allocated (and reallocated), with
+///   buckets from 16 bytes to 16 MiB, by powers of four
+///
+/// Sampled and coalesced events are scaled by [`AllocationEvent::weight`],
+/// except in the histogram, which records the size of each event once.
+///
+/// Requires the `opentelemetry` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::OtelMetricsLayer;
+///
+/// // install a meter provider, e.g., with `opentelemetry_sdk`
+/// let meter = opentelemetry::global::meter("my-service");
+///
+/// tracing_subscriber::registry()
+///     .with(OtelMetricsLayer::new(&meter))
+///     .init();
+///
+/// /* your code here */
+/// ```
+#[derive(Clone)]
+pub struct OtelMetricsLayer {
+    live_bytes: UpDownCounter<i64>,
+    live_blocks: UpDownCounter<i64>,
+    allocations: Counter<u64>,
+    deallocations: Counter<u64>,
+    reallocations: Counter<u64>,
+    bytes_allocated: Counter<u64>,
+    bytes_freed: Counter<u64>,
+    sizes: Histogram<u64>,
+}
+
+impl OtelMetricsLayer {
+    /// Constructs a new `OtelMetricsLayer`, whose instruments are created
+    /// with `meter`.
+    pub fn new(meter: &Meter) -> Self {
+        crate::disable_in_scope(|| Self {
+            live_bytes: meter
+                .i64_up_down_counter("tracing_allocations.live")
+                .with_unit("By")
+                .with_description("Bytes allocated and not yet freed.")
+                .build(),
+            live_blocks: meter
+                .i64_up_down_counter("tracing_allocations.live_blocks")
+                .with_unit("{block}")
+                .with_description("Blocks allocated and not yet freed.")
+                .build(),
+            allocations: meter
+                .u64_counter("tracing_allocations.allocations")
+                .with_unit("{allocation}")
+                .with_description("Allocations, zeroed or not.")
+                .build(),
+            deallocations: meter
+                .u64_counter("tracing_allocations.deallocations")
+                .with_unit("{deallocation}")
+                .with_description("Deallocations.")
+                .build(),
+            reallocations: meter
+                .u64_counter("tracing_allocations.reallocations")
+                .with_unit("{reallocation}")
+                .with_description("Reallocations.")
+                .build(),
+            bytes_allocated: meter
+                .u64_counter("tracing_allocations.allocated")
+                .with_unit("By")
+                .with_description("Bytes allocated.")
+                .build(),
+            bytes_freed: meter
+                .u64_counter("tracing_allocations.freed")
+                .with_unit("By")
+                .with_description("Bytes freed.")
+                .build(),
+            sizes: meter
+                .u64_histogram("tracing_allocations.allocation_size")
+                .with_unit("By")
+                .with_description("Sizes of the blocks allocated.")
+                .with_boundaries((2..=12).map(|i| (1u64 << (2 * i)) as f64).collect())
+                .build(),
+        })
+    }
+
+    /// Records the operation described by `event`.
+    fn record(&self, event: &AllocationEvent) {
+        let weight = event.weight();
+        let scale = |n: u64| (n as f64 * weight).round() as u64;
+        let objects = scale(1);
+        match event.kind {
+            AllocationKind::Alloc | AllocationKind::AllocZeroed => {
+                self.allocations.add(objects, &[]);
+                self.live_blocks.add(objects as i64, &[]);
+            }
+            AllocationKind::Dealloc => {
+                let size = scale(event.size);
+                self.deallocations.add(objects, &[]);
+                self.bytes_freed.add(size, &[]);
+                self.live_blocks.add(-(objects as i64), &[]);
+                self.live_bytes.add(-(size as i64), &[]);
+                return;
+            }
+            AllocationKind::Realloc => {
+                let old_size = scale(event.old_size.unwrap_or(0));
+                self.reallocations.add(objects, &[]);
+                self.bytes_freed.add(old_size, &[]);
+                self.live_bytes.add(-(old_size as i64), &[]);
+            }
+        }
+        let size = scale(event.size);
+        self.bytes_allocated.add(size, &[]);
+        self.live_bytes.add(size as i64, &[]);
+        self.sizes.record(event.size, &[]);
+    }
+}
+
+impl<S> Layer<S> for OtelMetricsLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        // exporters may allocate, e.g., to aggregate a new attribute set
+        crate::disable_in_scope(|| self.record(&event));
+    }
+}
+
+impl core::fmt::Debug for OtelMetricsLayer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("OtelMetricsLayer").finish_non_exhaustive()
+    }
+}
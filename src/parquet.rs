@@ -0,0 +1,218 @@
+//! Allocation events as Parquet files.
+//!
+//! [`ParquetLayer`] gathers the events it observes into the same record
+//! batches as [`ArrowLayer`](crate::ArrowLayer), and writes them as a single
+//! Parquet file, for long-term storage of traces and their analysis with SQL
+//! engines such as DuckDB and DataFusion.
+
+use std::{
+    io::Write,
+    mem,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use arrow_schema::SchemaRef;
+use parquet::{
+    arrow::ArrowWriter, basic::Compression, errors::ParquetError,
+    file::properties::WriterProperties,
+};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    arrow::{schema, Batch},
+    event::AllocationEvent,
+};
+
+/// The number of events in each record batch.
+const BATCH_SIZE: usize = 8192;
+
+/// A [`Layer`] that writes the allocation events it observes as a Parquet
+/// file, with the columns of the record batches of
+/// [`ArrowLayer`](crate::ArrowLayer), compressed with Snappy.
+///
+/// Events are gathered into row groups of 1048576 events, or of the number
+/// given to [`ParquetLayer::with_row_group_size`], which are buffered in
+/// memory until they are full; errors in writing the file are reported by
+/// [`ParquetHandle::finish`]. As a Parquet file cannot be read until its
+/// footer is written, the file is completed only when the layer and all
+/// [`ParquetHandle`]s to it are dropped, or when [`ParquetHandle::finish`] is
+/// called. Events that are not allocation events are ignored.
+///
+/// Requires the `parquet` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::ParquetLayer;
+///
+/// let file = std::fs::File::create("allocations.parquet").unwrap();
+/// let layer = ParquetLayer::new(file);
+/// let capture = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// capture.finish().unwrap();
+/// ```
+///
+/// ```sql
+/// SELECT caller, sum(size) FROM 'allocations.parquet'
+/// WHERE kind = 'alloc' GROUP BY caller ORDER BY 2 DESC;
+/// ```
+#[derive(Clone, Debug)]
+pub struct ParquetLayer {
+    handle: ParquetHandle,
+}
+
+impl ParquetLayer {
+    /// Constructs a new `ParquetLayer`, which writes its file to `writer`.
+    pub fn new<W>(writer: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        let file = crate::disable_in_scope(|| File {
+            writer: Writer::Pending(Box::new(writer)),
+            schema: schema(),
+            row_group_size: 1 << 20,
+            batch: Batch::default(),
+        });
+        Self {
+            handle: ParquetHandle {
+                file: Arc::new(Mutex::new(file)),
+            },
+        }
+    }
+
+    /// Gather events into row groups of `size` events, rather than 1048576.
+    ///
+    /// Smaller row groups take less memory to buffer, at the cost of a larger
+    /// file.
+    pub fn with_row_group_size(self, size: usize) -> Self {
+        self.handle.file().row_group_size = size.max(1);
+        self
+    }
+
+    /// A handle through which to finish the file of this layer.
+    pub fn handle(&self) -> ParquetHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for ParquetLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(decoded) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let mut file = self.handle.file();
+            if let Writer::Finished = file.writer {
+                return;
+            }
+            file.batch.push(event, &decoded);
+            if file.batch.len >= BATCH_SIZE.min(file.row_group_size) {
+                let _ = file.write_batch();
+            }
+        });
+    }
+}
+
+/// A handle to the file of a [`ParquetLayer`].
+///
+/// Handles are cheap to clone, and all clones refer to the same file.
+#[derive(Clone)]
+pub struct ParquetHandle {
+    file: Arc<Mutex<File>>,
+}
+
+impl ParquetHandle {
+    /// Writes the events gathered so far, and the footer of the file;
+    /// subsequent events are not written.
+    pub fn finish(&self) -> Result<(), ParquetError> {
+        crate::disable_in_scope(|| self.file().finish())
+    }
+
+    fn file(&self) -> MutexGuard<'_, File> {
+        self.file.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl core::fmt::Debug for ParquetHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ParquetHandle").finish_non_exhaustive()
+    }
+}
+
+/// The file written by a [`ParquetLayer`].
+struct File {
+    writer: Writer,
+    schema: SchemaRef,
+    /// The number of events in each row group.
+    row_group_size: usize,
+    /// The events gathered since the last record batch was written.
+    batch: Batch,
+}
+
+/// The writer of a [`File`].
+enum Writer {
+    /// No record batch has yet been written.
+    Pending(Box<dyn Write + Send>),
+    Writing(ArrowWriter<Box<dyn Write + Send>>),
+    /// The footer has been written, or writing failed.
+    Finished,
+}
+
+impl File {
+    /// Writes the header of the file, unless it has been written.
+    fn open(&mut self) -> Result<(), ParquetError> {
+        let Writer::Pending(_) = self.writer else {
+            return Ok(());
+        };
+        let Writer::Pending(writer) = mem::replace(&mut self.writer, Writer::Finished) else {
+            unreachable!()
+        };
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(self.row_group_size)
+            .build();
+        let writer = ArrowWriter::try_new(writer, self.schema.clone(), Some(properties))?;
+        self.writer = Writer::Writing(writer);
+        Ok(())
+    }
+
+    /// Writes the events gathered so far as a record batch, if there are any.
+    fn write_batch(&mut self) -> Result<(), ParquetError> {
+        if self.batch.len == 0 {
+            return Ok(());
+        }
+        self.open()?;
+        let Writer::Writing(writer) = &mut self.writer else {
+            return Ok(());
+        };
+        let batch = self.batch.finish(self.schema.clone())?;
+        writer.write(&batch)
+    }
+
+    /// Writes the events gathered so far, and the footer of the file.
+    fn finish(&mut self) -> Result<(), ParquetError> {
+        // a file without events still has a schema
+        self.open()?;
+        self.write_batch()?;
+        if let Writer::Writing(writer) = mem::replace(&mut self.writer, Writer::Finished) {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        crate::disable_in_scope(|| {
+            let _ = self.finish();
+        });
+    }
+}
@@ -0,0 +1,301 @@
+//! Per-thread counters of allocator operations.
+//!
+//! Each thread that performs an allocator operation claims one of a fixed
+//! number of slots, in which [`TracingAllocator`] counts the operations that
+//! thread performs. The slots are statically allocated, so that claiming one
+//! never allocates; threads that start while all slots are claimed by running
+//! threads are not counted. The slot of an exited thread is retained, so that
+//! its counts may still be read, until it is claimed by another thread.
+//!
+//! [`TracingAllocator`]: crate::TracingAllocator
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicU8, Ordering},
+};
+use std::sync::{Mutex, PoisonError};
+
+use crate::{
+    event::AllocationKind,
+    global::{AllocationCounts, Counters},
+};
+
+/// The number of threads whose operations may be counted at once.
+const MAX_THREADS: usize = 256;
+
+/// The state of a slot that has never been claimed.
+const FREE: u8 = 0;
+/// The state of a slot claimed by a running thread.
+const RUNNING: u8 = 1;
+/// The state of a slot claimed by a thread that has since exited.
+const EXITED: u8 = 2;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNCLAIMED: Slot = Slot::new();
+
+/// The slots in which threads' operations are counted.
+static SLOTS: [Slot; MAX_THREADS] = [UNCLAIMED; MAX_THREADS];
+
+/// The number of slots claimed so far, which numbers the threads that claim
+/// them.
+static CLAIMS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The slot of this thread.
+    static REGISTRATION: Registration = const {
+        Registration {
+            slot: Cell::new(Claim::Unclaimed),
+            named: Cell::new(false),
+        }
+    };
+}
+
+/// The counts of the operations performed by one thread.
+struct Slot {
+    /// `FREE`, `RUNNING` or `EXITED`.
+    state: AtomicU8,
+    /// The number of the thread that claimed this slot.
+    id: AtomicU64,
+    /// Whether `name` is that of the thread that claimed this slot.
+    named: AtomicBool,
+    /// The name of the thread that claimed this slot, if it has one.
+    name: Mutex<Option<String>>,
+    /// The counts of the thread's operations.
+    counters: Counters,
+    /// The number of bytes allocated by the thread, less the number freed by
+    /// it.
+    live: AtomicI64,
+    /// The greatest value of `live` since the peak was last reset.
+    peak: AtomicI64,
+}
+
+impl Slot {
+    /// An unclaimed slot.
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(FREE),
+            id: AtomicU64::new(0),
+            named: AtomicBool::new(false),
+            name: Mutex::new(None),
+            counters: Counters::new(),
+            live: AtomicI64::new(0),
+            peak: AtomicI64::new(0),
+        }
+    }
+
+    /// Claims this slot, whose state is `from`, for the current thread.
+    fn claim(&'static self, from: u8) -> Option<&'static Self> {
+        self.state
+            .compare_exchange(from, RUNNING, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        // the name of the previous claimant is dropped by `name_thread`, as
+        // dropping it here would reenter the allocator
+        self.named.store(false, Ordering::Relaxed);
+        self.counters.reset();
+        self.live.store(0, Ordering::Relaxed);
+        self.peak.store(0, Ordering::Relaxed);
+        self.id.store(
+            CLAIMS.fetch_add(1, Ordering::Relaxed) + 1,
+            Ordering::Relaxed,
+        );
+        Some(self)
+    }
+}
+
+/// The slot of a thread.
+#[derive(Clone, Copy)]
+enum Claim {
+    /// The thread has not yet tried to claim a slot.
+    Unclaimed,
+    /// The thread has claimed the given slot.
+    Claimed(&'static Slot),
+    /// All slots were claimed when the thread tried to claim one.
+    Unavailable,
+}
+
+/// The state of the current thread's slot.
+struct Registration {
+    slot: Cell<Claim>,
+    /// Whether the name of the thread has been recorded in its slot.
+    named: Cell<bool>,
+}
+
+impl Registration {
+    /// The slot of the current thread, claiming one if necessary.
+    fn slot(&self) -> Option<&'static Slot> {
+        match self.slot.get() {
+            Claim::Claimed(slot) => Some(slot),
+            Claim::Unavailable => None,
+            Claim::Unclaimed => {
+                // prefer slots that have never been claimed, so that the
+                // counts of exited threads are retained as long as possible
+                let slot = [FREE, EXITED]
+                    .into_iter()
+                    .find_map(|from| SLOTS.iter().find_map(|slot| slot.claim(from)));
+                self.slot
+                    .set(slot.map_or(Claim::Unavailable, Claim::Claimed));
+                slot
+            }
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Claim::Claimed(slot) = self.slot.get() {
+            slot.state.store(EXITED, Ordering::Release);
+        }
+    }
+}
+
+/// Records an operation that allocated `allocated` bytes and freed `freed`
+/// bytes, in the current thread's slot.
+pub(crate) fn record(kind: AllocationKind, allocated: usize, freed: usize) {
+    let _ = REGISTRATION.try_with(|registration| {
+        let Some(slot) = registration.slot() else {
+            return;
+        };
+        slot.counters.add(kind, 1, allocated as u64, freed as u64);
+        // only this thread modifies its live bytes, so they need not be
+        // updated atomically
+        let delta = (allocated as i64).wrapping_sub(freed as i64);
+        let live = slot.live.load(Ordering::Relaxed).wrapping_add(delta);
+        slot.live.store(live, Ordering::Relaxed);
+        if delta > 0 {
+            slot.peak.fetch_max(live, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Whether the name of the current thread is yet to be recorded in its slot.
+pub(crate) fn naming() -> bool {
+    REGISTRATION
+        .try_with(|registration| {
+            !registration.named.get() && matches!(registration.slot.get(), Claim::Claimed(_))
+        })
+        .unwrap_or(false)
+}
+
+/// Records the name of the current thread in its slot.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+pub(crate) fn name_thread() {
+    let _ = REGISTRATION.try_with(|registration| {
+        let Claim::Claimed(slot) = registration.slot.get() else {
+            return;
+        };
+        registration.named.set(true);
+        let name = std::thread::current().name().map(String::from);
+        *slot.name.lock().unwrap_or_else(PoisonError::into_inner) = name;
+        slot.named.store(true, Ordering::Release);
+    });
+}
+
+/// The counts of the operations performed by a thread. See
+/// [`thread_counts`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct ThreadCounts {
+    /// The number of the thread, in the order in which threads first
+    /// performed an allocator operation, starting from 1.
+    pub id: u64,
+    /// The name of the thread, if it has one and it is known.
+    pub name: Option<String>,
+    /// Whether the thread is still running.
+    pub running: bool,
+    /// The counts of the thread's operations.
+    pub counts: AllocationCounts,
+    /// The number of bytes allocated by the thread, less the number freed by
+    /// it. Blocks allocated by one thread and freed by another reduce the
+    /// live bytes of the latter.
+    pub live_bytes: i64,
+    /// The greatest value of `live_bytes` since the thread began, or last
+    /// reset its peak with [`reset_thread_peak`].
+    pub peak_bytes: u64,
+}
+
+/// The counts of the operations performed by each thread that is running, or
+/// that has exited but whose counts have been retained.
+///
+/// Operations are counted by [`TracingAllocator`](crate::TracingAllocator),
+/// which must be the global allocator, whether or not they are traced; no
+/// subscriber is required. The operations of this function are performed as
+/// instrumentation, so [`count_allocations`](crate::count_allocations) does
+/// not count them, nor does [`forbid_allocations`](crate::forbid_allocations)
+/// forbid them.
+///
+/// ## Usage
+/// ```
+/// use std::alloc::System;
+/// use tracing_allocations::{thread_counts, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// fn main() {
+///     std::thread::spawn(|| drop(vec![0u8; 4096])).join().unwrap();
+///     for thread in thread_counts() {
+///         println!(
+///             "thread {} ({:?}) allocated {} bytes",
+///             thread.id, thread.name, thread.counts.bytes_allocated
+///         );
+///     }
+/// }
+/// ```
+pub fn thread_counts() -> Vec<ThreadCounts> {
+    crate::as_instrumentation(|| {
+        SLOTS
+            .iter()
+            .filter_map(|slot| {
+                let running = match slot.state.load(Ordering::Acquire) {
+                    FREE => return None,
+                    state => state == RUNNING,
+                };
+                let name = if slot.named.load(Ordering::Acquire) {
+                    slot.name
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .clone()
+                } else {
+                    None
+                };
+                Some(ThreadCounts {
+                    id: slot.id.load(Ordering::Relaxed),
+                    name,
+                    running,
+                    counts: slot.counters.load(),
+                    live_bytes: slot.live.load(Ordering::Relaxed),
+                    peak_bytes: slot.peak.load(Ordering::Relaxed).max(0) as u64,
+                })
+            })
+            .collect()
+    })
+}
+
+/// The greatest number of bytes that the current thread has allocated and not
+/// itself freed, since it began or [`reset_thread_peak`] was last called.
+///
+/// Blocks that are allocated by one thread and freed by another count towards
+/// the peak of the thread that allocated them.
+pub fn thread_peak_bytes() -> u64 {
+    REGISTRATION
+        .try_with(|registration| match registration.slot.get() {
+            Claim::Claimed(slot) => slot.peak.load(Ordering::Relaxed).max(0) as u64,
+            _ => 0,
+        })
+        .unwrap_or(0)
+}
+
+/// Resets the peak reported by [`thread_peak_bytes`] to the number of bytes
+/// that the current thread has allocated and not itself freed.
+pub fn reset_thread_peak() {
+    let _ = REGISTRATION.try_with(|registration| {
+        if let Claim::Claimed(slot) = registration.slot.get() {
+            slot.peak
+                .store(slot.live.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    });
+}
@@ -0,0 +1,318 @@
+//! Heap profiles in the format of pprof.
+//!
+//! [`PprofLayer`] captures the stack of each allocation described by the
+//! events it observes, and writes gzipped heap profiles in pprof's
+//! `profile.proto` format, the de facto interchange format of heap profiles,
+//! as read by `go tool pprof` and by continuous profilers such as Polar
+//! Signals.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    event::{AllocationEvent, AllocationKind},
+    stack::{self, Profile, Symbolizer, Totals},
+};
+
+/// The types and units of the values of each sample, in order.
+const SAMPLE_TYPES: [(&str, &str); 4] = [
+    ("alloc_objects", "count"),
+    ("alloc_space", "bytes"),
+    ("inuse_objects", "count"),
+    ("inuse_space", "bytes"),
+];
+
+/// A [`Layer`] that profiles the allocations described by the events it
+/// observes, by the stack that requested them.
+///
+/// The layer captures the stack of each allocation (and reallocation) as its
+/// event is observed, and pairs it with the deallocation of the same block,
+/// if observed. Profiles may be written at any time, from any thread, through
+/// the [`PprofHandle`] returned by [`PprofLayer::handle`]; each has the
+/// sample types `alloc_objects`, `alloc_space`, `inuse_objects` and
+/// `inuse_space` (the default), as in the heap profiles of Go. Sampled and
+/// coalesced events are scaled by [`AllocationEvent::weight`], so the layer
+/// combines well with [byte-weighted
+/// sampling](crate::TracingAllocator::with_byte_sampling); the in-use values
+/// are accurate only if the deallocations of sampled allocations are also
+/// observed.
+///
+/// Requires the `pprof` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::PprofLayer;
+///
+/// let layer = PprofLayer::new();
+/// let profile = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// let file = std::fs::File::create("heap.pb.gz").unwrap();
+/// profile.write_to(file).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PprofLayer {
+    handle: PprofHandle,
+}
+
+impl PprofLayer {
+    /// Constructs a new `PprofLayer`, with an empty profile.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle through which to write the profile of this layer.
+    pub fn handle(&self) -> PprofHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for PprofLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let stack = (event.kind != AllocationKind::Dealloc).then(stack::capture);
+            self.handle
+                .profile
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(&event, stack);
+        });
+    }
+}
+
+/// A handle to the profile of a [`PprofLayer`].
+///
+/// Handles are cheap to clone, and all clones write the same profile.
+#[derive(Clone, Default)]
+pub struct PprofHandle {
+    profile: Arc<Mutex<Profile>>,
+    symbolizer: Arc<Mutex<Symbolizer>>,
+}
+
+impl PprofHandle {
+    /// Writes a gzipped `profile.proto` heap profile of the allocations
+    /// observed so far to `writer`.
+    ///
+    /// Stacks are symbolized as the profile is written, which may be slow the
+    /// first time each stack frame is encountered.
+    pub fn write_to<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        crate::disable_in_scope(|| {
+            let (stacks, duration) = {
+                let profile = self.profile.lock().unwrap_or_else(PoisonError::into_inner);
+                (profile.stacks.clone(), profile.began.elapsed())
+            };
+            let mut symbolizer = self
+                .symbolizer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .saturating_sub(duration);
+            let encoded = encode(
+                &stacks,
+                &mut symbolizer,
+                time.as_nanos() as u64,
+                duration.as_nanos() as u64,
+            );
+            let mut writer = GzEncoder::new(writer, Compression::default());
+            io::Write::write_all(&mut writer, &encoded)?;
+            writer.finish()?;
+            Ok(())
+        })
+    }
+}
+
+impl core::fmt::Debug for PprofHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PprofHandle").finish_non_exhaustive()
+    }
+}
+
+/// Encodes a `profile.proto` message of the given `stacks`, which were
+/// observed over `duration_ns` nanoseconds from `time_ns` nanoseconds after
+/// the Unix epoch.
+fn encode(
+    stacks: &[Totals],
+    symbolizer: &mut Symbolizer,
+    time_ns: u64,
+    duration_ns: u64,
+) -> Vec<u8> {
+    let mut strings = Strings::default();
+    let mut profile = Message::default();
+    for (kind, unit) in SAMPLE_TYPES {
+        profile.message(1, value_type(&mut strings, kind, unit));
+    }
+
+    // the order of repeated fields is immaterial, so samples, locations and
+    // functions are each written as they are first encountered
+    let mut location_ids: HashMap<usize, u64> = HashMap::new();
+    let mut function_ids: HashMap<(String, Option<String>), u64> = HashMap::new();
+    for totals in stacks {
+        let mut ids = Vec::new();
+        for &ip in symbolizer.trim(&totals.stack) {
+            if let Some(&id) = location_ids.get(&ip) {
+                ids.push(id);
+                continue;
+            }
+            let id = location_ids.len() as u64 + 1;
+            location_ids.insert(ip, id);
+            ids.push(id);
+            let mut location = Message::default();
+            location.uint(1, id);
+            location.uint(3, ip as u64);
+            for line in &symbolizer.frame(ip).lines {
+                let key = (line.function.clone(), line.file.clone());
+                let function_id = match function_ids.get(&key) {
+                    Some(&id) => id,
+                    None => {
+                        let id = function_ids.len() as u64 + 1;
+                        function_ids.insert(key, id);
+                        let name = strings.index(&line.function);
+                        let mut function = Message::default();
+                        function.uint(1, id);
+                        function.uint(2, name);
+                        function.uint(3, name);
+                        function.uint(
+                            4,
+                            line.file.as_deref().map_or(0, |file| strings.index(file)),
+                        );
+                        profile.message(5, function);
+                        id
+                    }
+                };
+                let mut encoded = Message::default();
+                encoded.uint(1, function_id);
+                encoded.uint(2, line.line.unwrap_or(0).into());
+                location.message(4, encoded);
+            }
+            profile.message(4, location);
+        }
+        let mut sample = Message::default();
+        sample.packed(1, ids);
+        sample.packed(
+            2,
+            [
+                totals.alloc_objects,
+                totals.alloc_space,
+                totals.inuse_objects,
+                totals.inuse_space,
+            ],
+        );
+        profile.message(2, sample);
+    }
+    profile.uint(9, time_ns);
+    profile.uint(10, duration_ns);
+    profile.message(11, value_type(&mut strings, "space", "bytes"));
+    let default_sample_type = strings.index("inuse_space");
+    profile.uint(14, default_sample_type);
+    for string in strings.table {
+        profile.bytes(6, string.as_bytes());
+    }
+    profile.0
+}
+
+/// A `ValueType` message.
+fn value_type(strings: &mut Strings, kind: &str, unit: &str) -> Message {
+    let mut message = Message::default();
+    message.uint(1, strings.index(kind));
+    message.uint(2, strings.index(unit));
+    message
+}
+
+/// The string table of a profile, which begins with the empty string.
+struct Strings {
+    table: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Self {
+            table: vec![String::new()],
+            indices: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl Strings {
+    /// The index of `string` in the table, adding it if necessary.
+    fn index(&mut self, string: &str) -> u64 {
+        if let Some(&index) = self.indices.get(string) {
+            return index;
+        }
+        let index = self.table.len() as u64;
+        self.table.push(String::from(string));
+        self.indices.insert(String::from(string), index);
+        index
+    }
+}
+
+/// A protocol buffer message, being encoded.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    /// Appends `value` as a varint.
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    /// Appends the key of `field`, with the given wire type.
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    /// Appends an integer `field`, unless it is zero (the default).
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, 0);
+            self.varint(value);
+        }
+    }
+
+    /// Appends a length-delimited `field`.
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, 2);
+        self.varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    /// Appends an embedded message `field`.
+    fn message(&mut self, field: u32, message: Message) {
+        self.bytes(field, &message.0);
+    }
+
+    /// Appends a packed repeated integer `field`, unless it is empty.
+    fn packed(&mut self, field: u32, values: impl IntoIterator<Item = u64>) {
+        let mut packed = Message::default();
+        for value in values {
+            packed.varint(value);
+        }
+        if !packed.0.is_empty() {
+            self.bytes(field, &packed.0);
+        }
+    }
+}
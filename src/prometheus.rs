@@ -0,0 +1,343 @@
+//! Allocation metrics in the text format of Prometheus.
+//!
+//! [`PrometheusLayer`] maintains counters, gauges and a histogram of sizes
+//! from the events it observes, and writes them in Prometheus's text
+//! exposition format, to be scraped from an HTTP handler of the application
+//! (or from [`HeapEndpoint`](crate::HeapEndpoint), with the `http` feature).
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::event::{AllocationEvent, AllocationKind};
+
+/// The upper bounds of the buckets of the histogram of sizes, in bytes.
+const BUCKETS: [u64; 11] = [
+    16,
+    64,
+    256,
+    1 << 10,
+    4 << 10,
+    16 << 10,
+    64 << 10,
+    256 << 10,
+    1 << 20,
+    4 << 20,
+    16 << 20,
+];
+
+/// A [`Layer`] that maintains metrics of the allocator operations described
+/// by the events it observes.
+///
+/// The metrics may be written at any time, from any thread, through the
+/// [`PrometheusHandle`] returned by [`PrometheusLayer::handle`], in
+/// Prometheus's text exposition format. Each is named with the prefix
+/// `tracing_allocations_`:
+/// - **`live_bytes`**, **`live_blocks`**  
+///   gauges of the bytes and blocks allocated and not yet freed; only blocks
+///   whose allocation was observed are counted
+/// - **`tag_live_bytes`**  
+///   a gauge of the live bytes allocated under each
+///   [tag](crate::tag_in_scope), labeled `tag`
+/// - **`allocations_total`**, **`deallocations_total`**,
+///   **`reallocations_total`**  
+///   counters of the operations of each kind
+/// - **`allocated_bytes_total`**, **`freed_bytes_total`**  
+///   counters of the bytes allocated and freed; reallocations count the size
+///   of the new block as allocated, and that of the existing block as freed
+/// - **`allocation_size_bytes`**  
+///   a histogram of the sizes of the blocks allocated (and reallocated), with
+///   buckets from 16 bytes to 16 MiB, by powers of four
+///
+/// Sampled and coalesced events are scaled by [`AllocationEvent::weight`].
+///
+/// Requires the `prometheus` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::PrometheusLayer;
+///
+/// let layer = PrometheusLayer::new();
+/// let metrics = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// // e.g., in the handler of `/metrics`
+/// let mut body = Vec::new();
+/// metrics.write_to(&mut body).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusLayer {
+    handle: PrometheusHandle,
+}
+
+impl PrometheusLayer {
+    /// Constructs a new `PrometheusLayer`, with all metrics zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle through which to write the metrics of this layer.
+    pub fn handle(&self) -> PrometheusHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for PrometheusLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(decoded) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let mut tag = String::new();
+            let tag = crate::event::tag_of(event, &mut tag).then_some(tag);
+            self.handle.metrics().record(&decoded, tag);
+        });
+    }
+}
+
+/// A handle to the metrics of a [`PrometheusLayer`].
+///
+/// Handles are cheap to clone, and all clones write the same metrics.
+#[derive(Clone, Default)]
+pub struct PrometheusHandle {
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl PrometheusHandle {
+    /// Writes the metrics to `writer`, in Prometheus's text exposition format
+    /// (version 0.0.4), as served with the content type `text/plain;
+    /// version=0.0.4`.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        crate::disable_in_scope(|| {
+            let metrics = self.metrics();
+            let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+                writeln!(writer, "# HELP tracing_allocations_{} {}", name, help)?;
+                writeln!(writer, "# TYPE tracing_allocations_{} {}", name, kind)?;
+                writeln!(writer, "tracing_allocations_{} {}", name, value)
+            };
+            metric(
+                "live_bytes",
+                "gauge",
+                "Bytes allocated and not yet freed.",
+                metrics.live_bytes,
+            )?;
+            metric(
+                "live_blocks",
+                "gauge",
+                "Blocks allocated and not yet freed.",
+                metrics.live_blocks,
+            )?;
+            metric(
+                "allocations_total",
+                "counter",
+                "Allocations, zeroed or not.",
+                metrics.allocations,
+            )?;
+            metric(
+                "deallocations_total",
+                "counter",
+                "Deallocations.",
+                metrics.deallocations,
+            )?;
+            metric(
+                "reallocations_total",
+                "counter",
+                "Reallocations.",
+                metrics.reallocations,
+            )?;
+            metric(
+                "allocated_bytes_total",
+                "counter",
+                "Bytes allocated.",
+                metrics.bytes_allocated,
+            )?;
+            metric(
+                "freed_bytes_total",
+                "counter",
+                "Bytes freed.",
+                metrics.bytes_freed,
+            )?;
+
+            writeln!(
+                writer,
+                "# HELP tracing_allocations_tag_live_bytes Bytes allocated under each tag and not yet freed."
+            )?;
+            writeln!(writer, "# TYPE tracing_allocations_tag_live_bytes gauge")?;
+            let mut tags: Vec<_> = metrics.tags.iter().collect();
+            tags.sort();
+            for (tag, live) in tags {
+                write!(writer, "tracing_allocations_tag_live_bytes{{tag=\"")?;
+                escape(&mut writer, tag)?;
+                writeln!(writer, "\"}} {}", live)?;
+            }
+
+            writeln!(
+                writer,
+                "# HELP tracing_allocations_allocation_size_bytes Sizes of the blocks allocated."
+            )?;
+            writeln!(
+                writer,
+                "# TYPE tracing_allocations_allocation_size_bytes histogram"
+            )?;
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&metrics.buckets) {
+                cumulative += count;
+                writeln!(
+                    writer,
+                    "tracing_allocations_allocation_size_bytes_bucket{{le=\"{}\"}} {}",
+                    bound, cumulative
+                )?;
+            }
+            writeln!(
+                writer,
+                "tracing_allocations_allocation_size_bytes_bucket{{le=\"+Inf\"}} {}",
+                metrics.sizes_count
+            )?;
+            writeln!(
+                writer,
+                "tracing_allocations_allocation_size_bytes_sum {}",
+                metrics.sizes_sum
+            )?;
+            writeln!(
+                writer,
+                "tracing_allocations_allocation_size_bytes_count {}",
+                metrics.sizes_count
+            )
+        })
+    }
+
+    fn metrics(&self) -> MutexGuard<'_, Metrics> {
+        self.metrics.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl core::fmt::Debug for PrometheusHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PrometheusHandle").finish_non_exhaustive()
+    }
+}
+
+/// The metrics maintained by a [`PrometheusLayer`].
+#[derive(Default)]
+struct Metrics {
+    live_bytes: u64,
+    live_blocks: u64,
+    allocations: u64,
+    deallocations: u64,
+    reallocations: u64,
+    bytes_allocated: u64,
+    bytes_freed: u64,
+    /// The number of blocks allocated with a size in each bucket, and not in
+    /// a lesser one.
+    buckets: [u64; BUCKETS.len()],
+    sizes_sum: u64,
+    sizes_count: u64,
+    /// The live bytes of each tag.
+    tags: HashMap<String, u64>,
+    /// The live blocks, by address.
+    blocks: HashMap<u64, Block>,
+}
+
+/// A live block.
+struct Block {
+    /// The size of the block, scaled by the weight of its event.
+    size: u64,
+    /// The number of blocks this block stands for.
+    weight: u64,
+    /// The tag under which it was allocated, if any.
+    tag: Option<String>,
+}
+
+impl Metrics {
+    /// Records the operation described by `event`, which was performed under
+    /// `tag`.
+    fn record(&mut self, event: &AllocationEvent, tag: Option<String>) {
+        let weight = event.weight();
+        let scale = |n: u64| (n as f64 * weight).round() as u64;
+        let objects = scale(1);
+        match event.kind {
+            AllocationKind::Alloc | AllocationKind::AllocZeroed => self.allocations += objects,
+            AllocationKind::Dealloc => {
+                self.deallocations += objects;
+                self.bytes_freed += scale(event.size);
+                self.free(event.addr);
+                return;
+            }
+            AllocationKind::Realloc => {
+                self.reallocations += objects;
+                self.bytes_freed += scale(event.old_size.unwrap_or(0));
+                self.free(event.old_addr.unwrap_or(event.addr));
+            }
+        }
+        let size = scale(event.size);
+        self.bytes_allocated += size;
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| event.size <= bound)
+            .unwrap_or(BUCKETS.len());
+        if let Some(count) = self.buckets.get_mut(bucket) {
+            *count += objects;
+        }
+        self.sizes_sum += size;
+        self.sizes_count += objects;
+
+        self.live_bytes += size;
+        self.live_blocks += objects;
+        if let Some(tag) = &tag {
+            *self.tags.entry(tag.clone()).or_default() += size;
+        }
+        let block = Block {
+            size,
+            weight: objects,
+            tag,
+        };
+        if let Some(previous) = self.blocks.insert(event.addr, block) {
+            // the deallocation of the previous block was not observed
+            self.forget(&previous);
+        }
+    }
+
+    /// Records the deallocation of the block at `addr`, if its allocation
+    /// was observed.
+    fn free(&mut self, addr: u64) {
+        if let Some(block) = self.blocks.remove(&addr) {
+            self.forget(&block);
+        }
+    }
+
+    /// Stops counting `block` as live.
+    fn forget(&mut self, block: &Block) {
+        self.live_bytes -= block.size;
+        self.live_blocks -= block.weight;
+        if let Some(tag) = &block.tag {
+            if let Some(live) = self.tags.get_mut(tag) {
+                *live -= block.size;
+            }
+        }
+    }
+}
+
+/// Writes `value` escaped for use within a label value.
+fn escape<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    for c in value.chars() {
+        match c {
+            '\\' => writer.write_all(b"\\\\")?,
+            '"' => writer.write_all(b"\\\"")?,
+            '\n' => writer.write_all(b"\\n")?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+    Ok(())
+}
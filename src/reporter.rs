@@ -0,0 +1,105 @@
+//! Periodic reports of heap usage.
+//!
+//! [`start_reporter`] spawns a thread that emits a summary of the
+//! process-wide counters (see [`stats`](crate::stats())) at a fixed interval,
+//! which is enough to chart the heap of a service over time without consuming
+//! the allocation events themselves.
+
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Starts a thread that emits an `INFO` event, with target
+/// "tracing::allocator", every `interval`, until the returned [`Reporter`] is
+/// dropped.
+///
+/// Allocation tracing is disabled on the reporter thread. Each event has the
+/// following fields:
+/// - **`live_bytes`: [`u64`]**  
+///   the number of bytes currently allocated
+/// - **`allocated_bytes`: [`u64`]**  
+///   the total size of the blocks allocated since the previous report
+/// - **`alloc_rate`: [`u64`]**  
+///   `allocated_bytes`, per second
+/// - **`peak_bytes`: [`u64`]**  
+///   the greatest number of bytes that were allocated at once since the
+///   previous report
+///
+/// Operations are counted by [`TracingAllocator`](crate::TracingAllocator),
+/// which must be the global allocator, whether or not they are traced.
+///
+/// ## Usage
+/// ```
+/// use std::{alloc::System, time::Duration};
+/// use tracing_allocations::TracingAllocator;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// fn main() {
+///     let _reporter = tracing_allocations::start_reporter(Duration::from_secs(10));
+///     /* your code here */
+/// }
+/// ```
+pub fn start_reporter(interval: Duration) -> Reporter {
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = thread::Builder::new()
+        .name(String::from("tracing-allocations-reporter"))
+        .spawn(move || {
+            crate::disable_for_thread();
+            let mut previous = (Instant::now(), crate::stats().counts.bytes_allocated);
+            crate::global::take_reported_peak();
+            // the sender is never used; it is dropped to stop the reporter
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let now = Instant::now();
+                let stats = crate::stats();
+                let allocated_bytes = stats.counts.bytes_allocated.wrapping_sub(previous.1);
+                let elapsed = now.duration_since(previous.0).as_secs_f64();
+                let alloc_rate = if elapsed > 0.0 {
+                    (allocated_bytes as f64 / elapsed) as u64
+                } else {
+                    0
+                };
+                tracing::event!(
+                    target: "tracing::allocator",
+                    tracing::Level::INFO,
+                    live_bytes = stats.live_bytes,
+                    allocated_bytes,
+                    alloc_rate,
+                    peak_bytes = crate::global::take_reported_peak(),
+                    "heap report"
+                );
+                previous = (now, stats.counts.bytes_allocated);
+            }
+        })
+        .expect("failed to spawn the reporter thread");
+    Reporter {
+        stop: Some(stop),
+        thread: Some(thread),
+    }
+}
+
+/// A handle to the thread started by [`start_reporter`]; dropping it stops
+/// the thread.
+#[derive(Debug)]
+#[must_use = "the reporter stops when dropped"]
+pub struct Reporter {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reporter {
+    /// Stops the reporter thread, and waits for it to exit.
+    pub fn stop(self) {}
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
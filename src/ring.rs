@@ -0,0 +1,539 @@
+//! A ring buffer of allocation events in shared memory.
+//!
+//! [`RingLayer`] writes the events it observes into a ring buffer in a
+//! memory-mapped file, which a [`RingReader`] in another process maps and
+//! reads. Writing an event takes neither a lock nor a system call, and the
+//! instrumented process never waits on, nor is affected by, the reader: a
+//! reader that falls behind, or crashes, only loses events.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+    ptr::NonNull,
+    sync::{
+        atomic::{fence, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use memmap2::{Mmap, MmapMut};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::event::{AllocationEvent, AllocationKind};
+
+/// The first word of a ring buffer.
+const MAGIC: u64 = u64::from_le_bytes(*b"TAEVRING");
+
+/// The version of the layout.
+const VERSION: u64 = 1;
+
+/// The number of words of the header, and of each slot.
+const WORDS: usize = 16;
+
+/// The index in the header of the number of slots.
+const CAPACITY: usize = 2;
+
+/// The index in the header of the number of events claimed so far.
+const HEAD: usize = 3;
+
+/// The kinds of operation, by index.
+const KINDS: [AllocationKind; 4] = [
+    AllocationKind::Alloc,
+    AllocationKind::AllocZeroed,
+    AllocationKind::Dealloc,
+    AllocationKind::Realloc,
+];
+
+/// A [`Layer`] that writes the allocator operations described by the events
+/// it observes into a ring buffer in shared memory, from which a
+/// [`RingReader`] in another process reads them.
+///
+/// The buffer is a memory-mapped file, created by [`RingLayer::create`]; on
+/// Linux, a file in `/dev/shm` is kept in memory. All threads write to the
+/// same buffer: each event claims the next slot with an atomic increment,
+/// and is written there with atomic stores, so that observing an event takes
+/// neither a lock nor a system call, nor allocates. The buffer holds the most
+/// recent events; once it is full, each event overwrites the oldest, whether
+/// or not it has been read, so the instrumented process never waits on the
+/// reader, and a reader that crashes, or never attaches, has no effect on it.
+/// Callers and tags are not recorded.
+///
+/// Requires the `ring` feature.
+///
+/// ## Layout
+/// The file is an array of 64-bit words, in the byte order of the machine,
+/// in blocks of 16: a header, then the slots. The header holds the magic
+/// bytes `TAEVRING`, the version of the layout (currently 1), the number of
+/// slots (a power of two), and the number of events claimed so far, from
+/// which the slot of the event with sequence number `n` is `n` modulo the
+/// number of slots.
+///
+/// The first word of each slot is a stamp: `2n + 1` while the event with
+/// sequence number `n` is being written, and `2n + 2` once it has been. Then
+/// follow the index of the event's kind (alloc, alloc_zeroed, dealloc,
+/// realloc) in the low byte, and a bit set of the optional fields that are
+/// present above it; the event's address and size; and each optional field,
+/// present or not, in the order of the bits: alignment (bit 0), usable size
+/// (1), old address (2), old size (3), zeroed (4; 1 if zeroed), timestamp
+/// (5), span ID (6), sample rate (7), sample interval (8), count (9), age
+/// (10), and age in events (11).
+///
+/// A writer that finds its slot still being written by a writer a lap
+/// behind discards its event, rather than wait; [`RingReader`] counts such
+/// events as lost once they have been overwritten.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::RingLayer;
+///
+/// let layer = RingLayer::create("/dev/shm/allocations", 1 << 20).unwrap();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+/// ```
+#[derive(Clone, Debug)]
+pub struct RingLayer {
+    ring: Arc<Ring>,
+}
+
+impl RingLayer {
+    /// Creates a ring buffer of at least `capacity` events (rounded up to a
+    /// power of two) in the file at `path`, replacing its contents, and
+    /// constructs a new `RingLayer` that writes to it.
+    ///
+    /// Each event takes 128 bytes.
+    pub fn create<P: AsRef<Path>>(path: P, capacity: usize) -> io::Result<Self> {
+        crate::disable_in_scope(|| {
+            let capacity = capacity.max(1).next_power_of_two();
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            file.set_len(((capacity + 1) * WORDS * 8) as u64)?;
+            // SAFETY: the file was just created with this length, and is only
+            // accessed through atomics
+            let mut map = unsafe { MmapMut::map_mut(&file)? };
+            let words =
+                NonNull::new(map.as_mut_ptr().cast::<AtomicU64>()).expect("a mapping is not null");
+            let ring = Ring {
+                _map: map,
+                words,
+                mask: capacity as u64 - 1,
+            };
+            let header = ring.slot(None);
+            header[1].store(VERSION, Ordering::Relaxed);
+            header[CAPACITY].store(capacity as u64, Ordering::Relaxed);
+            // readers check the magic bytes last
+            header[0].store(MAGIC, Ordering::Release);
+            Ok(Self {
+                ring: Arc::new(ring),
+            })
+        })
+    }
+}
+
+impl<S> Layer<S> for RingLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if let Some(event) = AllocationEvent::from_event(event) {
+            self.ring.push(&event);
+        }
+    }
+}
+
+/// The mapping of a ring buffer.
+struct Ring {
+    _map: MmapMut,
+    /// The words of the mapping.
+    words: NonNull<AtomicU64>,
+    /// The number of slots, less one.
+    mask: u64,
+}
+
+// SAFETY: the words of the mapping are only accessed through atomics
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// The words of the slot of sequence number `n`, or of the header.
+    fn slot(&self, n: Option<u64>) -> &[AtomicU64] {
+        let index = n.map_or(0, |n| (n & self.mask) as usize + 1);
+        // SAFETY: the mapping holds `mask + 2` blocks of words, and lives as
+        // long as `self`
+        unsafe { std::slice::from_raw_parts(self.words.as_ptr().add(index * WORDS), WORDS) }
+    }
+
+    /// Writes `event` into the next slot.
+    fn push(&self, event: &AllocationEvent) {
+        let n = self.slot(None)[HEAD].fetch_add(1, Ordering::Relaxed);
+        let slot = self.slot(Some(n));
+        let stamp = slot[0].load(Ordering::Relaxed);
+        // a writer a lap behind is still writing, or a writer a lap ahead has
+        // claimed the slot
+        if stamp % 2 == 1 || stamp > 2 * n {
+            return;
+        }
+        if slot[0]
+            .compare_exchange(stamp, 2 * n + 1, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        fence(Ordering::Release);
+        for (word, value) in slot[1..].iter().zip(encode(event)) {
+            word.store(value, Ordering::Relaxed);
+        }
+        slot[0].store(2 * n + 2, Ordering::Release);
+    }
+}
+
+impl core::fmt::Debug for Ring {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Ring")
+            .field("capacity", &(self.mask + 1))
+            .finish_non_exhaustive()
+    }
+}
+
+/// The optional fields of an event, in the order of their bits.
+fn optional_fields(event: &AllocationEvent) -> [Option<u64>; 12] {
+    [
+        event.align,
+        event.usable_size,
+        event.old_addr,
+        event.old_size,
+        event.zeroed.map(u64::from),
+        event.timestamp_ns,
+        event.span_id,
+        event.sample_rate,
+        event.sample_interval,
+        event.count,
+        event.age_ns,
+        event.age_events,
+    ]
+}
+
+/// The words of a slot, after its stamp, that encode `event`.
+fn encode(event: &AllocationEvent) -> impl Iterator<Item = u64> {
+    let kind = KINDS
+        .iter()
+        .position(|&kind| kind == event.kind)
+        .unwrap_or(0) as u64;
+    let fields = optional_fields(event);
+    let present = fields
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| field.is_some())
+        .fold(0, |present, (bit, _)| present | 1 << bit);
+    [kind | present << 8, event.addr, event.size]
+        .into_iter()
+        .chain(fields.into_iter().map(|field| field.unwrap_or(0)))
+}
+
+/// The event encoded by the words of a slot, after its stamp, or `None` if
+/// its kind is unknown.
+fn decode(words: &[u64; WORDS - 1]) -> Option<AllocationEvent> {
+    let kind = *KINDS.get((words[0] & 0xff) as usize)?;
+    let present = words[0] >> 8;
+    let mut event = AllocationEvent::new(kind, words[1], words[2]);
+    let field = |bit: usize| (present & 1 << bit != 0).then_some(words[3 + bit]);
+    event.align = field(0);
+    event.usable_size = field(1);
+    event.old_addr = field(2);
+    event.old_size = field(3);
+    event.zeroed = field(4).map(|zeroed| zeroed != 0);
+    event.timestamp_ns = field(5);
+    event.span_id = field(6);
+    event.sample_rate = field(7);
+    event.sample_interval = field(8);
+    event.count = field(9);
+    event.age_ns = field(10);
+    event.age_events = field(11);
+    Some(event)
+}
+
+/// Reads allocation events from the ring buffer of a [`RingLayer`], in
+/// another process.
+///
+/// The reader maps the file read-only, so it cannot disturb the writers. It
+/// begins with the oldest event still in the buffer, and reads events in the
+/// order in which their slots were claimed; events that are overwritten
+/// before they are read, or that were discarded by their writers, are
+/// skipped and counted by [`RingReader::lost`].
+///
+/// Requires the `ring` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use std::{thread, time::Duration};
+/// use tracing_allocations::RingReader;
+///
+/// let mut reader = RingReader::open("/dev/shm/allocations")?;
+/// loop {
+///     while let Some(event) = reader.read() {
+///         /* your code here */
+///     }
+///     thread::sleep(Duration::from_millis(10));
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct RingReader {
+    map: Mmap,
+    /// The number of slots, less one.
+    mask: u64,
+    /// The sequence number of the next event to read.
+    next: u64,
+    /// The number of events skipped.
+    lost: u64,
+}
+
+impl RingReader {
+    /// Maps the ring buffer in the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is only accessed through atomics
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+        if map.len() < WORDS * 8 || map.as_ptr().align_offset(8) != 0 {
+            return Err(invalid("not an allocation event ring buffer"));
+        }
+        let mut reader = Self {
+            map,
+            mask: 0,
+            next: 0,
+            lost: 0,
+        };
+        let header = reader.slot(None);
+        if header[0].load(Ordering::Acquire) != MAGIC {
+            return Err(invalid("not an allocation event ring buffer"));
+        }
+        if header[1].load(Ordering::Relaxed) != VERSION {
+            return Err(invalid("unsupported version of the ring buffer layout"));
+        }
+        let capacity = header[CAPACITY].load(Ordering::Relaxed);
+        if !capacity.is_power_of_two()
+            || reader.map.len() as u64 != (capacity + 1) * WORDS as u64 * 8
+        {
+            return Err(invalid("ring buffer has an invalid capacity"));
+        }
+        let head = header[HEAD].load(Ordering::Relaxed);
+        reader.mask = capacity - 1;
+        reader.next = head.saturating_sub(capacity);
+        Ok(reader)
+    }
+
+    /// The next event, or `None` if it has not yet been written; call again
+    /// later to read the events written since.
+    pub fn read(&mut self) -> Option<AllocationEvent> {
+        loop {
+            let capacity = self.mask + 1;
+            let head = self.slot(None)[HEAD].load(Ordering::Relaxed);
+            if head.saturating_sub(self.next) > capacity {
+                // the writers have lapped the reader
+                let oldest = head - capacity;
+                self.lost += oldest - self.next;
+                self.next = oldest;
+            }
+            if self.next == head {
+                return None;
+            }
+            let n = self.next;
+            let slot = self.slot(Some(n));
+            let stamp = slot[0].load(Ordering::Acquire);
+            if stamp < 2 * n + 2 {
+                // still being written, or discarded by its writer, in which
+                // case it is skipped once lapped
+                return None;
+            }
+            let mut words = [0; WORDS - 1];
+            for (value, word) in words.iter_mut().zip(&slot[1..]) {
+                *value = word.load(Ordering::Relaxed);
+            }
+            fence(Ordering::Acquire);
+            let overwritten = slot[0].load(Ordering::Relaxed) != 2 * n + 2;
+            self.next += 1;
+            match decode(&words) {
+                Some(event) if stamp == 2 * n + 2 && !overwritten => return Some(event),
+                _ => self.lost += 1,
+            }
+        }
+    }
+
+    /// The number of events skipped so far because they were overwritten
+    /// before they were read, or discarded by their writers.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// The words of the slot of sequence number `n`, or of the header.
+    fn slot(&self, n: Option<u64>) -> &[AtomicU64] {
+        let index = n.map_or(0, |n| (n & self.mask) as usize + 1);
+        // SAFETY: the mapping holds at least `mask + 2` blocks of words, is
+        // aligned, and lives as long as `self`
+        unsafe {
+            std::slice::from_raw_parts(
+                self.map.as_ptr().cast::<AtomicU64>().add(index * WORDS),
+                WORDS,
+            )
+        }
+    }
+}
+
+impl core::fmt::Debug for RingReader {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RingReader")
+            .field("capacity", &(self.mask + 1))
+            .field("next", &self.next)
+            .field("lost", &self.lost)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, thread};
+
+    use super::{RingLayer, RingReader};
+    use crate::event::{AllocationEvent, AllocationKind};
+
+    /// A path in the temporary directory that is unique to the named test.
+    fn path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "tracing-allocations-ring-{}-{}",
+            std::process::id(),
+            test
+        ))
+    }
+
+    /// The `n`th event, a reallocation with every optional field present.
+    fn event(n: u64) -> AllocationEvent {
+        let mut event = AllocationEvent::new(AllocationKind::Realloc, 0x1000 + n, n);
+        event.align = Some(8);
+        event.usable_size = Some(n + 8);
+        event.old_addr = Some(0x2000 + n);
+        event.old_size = Some(n / 2);
+        event.zeroed = Some(n & 1 == 0);
+        event.timestamp_ns = Some(n * 1000);
+        event.span_id = Some(n + 1);
+        event.sample_rate = Some(1);
+        event.sample_interval = Some(2);
+        event.count = Some(3);
+        event.age_ns = Some(n * 10);
+        event.age_events = Some(n * 20);
+        event
+    }
+
+    fn read_all(reader: &mut RingReader) -> Vec<AllocationEvent> {
+        std::iter::from_fn(|| reader.read()).collect()
+    }
+
+    #[test]
+    fn overwrites_the_oldest_events() {
+        let path = path("overwrites");
+        let layer = RingLayer::create(&path, 8).unwrap();
+        let mut reader = RingReader::open(&path).unwrap();
+        for n in 0..20 {
+            layer.ring.push(&event(n));
+        }
+        let expected: Vec<_> = (12..20).map(event).collect();
+        assert_eq!(read_all(&mut reader), expected);
+        assert_eq!(reader.lost(), 12);
+
+        // the reader resumes where it left off
+        for n in 20..23 {
+            layer.ring.push(&event(n));
+        }
+        let expected: Vec<_> = (20..23).map(event).collect();
+        assert_eq!(read_all(&mut reader), expected);
+        assert_eq!(reader.lost(), 12);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reader_opened_mid_stream() {
+        let path = path("mid_stream");
+        let layer = RingLayer::create(&path, 8).unwrap();
+        for n in 0..5 {
+            layer.ring.push(&event(n));
+        }
+        let mut early = RingReader::open(&path).unwrap();
+        let expected: Vec<_> = (0..5).map(event).collect();
+        assert_eq!(read_all(&mut early), expected);
+        assert_eq!(early.lost(), 0);
+
+        for n in 5..30 {
+            layer.ring.push(&event(n));
+        }
+        // a reader opened once the buffer has wrapped begins with the oldest
+        // event still in it, and has lost nothing
+        let mut late = RingReader::open(&path).unwrap();
+        let expected: Vec<_> = (22..30).map(event).collect();
+        assert_eq!(read_all(&mut late), expected);
+        assert_eq!(late.lost(), 0);
+        assert_eq!(read_all(&mut early), expected);
+        assert_eq!(early.lost(), 17);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writers() {
+        const THREADS: u64 = 4;
+        const EVENTS: u64 = 50_000;
+        const CAPACITY: u64 = 1024;
+        let path = path("concurrent");
+        let layer = RingLayer::create(&path, CAPACITY as usize).unwrap();
+        let mut reader = RingReader::open(&path).unwrap();
+
+        let writers: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let layer = layer.clone();
+                thread::spawn(move || {
+                    for i in 0..EVENTS {
+                        let addr = thread << 32 | i;
+                        let event = AllocationEvent::new(AllocationKind::Alloc, addr, addr ^ 0xff);
+                        layer.ring.push(&event);
+                    }
+                })
+            })
+            .collect();
+        let mut read = Vec::new();
+        while writers.iter().any(|writer| !writer.is_finished()) {
+            read.extend(std::iter::from_fn(|| reader.read()));
+        }
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+        // a lap of events from one writer ends any run of events discarded by
+        // their writers, so that they are counted as lost
+        for i in 0..CAPACITY {
+            let addr = THREADS << 32 | i;
+            let event = AllocationEvent::new(AllocationKind::Alloc, addr, addr ^ 0xff);
+            layer.ring.push(&event);
+        }
+        read.extend(std::iter::from_fn(|| reader.read()));
+
+        assert_eq!(
+            read.len() as u64 + reader.lost(),
+            THREADS * EVENTS + CAPACITY
+        );
+        let mut last = vec![None; THREADS as usize + 1];
+        for event in &read {
+            // no event is torn
+            assert_eq!(event.size, event.addr ^ 0xff);
+            // the events of each thread are read in order
+            let (thread, i) = ((event.addr >> 32) as usize, event.addr & 0xffff_ffff);
+            assert!(last[thread] < Some(i));
+            last[thread] = Some(i);
+        }
+        assert_eq!(last[THREADS as usize], Some(CAPACITY - 1));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+//! Allocation context on Sentry events.
+//!
+//! [`SentryIntegration`] attaches the process-wide allocation statistics, and
+//! the tags whose live bytes grew the most since the previous event, to each
+//! event reported to Sentry, so that errors and crashes that follow memory
+//! pressure arrive with evidence of it.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+
+use sentry::{
+    protocol::{Context, Event, Map, Value},
+    ClientOptions, Integration,
+};
+
+/// A Sentry [`Integration`] that attaches allocation statistics to events.
+///
+/// Each event reported to Sentry is given an `allocations` context, with:
+/// - **`live_bytes`**, **`peak_bytes`**  
+///   the bytes currently allocated, and the greatest number allocated at
+///   once (see [`stats`](crate::stats()))
+/// - **`allocations`**, **`deallocations`**, **`reallocations`**,
+///   **`bytes_allocated`**, **`bytes_freed`**  
+///   the process-wide counts of allocator operations
+/// - **`top_tags`**  
+///   the five [tags](crate::tag_in_scope), or the number given to
+///   [`SentryIntegration::with_top_tags`], whose live bytes grew the most
+///   since the previous event (or since the program began), each with its
+///   `tag`, its `live_bytes`, and their `growth`
+/// - **`busy_shards`**  
+///   the number of shards of the live table that were busy, and whose blocks
+///   are not included in `top_tags`
+///
+/// Operations are counted by [`TracingAllocator`](crate::TracingAllocator),
+/// which must be the global allocator. The live bytes of each tag are read
+/// from a [snapshot](crate::snapshot()) of the live table, so `top_tags` is
+/// empty unless the [live table](crate::TracingAllocator::with_live_table)
+/// is enabled. The snapshot skips, rather than waits for, the shards of the
+/// live table that are locked when the event is reported, so that an event
+/// reported while the thread itself holds one does not deadlock.
+///
+/// Requires the `sentry` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_allocations::SentryIntegration;
+///
+/// let _guard = sentry::init(
+///     sentry::ClientOptions::default().add_integration(SentryIntegration::new()),
+/// );
+///
+/// /* your code here */
+/// ```
+pub struct SentryIntegration {
+    /// The number of tags to attach.
+    top_tags: usize,
+    /// The live bytes of each tag, as of the previous event.
+    previous: Mutex<HashMap<String, u64>>,
+}
+
+impl SentryIntegration {
+    /// Constructs a new `SentryIntegration`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attach the `n` tags whose live bytes grew the most, rather than five.
+    pub fn with_top_tags(mut self, n: usize) -> Self {
+        self.top_tags = n;
+        self
+    }
+
+    /// The `allocations` context.
+    fn context(&self) -> Map<String, Value> {
+        let stats = crate::stats();
+        let counts = &stats.counts;
+
+        let mut tags: HashMap<String, u64> = HashMap::new();
+        let (snapshot, busy) = crate::snapshot::try_snapshot();
+        for group in snapshot.groups {
+            if let Some(tag) = group.tag {
+                *tags.entry(tag).or_default() += group.bytes;
+            }
+        }
+        let mut previous = self.previous.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut growth: Vec<(&String, u64, i64)> = tags
+            .iter()
+            .map(|(tag, &live)| {
+                let before = previous.get(tag).copied().unwrap_or(0);
+                (tag, live, live as i64 - before as i64)
+            })
+            .collect();
+        growth.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(b.0)));
+        let top_tags: Vec<Value> = growth
+            .into_iter()
+            .take(self.top_tags)
+            .map(|(tag, live, growth)| {
+                Value::from_iter([
+                    ("tag", Value::from(tag.as_str())),
+                    ("live_bytes", Value::from(live)),
+                    ("growth", Value::from(growth)),
+                ])
+            })
+            .collect();
+        *previous = tags;
+
+        let mut context = Map::new();
+        let mut insert = |key: &str, value: Value| {
+            context.insert(String::from(key), value);
+        };
+        insert("live_bytes", Value::from(stats.live_bytes));
+        insert("peak_bytes", Value::from(stats.peak_bytes));
+        insert("allocations", Value::from(counts.allocations));
+        insert("deallocations", Value::from(counts.deallocations));
+        insert("reallocations", Value::from(counts.reallocations));
+        insert("bytes_allocated", Value::from(counts.bytes_allocated));
+        insert("bytes_freed", Value::from(counts.bytes_freed));
+        insert("top_tags", Value::from(top_tags));
+        insert("busy_shards", Value::from(busy as u64));
+        context
+    }
+}
+
+impl Default for SentryIntegration {
+    fn default() -> Self {
+        Self {
+            top_tags: 5,
+            previous: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Integration for SentryIntegration {
+    fn name(&self) -> &'static str {
+        "tracing-allocations"
+    }
+
+    fn process_event(
+        &self,
+        mut event: Event<'static>,
+        _options: &ClientOptions,
+    ) -> Option<Event<'static>> {
+        crate::disable_in_scope(|| {
+            let context = Context::Other(self.context());
+            event.contexts.insert(String::from("allocations"), context);
+        });
+        Some(event)
+    }
+}
+
+impl core::fmt::Debug for SentryIntegration {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SentryIntegration")
+            .field("top_tags", &self.top_tags)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,275 @@
+//! Snapshots of the live heap.
+//!
+//! A [`HeapSnapshot`] summarizes the blocks recorded in the live table at an
+//! instant, grouped by the caller that allocated them and the
+//! [tag](crate::tag_in_scope) under which they were allocated. Unlike the
+//! table, a snapshot owns its contents, so it may be kept, serialized (with
+//! the `serde` feature), and compared with later snapshots by
+//! [`HeapSnapshot::diff`].
+
+use std::collections::BTreeMap;
+
+use crate::live;
+
+/// A summary of the live heap at an instant. See [`snapshot`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct HeapSnapshot {
+    /// The groups of live blocks, in descending order of their total size.
+    pub groups: Vec<HeapGroup>,
+}
+
+/// The live blocks allocated by one caller, under one tag.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct HeapGroup {
+    /// The demangled name of the function that allocated the blocks, if
+    /// known.
+    pub symbol: Option<String>,
+    /// The source file of the allocations, if known.
+    pub file: Option<String>,
+    /// The source line of the allocations, if known.
+    pub line: Option<u32>,
+    /// The tag under which the blocks were allocated, if any.
+    pub tag: Option<String>,
+    /// The number of live blocks.
+    pub blocks: u64,
+    /// The total size of the live blocks, as requested.
+    pub bytes: u64,
+}
+
+impl HeapSnapshot {
+    /// The number of live blocks in all groups.
+    pub fn total_blocks(&self) -> u64 {
+        self.groups.iter().map(|group| group.blocks).sum()
+    }
+
+    /// The total size of the live blocks in all groups.
+    pub fn total_bytes(&self) -> u64 {
+        self.groups.iter().map(|group| group.bytes).sum()
+    }
+
+    /// The changes to each group of live blocks between this snapshot and a
+    /// `later` one, in descending order of growth.
+    ///
+    /// Groups are identified by their caller and tag. Diffing snapshots taken
+    /// at two points in time is the standard means of finding slow leaks: the
+    /// groups that grow the most are the likeliest culprits.
+    ///
+    /// ## Usage
+    /// ```
+    /// use std::alloc::System;
+    /// use tracing_allocations::{snapshot, TracingAllocator};
+    ///
+    /// #[global_allocator]
+    /// static ALLOCATOR: TracingAllocator<System> =
+    ///     TracingAllocator::new(System).with_live_table(true);
+    ///
+    /// fn main() {
+    ///     let before = snapshot();
+    ///     /* your code here */
+    ///     let after = snapshot();
+    ///
+    ///     for group in before.diff(&after).groups.iter().take(10) {
+    ///         println!("{:+} bytes: {:?}", group.bytes_delta(), group);
+    ///     }
+    /// }
+    /// ```
+    pub fn diff(&self, later: &HeapSnapshot) -> HeapDiff {
+        let mut groups: BTreeMap<_, HeapGroupDiff> = BTreeMap::new();
+        for (group, later) in self
+            .groups
+            .iter()
+            .map(|group| (group, false))
+            .chain(later.groups.iter().map(|group| (group, true)))
+        {
+            let diff = groups.entry(group.key()).or_insert_with(|| HeapGroupDiff {
+                symbol: group.symbol.clone(),
+                file: group.file.clone(),
+                line: group.line,
+                tag: group.tag.clone(),
+                change: GroupChange::Retained,
+                blocks_before: 0,
+                blocks_after: 0,
+                bytes_before: 0,
+                bytes_after: 0,
+            });
+            if later {
+                diff.blocks_after += group.blocks;
+                diff.bytes_after += group.bytes;
+            } else {
+                diff.blocks_before += group.blocks;
+                diff.bytes_before += group.bytes;
+            }
+        }
+        let mut groups: Vec<HeapGroupDiff> = groups
+            .into_values()
+            .map(|mut diff| {
+                diff.change = match (diff.blocks_before, diff.blocks_after) {
+                    (0, _) => GroupChange::Added,
+                    (_, 0) => GroupChange::Removed,
+                    _ => GroupChange::Retained,
+                };
+                diff
+            })
+            .collect();
+        groups.sort_by_key(|diff| core::cmp::Reverse(diff.bytes_delta()));
+        HeapDiff { groups }
+    }
+}
+
+impl HeapGroup {
+    /// The caller and tag that identify this group.
+    fn key(&self) -> (Option<&str>, Option<&str>, Option<u32>, Option<&str>) {
+        (
+            self.symbol.as_deref(),
+            self.file.as_deref(),
+            self.line,
+            self.tag.as_deref(),
+        )
+    }
+}
+
+/// The changes to the live heap between two snapshots. See
+/// [`HeapSnapshot::diff`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct HeapDiff {
+    /// The changes to each group, in descending order of growth.
+    pub groups: Vec<HeapGroupDiff>,
+}
+
+impl HeapDiff {
+    /// The change in the total size of the live blocks in all groups.
+    pub fn bytes_delta(&self) -> i64 {
+        self.groups.iter().map(HeapGroupDiff::bytes_delta).sum()
+    }
+}
+
+/// How a group of live blocks changed between two snapshots.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub enum GroupChange {
+    /// The group is only present in the later snapshot.
+    Added,
+    /// The group is only present in the earlier snapshot.
+    Removed,
+    /// The group is present in both snapshots.
+    Retained,
+}
+
+/// The change to a group of live blocks between two snapshots.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct HeapGroupDiff {
+    /// The demangled name of the function that allocated the blocks, if
+    /// known.
+    pub symbol: Option<String>,
+    /// The source file of the allocations, if known.
+    pub file: Option<String>,
+    /// The source line of the allocations, if known.
+    pub line: Option<u32>,
+    /// The tag under which the blocks were allocated, if any.
+    pub tag: Option<String>,
+    /// Whether the group was added, removed or retained.
+    pub change: GroupChange,
+    /// The number of live blocks in the earlier snapshot.
+    pub blocks_before: u64,
+    /// The number of live blocks in the later snapshot.
+    pub blocks_after: u64,
+    /// The total size of the live blocks in the earlier snapshot.
+    pub bytes_before: u64,
+    /// The total size of the live blocks in the later snapshot.
+    pub bytes_after: u64,
+}
+
+impl HeapGroupDiff {
+    /// The change in the number of live blocks.
+    pub fn blocks_delta(&self) -> i64 {
+        self.blocks_after as i64 - self.blocks_before as i64
+    }
+
+    /// The change in the total size of the live blocks.
+    pub fn bytes_delta(&self) -> i64 {
+        self.bytes_after as i64 - self.bytes_before as i64
+    }
+}
+
+/// A snapshot of the blocks currently recorded in the live table, grouped by
+/// caller and tag.
+///
+/// Allocations are recorded if the [live
+/// table](crate::TracingAllocator::with_live_table) is enabled and they are
+/// traced; callers are identified only with the `backtrace` feature. Taking a
+/// snapshot briefly locks each shard of the table in turn, so it is cheap
+/// enough to trigger on demand (e.g., from an admin endpoint).
+///
+/// ## Usage
+/// ```
+/// use std::alloc::System;
+/// use tracing_allocations::{snapshot, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> =
+///     TracingAllocator::new(System).with_live_table(true);
+///
+/// fn main() {
+///     /* your code here */
+///
+///     let snapshot = snapshot();
+///     println!("{} bytes live", snapshot.total_bytes());
+///     for group in snapshot.groups.iter().take(10) {
+///         println!("{:?}", group);
+///     }
+/// }
+/// ```
+pub fn snapshot() -> HeapSnapshot {
+    crate::as_instrumentation(|| summarize(|visit| live::for_each(visit)))
+}
+
+/// A snapshot of the live heap that omits the shards of the live table held
+/// by other threads (or by the current thread), rather than wait for them,
+/// and the number of shards omitted. See [`live::try_for_each`].
+pub(crate) fn try_snapshot() -> (HeapSnapshot, usize) {
+    crate::as_instrumentation(|| {
+        let mut busy = 0;
+        let snapshot = summarize(|visit| busy = live::try_for_each(visit));
+        (snapshot, busy)
+    })
+}
+
+/// Groups the blocks visited by `for_each` by caller and tag.
+fn summarize(for_each: impl FnOnce(&mut dyn FnMut(usize, &live::Block))) -> HeapSnapshot {
+    let mut groups: BTreeMap<(usize, Option<&'static str>), (live::Location, u64, u64)> =
+        BTreeMap::new();
+    for_each(&mut |_, block| {
+        let (_, blocks, bytes) = groups
+            .entry((block.caller.key(), block.tag))
+            .or_insert_with(|| (block.caller.location(), 0, 0));
+        *blocks += 1;
+        *bytes += block.size as u64;
+    });
+    let mut groups: Vec<HeapGroup> = groups
+        .into_iter()
+        .map(|((_, tag), (location, blocks, bytes))| HeapGroup {
+            symbol: location.symbol.map(String::from),
+            file: location.file.map(String::from),
+            line: location.line,
+            tag: tag.map(String::from),
+            blocks,
+            bytes,
+        })
+        .collect();
+    groups.sort_by_key(|group| core::cmp::Reverse(group.bytes));
+    HeapSnapshot { groups }
+}
@@ -0,0 +1,591 @@
+//! Streams of allocation events to collector processes.
+//!
+//! [`SocketLayer`] queues the events it observes in a bounded buffer, from
+//! which a thread writes them, in the binary format of
+//! [`BinaryWriter`](crate::BinaryWriter), to a socket connected to a
+//! collector, such as `tracing-allocations-top`; the analysis of the events
+//! is thus kept out of the instrumented process. Over TCP, the stream is
+//! divided into length-prefixed frames, which [`FrameReader`] joins again, so
+//! that a central service may collect the streams of many machines.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufWriter, Read, Write},
+    mem,
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{event::AllocationEvent, BinaryWriter};
+
+/// The delay before the first attempt to reconnect.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The greatest delay between attempts to reconnect.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The size at which a frame is written, even if the stream is not flushed.
+const FRAME_SIZE: usize = 1 << 16;
+
+/// Opens a connection to the collector.
+type Connect = Box<dyn FnMut() -> io::Result<Box<dyn Write + Send>> + Send>;
+
+/// A [`Layer`] that streams the allocator operations described by the
+/// events it observes, together with their callers, if known (see
+/// [`Detail::Caller`](crate::Detail::Caller)), and their tags, to a collector
+/// process, in the binary format of [`BinaryWriter`](crate::BinaryWriter).
+///
+/// Events are queued in a buffer of up to 65536 events, or of the number
+/// given to [`SocketLayer::with_capacity`], from which a thread, on which
+/// allocation tracing is disabled, writes them to the collector; the thread
+/// that performed an operation thus waits neither on the collector nor on
+/// the network. Events that arrive while the buffer is full are dropped, and
+/// counted by [`SocketHandle::dropped`].
+///
+/// The thread connects to the collector when the first event is queued, and,
+/// if the connection fails or is lost, reconnects after a delay that doubles
+/// with each failed attempt, from 100 milliseconds up to 5 seconds; each
+/// connection begins a new stream. Events written to a connection as it was
+/// lost are lost with it. The stream is completed when the layer and all
+/// [`SocketHandle`]s to it are dropped, or when [`SocketHandle::finish`] is
+/// called.
+///
+/// Over TCP (see [`SocketLayer::tcp`]), the stream of each connection is
+/// written as a sequence of frames, each a 4-byte big-endian length followed
+/// by that many bytes of the stream, at most 64 KiB; a frame is written
+/// whenever the thread has written all the events queued so far, so that the
+/// collector receives them without waiting for more. Frames need not end at
+/// the end of an event: the concatenation of the frames of a connection is
+/// its stream, which [`FrameReader`] recovers.
+///
+/// Requires the `socket` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::SocketLayer;
+///
+/// let layer = SocketLayer::unix("/tmp/allocations.sock");
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+/// ```
+///
+/// ```sh
+/// tracing-allocations-top /tmp/allocations.sock
+/// ```
+#[derive(Clone, Debug)]
+pub struct SocketLayer {
+    handle: SocketHandle,
+}
+
+impl SocketLayer {
+    /// Constructs a new `SocketLayer`, which streams events to the Unix
+    /// domain socket at `path`.
+    ///
+    /// Only available on Unix.
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<std::path::Path>>(path: P) -> Self {
+        use std::os::unix::net::UnixStream;
+
+        let path = crate::disable_in_scope(|| path.as_ref().to_path_buf());
+        Self::new(Box::new(move || {
+            let stream = UnixStream::connect(&path)?;
+            Ok(Box::new(stream) as Box<dyn Write + Send>)
+        }))
+    }
+
+    /// Constructs a new `SocketLayer`, which streams events in frames to the
+    /// TCP listener at `addr`.
+    ///
+    /// The address is resolved again at each attempt to connect.
+    pub fn tcp<A>(addr: A) -> Self
+    where
+        A: ToSocketAddrs + Send + 'static,
+    {
+        Self::new(Box::new(move || {
+            let stream = TcpStream::connect(&addr)?;
+            // frames are only written once the queue is drained
+            stream.set_nodelay(true)?;
+            Ok(Box::new(Framed::new(stream)) as Box<dyn Write + Send>)
+        }))
+    }
+
+    /// Constructs a new `SocketLayer`, which streams events to the
+    /// connections opened by `connect`.
+    fn new(connect: Connect) -> Self {
+        let sink = crate::disable_in_scope(|| {
+            let shared = Arc::new(Shared {
+                queue: Mutex::new(Queue {
+                    events: VecDeque::new(),
+                    capacity: 1 << 16,
+                    dropped: 0,
+                    closed: false,
+                }),
+                ready: Condvar::new(),
+            });
+            let thread = thread::Builder::new()
+                .name(String::from("tracing-allocations-socket"))
+                .spawn({
+                    let shared = Arc::clone(&shared);
+                    move || {
+                        crate::disable_for_thread();
+                        Writer {
+                            connect,
+                            connection: None,
+                            backoff: MIN_BACKOFF,
+                        }
+                        .run(&shared)
+                    }
+                })
+                .expect("failed to spawn the socket thread");
+            Arc::new(Sink {
+                shared,
+                thread: Mutex::new(Some(thread)),
+            })
+        });
+        Self {
+            handle: SocketHandle { sink },
+        }
+    }
+
+    /// Buffer up to `capacity` events, rather than 65536.
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        self.handle.sink.shared.queue().capacity = capacity.max(1);
+        self
+    }
+
+    /// A handle through which to finish the stream of this layer.
+    pub fn handle(&self) -> SocketHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for SocketLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(decoded) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let mut caller = String::new();
+            let caller = crate::event::caller_of(event, &mut caller).then_some(caller);
+            let mut tag = String::new();
+            let tag = crate::event::tag_of(event, &mut tag).then_some(tag);
+            self.handle.sink.push((decoded, caller, tag));
+        });
+    }
+}
+
+/// A handle to the stream of a [`SocketLayer`].
+///
+/// Handles are cheap to clone, and all clones refer to the same stream.
+#[derive(Clone)]
+pub struct SocketHandle {
+    sink: Arc<Sink>,
+}
+
+impl SocketHandle {
+    /// The number of events dropped so far because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.sink.shared.queue().dropped
+    }
+
+    /// Writes the events queued so far, if the collector is connected, and
+    /// closes the stream; subsequent events are not written.
+    pub fn finish(&self) {
+        crate::disable_in_scope(|| self.sink.finish())
+    }
+}
+
+impl core::fmt::Debug for SocketHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SocketHandle")
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+/// An event, and its caller and tag, if known.
+type Record = (AllocationEvent, Option<String>, Option<String>);
+
+/// The buffer of a [`SocketLayer`], and its thread.
+struct Sink {
+    shared: Arc<Shared>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// The state shared with the thread of a [`SocketLayer`].
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Notified when events are queued, or the stream is finished.
+    ready: Condvar,
+}
+
+/// The events waiting to be written.
+struct Queue {
+    events: VecDeque<Record>,
+    /// The greatest number of events to buffer.
+    capacity: usize,
+    /// The number of events dropped because the buffer was full.
+    dropped: u64,
+    /// Whether the stream is finished.
+    closed: bool,
+}
+
+impl Sink {
+    /// Queues `record`, unless the buffer is full or the stream is finished.
+    fn push(&self, record: Record) {
+        {
+            let mut queue = self.shared.queue();
+            if queue.closed {
+                return;
+            }
+            if queue.events.len() >= queue.capacity {
+                queue.dropped += 1;
+                return;
+            }
+            queue.events.push_back(record);
+        }
+        self.shared.ready.notify_one();
+    }
+
+    /// Closes the stream, and waits for the thread to write the events
+    /// queued so far and exit.
+    fn finish(&self) {
+        self.shared.queue().closed = true;
+        self.shared.ready.notify_all();
+        let thread = self
+            .thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        crate::disable_in_scope(|| self.finish());
+    }
+}
+
+impl Shared {
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes the events queued so far, waiting until there are any; `None`
+    /// once the stream is finished and they have all been taken.
+    fn take(&self) -> Option<VecDeque<Record>> {
+        let queue = self.queue();
+        let mut queue = self
+            .ready
+            .wait_while(queue, |queue| queue.events.is_empty() && !queue.closed)
+            .unwrap_or_else(PoisonError::into_inner);
+        if queue.events.is_empty() {
+            return None;
+        }
+        Some(mem::take(&mut queue.events))
+    }
+}
+
+/// The state of the thread of a [`SocketLayer`].
+struct Writer {
+    connect: Connect,
+    connection: Option<BinaryWriter<BufWriter<Box<dyn Write + Send>>>>,
+    /// The delay before the next attempt to reconnect.
+    backoff: Duration,
+}
+
+impl Writer {
+    /// Writes the events queued in `shared` until the stream is finished.
+    fn run(mut self, shared: &Shared) {
+        while let Some(records) = shared.take() {
+            for (event, caller, tag) in &records {
+                if !self.connect(shared) {
+                    return;
+                }
+                let Some(connection) = &mut self.connection else {
+                    continue;
+                };
+                if connection
+                    .write_tagged(event, caller.as_deref(), tag.as_deref())
+                    .is_err()
+                {
+                    self.connection = None;
+                }
+            }
+            // flush once the queue is drained, so that events are not held
+            // back
+            if let Some(connection) = &mut self.connection {
+                if shared.queue().events.is_empty() && connection.flush().is_err() {
+                    self.connection = None;
+                }
+            }
+        }
+        if let Some(connection) = &mut self.connection {
+            let _ = connection.flush();
+        }
+    }
+
+    /// Connects to the collector, unless connected, retrying until it
+    /// succeeds; returns whether it did before the stream was finished.
+    fn connect(&mut self, shared: &Shared) -> bool {
+        while self.connection.is_none() {
+            match (self.connect)() {
+                Ok(stream) => {
+                    self.connection = Some(BinaryWriter::new(BufWriter::new(stream)));
+                    self.backoff = MIN_BACKOFF;
+                }
+                Err(_) => {
+                    let queue = shared.queue();
+                    if queue.closed {
+                        return false;
+                    }
+                    let (queue, _) = shared
+                        .ready
+                        .wait_timeout_while(queue, self.backoff, |queue| !queue.closed)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    if queue.closed {
+                        // make one last attempt
+                        continue;
+                    }
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        true
+    }
+}
+
+/// A writer that divides the stream written to it into length-prefixed
+/// frames.
+struct Framed<W> {
+    writer: W,
+    /// The bytes of the frame being written.
+    frame: Vec<u8>,
+}
+
+impl<W: Write> Framed<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            frame: Vec::new(),
+        }
+    }
+
+    /// Writes the current frame, unless it is empty.
+    fn write_frame(&mut self) -> io::Result<()> {
+        if self.frame.is_empty() {
+            return Ok(());
+        }
+        let len = self.frame.len() as u32;
+        self.writer.write_all(&len.to_be_bytes())?;
+        self.writer.write_all(&self.frame)?;
+        self.frame.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Framed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(FRAME_SIZE - self.frame.len());
+        self.frame.extend_from_slice(&buf[..len]);
+        if self.frame.len() == FRAME_SIZE {
+            self.write_frame()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_frame()?;
+        self.writer.flush()
+    }
+}
+
+/// A reader of the stream carried by the frames of a [`SocketLayer`] over
+/// TCP.
+///
+/// ## Usage
+/// ```no_run
+/// use std::{io::BufReader, net::TcpListener};
+/// use tracing_allocations::{BinaryReader, FrameReader};
+///
+/// let listener = TcpListener::bind("0.0.0.0:7070")?;
+/// for connection in listener.incoming() {
+///     let reader = FrameReader::new(BufReader::new(connection?));
+///     for record in BinaryReader::new(reader) {
+///         let record = record?;
+///         /* your code here */
+///     }
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    reader: R,
+    /// The bytes of the current frame not yet read.
+    remaining: u32,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Constructs a new `FrameReader`, which reads frames from `reader`.
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            remaining: 0,
+        }
+    }
+
+    /// The underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.remaining == 0 {
+            let mut len = [0; 4];
+            // the stream ends cleanly only between frames
+            match self.reader.read(&mut len[..1])? {
+                0 => return Ok(0),
+                _ => self.reader.read_exact(&mut len[1..])?,
+            }
+            self.remaining = u32::from_be_bytes(len);
+            if self.remaining as usize > FRAME_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "frame is too long",
+                ));
+            }
+        }
+        let len = buf.len().min(self.remaining as usize);
+        let read = self.reader.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u32;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+
+    use super::{FrameReader, Framed, Record, FRAME_SIZE};
+    use crate::{
+        event::{AllocationEvent, AllocationKind},
+        BinaryReader, BinaryRecord, BinaryWriter,
+    };
+
+    /// Distinct events, every third of which has a caller, and every fifth
+    /// a tag.
+    fn events(n: u64) -> Vec<Record> {
+        (0..n)
+            .map(|i| {
+                let mut event = AllocationEvent::new(AllocationKind::Alloc, 0x1000 + i * 16, i);
+                event.timestamp_ns = Some(i * 1000);
+                let caller = (i % 3 == 0).then(|| format!("caller_{}", i % 7));
+                let tag = (i % 5 == 0).then(|| format!("tag_{}", i % 4));
+                (event, caller, tag)
+            })
+            .collect()
+    }
+
+    /// The events in the binary format.
+    fn encode(events: &[Record]) -> Vec<u8> {
+        let mut writer = BinaryWriter::new(Vec::new());
+        for (event, caller, tag) in events {
+            writer
+                .write_tagged(event, caller.as_deref(), tag.as_deref())
+                .unwrap();
+        }
+        writer.into_inner()
+    }
+
+    /// The frames carrying `stream`, flushed after every `chunk` bytes.
+    fn frame(stream: &[u8], chunk: usize) -> Vec<u8> {
+        let mut framed = Framed::new(Vec::new());
+        for chunk in stream.chunks(chunk) {
+            framed.write_all(chunk).unwrap();
+            framed.flush().unwrap();
+        }
+        framed.writer
+    }
+
+    fn decode(frames: &[u8]) -> io::Result<Vec<BinaryRecord>> {
+        BinaryReader::new(FrameReader::new(frames)).collect()
+    }
+
+    fn assert_decoded(records: &[BinaryRecord], events: &[Record]) {
+        assert_eq!(records.len(), events.len());
+        for (record, (event, caller, tag)) in records.iter().zip(events) {
+            assert_eq!(&record.event, event);
+            assert_eq!(record.caller.as_deref(), caller.as_deref());
+            assert_eq!(record.tag.as_deref(), tag.as_deref());
+        }
+    }
+
+    #[test]
+    fn round_trip_in_full_frames() {
+        let events = events(20_000);
+        let stream = encode(&events);
+        assert!(stream.len() > 2 * FRAME_SIZE);
+        let frames = frame(&stream, stream.len());
+        assert_decoded(&decode(&frames).unwrap(), &events);
+    }
+
+    #[test]
+    fn round_trip_with_frames_split_mid_event() {
+        let events = events(1_000);
+        let stream = encode(&events);
+        for chunk in [1, 3, 7, 64] {
+            let frames = frame(&stream, chunk);
+            assert_decoded(&decode(&frames).unwrap(), &events);
+        }
+    }
+
+    #[test]
+    fn eof_inside_frame_header() {
+        let events = events(10);
+        let frames = frame(&encode(&events), 16);
+        // ends after the first two bytes of the second frame's header
+        let truncated = &frames[..4 + 16 + 2];
+        let mut reader = FrameReader::new(truncated);
+        let mut buf = Vec::new();
+        let err = reader.read_to_end(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(buf.len(), 16);
+    }
+
+    #[test]
+    fn eof_between_frames() {
+        let frames = frame(b"abcdef", 4);
+        let mut buf = Vec::new();
+        FrameReader::new(&frames[..]).read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"abcdef");
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let len = (FRAME_SIZE as u32 + 1).to_be_bytes();
+        let err = FrameReader::new(&len[..]).read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,193 @@
+//! Allocation profiles in the file format of speedscope.
+//!
+//! [`SpeedscopeLayer`] captures the stack of each allocation described by the
+//! events it observes, and writes the stacks, weighted by bytes, in
+//! speedscope's JSON file format, so that heavy allocation paths may be
+//! explored in the browser at <https://www.speedscope.app>.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    event::{AllocationEvent, AllocationKind},
+    json::JsonStr,
+    stack::{self, Profile, Symbolizer},
+};
+
+/// A [`Layer`] that profiles the allocations described by the events it
+/// observes, by the stack that requested them.
+///
+/// Profiles may be written at any time, from any thread, through the
+/// [`SpeedscopeHandle`] returned by [`SpeedscopeLayer::handle`]; each file
+/// has two of speedscope's sampled profiles, in which each stack that
+/// allocated is one sample, weighted by the bytes it allocated
+/// (`alloc_space`), or by those of its blocks not yet freed by an observed
+/// deallocation (`inuse_space`). Sampled and coalesced events are scaled by
+/// [`AllocationEvent::weight`].
+///
+/// Requires the `speedscope` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::SpeedscopeLayer;
+///
+/// let layer = SpeedscopeLayer::new();
+/// let profile = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// let file = std::fs::File::create("allocations.speedscope.json").unwrap();
+/// profile.write_to(file).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SpeedscopeLayer {
+    handle: SpeedscopeHandle,
+}
+
+impl SpeedscopeLayer {
+    /// Constructs a new `SpeedscopeLayer`, with an empty profile.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle through which to write the profile of this layer.
+    pub fn handle(&self) -> SpeedscopeHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for SpeedscopeLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let stack = (event.kind != AllocationKind::Dealloc).then(stack::capture);
+            self.handle
+                .profile
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(&event, stack);
+        });
+    }
+}
+
+/// A handle to the profile of a [`SpeedscopeLayer`].
+///
+/// Handles are cheap to clone, and all clones write the same profile.
+#[derive(Clone, Default)]
+pub struct SpeedscopeHandle {
+    profile: Arc<Mutex<Profile>>,
+    symbolizer: Arc<Mutex<Symbolizer>>,
+}
+
+impl SpeedscopeHandle {
+    /// Writes a speedscope file of the allocations observed so far to
+    /// `writer`.
+    ///
+    /// Stacks are symbolized as the profile is written, which may be slow the
+    /// first time each stack frame is encountered.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        crate::disable_in_scope(|| {
+            let stacks = self
+                .profile
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .stacks
+                .clone();
+            let mut symbolizer = self
+                .symbolizer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+
+            // the frames of each stack, outermost first, by their indices in
+            // the shared list of frames; a frame is a source location, so
+            // inlined calls have frames of their own
+            let mut frames = Vec::new();
+            let mut indices = HashMap::new();
+            let mut samples = Vec::new();
+            for totals in &stacks {
+                let mut sample = Vec::new();
+                for &ip in symbolizer.trim(&totals.stack).iter().rev() {
+                    for line in symbolizer.frame(ip).lines.iter().rev() {
+                        let key = (line.function.clone(), line.file.clone(), line.line);
+                        let index = *indices.entry(key.clone()).or_insert_with(|| {
+                            frames.push(key);
+                            frames.len() - 1
+                        });
+                        sample.push(index);
+                    }
+                }
+                samples.push(sample);
+            }
+
+            write!(
+                writer,
+                r#"{{"$schema":"https://www.speedscope.app/file-format-schema.json","exporter":"tracing-allocations {}","name":"allocations","shared":{{"frames":["#,
+                env!("CARGO_PKG_VERSION"),
+            )?;
+            for (index, (function, file, line)) in frames.iter().enumerate() {
+                let separator = if index == 0 { "" } else { "," };
+                write!(writer, r#"{}{{"name":{}"#, separator, JsonStr(function))?;
+                if let Some(file) = file {
+                    write!(writer, r#","file":{}"#, JsonStr(file))?;
+                }
+                if let Some(number) = line {
+                    write!(writer, r#","line":{}"#, number)?;
+                }
+                write!(writer, "}}")?;
+            }
+            write!(writer, r#"]}},"profiles":["#)?;
+            let alloc_space: Vec<u64> = stacks.iter().map(|totals| totals.alloc_space).collect();
+            let inuse_space: Vec<u64> = stacks.iter().map(|totals| totals.inuse_space).collect();
+            let profiles = [("alloc_space", alloc_space), ("inuse_space", inuse_space)];
+            for (index, (name, weights)) in profiles.into_iter().enumerate() {
+                let separator = if index == 0 { "" } else { "," };
+                let weighted = samples
+                    .iter()
+                    .zip(weights)
+                    .filter(|&(_, weight)| weight > 0);
+                let total: u64 = weighted.clone().map(|(_, weight)| weight).sum();
+                write!(
+                    writer,
+                    r#"{}{{"type":"sampled","name":"{}","unit":"bytes","startValue":0,"endValue":{},"samples":["#,
+                    separator, name, total,
+                )?;
+                for (index, (sample, _)) in weighted.clone().enumerate() {
+                    let separator = if index == 0 { "" } else { "," };
+                    write!(writer, "{}[", separator)?;
+                    for (index, frame) in sample.iter().enumerate() {
+                        let separator = if index == 0 { "" } else { "," };
+                        write!(writer, "{}{}", separator, frame)?;
+                    }
+                    write!(writer, "]")?;
+                }
+                write!(writer, r#"],"weights":["#)?;
+                for (index, (_, weight)) in weighted.enumerate() {
+                    let separator = if index == 0 { "" } else { "," };
+                    write!(writer, "{}{}", separator, weight)?;
+                }
+                write!(writer, "]}}")?;
+            }
+            writeln!(writer, "]}}")
+        })
+    }
+}
+
+impl core::fmt::Debug for SpeedscopeHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SpeedscopeHandle").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,374 @@
+//! Allocation events as SQLite databases.
+//!
+//! [`SqliteLayer`] sends the events it observes to a thread that inserts
+//! them into a SQLite database, so that traces may be interrogated with
+//! ad-hoc SQL.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+};
+
+use rusqlite::{params, Connection};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::event::AllocationEvent;
+
+/// The schema of the database.
+const SCHEMA: &str = "
+CREATE TABLE threads (
+    id INTEGER PRIMARY KEY,
+    name TEXT
+);
+CREATE TABLE callsites (
+    id INTEGER PRIMARY KEY,
+    caller TEXT NOT NULL UNIQUE
+);
+CREATE TABLE events (
+    seq INTEGER PRIMARY KEY,
+    kind TEXT NOT NULL,
+    addr INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    align INTEGER,
+    usable_size INTEGER,
+    old_addr INTEGER,
+    old_size INTEGER,
+    zeroed INTEGER,
+    timestamp_ns INTEGER,
+    span_id INTEGER,
+    sample_rate INTEGER,
+    sample_interval INTEGER,
+    count INTEGER,
+    age_ns INTEGER,
+    age_events INTEGER,
+    thread INTEGER NOT NULL REFERENCES threads (id),
+    callsite INTEGER REFERENCES callsites (id)
+);
+";
+
+/// The greatest number of events inserted in a single transaction.
+const TRANSACTION_SIZE: usize = 4096;
+
+/// A [`Layer`] that writes the allocation events it observes into a SQLite
+/// database, with the following tables:
+/// - **`events`**  
+///   one row per event, numbered by `seq` in the order in which they were
+///   observed, with a column for each field of [`AllocationEvent`], of the
+///   same name; `thread`, the number of the thread that performed the
+///   operation; and `callsite`, the code that requested it, if known (see
+///   [`Detail::Caller`](crate::Detail::Caller))
+/// - **`threads`**  
+///   one row per thread, with its number, `id`, and its `name`, if any;
+///   threads are numbered in the order in which they first performed a
+///   traced operation, starting from 1
+/// - **`callsites`**  
+///   one row per caller, with its `id`, and the name of the `caller`
+///
+/// Integers are stored as signed 64-bit integers, as SQLite has no unsigned
+/// integers; addresses and sizes of 2<sup>63</sup> or more are therefore
+/// negative.
+///
+/// Events are sent to a thread, on which allocation tracing is disabled, that
+/// inserts them in transactions of up to 4096 events, so that the thread that
+/// performed an operation waits neither on SQLite nor on the disk. The
+/// database is completed when the layer and all [`SqliteHandle`]s to it are
+/// dropped, or when [`SqliteHandle::finish`] is called. Events that are not
+/// allocation events are ignored.
+///
+/// Requires the `sqlite` feature. SQLite itself is linked as configured by
+/// the features of `rusqlite`; e.g., its `bundled` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::SqliteLayer;
+///
+/// let layer = SqliteLayer::new("allocations.db").unwrap();
+/// let database = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// database.finish().unwrap();
+/// ```
+///
+/// ```sql
+/// SELECT caller, sum(size) FROM events JOIN callsites ON callsite = callsites.id
+/// WHERE kind = 'alloc' GROUP BY caller ORDER BY 2 DESC;
+/// ```
+#[derive(Clone, Debug)]
+pub struct SqliteLayer {
+    handle: SqliteHandle,
+}
+
+impl SqliteLayer {
+    /// Constructs a new `SqliteLayer`, which writes to the database at
+    /// `path`, creating it if it does not exist.
+    ///
+    /// Fails if the database cannot be opened, or its tables cannot be
+    /// created; e.g., because it already has them.
+    pub fn new<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+        crate::disable_in_scope(|| {
+            let connection = Connection::open(path)?;
+            connection.execute_batch(SCHEMA)?;
+            let (sender, receiver) = mpsc::channel();
+            let thread = thread::Builder::new()
+                .name(String::from("tracing-allocations-sqlite"))
+                .spawn(move || {
+                    crate::disable_for_thread();
+                    Writer {
+                        connection,
+                        callsites: HashMap::new(),
+                    }
+                    .run(receiver)
+                })
+                .expect("failed to spawn the sqlite thread");
+            Ok(Self {
+                handle: SqliteHandle {
+                    sink: Arc::new(Sink {
+                        sender: Mutex::new(Some(sender)),
+                        thread: Mutex::new(Some(thread)),
+                        threads: Mutex::new(HashSet::new()),
+                    }),
+                },
+            })
+        })
+    }
+
+    /// A handle through which to flush or finish the database of this layer.
+    pub fn handle(&self) -> SqliteHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for SqliteLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(decoded) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let sink = &self.handle.sink;
+            let thread = crate::thread::number();
+            let thread_name = match sink.threads().insert(thread) {
+                true => Some(thread::current().name().map(String::from)),
+                false => None,
+            };
+            let mut caller = String::new();
+            let caller = crate::event::caller_of(event, &mut caller).then_some(caller);
+            sink.send(Message::Event(Box::new(Row {
+                event: decoded,
+                thread,
+                thread_name,
+                caller,
+            })));
+        });
+    }
+}
+
+/// A handle to the database of a [`SqliteLayer`].
+///
+/// Handles are cheap to clone, and all clones refer to the same database.
+#[derive(Clone)]
+pub struct SqliteHandle {
+    sink: Arc<Sink>,
+}
+
+impl SqliteHandle {
+    /// Waits until the events observed so far have been inserted.
+    pub fn flush(&self) {
+        crate::disable_in_scope(|| {
+            let (done, flushed) = mpsc::channel();
+            self.sink.send(Message::Flush(done));
+            let _ = flushed.recv();
+        })
+    }
+
+    /// Inserts the events observed so far, and closes the database;
+    /// subsequent events are not written.
+    ///
+    /// Fails if any event could not be inserted.
+    pub fn finish(&self) -> rusqlite::Result<()> {
+        crate::disable_in_scope(|| self.sink.finish())
+    }
+}
+
+impl core::fmt::Debug for SqliteHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SqliteHandle").finish_non_exhaustive()
+    }
+}
+
+/// The channel to the thread of a [`SqliteLayer`].
+struct Sink {
+    /// The sender of messages to the thread, until the database is finished.
+    sender: Mutex<Option<Sender<Message>>>,
+    thread: Mutex<Option<JoinHandle<rusqlite::Result<()>>>>,
+    /// The numbers of the threads that have been sent.
+    threads: Mutex<HashSet<u64>>,
+}
+
+impl Sink {
+    /// Sends `message` to the thread, unless the database is finished.
+    fn send(&self, message: Message) {
+        let sender = self.sender.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(sender) = &*sender {
+            let _ = sender.send(message);
+        }
+    }
+
+    fn threads(&self) -> MutexGuard<'_, HashSet<u64>> {
+        self.threads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Stops the thread, once it has inserted the events sent to it, and
+    /// waits for it to exit.
+    fn finish(&self) -> rusqlite::Result<()> {
+        drop(
+            self.sender
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take(),
+        );
+        let thread = self
+            .thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        match thread.map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        crate::disable_in_scope(|| {
+            let _ = self.finish();
+        });
+    }
+}
+
+/// A message to the thread of a [`SqliteLayer`].
+enum Message {
+    Event(Box<Row>),
+    /// A request to be told once the events sent before it are inserted.
+    Flush(Sender<()>),
+}
+
+/// An event, as sent to the thread of a [`SqliteLayer`].
+struct Row {
+    event: AllocationEvent,
+    /// The number of the thread that performed the operation.
+    thread: u64,
+    /// The name of that thread, if this is its first event.
+    thread_name: Option<Option<String>>,
+    caller: Option<String>,
+}
+
+/// The state of the thread of a [`SqliteLayer`].
+struct Writer {
+    connection: Connection,
+    /// The IDs of the callsites inserted so far, by caller.
+    callsites: HashMap<String, i64>,
+}
+
+impl Writer {
+    /// Inserts the events received from `receiver` until it is disconnected.
+    fn run(mut self, receiver: Receiver<Message>) -> rusqlite::Result<()> {
+        let mut flushes = Vec::new();
+        // block until a message arrives, then take those that have also
+        // arrived, up to the size of a transaction
+        while let Ok(message) = receiver.recv() {
+            let transaction = self.connection.transaction()?;
+            let mut next = Some(message);
+            let mut rows = 0;
+            while let Some(message) = next.take() {
+                match message {
+                    Message::Event(row) => {
+                        Self::insert(&transaction, &mut self.callsites, &row)?;
+                        rows += 1;
+                    }
+                    Message::Flush(done) => flushes.push(done),
+                }
+                if rows < TRANSACTION_SIZE {
+                    next = receiver.try_recv().ok();
+                }
+            }
+            transaction.commit()?;
+            for done in flushes.drain(..) {
+                let _ = done.send(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts `row`, and its thread and callsite, if they are new.
+    fn insert(
+        connection: &Connection,
+        callsites: &mut HashMap<String, i64>,
+        row: &Row,
+    ) -> rusqlite::Result<()> {
+        let event = &row.event;
+        let thread = row.thread as i64;
+        if let Some(name) = &row.thread_name {
+            connection
+                .prepare_cached("INSERT INTO threads (id, name) VALUES (?1, ?2)")?
+                .execute(params![thread, name])?;
+        }
+        let callsite = match &row.caller {
+            None => None,
+            Some(caller) => Some(match callsites.get(caller) {
+                Some(&id) => id,
+                None => {
+                    connection
+                        .prepare_cached("INSERT INTO callsites (caller) VALUES (?1)")?
+                        .execute(params![caller])?;
+                    let id = connection.last_insert_rowid();
+                    callsites.insert(caller.clone(), id);
+                    id
+                }
+            }),
+        };
+        let int = |n: Option<u64>| n.map(|n| n as i64);
+        connection
+            .prepare_cached(
+                "INSERT INTO events (kind, addr, size, align, usable_size, old_addr, \
+                 old_size, zeroed, timestamp_ns, span_id, sample_rate, sample_interval, \
+                 count, age_ns, age_events, thread, callsite) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, \
+                 ?16, ?17)",
+            )?
+            .execute(params![
+                event.kind.as_str(),
+                event.addr as i64,
+                event.size as i64,
+                int(event.align),
+                int(event.usable_size),
+                int(event.old_addr),
+                int(event.old_size),
+                event.zeroed,
+                int(event.timestamp_ns),
+                int(event.span_id),
+                int(event.sample_rate),
+                int(event.sample_interval),
+                int(event.count),
+                int(event.age_ns),
+                int(event.age_events),
+                thread,
+                callsite,
+            ])?;
+        Ok(())
+    }
+}
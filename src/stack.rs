@@ -0,0 +1,219 @@
+//! Stacks of the code that requested allocator operations, for profiles.
+//!
+//! Capturing a stack merely records its instruction pointers; they are
+//! symbolized when a profile is written, once per distinct instruction
+//! pointer, by a [`Symbolizer`].
+
+use std::{collections::HashMap, time::Instant};
+
+use crate::{callsite::INTERNAL_PREFIXES, event::AllocationEvent};
+
+/// The greatest number of frames captured for each stack.
+const MAX_DEPTH: usize = 128;
+
+/// Symbol prefixes of frames that belong to the machinery by which events
+/// reach the layers that capture stacks, in addition to
+/// [`INTERNAL_PREFIXES`].
+const DISPATCH_PREFIXES: &[&str] = &["tracing::", "tracing_core::", "tracing_subscriber::"];
+
+/// The instruction pointers of the current stack, innermost first.
+///
+/// This allocates, and so must only be called while allocator operations on
+/// the current thread are untraced.
+pub(crate) fn capture() -> Vec<usize> {
+    let mut stack = Vec::new();
+    backtrace::trace(|frame| {
+        stack.push(frame.ip() as usize);
+        stack.len() < MAX_DEPTH
+    });
+    stack
+}
+
+/// A source location within a frame.
+pub(crate) struct Line {
+    /// The demangled name of the function, without its hash.
+    pub(crate) function: String,
+    /// The source file, if known.
+    pub(crate) file: Option<String>,
+    /// The source line, if known.
+    pub(crate) line: Option<u32>,
+}
+
+/// A symbolized frame.
+pub(crate) struct Frame {
+    /// The source locations of the frame, innermost (i.e., most deeply
+    /// inlined) first; empty if the frame could not be symbolized.
+    pub(crate) lines: Vec<Line>,
+    /// Whether the frame belongs to the allocator machinery.
+    internal: bool,
+}
+
+impl Frame {
+    /// Symbolizes the frame at `ip`.
+    fn resolve(ip: usize) -> Self {
+        let mut lines = Vec::new();
+        backtrace::resolve(ip as *mut _, |symbol| {
+            let function = symbol
+                .name()
+                // the alternate form omits the hash
+                .map(|name| format!("{:#}", name))
+                .unwrap_or_default();
+            lines.push(Line {
+                function,
+                file: symbol
+                    .filename()
+                    .and_then(|file| file.to_str())
+                    .map(String::from),
+                line: symbol.lineno(),
+            });
+        });
+        let internal = lines.first().is_some_and(|line| {
+            let path = line.function.trim_start_matches('<');
+            INTERNAL_PREFIXES
+                .iter()
+                .chain(DISPATCH_PREFIXES)
+                .any(|prefix| path.starts_with(prefix))
+                // the standard library's sources are remapped to `/rustc/<hash>/`
+                || line.file.as_deref().is_some_and(|file| file.starts_with("/rustc/"))
+        });
+        Self { lines, internal }
+    }
+}
+
+/// Symbolizes frames, caching the frame of each instruction pointer.
+#[derive(Default)]
+pub(crate) struct Symbolizer {
+    frames: HashMap<usize, Frame>,
+}
+
+impl Symbolizer {
+    /// The frame at `ip`.
+    pub(crate) fn frame(&mut self, ip: usize) -> &Frame {
+        self.frames.entry(ip).or_insert_with(|| Frame::resolve(ip))
+    }
+
+    /// `stack`, without the frames of the allocator machinery at its top. If
+    /// the stack consists only of such frames, it is returned whole.
+    pub(crate) fn trim<'s>(&mut self, stack: &'s [usize]) -> &'s [usize] {
+        match stack.iter().position(|&ip| !self.frame(ip).internal) {
+            Some(start) => &stack[start..],
+            None => stack,
+        }
+    }
+}
+
+/// The allocations observed by a profiling layer, by the stack that
+/// requested them.
+pub(crate) struct Profile {
+    /// When the layer was constructed.
+    pub(crate) began: Instant,
+    /// The index in `stacks` of each stack.
+    indices: HashMap<Vec<usize>, usize>,
+    /// The totals of each stack.
+    pub(crate) stacks: Vec<Totals>,
+    /// The live blocks, by address.
+    live: HashMap<u64, Block>,
+    /// The total size of the live blocks.
+    pub(crate) live_bytes: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            began: Instant::now(),
+            indices: HashMap::new(),
+            stacks: Vec::new(),
+            live: HashMap::new(),
+            live_bytes: 0,
+        }
+    }
+}
+
+/// The allocations requested by a stack.
+#[derive(Clone)]
+pub(crate) struct Totals {
+    /// The instruction pointers of the stack, innermost first.
+    pub(crate) stack: Vec<usize>,
+    /// The number of blocks allocated.
+    pub(crate) alloc_objects: u64,
+    /// The total size of the blocks allocated.
+    pub(crate) alloc_space: u64,
+    /// The number of those blocks that are live.
+    pub(crate) inuse_objects: u64,
+    /// The total size of those blocks that are live.
+    pub(crate) inuse_space: u64,
+}
+
+/// A live block.
+struct Block {
+    /// The index of the stack that allocated the block.
+    stack: usize,
+    /// The number of blocks this stands for.
+    objects: u64,
+    /// The number of bytes this stands for.
+    bytes: u64,
+}
+
+impl Profile {
+    /// Records the operation described by `event`, which was requested by
+    /// `stack` unless it is a deallocation.
+    pub(crate) fn record(&mut self, event: &AllocationEvent, stack: Option<Vec<usize>>) {
+        if let Some(old_addr) = event.old_addr {
+            self.free(old_addr);
+        }
+        let Some(stack) = stack else {
+            return self.free(event.addr);
+        };
+        if event.addr == 0 {
+            return;
+        }
+        let weight = event.weight();
+        let scale = |n: u64| (n as f64 * weight).round() as u64;
+        let (objects, bytes) = (scale(1), scale(event.size));
+        let index = match self.indices.get(&stack) {
+            Some(&index) => index,
+            None => {
+                let index = self.stacks.len();
+                self.indices.insert(stack.clone(), index);
+                self.stacks.push(Totals {
+                    stack,
+                    alloc_objects: 0,
+                    alloc_space: 0,
+                    inuse_objects: 0,
+                    inuse_space: 0,
+                });
+                index
+            }
+        };
+        let totals = &mut self.stacks[index];
+        totals.alloc_objects += objects;
+        totals.alloc_space += bytes;
+        totals.inuse_objects += objects;
+        totals.inuse_space += bytes;
+        self.live_bytes += bytes;
+        let block = Block {
+            stack: index,
+            objects,
+            bytes,
+        };
+        // a block at the same address must have been freed unobserved
+        if let Some(stale) = self.live.insert(event.addr, block) {
+            self.release(&stale);
+        }
+    }
+
+    /// Records the deallocation of the block at `addr`, if it is live.
+    fn free(&mut self, addr: u64) {
+        if let Some(block) = self.live.remove(&addr) {
+            self.release(&block);
+        }
+    }
+
+    /// Deducts `block` from the in-use totals of its stack.
+    fn release(&mut self, block: &Block) {
+        let totals = &mut self.stacks[block.stack];
+        totals.inuse_objects = totals.inuse_objects.saturating_sub(block.objects);
+        totals.inuse_space = totals.inuse_space.saturating_sub(block.bytes);
+        self.live_bytes = self.live_bytes.saturating_sub(block.bytes);
+    }
+}
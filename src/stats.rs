@@ -0,0 +1,496 @@
+//! Counting of allocator operations, independently of tracing.
+//!
+//! [`count_allocations`] installs a counter in thread-local storage for the
+//! duration of a closure; [`TracingAllocator`] records each operation it
+//! performs with the innermost counter of the current thread. No subscriber
+//! is involved, so counting is cheap enough for benchmarks and unit tests.
+//!
+//! [`TracingAllocator`]: crate::TracingAllocator
+
+use core::{
+    cell::{Cell, RefCell},
+    fmt::{self, Write as _},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tracing::Span;
+
+use crate::event::AllocationKind;
+
+/// The greatest number of offending allocations listed by
+/// [`assert_alloc_budget`].
+const MAX_OFFENDERS: usize = 16;
+
+thread_local! {
+    /// The counter of the innermost call to [`count_allocations`] on this
+    /// thread, if any.
+    static COUNTER: Cell<Option<Counter>> = const { Cell::new(None) };
+
+    /// The allocations that exceeded the budget of the innermost call to
+    /// [`assert_alloc_budget`] on this thread.
+    static OFFENDERS: RefCell<Offenders> = const { RefCell::new(Offenders::new()) };
+}
+
+/// Summary statistics of the allocator operations performed by a closure.
+/// See [`count_allocations`].
+///
+/// Reallocations are counted neither as allocations nor as deallocations, but
+/// contribute the size of the new block to `bytes_allocated`, and the size of
+/// the existing block to `bytes_freed`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "valuable", derive(valuable::Valuable))]
+#[non_exhaustive]
+pub struct AllocationStats {
+    /// The number of allocations, zeroed or not.
+    pub allocations: u64,
+    /// The number of deallocations.
+    pub deallocations: u64,
+    /// The number of reallocations.
+    pub reallocations: u64,
+    /// The total size of all allocated blocks.
+    pub bytes_allocated: u64,
+    /// The total size of all freed blocks.
+    pub bytes_freed: u64,
+    /// The greatest number of bytes that were allocated, but not yet freed, at
+    /// any one time.
+    pub peak_bytes: u64,
+}
+
+/// The state of a call to [`count_allocations`].
+#[derive(Clone, Copy, Default)]
+struct Counter {
+    /// The operations counted so far.
+    stats: AllocationStats,
+    /// The number of bytes allocated, but not yet freed; negative if more
+    /// bytes were freed than allocated.
+    live: i64,
+    /// The budget of allocations still available, if any.
+    budget: Option<Budget>,
+}
+
+impl Counter {
+    /// A counter with the given budget.
+    fn new(budget: Option<Budget>) -> Self {
+        Self {
+            budget,
+            ..Self::default()
+        }
+    }
+
+    /// Records an operation that allocated `allocated` bytes and freed `freed`
+    /// bytes; returns whether the operation allocated in excess of the budget.
+    fn record(&mut self, kind: AllocationKind, allocated: usize, freed: usize) -> bool {
+        let stats = &mut self.stats;
+        match kind {
+            AllocationKind::Alloc | AllocationKind::AllocZeroed => stats.allocations += 1,
+            AllocationKind::Dealloc => stats.deallocations += 1,
+            AllocationKind::Realloc => stats.reallocations += 1,
+        }
+        stats.bytes_allocated = stats.bytes_allocated.saturating_add(allocated as u64);
+        stats.bytes_freed = stats.bytes_freed.saturating_add(freed as u64);
+        self.live = self
+            .live
+            .saturating_add(allocated as i64)
+            .saturating_sub(freed as i64);
+        stats.peak_bytes = stats.peak_bytes.max(self.live.max(0) as u64);
+        allocated > 0 && self.budget.is_some_and(|budget| budget.exceeded_by(stats))
+    }
+
+    /// The budget remaining for nested calls to [`count_allocations`].
+    fn remaining(&self) -> Option<Budget> {
+        self.budget.map(|budget| Budget {
+            allocations: budget
+                .allocations
+                .saturating_sub(self.stats.allocations + self.stats.reallocations),
+            bytes: budget.bytes.saturating_sub(self.stats.bytes_allocated),
+        })
+    }
+
+    /// Adds the operations counted by a nested call to [`count_allocations`].
+    fn absorb(&mut self, inner: &Counter) {
+        let (stats, inner_stats) = (&mut self.stats, &inner.stats);
+        stats.allocations += inner_stats.allocations;
+        stats.deallocations += inner_stats.deallocations;
+        stats.reallocations += inner_stats.reallocations;
+        stats.bytes_allocated = stats
+            .bytes_allocated
+            .saturating_add(inner_stats.bytes_allocated);
+        stats.bytes_freed = stats.bytes_freed.saturating_add(inner_stats.bytes_freed);
+        let peak = self.live.saturating_add(inner_stats.peak_bytes as i64);
+        stats.peak_bytes = stats.peak_bytes.max(peak.max(0) as u64);
+        self.live = self.live.saturating_add(inner.live);
+    }
+}
+
+/// Whether any call to [`count_allocations`] is in progress on the current
+/// thread.
+pub(crate) fn counting() -> bool {
+    COUNTER
+        .try_with(|counter| counter.get().is_some())
+        .unwrap_or(false)
+}
+
+/// Records an operation that allocated `allocated` bytes and freed `freed`
+/// bytes with the innermost call to [`count_allocations`] on the current
+/// thread, if any; returns whether it must be recorded with
+/// [`record_offender`].
+pub(crate) fn record(kind: AllocationKind, allocated: usize, freed: usize) -> bool {
+    COUNTER
+        .try_with(|counter| {
+            let Some(mut current) = counter.get() else {
+                return false;
+            };
+            let offending = current.record(kind, allocated, freed);
+            counter.set(Some(current));
+            offending
+        })
+        .unwrap_or(false)
+}
+
+/// Records an allocation of `size` bytes that exceeded the budget of the
+/// innermost call to [`assert_alloc_budget`] on the current thread.
+///
+/// This allocates (if the `backtrace` feature is enabled), and so must only be
+/// called while allocator operations on the current thread are untraced.
+pub(crate) fn record_offender(kind: AllocationKind, size: usize) {
+    let offender = Offender {
+        kind,
+        size,
+        #[cfg(feature = "backtrace")]
+        caller: crate::callsite::caller(),
+    };
+    let _ = OFFENDERS.try_with(|offenders| {
+        if let Ok(mut offenders) = offenders.try_borrow_mut() {
+            offenders.push(offender);
+        }
+    });
+}
+
+/// Run the given function, and count the allocator operations it performs on
+/// the current thread.
+///
+/// Operations are counted by [`TracingAllocator`](crate::TracingAllocator),
+/// which must be the global allocator, whether or not they are traced; no
+/// subscriber is required. Operations performed by the instrumentation itself
+/// (e.g., by subscribers handling allocation events) are not counted. Calls
+/// may be nested; the operations counted by an inner call are also counted by
+/// the outer call.
+///
+/// ## Usage
+#[cfg_attr(feature = "off", doc = "```ignore")]
+#[cfg_attr(not(feature = "off"), doc = "```")]
+/// use std::alloc::System;
+/// use tracing_allocations::{count_allocations, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// fn main() {
+///     let stats = count_allocations(|| {
+///         let mut v = Vec::<u8>::with_capacity(8);
+///         v.extend_from_slice(&[0; 16]);
+///     });
+///     assert_eq!(stats.allocations, 1);
+///     assert_eq!(stats.reallocations, 1);
+///     assert_eq!(stats.deallocations, 1);
+///     assert_eq!(stats.peak_bytes, 16);
+/// }
+/// ```
+pub fn count_allocations<F>(f: F) -> AllocationStats
+where
+    F: FnOnce(),
+{
+    let scope = Scope::enter(None);
+    f();
+    scope.exit()
+}
+
+/// Run the given function, and panic if it exceeds the given budget of
+/// allocations on the current thread.
+///
+/// The function may perform at most `max_allocs` allocations and
+/// reallocations, which may allocate at most `max_bytes` bytes in total; see
+/// [`AllocationStats`]. Operations are counted as by [`count_allocations`].
+/// If the budget is exceeded, the panic message lists the first few offending
+/// allocations, one per line; with the `backtrace` feature enabled, it also
+/// lists the code that requested each of them.
+///
+/// ## Usage
+#[cfg_attr(feature = "off", doc = "```ignore")]
+#[cfg_attr(not(feature = "off"), doc = "```should_panic")]
+/// use std::alloc::System;
+/// use tracing_allocations::{assert_alloc_budget, TracingAllocator};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+///
+/// fn main() {
+///     let greeting = assert_alloc_budget(1, 64, || format!("hello, {}", "world"));
+///     assert_eq!(greeting, "hello, world");
+///
+///     // panics
+///     assert_alloc_budget(1, 64, || vec![String::from("a"), String::from("b")]);
+/// }
+/// ```
+#[track_caller]
+pub fn assert_alloc_budget<F, R>(max_allocs: u64, max_bytes: u64, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let budget = Budget {
+        allocations: max_allocs,
+        bytes: max_bytes,
+    };
+    let scope = Scope::enter(Some(budget));
+    let result = f();
+    let offenders = OFFENDERS.with(|offenders| *offenders.borrow());
+    let stats = scope.exit();
+    if budget.exceeded_by(&stats) {
+        let mut message = format!(
+            "allocation budget exceeded: {} allocation(s) of {} bytes were performed, \
+             but the budget is {} allocation(s) of {} bytes",
+            stats.allocations + stats.reallocations,
+            stats.bytes_allocated,
+            max_allocs,
+            max_bytes,
+        );
+        let _ = write!(message, "{}", offenders);
+        panic!("{}", message);
+    }
+    result
+}
+
+/// Count the allocator operations performed on the current thread until the
+/// returned guard is dropped, and then record them as the `alloc_bytes` and
+/// `alloc_count` fields of `span`. This supports `#[instrument_allocations]`.
+#[doc(hidden)]
+pub fn __count_in_span(span: &Span) -> SpanCount<'_> {
+    SpanCount {
+        span,
+        scope: Some(Scope::enter(None)),
+    }
+}
+
+/// Count the allocator operations performed whenever `future` is polled, and
+/// record them as the `alloc_bytes` and `alloc_count` fields of `span` when
+/// it completes, or when it is dropped before completing. `span` is entered
+/// whenever `future` is polled. This supports `#[instrument_allocations]` on
+/// `async` functions.
+#[doc(hidden)]
+pub fn __count_in_span_async<F: Future>(span: Span, future: F) -> CountedInSpan<F> {
+    CountedInSpan {
+        inner: future,
+        span,
+        stats: AllocationStats::default(),
+        recorded: false,
+    }
+}
+
+/// Records `stats` as the `alloc_bytes` and `alloc_count` fields of `span`;
+/// like a budget, `alloc_count` counts both allocations and reallocations.
+fn record_in_span(span: &Span, stats: &AllocationStats) {
+    span.record("alloc_bytes", stats.bytes_allocated);
+    span.record("alloc_count", stats.allocations + stats.reallocations);
+}
+
+/// A guard returned by [`__count_in_span`].
+#[doc(hidden)]
+pub struct SpanCount<'a> {
+    span: &'a Span,
+    scope: Option<Scope>,
+}
+
+impl Drop for SpanCount<'_> {
+    fn drop(&mut self) {
+        if let Some(scope) = self.scope.take() {
+            record_in_span(self.span, &scope.exit());
+        }
+    }
+}
+
+/// A future returned by [`__count_in_span_async`].
+#[doc(hidden)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct CountedInSpan<F> {
+    inner: F,
+    span: Span,
+    /// The operations counted by previous polls.
+    stats: AllocationStats,
+    /// Whether `stats` have been recorded in `span`.
+    recorded: bool,
+}
+
+impl<F: Future> Future for CountedInSpan<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // safety: `inner` is structurally pinned; it is never moved
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+        let _entered = this.span.enter();
+        let scope = Scope::enter(None);
+        let poll = inner.poll(cx);
+        let polled = scope.exit();
+        this.stats.allocations += polled.allocations;
+        this.stats.reallocations += polled.reallocations;
+        this.stats.bytes_allocated = this
+            .stats
+            .bytes_allocated
+            .saturating_add(polled.bytes_allocated);
+        if poll.is_ready() {
+            record_in_span(&this.span, &this.stats);
+            this.recorded = true;
+        }
+        poll
+    }
+}
+
+impl<F> Drop for CountedInSpan<F> {
+    fn drop(&mut self) {
+        // a future that is cancelled records what it counted until then
+        if !self.recorded {
+            record_in_span(&self.span, &self.stats);
+        }
+    }
+}
+
+/// A limit on the allocations performed by a call to [`assert_alloc_budget`].
+#[derive(Clone, Copy, Default)]
+struct Budget {
+    /// The greatest number of allocations and reallocations.
+    allocations: u64,
+    /// The greatest number of bytes allocated.
+    bytes: u64,
+}
+
+impl Budget {
+    /// Whether `stats` exceeds this budget.
+    fn exceeded_by(&self, stats: &AllocationStats) -> bool {
+        stats.allocations + stats.reallocations > self.allocations
+            || stats.bytes_allocated > self.bytes
+    }
+}
+
+/// An allocation in excess of a budget.
+#[derive(Clone, Copy)]
+struct Offender {
+    /// The kind of allocation.
+    kind: AllocationKind,
+    /// The size of the allocated block.
+    size: usize,
+    /// The code that requested the allocation, if known.
+    #[cfg(feature = "backtrace")]
+    caller: Option<&'static crate::callsite::Callsite>,
+}
+
+impl fmt::Display for Offender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} bytes", self.kind, self.size)?;
+        #[cfg(feature = "backtrace")]
+        if let Some(caller) = self.caller {
+            write!(f, " at {}", caller)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first few allocations in excess of a budget.
+#[derive(Clone, Copy)]
+struct Offenders {
+    /// The offending allocations, in order.
+    listed: [Option<Offender>; MAX_OFFENDERS],
+    /// The number of offending allocations that are not listed.
+    omitted: u64,
+}
+
+impl Offenders {
+    /// An empty list of offending allocations.
+    const fn new() -> Self {
+        Self {
+            listed: [None; MAX_OFFENDERS],
+            omitted: 0,
+        }
+    }
+
+    /// Adds an offending allocation to this list.
+    fn push(&mut self, offender: Offender) {
+        match self.listed.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(offender),
+            None => self.omitted += 1,
+        }
+    }
+}
+
+impl fmt::Display for Offenders {
+    /// Lists offending allocations, one per line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.listed[0].is_some() {
+            f.write_str("\noffending allocations:")?;
+        }
+        for offender in self.listed.iter().flatten() {
+            write!(f, "\n  {}", offender)?;
+        }
+        if self.omitted > 0 {
+            write!(f, "\n  ... and {} more", self.omitted)?;
+        }
+        Ok(())
+    }
+}
+
+/// A call to [`count_allocations`] or [`assert_alloc_budget`]; restores the
+/// enclosing counter (if any) when dropped, even if the counted function
+/// panics.
+struct Scope {
+    /// The counter of the enclosing call.
+    outer: Option<Counter>,
+    /// If this scope has a budget of its own, the offending allocations of the
+    /// enclosing call.
+    outer_offenders: Option<Offenders>,
+}
+
+impl Scope {
+    /// Installs a fresh counter on the current thread, with the given budget
+    /// or, failing that, whatever remains of the enclosing call's budget.
+    fn enter(budget: Option<Budget>) -> Self {
+        let outer_offenders =
+            budget.map(|_| OFFENDERS.with(|offenders| offenders.replace(Offenders::new())));
+        let outer = COUNTER.with(|counter| {
+            let outer = counter.get();
+            let budget = budget.or_else(|| outer.and_then(|outer| outer.remaining()));
+            counter.replace(Some(Counter::new(budget)))
+        });
+        Self {
+            outer,
+            outer_offenders,
+        }
+    }
+
+    /// Returns the operations counted by this scope, and restores the
+    /// enclosing counter.
+    fn exit(self) -> AllocationStats {
+        let counter = COUNTER.with(Cell::get).unwrap_or_default();
+        drop(self);
+        counter.stats
+    }
+}
+
+impl Drop for Scope {
+    /// Restores the enclosing counter, after adding the operations counted by
+    /// this scope to it.
+    fn drop(&mut self) {
+        if let Some(outer_offenders) = self.outer_offenders.take() {
+            let _ = OFFENDERS.try_with(|offenders| offenders.replace(outer_offenders));
+        }
+        let outer = self.outer.take();
+        let _ = COUNTER.try_with(|counter| {
+            let inner = counter.take().unwrap_or_default();
+            counter.set(outer.map(|mut outer| {
+                outer.absorb(&inner);
+                outer
+            }));
+        });
+    }
+}
@@ -0,0 +1,135 @@
+//! Human-readable summaries of the allocator operations performed inside each
+//! span.
+//!
+//! [`SummaryLayer`] writes one line per span as it closes, in the manner of
+//! the `fmt` layer of `tracing_subscriber`, so that the cost of each span can
+//! be read without any further tooling.
+
+use std::io::{self, Write as _};
+
+use tracing::{span, Event, Subscriber};
+use tracing_subscriber::{fmt::MakeWriter, layer::Context, registry::LookupSpan, Layer};
+
+use crate::{event::AllocationEvent, global::Bytes, layer::Tally};
+
+/// A [`Layer`] that writes a one-line summary of the allocator operations
+/// performed inside each span when it closes, e.g.:
+///
+/// ```text
+/// span `handle_request` closed: +1.2 MiB / 493 allocs / peak 2.1 MiB
+/// ```
+///
+/// The summary gives the number of bytes allocated inside the span less the
+/// number freed, the number of allocations, and the greatest number of bytes
+/// that were allocated and not yet freed at once. As with
+/// [`SpanStatsLayer`](crate::SpanStatsLayer), the operations performed inside
+/// a span include those performed inside its descendants, and sampled and
+/// coalesced events are scaled by [`AllocationEvent::weight`]. Summaries are
+/// written to standard error, unless another writer is given with
+/// [`SummaryLayer::with_writer`].
+///
+/// ## Usage
+/// ```
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::SummaryLayer;
+///
+/// tracing_subscriber::registry().with(SummaryLayer::new()).init();
+///
+/// tracing::info_span!("handle_request").in_scope(|| { /* your code here */ });
+/// ```
+#[derive(Clone, Debug)]
+pub struct SummaryLayer<W = fn() -> io::Stderr> {
+    make_writer: W,
+}
+
+impl SummaryLayer {
+    /// Constructs a new `SummaryLayer`, which writes to standard error.
+    pub fn new() -> Self {
+        Self {
+            make_writer: io::stderr,
+        }
+    }
+}
+
+impl Default for SummaryLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W> SummaryLayer<W> {
+    /// Write summaries to the writers made by `make_writer`, rather than to
+    /// standard error.
+    ///
+    /// ## Usage
+    /// ```
+    /// use tracing_subscriber::prelude::*;
+    /// use tracing_allocations::SummaryLayer;
+    ///
+    /// tracing_subscriber::registry()
+    ///     .with(SummaryLayer::new().with_writer(std::io::stdout))
+    ///     .init();
+    /// ```
+    pub fn with_writer<W2>(self, make_writer: W2) -> SummaryLayer<W2>
+    where
+        W2: for<'w> MakeWriter<'w> + 'static,
+    {
+        SummaryLayer { make_writer }
+    }
+}
+
+/// The extension attached to each span, holding its counts.
+struct Summarized(Tally);
+
+impl<S, W> Layer<S> for SummaryLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            crate::disable_in_scope(|| {
+                span.extensions_mut().insert(Summarized(Tally::default()));
+            });
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(scope) = ctx.event_scope(event) else {
+            return;
+        };
+        let Some(event) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        for span in scope {
+            if let Some(Summarized(tally)) = span.extensions().get::<Summarized>() {
+                tally.record(&event);
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(stats) = span
+            .extensions()
+            .get::<Summarized>()
+            .map(|Summarized(tally)| tally.stats())
+        else {
+            return;
+        };
+        let net = stats.bytes_allocated.wrapping_sub(stats.bytes_freed) as i64;
+        crate::disable_in_scope(|| {
+            let _ = writeln!(
+                self.make_writer.make_writer(),
+                "span `{}` closed: {}{} / {} allocs / peak {}",
+                span.name(),
+                if net < 0 { '-' } else { '+' },
+                Bytes(net.unsigned_abs()),
+                stats.allocations,
+                Bytes(stats.peak_bytes),
+            );
+        });
+    }
+}
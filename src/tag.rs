@@ -0,0 +1,49 @@
+//! Tagging of the events emitted within a scope.
+//!
+//! Tags group allocations by the logical subsystem that performed them,
+//! rather than by callsite. The tag of the innermost call to [`tag_in_scope`]
+//! on the current thread is attached to each event as its `tag` field.
+
+use core::cell::Cell;
+
+thread_local! {
+    /// The tag of the innermost call to [`tag_in_scope`] on this thread, if
+    /// any.
+    static TAG: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// The `tag` field of emitted events, if any.
+pub(crate) fn current() -> Option<&'static str> {
+    TAG.try_with(Cell::get).ok().flatten()
+}
+
+/// Run the given function with `tag` attached to the allocation events it
+/// causes to be emitted on the current thread.
+///
+/// Calls may be nested; events are tagged by the innermost call.
+///
+/// ## Usage
+/// ```
+/// use tracing_allocations::tag_in_scope;
+///
+/// # fn upload_textures() {}
+/// tag_in_scope("texture-upload", || {
+///     upload_textures();
+/// });
+/// ```
+pub fn tag_in_scope<F, R>(tag: &'static str, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    /// Restores the enclosing tag, even if `f` panics.
+    struct Restore(Option<&'static str>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let _ = TAG.try_with(|current| current.set(self.0));
+        }
+    }
+
+    let _restore = Restore(TAG.with(|current| current.replace(Some(tag))));
+    f()
+}
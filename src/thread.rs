@@ -0,0 +1,162 @@
+//! Threads that inherit the allocation tracing state of their parent.
+//!
+//! Whether allocation tracing is enabled is a property of each thread, so a
+//! thread spawned with [`std::thread::spawn`] from within
+//! [`disable_in_scope`](crate::disable_in_scope) starts with tracing enabled.
+//! The [`spawn`] function and [`Builder`] of this module instead start the
+//! child with the state of the parent at the time it was spawned.
+
+#[cfg(any(
+    feature = "arrow",
+    feature = "chrome",
+    feature = "csv",
+    feature = "measureme",
+    feature = "sqlite",
+    feature = "timeline"
+))]
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::{io, thread};
+
+/// The number of threads that have been numbered so far.
+#[cfg(any(
+    feature = "arrow",
+    feature = "chrome",
+    feature = "csv",
+    feature = "measureme",
+    feature = "sqlite",
+    feature = "timeline"
+))]
+static NUMBERED: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(any(
+    feature = "arrow",
+    feature = "chrome",
+    feature = "csv",
+    feature = "measureme",
+    feature = "sqlite",
+    feature = "timeline"
+))]
+thread_local! {
+    /// The number of this thread; see [`number`].
+    static NUMBER: u64 = NUMBERED.fetch_add(1, Ordering::Relaxed) + 1;
+}
+
+/// The number of the current thread, in the order in which threads first
+/// asked for their number, starting from 1; unlike [`thread::ThreadId`], this
+/// is stable and compact enough to be written to traces.
+#[cfg(any(
+    feature = "arrow",
+    feature = "chrome",
+    feature = "csv",
+    feature = "measureme",
+    feature = "sqlite",
+    feature = "timeline"
+))]
+pub(crate) fn number() -> u64 {
+    NUMBER.try_with(|number| *number).unwrap_or(0)
+}
+
+/// Spawns a new thread, in which allocation tracing is enabled if and only if
+/// it is enabled in the current thread.
+///
+/// This is otherwise identical to [`std::thread::spawn`].
+///
+/// ## Usage
+/// ```
+/// tracing_allocations::disable_in_scope(|| {
+///     tracing_allocations::spawn(|| {
+///         assert!(!tracing_allocations::is_enabled());
+///     })
+///     .join()
+///     .unwrap();
+/// });
+/// ```
+pub fn spawn<F, T>(f: F) -> thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::spawn(inherit(f))
+}
+
+/// Thread factory, which can be used in order to configure the properties of
+/// a new thread, in which allocation tracing is enabled if and only if it is
+/// enabled in the thread that spawns it.
+///
+/// This wraps [`std::thread::Builder`]; see its documentation for details.
+///
+/// ## Usage
+/// ```
+/// use tracing_allocations::thread::Builder;
+///
+/// let handle = Builder::new()
+///     .name("worker".into())
+///     .spawn(|| { /* your code here */ })
+///     .unwrap();
+/// handle.join().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Builder {
+    inner: thread::Builder,
+}
+
+impl Builder {
+    /// Generates the base configuration for spawning a thread, from which
+    /// configuration methods can be chained.
+    pub fn new() -> Self {
+        Self {
+            inner: thread::Builder::new(),
+        }
+    }
+
+    /// Names the thread-to-be. See [`std::thread::Builder::name`].
+    pub fn name(self, name: String) -> Self {
+        Self {
+            inner: self.inner.name(name),
+        }
+    }
+
+    /// Sets the size of the stack (in bytes) for the new thread. See
+    /// [`std::thread::Builder::stack_size`].
+    pub fn stack_size(self, size: usize) -> Self {
+        Self {
+            inner: self.inner.stack_size(size),
+        }
+    }
+
+    /// Spawns a new thread by taking ownership of the `Builder`, and returns
+    /// an [`io::Result`] to its [`JoinHandle`](thread::JoinHandle). See
+    /// [`std::thread::Builder::spawn`].
+    pub fn spawn<F, T>(self, f: F) -> io::Result<thread::JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.inner.spawn(inherit(f))
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<thread::Builder> for Builder {
+    fn from(inner: thread::Builder) -> Self {
+        Self { inner }
+    }
+}
+
+/// Wraps `f` so that it runs with the allocation tracing state of the current
+/// thread.
+fn inherit<F, T>(f: F) -> impl FnOnce() -> T
+where
+    F: FnOnce() -> T,
+{
+    let enabled = crate::scope_enabled();
+    move || {
+        crate::replace_enabled(enabled);
+        f()
+    }
+}
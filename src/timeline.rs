@@ -0,0 +1,404 @@
+//! Time series of live bytes.
+//!
+//! [`TimelineLayer`] records the number of live bytes, in total, by thread
+//! and by [tag](crate::tag_in_scope), at a fixed resolution, so that the heap
+//! may be plotted over time, and its spikes correlated with the events of the
+//! application.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::{
+    event::{AllocationEvent, AllocationKind},
+    json::JsonStr,
+};
+
+/// A [`Layer`] that records a time series of the live bytes of the
+/// allocations described by the events it observes.
+///
+/// Time is divided into intervals of 100 milliseconds, or of the duration
+/// given to [`TimelineLayer::with_resolution`], and measured by the
+/// `timestamp_ns` field of events if present, and by
+/// [`monotonic_nanos`](crate::monotonic_nanos) otherwise. The layer records
+/// the following series, each of which is sampled at the end of each interval
+/// in which it changed:
+/// - **`total`**  
+///   the live bytes of all blocks
+/// - **`thread`**  
+///   for each thread, the live bytes of the blocks it allocated, whether or
+///   not they were freed by the same thread
+/// - **`tag`**  
+///   for each [tag](crate::tag_in_scope), the live bytes of the blocks
+///   allocated under it
+///
+/// Only blocks whose allocation was observed are
+/// counted. Sampled and coalesced events are scaled by
+/// [`AllocationEvent::weight`].
+///
+/// The time series may be written at any time, from any thread, through the
+/// [`TimelineHandle`] returned by [`TimelineLayer::handle`], as CSV or JSON.
+///
+/// Requires the `timeline` feature.
+///
+/// ## Usage
+/// ```no_run
+/// use std::time::Duration;
+/// use tracing_subscriber::prelude::*;
+/// use tracing_allocations::TimelineLayer;
+///
+/// let layer = TimelineLayer::new().with_resolution(Duration::from_millis(10));
+/// let timeline = layer.handle();
+///
+/// tracing_subscriber::registry().with(layer).init();
+///
+/// /* your code here */
+///
+/// let file = std::fs::File::create("timeline.csv").unwrap();
+/// timeline.write_csv_to(file).unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TimelineLayer {
+    handle: TimelineHandle,
+}
+
+impl TimelineLayer {
+    /// Constructs a new `TimelineLayer`, with an empty time series.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Divide time into intervals of `resolution`, rather than 100
+    /// milliseconds.
+    pub fn with_resolution(self, resolution: Duration) -> Self {
+        self.handle.timeline().resolution = (resolution.as_nanos() as u64).max(1);
+        self
+    }
+
+    /// A handle through which to write the time series of this layer.
+    pub fn handle(&self) -> TimelineHandle {
+        self.handle.clone()
+    }
+}
+
+impl<S> Layer<S> for TimelineLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(decoded) = AllocationEvent::from_event(event) else {
+            return;
+        };
+        crate::disable_in_scope(|| {
+            let mut tag = String::new();
+            let tag = crate::event::tag_of(event, &mut tag).then_some(tag);
+            self.handle.timeline().record(&decoded, tag);
+        });
+    }
+}
+
+/// A handle to the time series of a [`TimelineLayer`].
+///
+/// Handles are cheap to clone, and all clones write the same time series.
+#[derive(Clone, Default)]
+pub struct TimelineHandle {
+    timeline: Arc<Mutex<Timeline>>,
+}
+
+impl TimelineHandle {
+    /// Writes the time series recorded so far to `writer`, as CSV, with one
+    /// row per sample, in order of time, and the following columns:
+    /// - **`time_ns`**  
+    ///   the start of the interval, in nanoseconds
+    /// - **`series`**  
+    ///   "total", "thread" or "tag"
+    /// - **`name`**  
+    ///   the name of the thread (or, if it has none, "thread" followed by its
+    ///   number), or the tag; empty for "total"
+    /// - **`live_bytes`**  
+    ///   the number of live bytes at the end of the interval
+    pub fn write_csv_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        crate::disable_in_scope(|| {
+            let timeline = self.timeline();
+            let mut samples: Vec<(u64, &Series, u64)> = timeline
+                .series
+                .iter()
+                .flat_map(|series| {
+                    let samples = timeline.samples(series);
+                    samples.map(move |(time, live)| (time, series, live))
+                })
+                .collect();
+            samples.sort_by_key(|&(time, ..)| time);
+            writeln!(writer, "time_ns,series,name,live_bytes")?;
+            let mut name = String::new();
+            for (time, series, live) in samples {
+                name.clear();
+                quote(&mut name, &series.name);
+                writeln!(
+                    writer,
+                    "{},{},{},{}",
+                    time,
+                    series.kind.as_str(),
+                    name,
+                    live
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes the time series recorded so far to `writer`, as a JSON object,
+    /// with the following members:
+    /// - **`resolution_ns`**  
+    ///   the duration of each interval, in nanoseconds
+    /// - **`series`**  
+    ///   an array of objects, one per series, with its `kind` ("total",
+    ///   "thread" or "tag"), its `name` (as for
+    ///   [`write_csv_to`](TimelineHandle::write_csv_to); `null` for
+    ///   "total"), and its `samples`, an array of pairs of the start of an
+    ///   interval, in nanoseconds, and the number of live bytes at its end
+    pub fn write_json_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        crate::disable_in_scope(|| {
+            let timeline = self.timeline();
+            write!(
+                writer,
+                "{{\"resolution_ns\":{},\"series\":[",
+                timeline.resolution
+            )?;
+            for (i, series) in timeline.series.iter().enumerate() {
+                let separator = if i == 0 { "" } else { "," };
+                write!(
+                    writer,
+                    "{}{{\"kind\":\"{}\",",
+                    separator,
+                    series.kind.as_str()
+                )?;
+                match series.kind {
+                    Kind::Total => write!(writer, "\"name\":null,")?,
+                    _ => write!(writer, "\"name\":{},", JsonStr(&series.name))?,
+                }
+                write!(writer, "\"samples\":[")?;
+                for (j, (time, live)) in timeline.samples(series).enumerate() {
+                    let separator = if j == 0 { "" } else { "," };
+                    write!(writer, "{}[{},{}]", separator, time, live)?;
+                }
+                write!(writer, "]}}")?;
+            }
+            writeln!(writer, "]}}")
+        })
+    }
+
+    fn timeline(&self) -> MutexGuard<'_, Timeline> {
+        self.timeline.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl core::fmt::Debug for TimelineHandle {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TimelineHandle").finish_non_exhaustive()
+    }
+}
+
+/// The time series recorded by a [`TimelineLayer`].
+struct Timeline {
+    /// The duration of each interval, in nanoseconds.
+    resolution: u64,
+    /// The start of the current interval, once an event has been recorded.
+    interval: Option<u64>,
+    /// The series; the first is the total.
+    series: Vec<Series>,
+    /// The index of the series of each thread, by its number.
+    threads: HashMap<u64, usize>,
+    /// The index of the series of each tag.
+    tags: HashMap<String, usize>,
+    /// The live blocks, by address.
+    blocks: HashMap<u64, Block>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self {
+            resolution: 100_000_000,
+            interval: None,
+            series: vec![Series::new(Kind::Total, String::new())],
+            threads: HashMap::new(),
+            tags: HashMap::new(),
+            blocks: HashMap::new(),
+        }
+    }
+}
+
+/// A live block.
+struct Block {
+    /// The size of the block, scaled by the weight of its event.
+    size: u64,
+    /// The index of the series of the thread that allocated it.
+    thread: usize,
+    /// The index of the series of the tag under which it was allocated, if
+    /// any.
+    tag: Option<usize>,
+}
+
+impl Block {
+    /// The indices of the series that count this block.
+    fn series(&self) -> impl Iterator<Item = usize> {
+        [Some(0), Some(self.thread), self.tag].into_iter().flatten()
+    }
+}
+
+/// A time series of live bytes.
+struct Series {
+    kind: Kind,
+    name: String,
+    /// The number of live bytes.
+    live: u64,
+    /// Whether `live` has changed during the current interval.
+    changed: bool,
+    /// The start of each interval in which `live` changed, and its value at
+    /// the end of that interval.
+    samples: Vec<(u64, u64)>,
+}
+
+/// The kind of a [`Series`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Total,
+    Thread,
+    Tag,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Total => "total",
+            Kind::Thread => "thread",
+            Kind::Tag => "tag",
+        }
+    }
+}
+
+impl Series {
+    fn new(kind: Kind, name: String) -> Self {
+        Self {
+            kind,
+            name,
+            live: 0,
+            changed: false,
+            samples: Vec::new(),
+        }
+    }
+}
+
+impl Timeline {
+    /// Records the operation described by `event`, which was performed by the
+    /// current thread, under `tag`.
+    fn record(&mut self, event: &AllocationEvent, tag: Option<String>) {
+        let now = event.timestamp_ns.unwrap_or_else(crate::monotonic_nanos);
+        let start = now - now % self.resolution;
+        match self.interval {
+            // events of distinct threads may be observed slightly out of
+            // order; those of an earlier interval count towards the current
+            Some(interval) if start <= interval => {}
+            Some(interval) => {
+                self.close(interval);
+                self.interval = Some(start);
+            }
+            None => self.interval = Some(start),
+        }
+
+        let weight = event.weight();
+        let size = (event.size as f64 * weight).round() as u64;
+        match event.kind {
+            AllocationKind::Alloc | AllocationKind::AllocZeroed => {
+                self.allocate(event.addr, size, tag)
+            }
+            AllocationKind::Dealloc => self.free(event.addr),
+            AllocationKind::Realloc => {
+                self.free(event.old_addr.unwrap_or(event.addr));
+                self.allocate(event.addr, size, tag);
+            }
+        }
+    }
+
+    /// Records the allocation of `size` bytes at `addr`.
+    fn allocate(&mut self, addr: u64, size: u64, tag: Option<String>) {
+        self.free(addr);
+        let number = crate::thread::number();
+        let series = &mut self.series;
+        let thread = *self.threads.entry(number).or_insert_with(|| {
+            let name = match std::thread::current().name() {
+                Some(name) => String::from(name),
+                None => format!("thread {}", number),
+            };
+            series.push(Series::new(Kind::Thread, name));
+            series.len() - 1
+        });
+        let tag = tag.map(|tag| match self.tags.get(&tag) {
+            Some(&index) => index,
+            None => {
+                series.push(Series::new(Kind::Tag, tag.clone()));
+                self.tags.insert(tag, series.len() - 1);
+                series.len() - 1
+            }
+        });
+        let block = Block { size, thread, tag };
+        for index in block.series() {
+            let series = &mut self.series[index];
+            series.live = series.live.wrapping_add(size);
+            series.changed = true;
+        }
+        self.blocks.insert(addr, block);
+    }
+
+    /// Records the deallocation of the block at `addr`, if it is live.
+    fn free(&mut self, addr: u64) {
+        let Some(block) = self.blocks.remove(&addr) else {
+            return;
+        };
+        for index in block.series() {
+            let series = &mut self.series[index];
+            series.live = series.live.wrapping_sub(block.size);
+            series.changed = true;
+        }
+    }
+
+    /// Records the samples of the interval starting at `interval`.
+    fn close(&mut self, interval: u64) {
+        for series in &mut self.series {
+            if series.changed {
+                series.changed = false;
+                series.samples.push((interval, series.live));
+            }
+        }
+    }
+
+    /// The samples of `series`, including that of the current interval,
+    /// which has yet to end.
+    fn samples<'s>(&self, series: &'s Series) -> impl Iterator<Item = (u64, u64)> + 's {
+        let pending = series
+            .changed
+            .then_some((self.interval.unwrap_or(0), series.live));
+        series.samples.iter().copied().chain(pending)
+    }
+}
+
+/// Appends `field` to `row`, quoted if necessary.
+fn quote(row: &mut String, field: &str) {
+    if !field.contains([',', '"', '\n', '\r']) {
+        return row.push_str(field);
+    }
+    row.push('"');
+    for c in field.chars() {
+        if c == '"' {
+            row.push('"');
+        }
+        row.push(c);
+    }
+    row.push('"');
+}
@@ -0,0 +1,15 @@
+//! Checkpoints do not count the bookkeeping of checkpoints.
+
+use std::alloc::System;
+
+use tracing_allocations::{checkpoint, diff, AllocationCounts, TracingAllocator};
+
+#[global_allocator]
+static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System);
+
+#[test]
+fn consecutive_checkpoints_do_not_differ() {
+    checkpoint("first");
+    checkpoint("second");
+    assert_eq!(diff("first", "second"), Some(AllocationCounts::default()));
+}
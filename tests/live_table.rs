@@ -0,0 +1,53 @@
+//! The live table is kept up to date by operations that are not traced.
+
+use std::alloc::System;
+
+use tracing_allocations::{
+    disable_for_thread, disable_in_scope, snapshot, tag_in_scope, TracingAllocator,
+};
+
+#[global_allocator]
+static ALLOCATOR: TracingAllocator<System> = TracingAllocator::new(System).with_live_table(true);
+
+/// The live bytes allocated under `tag`, according to a snapshot.
+fn live_bytes(tag: &str) -> u64 {
+    snapshot()
+        .groups
+        .iter()
+        .filter(|group| group.tag.as_deref() == Some(tag))
+        .map(|group| group.bytes)
+        .sum()
+}
+
+#[test]
+fn free_in_disable_in_scope() {
+    let block = tag_in_scope("free_in_disable_in_scope", || vec![0u8; 4096]);
+    assert_eq!(live_bytes("free_in_disable_in_scope"), 4096);
+    disable_in_scope(|| drop(block));
+    assert_eq!(live_bytes("free_in_disable_in_scope"), 0);
+}
+
+#[test]
+fn realloc_in_disable_in_scope() {
+    let mut block = tag_in_scope("realloc_in_disable_in_scope", || vec![0u8; 4096]);
+    disable_in_scope(|| block.reserve_exact(1 << 20));
+    assert_eq!(
+        live_bytes("realloc_in_disable_in_scope"),
+        block.capacity() as u64
+    );
+    disable_in_scope(|| drop(block));
+    assert_eq!(live_bytes("realloc_in_disable_in_scope"), 0);
+}
+
+#[test]
+fn free_after_disable_for_thread() {
+    std::thread::spawn(|| {
+        let block = tag_in_scope("free_after_disable_for_thread", || vec![0u8; 4096]);
+        assert_eq!(live_bytes("free_after_disable_for_thread"), 4096);
+        disable_for_thread();
+        drop(block);
+    })
+    .join()
+    .unwrap();
+    assert_eq!(live_bytes("free_after_disable_for_thread"), 0);
+}